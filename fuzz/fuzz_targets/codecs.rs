@@ -0,0 +1,50 @@
+//! Feeds arbitrary byte streams split into arbitrary reads through the codecs the way `Framed`
+//! does, checking that every frame takes some of the data read but not more than that, and
+//! encodes back to exactly those bytes.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use uring::codec::{Codec, LengthPrefixed, Lines, Raw};
+
+const MAX_MESSAGE_SIZE: usize = 64;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&read_size, data)) = data.split_first() else {
+        return;
+    };
+
+    let read_size = usize::from(read_size).max(1);
+    let max_line_size = MAX_MESSAGE_SIZE;
+    let max_message_size = MAX_MESSAGE_SIZE;
+
+    decode(Raw, data, read_size);
+    decode(Lines { max_line_size }, data, read_size);
+    decode(LengthPrefixed { max_message_size }, data, read_size);
+});
+
+fn decode(mut codec: impl Codec<Frame = Vec<u8>>, data: &[u8], read_size: usize) {
+    let mut read_buffer = Vec::new();
+
+    for read in data.chunks(read_size) {
+        read_buffer.extend_from_slice(read);
+
+        loop {
+            let (frame, len) = match codec.decode(&read_buffer) {
+                Ok(Some(decoded)) => decoded,
+                Ok(None) => break,
+                // Protocol errors close the connection.
+                Err(_) => return,
+            };
+
+            assert!(len > 0, "No progress");
+            assert!(len <= read_buffer.len(), "Frame past the data read");
+
+            let mut encoded = Vec::new();
+            codec.encode(&frame, &mut encoded).unwrap();
+            assert_eq!(encoded, read_buffer[..len]);
+
+            read_buffer.drain(..len);
+        }
+    }
+}
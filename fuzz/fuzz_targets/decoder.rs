@@ -0,0 +1,66 @@
+//! Feeds arbitrary byte streams split into arbitrary reads through the streaming `Decoder` the
+//! echo uses, checking that it finds the same frames as `Framing::frame` does in all the data
+//! at once, failing on the same oversize frame, and as `Framing::payloads` does in its complete
+//! frames.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use uring::{Decoder, Framing, Message};
+
+const MAX_MESSAGE_SIZE: usize = 64;
+
+fuzz_target!(|data: &[u8]| {
+    let Some((&read_size, data)) = data.split_first() else {
+        return;
+    };
+
+    let read_size = usize::from(read_size).max(1);
+    check(Framing::Lines, data, read_size);
+    check(Framing::LengthPrefixed, data, read_size);
+});
+
+fn check(framing: Framing, data: &[u8], read_size: usize) {
+    let mut frames = Vec::new();
+    let mut complete_len = 0;
+
+    let oversize = loop {
+        match framing.frame(&data[complete_len..], MAX_MESSAGE_SIZE) {
+            Ok(Some((payload, len))) => {
+                frames.push(&data[complete_len..][payload]);
+                complete_len += len;
+            }
+            Ok(None) => break false,
+            Err(_) => break true,
+        }
+    };
+
+    if !oversize {
+        assert_eq!(framing.complete_len(data, MAX_MESSAGE_SIZE).unwrap(), complete_len);
+        assert!(framing.payloads(&data[..complete_len]).eq(frames.iter().copied()));
+    }
+
+    let mut decoder = Decoder::new(framing, MAX_MESSAGE_SIZE);
+    let mut frames = frames.iter();
+
+    for read in data.chunks(read_size) {
+        // Protocol errors close the connection.
+        let Ok(messages) = decoder.feed(read) else {
+            assert!(oversize, "Frame failed as oversize");
+            return;
+        };
+
+        for message in messages {
+            let frame = frames.next().expect("Frame not found in all the data");
+
+            match message {
+                Message::Whole(payload) => assert_eq!(payload, *frame),
+                Message::Streamed(len) => assert_eq!(len, frame.len()),
+            }
+        }
+    }
+
+    assert!(!oversize, "Oversize frame missed");
+    assert!(frames.next().is_none(), "Frame missed");
+    assert_eq!(decoder.at_boundary(), complete_len == data.len());
+}
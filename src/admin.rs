@@ -0,0 +1,422 @@
+//! Control socket served by the first worker: a command per line, answered with the lines of
+//! its output followed by `ok`, or with `error: <reason>`.
+
+use std::num::NonZeroU64;
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use clap::ValueEnum;
+use io_uring::opcode::{Read, Recv, Send};
+use io_uring::types::Fd;
+use socket2::{Domain, SockAddr, Socket, Type};
+
+use crate::builder::Handle;
+use crate::common::Id;
+use crate::handover;
+use crate::io::Io;
+use crate::log::{self, LogLevel};
+use crate::metrics::Metrics;
+use crate::remote::Command;
+use crate::utils::{self, Errno};
+
+/// Longer commands close the session as nothing this long is valid.
+const MAX_LINE: usize = 1024;
+
+/// How long to wait for the workers to answer, as those which have stopped never do.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+const HELP: &[&str] = &[
+    "stats                 metrics summed up over the workers",
+    "list-clients          connections of all the workers",
+    "kick [<worker>:]<id>  disconnect a client, of the first worker unless given",
+    "set-log-level <level> error, warn, info, debug or trace until the next reload",
+    "set <setting> <value> log-level, max-connections, idle-timeout-ms, rate-limit-bytes or",
+    "                      rate-limit-messages for new connections until the next reload, a",
+    "                      limit lifted with `none`; with --existing before the setting for the",
+    "                      connections served already as well",
+    "drain                 stop accepting, let clients finish until the drain deadline and",
+    "                      exit then, right away if repeated",
+    "help                  this list",
+];
+
+/// The listening socket, removed once the server is done with it.
+pub struct AdminSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl AdminSocket {
+    /// Serves on the `listener` bound at the `path`, e.g. one taken over from the previous
+    /// server.
+    pub fn new(listener: UnixListener, path: &Path) -> Self {
+        Self {
+            listener,
+            path: path.to_owned(),
+        }
+    }
+
+    /// Binds the socket accessible to the owner only. A socket left by a server which hasn't
+    /// exited cleanly is replaced unless somebody still listens on it.
+    ///
+    /// Restricted before listening, as connecting only takes the permissions then and a session
+    /// connected earlier would outlive the restriction.
+    pub fn listen(path: &Path) -> Result<UnixListener> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                bail!("Another server serves the admin socket");
+            }
+
+            std::fs::remove_file(path).context("Remove stale admin socket")?;
+        }
+
+        let socket = Socket::new(Domain::UNIX, Type::STREAM, None).context("Socket")?;
+
+        socket
+            .bind(&SockAddr::unix(path).context("Address")?)
+            .context("Bind admin socket")?;
+
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+            .context("Restrict admin socket")?;
+
+        socket.listen(128).context("Listen")?;
+        Ok(UnixListener::from(OwnedFd::from(socket)))
+    }
+}
+
+impl AsRawFd for AdminSocket {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for AdminSocket {
+    fn drop(&mut self) {
+        // The next server serves it then.
+        if !handover::is_handed_over() {
+            std::fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+/// What a session asks every worker about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Request {
+    /// A line per client.
+    ListClients,
+    /// Disconnect the client and answer with its line if the worker has it.
+    Kick { worker_id: usize, id: Id },
+    /// Change the setting for new connections, and for the existing ones as well if asked to,
+    /// answering with how many have been changed then.
+    Set { setting: Setting, existing: bool },
+}
+
+/// A setting of the workers the admin may change, `None` lifting the limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Setting {
+    /// Connections over it already are kept.
+    MaxConnections(Option<usize>),
+    IdleTimeout(Option<Duration>),
+    RateLimitBytes(Option<NonZeroU64>),
+    RateLimitMessages(Option<NonZeroU64>),
+}
+
+impl Setting {
+    fn parse(name: &str, value: &str) -> Result<Self> {
+        fn limit<T: std::str::FromStr>(value: &str) -> Result<Option<T>>
+        where
+            T::Err: std::fmt::Display,
+        {
+            match value {
+                "none" => Ok(None),
+                value => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|err| anyhow!("Invalid {value}: {err}")),
+            }
+        }
+
+        match name {
+            "max-connections" => Ok(Self::MaxConnections(limit(value)?)),
+            "idle-timeout-ms" => Ok(Self::IdleTimeout(limit(value)?.map(Duration::from_millis))),
+            "rate-limit-bytes" => Ok(Self::RateLimitBytes(limit(value)?)),
+            "rate-limit-messages" => Ok(Self::RateLimitMessages(limit(value)?)),
+            name => bail!("Unknown setting {name}"),
+        }
+    }
+}
+
+/// The lines the workers answer a [`Request`] with, each bumping the eventfd once it has added
+/// its part.
+pub struct Reply {
+    lines: Mutex<Vec<String>>,
+    eventfd: OwnedFd,
+}
+
+impl Reply {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            lines: Mutex::default(),
+            eventfd: utils::eventfd().context("Create eventfd")?,
+        })
+    }
+
+    pub fn add(&self, lines: Vec<String>) -> Result<()> {
+        self.lines().extend(lines);
+        utils::bump(&self.eventfd).context("Wake admin session up")
+    }
+
+    /// Waits for the parts of the `workers` and takes the lines.
+    async fn wait(&self, io: &Io, workers: usize) -> Result<Vec<String>> {
+        let mut answered = 0;
+        let mut count = Box::new(0u64);
+
+        while answered < workers as u64 {
+            let sqe = Read::new(
+                Fd(self.eventfd.as_raw_fd()),
+                &mut *count as *mut u64 as *mut u8,
+                std::mem::size_of::<u64>() as u32,
+            );
+
+            // Kept by the read which outlives waiting if the workers take too long.
+            let cqe;
+            (cqe, count) = io
+                .submit_keeping(sqe.build(), count, "admin reply read")
+                .await?;
+
+            match cqe.result() {
+                errno if errno < 0 => bail!("Read admin reply: {}", Errno(-errno)),
+                _ => answered += *count,
+            }
+        }
+
+        Ok(std::mem::take(&mut *self.lines()))
+    }
+
+    fn lines(&self) -> std::sync::MutexGuard<'_, Vec<String>> {
+        self.lines.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+fn set_log_level(level: &str) -> Result<Vec<String>> {
+    let level = LogLevel::from_str(level, true).map_err(|err| anyhow!(err))?;
+    log::set_level(level);
+    info!("Log level set to {level} by admin");
+    Ok(Vec::new())
+}
+
+/// A connection to the admin socket.
+pub struct Session {
+    socket: OwnedFd,
+    io: Io,
+    handle: Handle,
+    metrics: Arc<Metrics>,
+}
+
+impl Session {
+    pub fn new(socket: OwnedFd, io: Io, handle: Handle, metrics: Arc<Metrics>) -> Self {
+        Self {
+            socket,
+            io,
+            handle,
+            metrics,
+        }
+    }
+
+    /// Runs the commands until the other end closes the connection.
+    pub async fn serve(self) -> Result<()> {
+        let mut input = Vec::new();
+        let mut buffer = vec![0; MAX_LINE];
+
+        loop {
+            let sqe = Recv::new(
+                Fd(self.socket.as_raw_fd()),
+                buffer.as_mut_ptr(),
+                buffer.len() as u32,
+            );
+
+            let cqe;
+            (cqe, buffer) = self
+                .io
+                .submit_keeping(sqe.build(), buffer, "admin receive")
+                .await?;
+
+            let len = match cqe.result() {
+                0 => return Ok(()),
+                errno if errno < 0 => bail!("Receive admin command: {}", Errno(-errno)),
+                len => len as usize,
+            };
+
+            input.extend_from_slice(&buffer[..len]);
+
+            while let Some(end) = input.iter().position(|&byte| byte == b'\n') {
+                let line = input.drain(..=end).collect::<Vec<_>>();
+                let line = String::from_utf8_lossy(&line);
+                let line = line.trim();
+
+                if line.is_empty() {
+                    continue;
+                }
+
+                debug!("Admin command: {line}");
+
+                let output = match self.run(line).await {
+                    Ok(mut lines) => {
+                        lines.push("ok".into());
+                        lines
+                    }
+                    Err(err) => vec![format!("error: {err:#}")],
+                };
+
+                self.send(output).await?;
+            }
+
+            if input.len() > MAX_LINE {
+                return self.send(vec!["error: Command too long".into()]).await;
+            }
+        }
+    }
+
+    async fn run(&self, line: &str) -> Result<Vec<String>> {
+        let mut words = line.split_whitespace();
+        let command = words.next().unwrap_or_default();
+        let args = words.collect::<Vec<_>>();
+
+        match (command, args.as_slice()) {
+            ("help", []) => Ok(HELP.iter().map(|&line| line.into()).collect()),
+            ("stats", []) => Ok(self
+                .metrics
+                .snapshot()
+                .metrics()
+                .into_iter()
+                .map(|(name, _, value)| format!("{name}={value}"))
+                .collect()),
+            ("list-clients", []) => self.ask(Request::ListClients).await,
+            ("kick", [client]) => {
+                let (worker_id, id) = match client.split_once(':') {
+                    Some((worker_id, id)) => (worker_id.parse().ok(), id.parse().ok()),
+                    None => (Some(0), client.parse().ok()),
+                };
+
+                let (Some(worker_id), Some(id)) = (worker_id, id) else {
+                    bail!("Invalid client {client}, expected [<worker>:]<id>");
+                };
+
+                let lines = self.ask(Request::Kick { worker_id, id }).await?;
+
+                if lines.is_empty() {
+                    bail!("No client {worker_id}:{id}");
+                }
+
+                Ok(lines)
+            }
+            ("set-log-level", [level]) => set_log_level(level),
+            ("set", ["--existing", name, value]) => self.set(name, value, true).await,
+            ("set", [name, value]) => self.set(name, value, false).await,
+            ("drain", []) => {
+                self.handle.drain()?;
+                Ok(Vec::new())
+            }
+            _ => bail!("Unknown command or arguments, see `help`"),
+        }
+    }
+
+    async fn set(&self, name: &str, value: &str, existing: bool) -> Result<Vec<String>> {
+        // The same for all the connections anyway.
+        if name == "log-level" {
+            return set_log_level(value);
+        }
+
+        let setting = Setting::parse(name, value)?;
+        info!("{name} set to {value} by admin");
+        self.ask(Request::Set { setting, existing }).await
+    }
+
+    /// Sends the request to every worker and gathers their answers.
+    async fn ask(&self, request: Request) -> Result<Vec<String>> {
+        let reply = Arc::new(Reply::new()?);
+        let workers = self.handle.workers();
+        self.handle
+            .send(Command::Admin(request, Arc::clone(&reply)))?;
+
+        self.io
+            .timeout(REPLY_TIMEOUT, reply.wait(&self.io, workers))
+            .await
+            .context("Wait for the workers")?
+    }
+
+    async fn send(&self, lines: Vec<String>) -> Result<()> {
+        let mut output = lines.join("\n") + "\n";
+        let mut sent = 0;
+
+        while sent < output.len() {
+            let sqe = Send::new(
+                Fd(self.socket.as_raw_fd()),
+                output[sent..].as_ptr(),
+                (output.len() - sent) as u32,
+            )
+            .flags(libc::MSG_NOSIGNAL);
+
+            let cqe;
+            (cqe, output) = self
+                .io
+                .submit_keeping(sqe.build(), output, "admin send")
+                .await?;
+
+            match cqe.result() {
+                errno if errno < 0 => bail!("Send admin reply: {}", Errno(-errno)),
+                len => sent += len as usize,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settings() {
+        assert_eq!(
+            Setting::parse("max-connections", "100").unwrap(),
+            Setting::MaxConnections(Some(100))
+        );
+        assert_eq!(
+            Setting::parse("idle-timeout-ms", "1500").unwrap(),
+            Setting::IdleTimeout(Some(Duration::from_millis(1500)))
+        );
+        assert_eq!(
+            Setting::parse("rate-limit-bytes", "none").unwrap(),
+            Setting::RateLimitBytes(None)
+        );
+
+        // A zero rate would never let anything through, `none` lifts the limit instead.
+        assert!(Setting::parse("rate-limit-messages", "0").is_err());
+        assert!(Setting::parse("max-connections", "-1").is_err());
+        assert!(Setting::parse("framing", "lines").is_err());
+    }
+
+    #[test]
+    fn socket_is_the_owners_only() {
+        let path = std::env::temp_dir().join(format!("uring-admin-{}.sock", std::process::id()));
+        let listener = AdminSocket::listen(&path).unwrap();
+
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        UnixStream::connect(&path).unwrap();
+        assert!(listener.accept().is_ok());
+
+        // Replaced only once nobody listens on it.
+        assert!(AdminSocket::listen(&path).is_err());
+        drop(listener);
+        AdminSocket::listen(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
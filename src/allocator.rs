@@ -0,0 +1,256 @@
+use std::alloc::{self, Layout};
+
+use anyhow::{Context as _, Result};
+
+/// Size of the huge pages backing the pool with `huge_pages`, the default one on x86-64 and
+/// arm64.
+const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// `mbind` mode allocating memory on the node given, or on others if it runs out.
+const MPOL_PREFERRED: libc::c_int = 1;
+
+/// Alignment of buffers allocated on the heap, a cache line.
+const HEAP_ALIGN: usize = 64;
+
+/// Where the memory of the buffer pool comes from.
+pub trait BufferAllocator {
+    /// Allocates zeroed memory for `count` buffers of `size` bytes each.
+    fn allocate(&self, size: u32, count: u16) -> Result<Box<dyn BufferMemory>>;
+}
+
+/// Memory of a block of buffers, released on drop.
+pub trait BufferMemory {
+    /// Size of each buffer in bytes.
+    fn size(&self) -> u32;
+
+    /// Number of buffers.
+    fn count(&self) -> u16;
+
+    /// Pointer to the `i`th buffer, valid for its size while the memory lives. The memory is
+    /// only accessed through pointers so that buffers written into by the kernel don't alias
+    /// references to others.
+    fn buffer(&self, i: u16) -> *mut u8;
+
+    /// The buffers in order, to register them for fixed operations.
+    fn iovecs(&self) -> Vec<libc::iovec> {
+        (0..self.count())
+            .map(|i| libc::iovec {
+                iov_base: self.buffer(i) as *mut libc::c_void,
+                iov_len: self.size() as usize,
+            })
+            .collect()
+    }
+}
+
+/// Maps anonymous memory with buffers starting at page boundaries, backed by huge pages if
+/// `huge_pages` is set and preferably on the NUMA `node` if any.
+pub struct MmapAllocator {
+    pub huge_pages: bool,
+    pub node: Option<u32>,
+}
+
+impl BufferAllocator for MmapAllocator {
+    fn allocate(&self, size: u32, count: u16) -> Result<Box<dyn BufferMemory>> {
+        let stride = (size as usize).next_multiple_of(page_size());
+        let region = Region::new(stride * count as usize, self.huge_pages)?;
+
+        if let Some(node) = self.node {
+            // Not fatal as the memory is still there, just farther away.
+            if let Err(err) = region.bind(node) {
+                warn!("Bind buffer pool to NUMA node {node}: {err}");
+            }
+        }
+
+        Ok(Box::new(MappedBuffers {
+            region,
+            size,
+            count,
+            stride,
+        }))
+    }
+}
+
+struct MappedBuffers {
+    region: Region,
+    size: u32,
+    count: u16,
+    /// Distance between buffers, rounded up to the page size.
+    stride: usize,
+}
+
+impl BufferMemory for MappedBuffers {
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn count(&self) -> u16 {
+        self.count
+    }
+
+    fn buffer(&self, i: u16) -> *mut u8 {
+        assert!(i < self.count, "Buffer #{i} is out of the block");
+        unsafe { self.region.ptr.add(i as usize * self.stride) }
+    }
+}
+
+/// Zeroed anonymous memory, unmapped on drop.
+struct Region {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Region {
+    /// Maps `len` bytes, with huge pages if `huge_pages` is set. If there are no huge pages
+    /// reserved, asks for transparent ones instead which the kernel may or may not provide.
+    fn new(len: usize, huge_pages: bool) -> Result<Self> {
+        if !huge_pages {
+            return Self::map(len, 0).context("Map buffer pool");
+        }
+
+        let huge_len = len.next_multiple_of(HUGE_PAGE_SIZE);
+
+        match Self::map(huge_len, libc::MAP_HUGETLB) {
+            Ok(region) => Ok(region),
+            Err(err) => {
+                warn!("Huge pages unavailable ({err}), falling back to transparent ones");
+                let region = Self::map(len, 0).context("Map buffer pool")?;
+
+                let result =
+                    unsafe { libc::madvise(region.ptr as *mut _, region.len, libc::MADV_HUGEPAGE) };
+
+                if result == -1 {
+                    let err = std::io::Error::last_os_error();
+                    warn!("Transparent huge pages unavailable: {err}");
+                }
+
+                Ok(region)
+            }
+        }
+    }
+
+    fn map(len: usize, flags: libc::c_int) -> std::io::Result<Self> {
+        // A zero length mapping is invalid while an empty pool is fine.
+        let len = len.max(1);
+
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | flags,
+                -1,
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            ptr: ptr as *mut u8,
+            len,
+        })
+    }
+
+    /// Makes the pages come from the NUMA `node` when they're touched first. That happens when
+    /// the buffers are registered, so it has to be done before.
+    fn bind(&self, node: u32) -> std::io::Result<()> {
+        let bits = libc::c_ulong::BITS as usize;
+        let mut mask = vec![0 as libc::c_ulong; node as usize / bits + 1];
+        mask[node as usize / bits] |= 1 << (node as usize % bits);
+
+        // The kernel takes one less bit than given.
+        let max_node = mask.len() * bits + 1;
+
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_mbind,
+                self.ptr,
+                self.len,
+                MPOL_PREFERRED,
+                mask.as_ptr(),
+                max_node,
+                0,
+            )
+        };
+
+        match result {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+}
+
+impl Drop for Region {
+    fn drop(&mut self) {
+        if unsafe { libc::munmap(self.ptr as *mut _, self.len) } == -1 {
+            let err = std::io::Error::last_os_error();
+            error!("Unmap buffer pool: {err}");
+        }
+    }
+}
+
+/// Allocates buffers with the global allocator packed one after another, which wastes no
+/// memory on page alignment of small buffers.
+pub struct HeapAllocator;
+
+impl BufferAllocator for HeapAllocator {
+    fn allocate(&self, size: u32, count: u16) -> Result<Box<dyn BufferMemory>> {
+        let stride = (size as usize).next_multiple_of(HEAP_ALIGN);
+
+        // A zero size allocation is invalid while an empty pool is fine.
+        let layout = Layout::from_size_align((stride * count as usize).max(1), HEAP_ALIGN)
+            .context("Buffer pool layout")?;
+
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+
+        if ptr.is_null() {
+            bail!("Allocate buffer pool of {} bytes", layout.size());
+        }
+
+        Ok(Box::new(HeapBuffers {
+            ptr,
+            layout,
+            size,
+            count,
+            stride,
+        }))
+    }
+}
+
+struct HeapBuffers {
+    ptr: *mut u8,
+    layout: Layout,
+    size: u32,
+    count: u16,
+    stride: usize,
+}
+
+impl BufferMemory for HeapBuffers {
+    fn size(&self) -> u32 {
+        self.size
+    }
+
+    fn count(&self) -> u16 {
+        self.count
+    }
+
+    fn buffer(&self, i: u16) -> *mut u8 {
+        assert!(i < self.count, "Buffer #{i} is out of the block");
+        unsafe { self.ptr.add(i as usize * self.stride) }
+    }
+}
+
+impl Drop for HeapBuffers {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr, self.layout) };
+    }
+}
+
+fn page_size() -> usize {
+    match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+        size if size > 0 => size as usize,
+        _ => 4096,
+    }
+}
@@ -0,0 +1,119 @@
+use std::os::fd::{BorrowedFd, RawFd};
+
+use io_uring::cqueue::Entry as Cqe;
+use io_uring::squeue::Entry as Sqe;
+use io_uring::types::Timespec;
+
+/// Runs the operations submitted by the server and its clients and produces their completions.
+/// Operations are io_uring submission queue entries either way, so that the same logic works
+/// with a real ring as well as with [`crate::epoll::Epoll`] doing them on readiness.
+pub trait Backend {
+    /// Pushes the entries to be submitted together. If there's not enough room, submits the
+    /// queued entries first.
+    ///
+    /// # Safety
+    ///
+    /// The parameters of the entries must stay valid until the operations complete.
+    unsafe fn push(&mut self, sqes: &[Sqe]) -> std::io::Result<()>;
+
+    /// Submits all the queued entries and waits for at least `want` completions.
+    fn submit_and_wait(&mut self, want: usize) -> std::io::Result<usize>;
+
+    /// Same as [`Backend::submit_and_wait`] but gives up waiting after `timeout` with `ETIME`.
+    fn submit_with_timeout(&mut self, want: usize, timeout: &Timespec) -> std::io::Result<usize>;
+
+    /// Number of entries pushed but not submitted yet.
+    fn queued(&mut self) -> usize;
+
+    /// Whether there are completions to take without waiting.
+    fn is_ready(&mut self) -> bool;
+
+    /// Takes all the available completions into `cqes`. Returns whether the completion queue has
+    /// overflowed meanwhile.
+    fn complete(&mut self, cqes: &mut Vec<Cqe>) -> std::io::Result<bool>;
+
+    /// Number of times the completion queue has overflowed.
+    fn overflows(&self) -> u64;
+
+    /// Number of completions dropped because the completion queue was full.
+    fn dropped(&mut self) -> u32;
+
+    /// The fd of the ring for other rings to message it, if it's a ring.
+    fn ring_fd(&self) -> Option<BorrowedFd<'_>>;
+
+    /// Registers a ring of `entries` provided buffers at `addr` as buffer `group`.
+    ///
+    /// # Safety
+    ///
+    /// The ring must stay valid until it's unregistered.
+    unsafe fn register_buf_ring(
+        &mut self,
+        addr: u64,
+        entries: u16,
+        group: u16,
+    ) -> std::io::Result<()>;
+
+    fn unregister_buf_ring(&mut self, group: u16) -> std::io::Result<()>;
+
+    /// Registers the buffers for fixed operations by their indexes. Empty ones with null
+    /// addresses leave room to register buffers at their indexes later.
+    ///
+    /// # Safety
+    ///
+    /// The buffers must stay valid until they're unregistered.
+    unsafe fn register_buffers(&mut self, iovecs: &[libc::iovec]) -> std::io::Result<()>;
+
+    /// Replaces the registered buffers starting with index `offset`, unregistering those
+    /// replaced with empty ones.
+    ///
+    /// # Safety
+    ///
+    /// Same as for [`Backend::register_buffers`].
+    unsafe fn update_buffers(&mut self, offset: u32, iovecs: &[libc::iovec])
+        -> std::io::Result<()>;
+
+    /// Registers the files for operations to refer to by their indexes instead of descriptors.
+    fn register_files(&mut self, fds: &[RawFd]) -> std::io::Result<()>;
+}
+
+pub const IORING_CQE_F_BUFFER: u32 = 1 << 0;
+pub const IORING_CQE_BUFFER_SHIFT: u32 = 16;
+
+/// Mirrors `io_uring_sqe` which [`Sqe`] wraps, for backends other than the ring to tell what
+/// the operations are.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct RawSqe {
+    pub opcode: u8,
+    pub flags: u8,
+    pub ioprio: u16,
+    pub fd: i32,
+    pub off: u64,
+    pub addr: u64,
+    pub len: u32,
+    pub op_flags: u32,
+    pub user_data: u64,
+    pub buf_index: u16,
+    pub personality: u16,
+    pub file_index: i32,
+    pub addr3: u64,
+    pub pad: u64,
+}
+
+/// Mirrors `io_uring_cqe` which [`Cqe`] wraps.
+#[repr(C)]
+pub struct RawCqe {
+    pub user_data: u64,
+    pub res: i32,
+    pub flags: u32,
+}
+
+/// Mirrors `__kernel_timespec` which [`Timespec`] wraps.
+#[repr(C)]
+pub struct KernelTimespec {
+    pub tv_sec: i64,
+    pub tv_nsec: i64,
+}
+
+const _: () = assert!(std::mem::size_of::<RawSqe>() == std::mem::size_of::<Sqe>());
+const _: () = assert!(std::mem::size_of::<RawCqe>() == std::mem::size_of::<Cqe>());
@@ -0,0 +1,145 @@
+//! A high dynamic range histogram after HdrHistogram: values are counted in buckets doubling in
+//! width, each split into the same number of linear sub-buckets, so that any value up to
+//! `u64::MAX` is recorded with the same relative precision in a fixed amount of memory.
+
+/// Bits of the sub-bucket index. Half of the 2^11 sub-buckets tell 1024 values apart in every
+/// bucket but the first, which keeps three significant decimal digits.
+const SUB_BUCKET_BITS: u32 = 11;
+const SUB_BUCKET_COUNT: u64 = 1 << SUB_BUCKET_BITS;
+const SUB_BUCKET_HALF: u64 = SUB_BUCKET_COUNT / 2;
+
+/// Enough for the values with the highest bit set in the last bucket.
+const LEN: usize = ((u64::BITS - SUB_BUCKET_BITS + 2) as u64 * SUB_BUCKET_HALF) as usize;
+
+pub struct Histogram {
+    counts: Vec<u64>,
+    total: u64,
+    max: u64,
+}
+
+impl Histogram {
+    pub fn new() -> Self {
+        Self {
+            counts: vec![0; LEN],
+            total: 0,
+            max: 0,
+        }
+    }
+
+    pub fn record(&mut self, value: u64) {
+        self.counts[index(value)] += 1;
+        self.total += 1;
+        self.max = self.max.max(value);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    /// The value which the `quantile` of the recorded ones, between 0 and 1, are at most,
+    /// within the precision; 0 if there are none.
+    pub fn quantile(&self, quantile: f64) -> u64 {
+        let rank = ((quantile * self.total as f64).ceil() as u64).clamp(1, self.total.max(1));
+        let mut seen = 0;
+
+        for (index, &count) in self.counts.iter().enumerate() {
+            seen += count;
+
+            if seen >= rank {
+                return highest_equivalent(index).min(self.max);
+            }
+        }
+
+        0
+    }
+
+    pub fn clear(&mut self) {
+        self.counts.fill(0);
+        self.total = 0;
+        self.max = 0;
+    }
+}
+
+/// Values below the sub-bucket count are counted exactly, the others by their highest
+/// `SUB_BUCKET_BITS` bits.
+fn index(value: u64) -> usize {
+    if value < SUB_BUCKET_COUNT {
+        return value as usize;
+    }
+
+    let shift = shift(value);
+    (u64::from(shift) * SUB_BUCKET_HALF + (value >> shift)) as usize
+}
+
+/// How far the value is shifted to fit the upper half of the sub-buckets.
+fn shift(value: u64) -> u32 {
+    (u64::BITS - 1 - value.leading_zeros()) - (SUB_BUCKET_BITS - 1)
+}
+
+/// The largest value counted at the `index`.
+fn highest_equivalent(index: usize) -> u64 {
+    let index = index as u64;
+
+    if index < SUB_BUCKET_COUNT {
+        return index;
+    }
+
+    let shift = index / SUB_BUCKET_HALF - 1;
+    let sub_bucket = index - shift * SUB_BUCKET_HALF;
+    (sub_bucket << shift) + ((1 << shift) - 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn indexes_are_contiguous() {
+        assert_eq!(index(SUB_BUCKET_COUNT - 1), SUB_BUCKET_COUNT as usize - 1);
+        assert_eq!(index(SUB_BUCKET_COUNT), SUB_BUCKET_COUNT as usize);
+        assert_eq!(index(u64::MAX), LEN - 1);
+
+        for value in [
+            1,
+            2047,
+            2048,
+            2049,
+            4095,
+            4096,
+            1_000_000,
+            u64::MAX / 3,
+            u64::MAX,
+        ] {
+            let index = index(value);
+            assert!(highest_equivalent(index) >= value);
+            assert!(index == 0 || highest_equivalent(index - 1) < value);
+        }
+    }
+
+    #[test]
+    fn quantiles_keep_three_digits() {
+        let mut histogram = Histogram::new();
+
+        for micros in 1..=100_000 {
+            histogram.record(micros * 1000);
+        }
+
+        assert_eq!(histogram.count(), 100_000);
+        assert_eq!(histogram.max(), 100_000_000);
+
+        for (quantile, expected) in [(0.5, 50_000_000.0), (0.99, 99_000_000.0), (1.0, 1e8)] {
+            let value = histogram.quantile(quantile) as f64;
+            assert!(
+                (value - expected).abs() / expected < 0.001,
+                "{quantile}: {value}"
+            );
+        }
+
+        histogram.clear();
+        assert_eq!(histogram.quantile(0.5), 0);
+    }
+}
@@ -0,0 +1,466 @@
+//! Puts load on an echo server: opens connections over an io_uring of its own, sends payloads
+//! on each at a target rate, checks that they're echoed byte for byte and reports the
+//! throughput and round-trip latencies.
+
+mod histogram;
+
+use std::net::SocketAddr;
+use std::num::NonZeroU64;
+use std::os::fd::AsRawFd;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context as _, Result};
+use clap::Parser;
+use io_uring::cqueue::Entry as Cqe;
+use io_uring::opcode::{Connect, Recv, Send, Timeout};
+use io_uring::squeue::Entry as Sqe;
+use io_uring::types::{Fd, Timespec};
+use io_uring::IoUring;
+use socket2::{Domain, SockAddr, Socket, Type};
+
+use self::histogram::Histogram;
+
+/// Marks the completion of the timeout ending the run instead of a connection's operation.
+const DEADLINE: u64 = u64::MAX;
+
+/// Marks the completion of the timeout of the interval reports.
+const REPORT: u64 = u64::MAX - 1;
+
+/// Latency quantiles to report with their labels.
+const QUANTILES: [(&str, f64); 4] = [("p50", 0.5), ("p90", 0.9), ("p99", 0.99), ("p999", 0.999)];
+
+#[derive(Debug, Parser)]
+#[command(about = "Puts load on an echo server and measures it")]
+struct Args {
+    /// Address of the server.
+    #[arg(short, long, default_value = "127.0.0.1:3456")]
+    address: SocketAddr,
+    /// Number of concurrent connections.
+    #[arg(short, long, default_value_t = 16)]
+    connections: usize,
+    /// Bytes per message.
+    #[arg(short, long, default_value_t = 64)]
+    size: usize,
+    /// Messages per second across all the connections [default: as fast as they're echoed].
+    #[arg(short, long)]
+    rate: Option<NonZeroU64>,
+    /// How long to run for.
+    #[arg(short, long, default_value_t = 10)]
+    duration_secs: u64,
+    /// Also report the throughput and latencies of every this many seconds as they pass.
+    #[arg(short, long)]
+    interval_secs: Option<NonZeroU64>,
+    /// Number of entries in the submission queue.
+    #[arg(long, default_value_t = 1024)]
+    ring_entries: u32,
+}
+
+/// Operations of a connection, tagging their completions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Op {
+    Connect = 0,
+    Send = 1,
+    Recv = 2,
+    Timer = 3,
+}
+
+struct Connection {
+    socket: Socket,
+    address: SockAddr,
+    /// What's being sent and what should come back.
+    payload: Vec<u8>,
+    echo: Vec<u8>,
+    sent: usize,
+    received: usize,
+    /// When the current message was sent or due to be with a rate, so that the latency
+    /// includes the time it has waited behind a slow echo of the previous one.
+    started: Instant,
+    /// When to send the next message with a rate.
+    next: Instant,
+    timer: Box<Timespec>,
+    /// Failed or out of sync, so no longer sending.
+    closed: bool,
+    seed: u64,
+}
+
+/// Of the echoed messages.
+struct Stats {
+    bytes: u64,
+    /// Round trips in nanoseconds.
+    latencies: Histogram,
+}
+
+struct Report {
+    total: Stats,
+    /// Since the last interval report.
+    interval: Stats,
+    failed: u64,
+    mismatched: u64,
+}
+
+struct LoadGen {
+    ring: IoUring,
+    connections: Vec<Connection>,
+    /// Between the messages of each connection with a rate.
+    interval: Option<Duration>,
+    deadline: Box<Timespec>,
+    /// Between the interval reports if any.
+    report_interval: Option<Box<Timespec>>,
+    report: Report,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    if args.connections == 0 || args.size == 0 {
+        bail!("Need at least one connection and one byte per message");
+    }
+
+    let mut load_gen = LoadGen::new(&args)?;
+    let started = Instant::now();
+    load_gen.run(started)?;
+    load_gen.report.print(&args, started.elapsed());
+
+    match load_gen.report {
+        Report {
+            mismatched: 1.., ..
+        } => bail!("Some echoes didn't match what was sent"),
+        Report { failed, .. } if failed == args.connections as u64 => {
+            bail!("All the connections have failed")
+        }
+        _ => Ok(()),
+    }
+}
+
+impl LoadGen {
+    fn new(args: &Args) -> Result<Self> {
+        let ring = IoUring::new(args.ring_entries).context("Create ring")?;
+        let now = Instant::now();
+
+        let interval = args
+            .rate
+            .map(|rate| Duration::from_secs(args.connections as u64) / rate.get() as u32);
+
+        let connections = (0..args.connections)
+            .map(|i| {
+                let socket = Socket::new(Domain::for_address(args.address), Type::STREAM, None)
+                    .context("Socket")?;
+
+                // Spread the connections over the interval not to send in bursts.
+                let next = match interval {
+                    Some(interval) => now + interval * i as u32 / args.connections as u32,
+                    None => now,
+                };
+
+                Ok(Connection {
+                    socket,
+                    address: args.address.into(),
+                    payload: vec![0; args.size],
+                    echo: vec![0; args.size],
+                    sent: 0,
+                    received: 0,
+                    started: now,
+                    next,
+                    timer: Box::new(Timespec::new()),
+                    closed: false,
+                    seed: i as u64 + 1,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(Self {
+            ring,
+            connections,
+            interval,
+            deadline: Box::new(Timespec::from(Duration::from_secs(args.duration_secs))),
+            report_interval: args
+                .interval_secs
+                .map(|secs| Box::new(Timespec::from(Duration::from_secs(secs.get())))),
+            report: Report {
+                total: Stats::new(),
+                interval: Stats::new(),
+                failed: 0,
+                mismatched: 0,
+            },
+        })
+    }
+
+    fn run(&mut self, started: Instant) -> Result<()> {
+        let deadline = Timeout::new(&*self.deadline).build().user_data(DEADLINE);
+        self.push(deadline)?;
+        self.report_later()?;
+        let mut reported = started;
+
+        for id in 0..self.connections.len() {
+            let conn = &self.connections[id];
+            let sqe = Connect::new(
+                Fd(conn.socket.as_raw_fd()),
+                conn.address.as_ptr().cast(),
+                conn.address.len(),
+            );
+            self.push(sqe.build().user_data(user_data(id, Op::Connect)))?;
+        }
+
+        let mut cqes = Vec::new();
+
+        loop {
+            self.ring.submit_and_wait(1).context("Submit")?;
+            cqes.extend(self.ring.completion());
+
+            for cqe in cqes.drain(..) {
+                match cqe.user_data() {
+                    DEADLINE => return Ok(()),
+                    REPORT => {
+                        let now = Instant::now();
+                        self.report.print_interval(now - started, now - reported);
+                        reported = now;
+                        self.report_later()?;
+                    }
+                    _ => self.complete(cqe)?,
+                }
+            }
+
+            if self.connections.iter().all(|conn| conn.closed) {
+                return Ok(());
+            }
+        }
+    }
+
+    fn complete(&mut self, cqe: Cqe) -> Result<()> {
+        let id = (cqe.user_data() >> 8) as usize;
+        let op = match cqe.user_data() & 0xff {
+            0 => Op::Connect,
+            1 => Op::Send,
+            2 => Op::Recv,
+            _ => Op::Timer,
+        };
+
+        let result = cqe.result();
+
+        // Timers expire with ETIME.
+        if result < 0 && op != Op::Timer {
+            self.fail(id, op, std::io::Error::from_raw_os_error(-result));
+            return Ok(());
+        }
+
+        match op {
+            Op::Connect => self.schedule(id),
+            Op::Timer => self.start(id),
+            Op::Send => {
+                let conn = &mut self.connections[id];
+                conn.sent += result as usize;
+
+                if conn.sent < conn.payload.len() {
+                    self.send(id)?;
+                }
+
+                Ok(())
+            }
+            Op::Recv if result == 0 => {
+                let err = std::io::ErrorKind::UnexpectedEof.into();
+                self.fail(id, op, err);
+                Ok(())
+            }
+            Op::Recv => {
+                let conn = &mut self.connections[id];
+                conn.received += result as usize;
+
+                if conn.received < conn.echo.len() {
+                    return self.recv(id);
+                }
+
+                let latency = conn.started.elapsed();
+
+                if conn.echo != conn.payload {
+                    eprintln!("Connection #{id} got an echo which doesn't match");
+                    conn.closed = true;
+                    self.report.mismatched += 1;
+                    return Ok(());
+                }
+
+                self.report.total.record(conn.payload.len(), latency);
+                self.report.interval.record(conn.payload.len(), latency);
+                self.schedule(id)
+            }
+        }
+    }
+
+    fn report_later(&mut self) -> Result<()> {
+        let Some(ref interval) = self.report_interval else {
+            return Ok(());
+        };
+
+        let sqe = Timeout::new(&**interval).build().user_data(REPORT);
+        self.push(sqe)
+    }
+
+    /// Sends the next message right away or once it's due with a rate.
+    fn schedule(&mut self, id: usize) -> Result<()> {
+        let conn = &mut self.connections[id];
+
+        // Behind the rate, the message is sent right away.
+        let Some(wait) = conn.next.checked_duration_since(Instant::now()) else {
+            return self.start(id);
+        };
+
+        *conn.timer = Timespec::from(wait);
+        let sqe = Timeout::new(&*conn.timer).build();
+        self.push(sqe.user_data(user_data(id, Op::Timer)))
+    }
+
+    /// Sends a fresh payload and receives its echo at the same time, so that neither side
+    /// stalls with messages larger than the socket buffers.
+    fn start(&mut self, id: usize) -> Result<()> {
+        let conn = &mut self.connections[id];
+
+        match self.interval {
+            Some(interval) => {
+                conn.started = conn.next;
+                conn.next += interval;
+            }
+            None => conn.started = Instant::now(),
+        }
+
+        for byte in &mut conn.payload {
+            conn.seed ^= conn.seed << 13;
+            conn.seed ^= conn.seed >> 7;
+            conn.seed ^= conn.seed << 17;
+            *byte = conn.seed as u8;
+        }
+
+        conn.sent = 0;
+        conn.received = 0;
+        self.send(id)?;
+        self.recv(id)
+    }
+
+    fn send(&mut self, id: usize) -> Result<()> {
+        let conn = &self.connections[id];
+        let rest = &conn.payload[conn.sent..];
+        let sqe = Send::new(
+            Fd(conn.socket.as_raw_fd()),
+            rest.as_ptr(),
+            rest.len() as u32,
+        )
+        .flags(libc::MSG_NOSIGNAL)
+        .build();
+        self.push(sqe.user_data(user_data(id, Op::Send)))
+    }
+
+    fn recv(&mut self, id: usize) -> Result<()> {
+        let conn = &mut self.connections[id];
+        let rest = &mut conn.echo[conn.received..];
+        let sqe = Recv::new(
+            Fd(conn.socket.as_raw_fd()),
+            rest.as_mut_ptr(),
+            rest.len() as u32,
+        );
+        self.push(sqe.build().user_data(user_data(id, Op::Recv)))
+    }
+
+    /// Stops using the connection, reporting the failure once per connection.
+    fn fail(&mut self, id: usize, op: Op, err: std::io::Error) {
+        let conn = &mut self.connections[id];
+
+        if !conn.closed {
+            eprintln!("Connection #{id} failed to {op:?}: {err}");
+            conn.closed = true;
+            self.report.failed += 1;
+        }
+    }
+
+    fn push(&mut self, sqe: Sqe) -> Result<()> {
+        // The buffers of the entry stay put in the connections until the run is over.
+        while unsafe { self.ring.submission().push(&sqe) }.is_err() {
+            self.ring.submit().context("Submit")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            bytes: 0,
+            latencies: Histogram::new(),
+        }
+    }
+
+    fn record(&mut self, size: usize, latency: Duration) {
+        self.bytes += size as u64;
+        self.latencies.record(latency.as_nanos() as u64);
+    }
+
+    fn clear(&mut self) {
+        self.bytes = 0;
+        self.latencies.clear();
+    }
+
+    /// Messages and MiB per second over the `elapsed` time.
+    fn rates(&self, elapsed: Duration) -> (f64, f64) {
+        let secs = elapsed.as_secs_f64();
+        let messages = self.latencies.count() as f64 / secs;
+        (messages, self.bytes as f64 / secs / (1024.0 * 1024.0))
+    }
+
+    /// The latency quantiles and the maximum.
+    fn latencies(&self) -> String {
+        let mut line = String::new();
+
+        for (label, quantile) in QUANTILES {
+            let value = self.latencies.quantile(quantile);
+            line += &format!("{label} {} ", format_nanos(value));
+        }
+
+        line + &format!("max {}", format_nanos(self.latencies.max()))
+    }
+}
+
+impl Report {
+    fn print_interval(&mut self, since_start: Duration, elapsed: Duration) {
+        let (messages, mib) = self.interval.rates(elapsed);
+
+        println!(
+            "[{:>7.1}s] {messages:.0} messages/s, {mib:.2} MiB/s, {}",
+            since_start.as_secs_f64(),
+            self.interval.latencies(),
+        );
+
+        self.interval.clear();
+    }
+
+    fn print(&self, args: &Args, elapsed: Duration) {
+        let (messages, mib) = self.total.rates(elapsed);
+
+        println!(
+            "{} messages of {} bytes over {} connections in {:.2}s",
+            self.total.latencies.count(),
+            args.size,
+            args.connections,
+            elapsed.as_secs_f64(),
+        );
+        println!("Throughput: {messages:.0} messages/s, {mib:.2} MiB/s each way");
+        println!(
+            "Failed connections: {}, mismatched echoes: {}",
+            self.failed, self.mismatched
+        );
+
+        if self.total.latencies.count() > 0 {
+            println!("Latency: {}", self.total.latencies());
+        }
+    }
+}
+
+fn user_data(id: usize, op: Op) -> u64 {
+    (id as u64) << 8 | op as u64
+}
+
+fn format_nanos(nanos: u64) -> String {
+    match nanos {
+        0..1000 => format!("{nanos}ns"),
+        1000..1_000_000 => format!("{:.1}us", nanos as f64 / 1e3),
+        1_000_000..1_000_000_000 => format!("{:.2}ms", nanos as f64 / 1e6),
+        _ => format!("{:.2}s", nanos as f64 / 1e9),
+    }
+}
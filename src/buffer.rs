@@ -1,78 +1,1078 @@
+use std::alloc::{self, Layout};
 use std::cell::RefCell;
+use std::fmt;
+use std::ops::{Deref, DerefMut};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU16, Ordering};
+use std::time::{Duration, Instant};
 
-#[derive(Debug)]
+use anyhow::{Context as _, Result};
+use io_uring::opcode::ProvideBuffers;
+use io_uring::types::BufRingEntry;
+
+use crate::allocator::{BufferAllocator, BufferMemory};
+use crate::backend::Backend;
+use crate::common::{Id, ListenerId, Route};
+use crate::config::BufferClass;
+use crate::utils;
+
+const MEMLOCK_HINTS: &[(libc::c_int, &str)] = &[(
+    libc::ENOMEM,
+    "Registered buffers count against RLIMIT_MEMLOCK, raise it with `ulimit -l` or use fewer or \
+     smaller buffers",
+)];
+
+/// Pattern filling released buffers with [`Scrub::Poison`], standing out in hex dumps.
+const POISON: u8 = 0xa5;
+
+/// Buffers of a few size classes indexed in the order of the classes so that the indexes stay
+/// the same for registering them and providing them to the kernel.
+///
+/// A class may reserve more indexes than it has buffers at first and grow into them a block at
+/// a time with [`BufferPool::grow`]. Grown blocks retire with [`BufferPool::shrink`] once the
+/// class has been using fewer buffers than the rest of the blocks hold for a while: their
+/// buffers aren't acquired anymore and the block is released when all of them are free.
+///
+/// The memory of the blocks comes from a [`BufferAllocator`].
 pub struct BufferPool {
-    data: Rc<Vec<u8>>,
+    /// Ordered by size.
+    classes: Vec<Class>,
+    /// Number of indexes, including those reserved to grow into.
     count: u16,
+    allocator: Box<dyn BufferAllocator>,
+    holdings: Holdings,
+    scrub: Scrub,
+}
+
+/// What released buffers are filled with, so that a length accounting bug can't echo data of
+/// one client to another.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Scrub {
+    /// Left as they are.
+    #[default]
+    None,
+    Zero,
+    /// [`POISON`], to tell stale data apart from zeros when debugging.
+    Poison,
+}
+
+/// Who holds the acquired buffers and since when, by their indexes.
+type Holdings = Rc<RefCell<Vec<Option<Holding>>>>;
+
+#[derive(Debug)]
+struct Holding {
+    holder: Holder,
+    since: Instant,
+    /// Whether it's been reported as held for too long.
+    reported: bool,
+}
+
+/// Who holds a buffer, for diagnostics.
+#[derive(Clone, Copy, Debug)]
+pub enum Holder {
+    /// Provided for the kernel to read into.
+    Kernel,
+    Client(Id),
+    Upstream(Id),
+    /// Datagram sockets keep their buffers.
+    Datagram(ListenerId),
+    /// Lent to an operation dropped in flight, until the kernel is done with it.
+    Operation,
+}
+
+impl fmt::Display for Holder {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Kernel => write!(f, "the kernel"),
+            Self::Client(id) => write!(f, "client #{id}"),
+            Self::Upstream(id) => write!(f, "upstream of client #{id}"),
+            Self::Datagram(id) => write!(f, "datagram socket #{id}"),
+            Self::Operation => write!(f, "an abandoned operation"),
+        }
+    }
+}
+
+struct Class {
     size: u32,
+    /// Index of the first buffer.
+    first: u16,
+    /// Number of buffers to grow by, as many as the class starts with.
+    block_len: u16,
+    /// Number of indexes reserved for the class.
+    capacity: u16,
+    /// In index order, the first one is never released. Retiring ones follow the rest.
+    blocks: Vec<Block>,
+    /// Since when the buffers in use would fit without the last block.
+    underused_since: Option<Instant>,
+}
+
+/// Buffers allocated together.
+struct Block {
+    memory: Rc<dyn BufferMemory>,
+    /// Index of the first buffer.
+    first: u16,
+    len: u16,
+    retiring: bool,
     free_indexes: Rc<RefCell<Vec<u16>>>,
 }
 
 impl BufferPool {
-    pub fn new(count: u16, size: u32) -> Self {
-        Self {
-            data: Rc::new(vec![0; count as usize * size as usize]),
+    /// Allocates buffers of `classes` with `allocator`.
+    pub fn new(classes: &[BufferClass], allocator: Box<dyn BufferAllocator>) -> Result<Self> {
+        let mut classes = classes.to_vec();
+        classes.sort_by_key(|class| class.size);
+
+        let mut pool_classes = Vec::with_capacity(classes.len());
+        let mut count = 0u16;
+
+        for class in classes {
+            if class.size == 0 || class.count == 0 {
+                bail!("Buffer classes need a positive size and count");
+            }
+
+            let capacity = class.max_count.unwrap_or(class.count);
+
+            if capacity < class.count {
+                bail!("The maximum count of buffers is less than the count");
+            }
+
+            let first = count;
+
+            count = count
+                .checked_add(capacity)
+                .context("Too many buffers, there may be 65535 at most")?;
+
+            let block = Block::new(first, class.count, class.size, &*allocator)?;
+
+            pool_classes.push(Class {
+                size: class.size,
+                first,
+                block_len: class.count,
+                capacity,
+                blocks: vec![block],
+                underused_since: None,
+            });
+        }
+
+        Ok(Self {
+            classes: pool_classes,
             count,
-            size,
-            free_indexes: Rc::new(RefCell::new((0..count).collect::<Vec<_>>())),
+            allocator,
+            holdings: Rc::new(RefCell::new(
+                std::iter::repeat_with(|| None)
+                    .take(count as usize)
+                    .collect(),
+            )),
+            scrub: Scrub::None,
+        })
+    }
+
+    /// Makes released buffers get filled according to `scrub` before they're reused.
+    pub fn with_scrub(mut self, scrub: Scrub) -> Self {
+        self.scrub = scrub;
+        self
+    }
+
+    /// Acquires a buffer for `holder` of the smallest class fitting `size_hint` bytes, or the
+    /// largest one having free buffers if none of those has.
+    pub fn acquire(&self, size_hint: usize, holder: Holder) -> Option<Guard> {
+        let fitting = self
+            .classes
+            .iter()
+            .position(|class| class.size as usize >= size_hint)
+            .unwrap_or(self.classes.len());
+
+        (fitting..self.classes.len())
+            .chain((0..fitting).rev())
+            .find_map(|class| self.acquire_from(class, holder))
+    }
+
+    /// Acquires a buffer for `holder` of the `class`th smallest size class, from the first
+    /// blocks first so that the last ones may end up unused.
+    pub fn acquire_from(&self, class: usize, holder: Holder) -> Option<Guard> {
+        let buffers = self.classes.get(class)?;
+
+        buffers
+            .blocks
+            .iter()
+            .filter(|block| !block.retiring)
+            .find_map(|block| {
+                let idx = block.free_indexes.borrow_mut().pop()?;
+
+                let guard = Guard {
+                    buffer: Rc::new(Acquired {
+                        memory: Rc::clone(&block.memory),
+                        offset: idx - block.first,
+                        idx,
+                        free_indexes: Rc::clone(&block.free_indexes),
+                        holdings: Rc::clone(&self.holdings),
+                        scrub: self.scrub,
+                    }),
+                    class,
+                };
+
+                guard.hand_to(holder);
+                Some(guard)
+            })
+    }
+
+    /// Number of indexes, including those reserved to grow into.
+    pub fn count(&self) -> u16 {
+        self.count
+    }
+
+    /// Number of buffers allocated and how many of them are in the pool rather than acquired,
+    /// e.g. provided to the kernel.
+    pub fn usage(&self) -> (usize, usize) {
+        self.classes.iter().flat_map(|class| &class.blocks).fold(
+            (0, 0),
+            |(allocated, free), block| {
+                let block_free = block.free_indexes.borrow().len();
+                (allocated + block.len as usize, free + block_free)
+            },
+        )
+    }
+
+    /// Number of size classes.
+    pub fn classes(&self) -> usize {
+        self.classes.len()
+    }
+
+    /// Size and number of indexes of the `class`th smallest size class.
+    pub fn class(&self, class: usize) -> (u32, u16) {
+        let class = &self.classes[class];
+        (class.size, class.capacity)
+    }
+
+    /// Buffers held by clients for longer than `threshold` which haven't been reported yet
+    /// along with their holders and how long they've been held. The kernel and datagram sockets
+    /// hold buffers for as long as they need by design.
+    pub fn overdue(&self, threshold: Duration) -> Vec<(u16, Holder, Duration)> {
+        let mut holdings = self.holdings.borrow_mut();
+        let mut overdue = Vec::new();
+
+        for (idx, holding) in holdings.iter_mut().enumerate() {
+            let Some(holding) = holding else {
+                continue;
+            };
+
+            if matches!(holding.holder, Holder::Kernel | Holder::Datagram(_)) || holding.reported {
+                continue;
+            }
+
+            let held = holding.since.elapsed();
+
+            if held > threshold {
+                holding.reported = true;
+                overdue.push((idx as u16, holding.holder, held));
+            }
         }
+
+        overdue
+    }
+
+    /// Who holds each of the buffers acquired from the pool.
+    pub fn holders(&self) -> Vec<Holder> {
+        self.holdings
+            .borrow()
+            .iter()
+            .flatten()
+            .map(|holding| holding.holder)
+            .collect()
     }
 
-    pub fn acquire(&self) -> Option<Guard> {
-        let idx = self.free_indexes.borrow_mut().pop()?;
-        let start = idx as usize * self.size as usize;
-        let end = start + self.size as usize;
+    /// Registers the buffers for fixed operations, leaving the indexes reserved to grow into
+    /// empty.
+    pub fn register(&self, backend: &mut dyn Backend) -> Result<()> {
+        let mut iovecs = Vec::with_capacity(self.count as usize);
 
-        Some(Guard {
-            buffer: Rc::clone(&self.data),
-            start,
-            end,
-            idx,
-            free_indexes: Rc::clone(&self.free_indexes),
-        })
+        for class in &self.classes {
+            for block in &class.blocks {
+                iovecs.extend(block.memory.iovecs());
+            }
+
+            iovecs.resize(
+                (class.first + class.capacity) as usize,
+                libc::iovec {
+                    iov_base: std::ptr::null_mut(),
+                    iov_len: 0,
+                },
+            );
+        }
+
+        // The buffers live as long as the pool which unregisters them before releasing any.
+        unsafe { backend.register_buffers(&iovecs) }
+            .map_err(|err| utils::explain(err, MEMLOCK_HINTS))
+            .context("Register buffers")
     }
 
-    pub fn iovecs(&self) -> Vec<libc::iovec> {
-        let count = self.count as usize;
-        let size = self.size as usize;
-        let mut iovecs = Vec::with_capacity(count);
+    /// Grows the `class`th smallest size class by a block unless it has no indexes left.
+    /// Returns whether it has grown.
+    pub fn grow(&mut self, class: usize, backend: &mut dyn Backend) -> Result<bool> {
+        let buffers = &mut self.classes[class];
+        buffers.underused_since = None;
+
+        // A retiring block is as good as a new one.
+        if let Some(block) = buffers.blocks.iter_mut().find(|block| block.retiring) {
+            block.retiring = false;
+            return Ok(true);
+        }
 
-        for i in 0..count {
-            let ptr = self.data[(i * size)..].as_ptr();
+        let allocated = buffers.blocks.iter().map(|block| block.len).sum::<u16>();
 
-            iovecs.push(libc::iovec {
-                iov_base: ptr as *const _ as *mut libc::c_void,
-                iov_len: size,
-            });
+        if allocated == buffers.capacity {
+            return Ok(false);
         }
 
-        iovecs
+        let len = buffers.block_len.min(buffers.capacity - allocated);
+        let first = buffers.first + allocated;
+
+        let result = Block::new(first, len, buffers.size, &*self.allocator).and_then(|block| {
+            let iovecs = block.memory.iovecs();
+
+            unsafe { backend.update_buffers(first as u32, &iovecs) }
+                .context("Register grown buffers")?;
+
+            Ok(block)
+        });
+
+        match result {
+            Ok(block) => buffers.blocks.push(block),
+            Err(err) => {
+                // Don't try again on every event loop iteration.
+                buffers.capacity = allocated;
+                return Err(err).context("Grow buffer pool");
+            }
+        }
+
+        info!("Grown the pool by {len} buffers of {} bytes", buffers.size);
+        Ok(true)
+    }
+
+    /// Retires the last block of the `class`th smallest size class when the rest have been
+    /// enough for the buffers in use for `delay`, and releases the retired blocks whose buffers
+    /// are all free. The kernel holds the `provided` ones which aren't in use.
+    pub fn shrink(
+        &mut self,
+        class: usize,
+        provided: usize,
+        delay: Duration,
+        backend: &mut dyn Backend,
+    ) -> Result<()> {
+        let buffers = &mut self.classes[class];
+
+        while let Some(block) = buffers.blocks.last() {
+            if !block.retiring || block.free_indexes.borrow().len() < block.len as usize {
+                break;
+            }
+
+            let iovecs = vec![
+                libc::iovec {
+                    iov_base: std::ptr::null_mut(),
+                    iov_len: 0,
+                };
+                block.len as usize
+            ];
+
+            unsafe { backend.update_buffers(block.first as u32, &iovecs) }
+                .context("Unregister released buffers")?;
+
+            info!("Released {} buffers of {} bytes", block.len, buffers.size);
+            buffers.blocks.pop();
+        }
+
+        let mut allocated = 0;
+        let mut free = 0;
+        let mut active = 0;
+
+        for block in &buffers.blocks {
+            allocated += block.len as usize;
+            free += block.free_indexes.borrow().len();
+
+            if !block.retiring {
+                active += block.len as usize;
+            }
+        }
+
+        let in_use = allocated.saturating_sub(free + provided);
+
+        let last = match buffers.blocks.iter().rposition(|block| !block.retiring) {
+            Some(last) if last > 0 => last,
+            _ => {
+                buffers.underused_since = None;
+                return Ok(());
+            }
+        };
+
+        if in_use > active - buffers.blocks[last].len as usize {
+            buffers.underused_since = None;
+            return Ok(());
+        }
+
+        let since = *buffers.underused_since.get_or_insert_with(Instant::now);
+
+        if since.elapsed() >= delay {
+            let block = &mut buffers.blocks[last];
+            block.retiring = true;
+            buffers.underused_since = None;
+            info!("Retiring {} buffers of {} bytes", block.len, buffers.size);
+        }
+
+        Ok(())
+    }
+}
+
+impl Block {
+    fn new(first: u16, len: u16, size: u32, allocator: &dyn BufferAllocator) -> Result<Self> {
+        Ok(Self {
+            memory: Rc::from(allocator.allocate(size, len)?),
+            first,
+            len,
+            retiring: false,
+            free_indexes: Rc::new(RefCell::new((first..first + len).rev().collect())),
+        })
     }
 }
 
 pub struct Guard {
-    buffer: Rc<Vec<u8>>,
-    start: usize,
-    end: usize,
+    /// Shared with the loans of the buffer, the last of which releases it.
+    buffer: Rc<Acquired>,
+    class: usize,
+}
+
+/// Keeps a buffer from going back to the pool while an operation the kernel may still be
+/// running uses it, even once the guard is dropped, e.g. along with a write in flight.
+#[derive(Clone)]
+pub struct Loan {
+    buffer: Rc<Acquired>,
+}
+
+/// A buffer out of the pool, which goes back on drop.
+struct Acquired {
+    memory: Rc<dyn BufferMemory>,
+    /// Index of the buffer in its block.
+    offset: u16,
     idx: u16,
     free_indexes: Rc<RefCell<Vec<u16>>>,
+    holdings: Holdings,
+    scrub: Scrub,
 }
 
 impl Guard {
     pub fn idx(&self) -> u16 {
-        self.idx
+        self.buffer.idx
+    }
+
+    /// Lends the buffer to an operation which keeps the loan until the kernel is done with it.
+    pub fn lend(&self) -> Loan {
+        Loan {
+            buffer: Rc::clone(&self.buffer),
+        }
+    }
+
+    /// The size class of the buffer in the pool.
+    pub fn class(&self) -> usize {
+        self.class
+    }
+
+    /// Records that the buffer is held by `holder` from now on.
+    pub fn hand_to(&self, holder: Holder) {
+        self.buffer.holdings.borrow_mut()[self.buffer.idx as usize] = Some(Holding {
+            holder,
+            since: Instant::now(),
+            reported: false,
+        });
+    }
+
+    /// Pointer for the kernel to write into the buffer, taken from the exclusive borrow so that
+    /// no shared reference to the buffer is around meanwhile.
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.buffer.as_mut_ptr()
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        let len = self.buffer.memory.size() as usize;
+        unsafe { std::slice::from_raw_parts_mut(self.as_mut_ptr(), len) }
     }
 }
 
 impl AsRef<[u8]> for Guard {
     fn as_ref(&self) -> &[u8] {
-        &self.buffer[self.start..self.end]
+        self.buffer.as_slice()
     }
 }
 
 impl Drop for Guard {
     fn drop(&mut self) {
+        // Released once the operations it's been lent to complete.
+        if Rc::strong_count(&self.buffer) > 1 {
+            self.hand_to(Holder::Operation);
+        }
+    }
+}
+
+impl Loan {
+    pub fn idx(&self) -> u16 {
+        self.buffer.idx
+    }
+}
+
+impl AsRef<[u8]> for Loan {
+    fn as_ref(&self) -> &[u8] {
+        self.buffer.as_slice()
+    }
+}
+
+impl Acquired {
+    fn as_mut_ptr(&self) -> *mut u8 {
+        self.memory.buffer(self.offset)
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        let len = self.memory.size() as usize;
+        unsafe { std::slice::from_raw_parts(self.as_mut_ptr(), len) }
+    }
+}
+
+impl Drop for Acquired {
+    fn drop(&mut self) {
+        let len = self.memory.size() as usize;
+        let buffer = unsafe { std::slice::from_raw_parts_mut(self.as_mut_ptr(), len) };
+
+        match self.scrub {
+            Scrub::None => (),
+            Scrub::Zero => buffer.fill(0),
+            Scrub::Poison => buffer.fill(POISON),
+        }
+
+        self.holdings.borrow_mut()[self.idx as usize] = None;
         self.free_indexes.borrow_mut().push(self.idx);
     }
 }
+
+/// Buffers provided to the kernel with a registered ring so that it picks one for a read with
+/// `BUFFER_SELECT` only when data arrives and idle connections don't hold any. Kernels without
+/// buffer rings get the buffers with an operation each instead.
+///
+/// Each size class of the pool is a buffer group of its own, so that reads pick the size to
+/// read into with [`BufferRing::group`]. The buffers come from the pool and their ids are the
+/// pool indexes so that they may be used for fixed writes. Those released back to the pool are
+/// provided again with [`BufferRing::replenish`].
+#[derive(Clone)]
+pub struct BufferRing(Rc<RefCell<RingState>>);
+
+struct RingState {
+    ring: Rc<RefCell<dyn Backend>>,
+    /// Groups of the size classes of the pool in the same order.
+    groups: Vec<GroupState>,
+    /// Buffers provided to the kernel indexed by their ids.
+    guards: Vec<Option<Guard>>,
+}
+
+struct GroupState {
+    group: u16,
+    size: u32,
+    /// The registered ring if the kernel supports them.
+    mapped: Option<MappedRing>,
+    capacity: usize,
+    provided: usize,
+}
+
+struct MappedRing {
+    entries: *mut BufRingEntry,
+    layout: Layout,
+    tail: u16,
+    mask: usize,
+}
+
+impl BufferRing {
+    /// Registers a ring with enough entries for all the buffers of each size class of `pool`
+    /// as buffer groups starting with `first_group`.
+    pub fn register(
+        ring: Rc<RefCell<dyn Backend>>,
+        pool: &BufferPool,
+        first_group: u16,
+    ) -> Result<Self> {
+        let buffers = Self::new(Rc::clone(&ring), pool);
+
+        for class in 0..pool.classes() {
+            let (size, count) = pool.class(class);
+            let group = first_group + class as u16;
+
+            // The kernel limits the ring to 2^15 entries; the rest of the buffers stay in the
+            // pool.
+            let capacity = (count as usize).next_power_of_two().min(1 << 15);
+            let layout =
+                Layout::from_size_align(capacity * std::mem::size_of::<BufRingEntry>(), 4096)
+                    .context("Buffer ring layout")?;
+            let entries = unsafe { alloc::alloc_zeroed(layout) } as *mut BufRingEntry;
+
+            if entries.is_null() {
+                bail!("Allocate buffer ring of {} bytes", layout.size());
+            }
+
+            let result = unsafe {
+                ring.borrow_mut()
+                    .register_buf_ring(entries as u64, capacity as u16, group)
+            };
+
+            if let Err(err) = result {
+                unsafe { alloc::dealloc(entries as *mut u8, layout) };
+                return Err(utils::explain(err, MEMLOCK_HINTS)).context("Register buffer ring");
+            }
+
+            let mapped = MappedRing {
+                entries,
+                layout,
+                tail: 0,
+                mask: capacity - 1,
+            };
+
+            buffers.0.borrow_mut().groups.push(GroupState {
+                group,
+                size,
+                mapped: Some(mapped),
+                capacity,
+                provided: 0,
+            });
+        }
+
+        Ok(buffers)
+    }
+
+    /// Provides the buffers of each size class of `pool` as buffer groups starting with
+    /// `first_group` with an operation each, for kernels without buffer rings.
+    pub fn provide(ring: Rc<RefCell<dyn Backend>>, pool: &BufferPool, first_group: u16) -> Self {
+        let buffers = Self::new(ring, pool);
+
+        buffers.0.borrow_mut().groups = (0..pool.classes())
+            .map(|class| {
+                let (size, count) = pool.class(class);
+
+                GroupState {
+                    group: first_group + class as u16,
+                    size,
+                    mapped: None,
+                    capacity: count as usize,
+                    provided: 0,
+                }
+            })
+            .collect();
+
+        buffers
+    }
+
+    fn new(ring: Rc<RefCell<dyn Backend>>, pool: &BufferPool) -> Self {
+        Self(Rc::new(RefCell::new(RingState {
+            ring,
+            groups: Vec::with_capacity(pool.classes()),
+            guards: std::iter::repeat_with(|| None)
+                .take(pool.count() as usize)
+                .collect(),
+        })))
+    }
+
+    /// The group to read `size_hint` bytes from: the one of the smallest buffers fitting them,
+    /// or of the largest ones the kernel may currently pick from if there are none of those.
+    pub fn group(&self, size_hint: usize) -> u16 {
+        let state = self.0.borrow();
+
+        let fitting = state
+            .groups
+            .iter()
+            .position(|group| group.size as usize >= size_hint)
+            .unwrap_or(state.groups.len() - 1);
+
+        state.groups[fitting..]
+            .iter()
+            .chain(state.groups[..fitting].iter().rev())
+            .find(|group| group.provided > 0)
+            .unwrap_or(&state.groups[fitting])
+            .group
+    }
+
+    /// Number of buffers the kernel may currently pick from.
+    pub fn available(&self) -> usize {
+        self.0
+            .borrow()
+            .groups
+            .iter()
+            .map(|group| group.provided)
+            .sum()
+    }
+
+    /// Number of buffers of the `class`th smallest size class the kernel may pick from.
+    pub fn provided(&self, class: usize) -> usize {
+        self.0.borrow().groups[class].provided
+    }
+
+    /// Provides the free buffers of the pool to the kernel while there's room in the rings.
+    pub fn replenish(&self, pool: &BufferPool) {
+        let mut state = self.0.borrow_mut();
+        let state = &mut *state;
+
+        for (class, group) in state.groups.iter_mut().enumerate() {
+            let mut count = 0;
+
+            while group.provided < group.capacity {
+                let Some(mut guard) = pool.acquire_from(class, Holder::Kernel) else {
+                    break;
+                };
+
+                match group.mapped {
+                    Some(ref mapped) => mapped.set(count, &mut guard),
+                    None => {
+                        if let Err(err) = provide(&state.ring, group.group, &mut guard) {
+                            error!("{err:#}");
+                            break;
+                        }
+                    }
+                }
+
+                let bid = guard.idx() as usize;
+                state.guards[bid] = Some(guard);
+                group.provided += 1;
+                count += 1;
+            }
+
+            if let Some(ref mut mapped) = group.mapped {
+                mapped.publish(count);
+            }
+        }
+    }
+
+    /// Takes back the buffer the kernel has picked for a read of `len` bytes.
+    pub fn take(&self, bid: u16, len: usize) -> Result<Chunk> {
+        let mut state = self.0.borrow_mut();
+
+        let buffer = state
+            .guards
+            .get_mut(bid as usize)
+            .and_then(Option::take)
+            .with_context(|| format!("Buffer #{bid} is not provided"))?;
+
+        state.groups[buffer.class()].provided -= 1;
+        Ok(Chunk { buffer, len })
+    }
+}
+
+impl Drop for RingState {
+    fn drop(&mut self) {
+        for group in &self.groups {
+            // Buffers provided with operations stay with the kernel until the ring is closed.
+            let Some(ref mapped) = group.mapped else {
+                continue;
+            };
+
+            // Make sure the kernel doesn't touch the ring anymore before freeing it.
+            if let Err(err) = self.ring.borrow_mut().unregister_buf_ring(group.group) {
+                error!("Unregister buffer ring: {err}");
+            }
+
+            unsafe { alloc::dealloc(mapped.entries as *mut u8, mapped.layout) };
+        }
+    }
+}
+
+impl MappedRing {
+    /// Fills the entry `offset` places past the tail with the buffer without publishing it.
+    fn set(&self, offset: u16, guard: &mut Guard) {
+        let idx = (self.tail.wrapping_add(offset) as usize) & self.mask;
+        let entry = unsafe { &mut *self.entries.add(idx) };
+        entry.set_addr(guard.as_mut_ptr() as u64);
+        entry.set_len(guard.as_ref().len() as u32);
+        entry.set_bid(guard.idx());
+    }
+
+    /// Publishes the `count` entries filled past the tail to the kernel.
+    fn publish(&mut self, count: u16) {
+        if count > 0 {
+            self.tail = self.tail.wrapping_add(count);
+
+            let tail = unsafe { &*(BufRingEntry::tail(self.entries) as *const AtomicU16) };
+            tail.store(self.tail, Ordering::Release);
+        }
+    }
+}
+
+/// Pushes an operation providing the buffer whose completion is routed by its id, so that
+/// it's taken back if that fails.
+fn provide(ring: &RefCell<dyn Backend>, group: u16, guard: &mut Guard) -> Result<()> {
+    let len = guard.as_ref().len() as i32;
+
+    let sqe = ProvideBuffers::new(guard.as_mut_ptr(), len, 1, group, guard.idx())
+        .build()
+        .user_data(Route::ProvideBuffer(guard.idx() as u32).into());
+
+    unsafe { ring.borrow_mut().push(&[sqe]) }.context("Push buffer provision")
+}
+
+/// Data read into a buffer which returns to the pool on drop.
+pub struct Chunk {
+    buffer: Guard,
+    len: usize,
+}
+
+impl Chunk {
+    pub fn buffer(&self) -> &Guard {
+        &self.buffer
+    }
+
+    /// How much the next read is likely to get judging by this one: more than the buffer holds
+    /// if it has been filled up.
+    pub fn size_hint(&self) -> usize {
+        match self.len == self.buffer.as_ref().len() {
+            true => self.len + 1,
+            false => self.len,
+        }
+    }
+
+    /// Keeps the buffer to read into it again.
+    pub fn into_buffer(self) -> Guard {
+        self.buffer
+    }
+}
+
+impl Deref for Chunk {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.buffer.as_ref()[..self.len]
+    }
+}
+
+impl DerefMut for Chunk {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        &mut self.buffer.as_mut_slice()[..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+    use crate::allocator::HeapAllocator;
+    use crate::mock::Mock;
+
+    const CLASSES: [BufferClass; 3] = [
+        BufferClass {
+            size: 64,
+            count: 4,
+            max_count: Some(12),
+        },
+        BufferClass {
+            size: 256,
+            count: 3,
+            max_count: Some(6),
+        },
+        BufferClass {
+            size: 1024,
+            count: 2,
+            max_count: None,
+        },
+    ];
+
+    /// Number of random sequences to run, each from a seed of its own to reproduce it by.
+    const SEQUENCES: u64 = 32;
+    const STEPS: usize = 1000;
+
+    /// Xorshift seeded for each sequence, unlike [`utils::random`].
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: usize) -> usize {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            (self.0 % n as u64) as usize
+        }
+    }
+
+    /// What the guard of the buffer at `idx` fills it with to tell whether others write into it.
+    fn pattern(idx: u16) -> u8 {
+        (idx % 251) as u8 + 1
+    }
+
+    #[test]
+    fn interleaved_acquires_and_releases() {
+        for seed in 1..=SEQUENCES {
+            let mut rng = Rng(seed);
+            let mut pool = BufferPool::new(&CLASSES, Box::new(HeapAllocator)).unwrap();
+            let mut mock = Mock::default();
+            pool.register(&mut mock).unwrap();
+            let mut held = Vec::new();
+
+            for step in 0..STEPS {
+                match rng.below(10) {
+                    0..=4 => {
+                        let size_hint = rng.below(1500);
+
+                        let Some(mut guard) = pool.acquire(size_hint, Holder::Client(0)) else {
+                            // Only once all of them are taken.
+                            for class in 0..pool.classes() {
+                                assert!(pool.acquire_from(class, Holder::Kernel).is_none());
+                            }
+
+                            continue;
+                        };
+
+                        let size = guard.as_ref().len();
+
+                        // A smaller buffer only if none fitting is free.
+                        if size < size_hint {
+                            for class in 0..pool.classes() {
+                                if pool.class(class).0 as usize >= size_hint {
+                                    assert!(pool.acquire_from(class, Holder::Kernel).is_none());
+                                }
+                            }
+                        }
+
+                        let pattern = pattern(guard.idx());
+                        guard.as_mut_slice().fill(pattern);
+                        held.push(guard);
+                    }
+                    5..=7 if !held.is_empty() => {
+                        held.swap_remove(rng.below(held.len()));
+                    }
+                    8 => {
+                        pool.grow(rng.below(pool.classes()), &mut mock).unwrap();
+                    }
+                    _ => {
+                        let class = rng.below(pool.classes());
+                        pool.shrink(class, 0, Duration::ZERO, &mut mock).unwrap();
+                    }
+                }
+
+                check(&pool, &mock, &held).unwrap_or_else(|err| {
+                    panic!("Sequence #{seed} at step {step}: {err}");
+                });
+            }
+        }
+    }
+
+    /// Checks that the `held` buffers are distinct and registered at their indexes, that none
+    /// of the registered buffers overlap, and that the pool accounts for all of them.
+    fn check(pool: &BufferPool, mock: &Mock, held: &[Guard]) -> std::result::Result<(), String> {
+        let indexes = held.iter().map(Guard::idx).collect::<HashSet<_>>();
+
+        if indexes.len() != held.len() {
+            return Err("The same index held twice".into());
+        }
+
+        let (allocated, free) = pool.usage();
+
+        if allocated - free != held.len() || pool.holders().len() != held.len() {
+            return Err(format!(
+                "{} held but {allocated} allocated, {free} free and {} holders",
+                held.len(),
+                pool.holders().len()
+            ));
+        }
+
+        for guard in held {
+            let data = guard.as_ref();
+            let iovec = mock.registered()[guard.idx() as usize];
+
+            if data.len() != pool.class(guard.class()).0 as usize {
+                return Err(format!("Buffer #{} of the wrong size", guard.idx()));
+            }
+
+            if iovec.iov_base as *const u8 != data.as_ptr() || iovec.iov_len != data.len() {
+                return Err(format!("Buffer #{} registered elsewhere", guard.idx()));
+            }
+
+            if data.iter().any(|&byte| byte != pattern(guard.idx())) {
+                return Err(format!("Buffer #{} overwritten", guard.idx()));
+            }
+        }
+
+        let mut registered = mock
+            .registered()
+            .iter()
+            .filter(|iovec| !iovec.iov_base.is_null())
+            .map(|iovec| (iovec.iov_base as usize, iovec.iov_len))
+            .collect::<Vec<_>>();
+
+        if registered.len() != allocated {
+            return Err(format!(
+                "{} buffers registered but {allocated} allocated",
+                registered.len()
+            ));
+        }
+
+        registered.sort_unstable();
+
+        for pair in registered.windows(2) {
+            if pair[0].0 + pair[0].1 > pair[1].0 {
+                return Err(format!(
+                    "Buffers at {:#x} and {:#x} overlap",
+                    pair[0].0, pair[1].0
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn buffers_are_taken_back_once() {
+        let pool = BufferPool::new(&CLASSES, Box::new(HeapAllocator)).unwrap();
+        let ring = Rc::new(RefCell::new(Mock::default()));
+        let buffers = BufferRing::provide(ring, &pool, 0);
+        buffers.replenish(&pool);
+        assert_eq!(buffers.available(), 9);
+
+        // The second class starts after the indexes the first one may grow into.
+        let chunk = buffers.take(12, 10).unwrap();
+        assert_eq!(chunk.buffer().idx(), 12);
+        assert_eq!(chunk.buffer().class(), 1);
+        assert_eq!(chunk.len(), 10);
+        assert!(buffers.take(12, 10).is_err());
+        assert_eq!(buffers.available(), 8);
+
+        // Until it's provided again.
+        drop(chunk);
+        assert!(buffers.take(12, 10).is_err());
+        buffers.replenish(&pool);
+        assert_eq!(buffers.available(), 9);
+        assert!(buffers.take(12, 10).is_ok());
+    }
+
+    #[test]
+    fn released_buffers_are_scrubbed() {
+        let pool = BufferPool::new(&CLASSES, Box::new(HeapAllocator))
+            .unwrap()
+            .with_scrub(Scrub::Poison);
+
+        let mut guard = pool.acquire(64, Holder::Client(0)).unwrap();
+        let idx = guard.idx();
+        guard.as_mut_slice().fill(1);
+        drop(guard);
+
+        let guard = pool.acquire(64, Holder::Client(1)).unwrap();
+        assert_eq!(guard.idx(), idx);
+        assert!(guard.as_ref().iter().all(|&byte| byte == POISON));
+    }
+
+    #[test]
+    fn lent_buffers_are_released_with_the_last_loan() {
+        let pool = BufferPool::new(&CLASSES, Box::new(HeapAllocator)).unwrap();
+        let guard = pool.acquire(64, Holder::Client(0)).unwrap();
+        let idx = guard.idx();
+        let loan = guard.lend();
+        let other = loan.clone();
+        drop(guard);
+
+        let guard = pool.acquire(64, Holder::Client(1)).unwrap();
+        assert_ne!(guard.idx(), idx);
+        drop(guard);
+
+        drop(loan);
+        assert_eq!(other.idx(), idx);
+        drop(other);
+
+        let guard = pool.acquire(64, Holder::Client(2)).unwrap();
+        assert_eq!(guard.idx(), idx);
+    }
+}
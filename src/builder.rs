@@ -0,0 +1,413 @@
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Barrier, Mutex, PoisonError};
+use std::thread;
+
+use anyhow::{Context as _, Result};
+
+use crate::config::{BackendKind, ServerConfig};
+use crate::connection_limit::ConnectionsPerIp;
+use crate::handler::{self, Handler, MakeServe};
+use crate::handover::Handover;
+use crate::mesh::Mesh;
+use crate::metrics::Metrics;
+use crate::middleware::Middleware;
+use crate::privileges::Privileges;
+use crate::remote::{Command, Remote};
+use crate::server::{ConfigLoader, Server};
+use crate::signal::{self, DRAIN_SIGNAL, HANDLED_SIGNALS, RELOAD_SIGNAL, STATS_SIGNAL};
+
+/// Loads the config anew for reloads, shared by the workers.
+pub type SharedConfigLoader = Arc<dyn Fn() -> Result<ServerConfig> + Send + Sync>;
+
+/// Sets up and runs the servers, one per worker thread, until they're shut down.
+pub struct ServerBuilder {
+    config: ServerConfig,
+    config_loader: Option<SharedConfigLoader>,
+    handler: Option<MakeServe>,
+    middleware: Vec<Arc<dyn Middleware>>,
+    handle_signals: bool,
+    on_ready: Option<Arc<dyn Fn() -> Result<()> + Send + Sync>>,
+    handle: Handle,
+}
+
+impl ServerBuilder {
+    /// Starts with the default config.
+    pub fn new() -> Self {
+        Self::from_config(ServerConfig::default())
+    }
+
+    pub fn from_config(config: ServerConfig) -> Self {
+        Self {
+            config,
+            config_loader: None,
+            handler: None,
+            middleware: Vec::new(),
+            handle_signals: false,
+            on_ready: None,
+            handle: Handle::default(),
+        }
+    }
+
+    pub fn address(mut self, address: SocketAddr) -> Self {
+        self.config.address = address;
+        self
+    }
+
+    /// Listens on the `address` as well.
+    pub fn listen(mut self, address: SocketAddr) -> Self {
+        self.config.listen.push(address);
+        self
+    }
+
+    /// Makes the pool of `count` buffers of `size` bytes each.
+    pub fn buffers(mut self, count: u16, size: u32) -> Self {
+        self.config.buffers_count = count;
+        self.config.buffer_size = size;
+        self
+    }
+
+    pub fn backend(mut self, backend: BackendKind) -> Self {
+        self.config.backend = backend;
+        self
+    }
+
+    /// Number of io_uring submission queue entries.
+    pub fn ring_entries(mut self, entries: u32) -> Self {
+        self.config.ring_entries = entries;
+        self
+    }
+
+    /// Polls the submission queue with a kernel thread which sleeps after being idle for
+    /// `idle_ms` milliseconds.
+    pub fn sqpoll(mut self, idle_ms: u32) -> Self {
+        self.config.sqpoll_idle_ms = Some(idle_ms);
+        self
+    }
+
+    pub fn workers(mut self, workers: usize) -> Self {
+        self.config.workers = workers;
+        self
+    }
+
+    /// Reloads the runtime config with `config_loader` on [`RELOAD_SIGNAL`] or
+    /// [`Handle::reload`].
+    pub fn config_loader(mut self, config_loader: SharedConfigLoader) -> Self {
+        self.config_loader = Some(config_loader);
+        self
+    }
+
+    /// Serves each connection with a clone of the `handler` instead of echoing.
+    pub fn handler<H: Handler + Clone + Send + Sync + 'static>(mut self, handler: H) -> Self {
+        self.handler = Some(handler::make_serve(handler));
+        self
+    }
+
+    /// Runs the `middleware` hooks for each connection after those added before.
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Makes the servers handle [`HANDLED_SIGNALS`] instead of leaving them to the process.
+    /// They're blocked for the calling thread then and for the threads it spawns afterwards.
+    pub fn handle_signals(mut self, handle_signals: bool) -> Self {
+        self.handle_signals = handle_signals;
+        self
+    }
+
+    /// Calls `on_ready` once all the servers have bound and the server the listeners have been
+    /// taken over from, if any, has been told to drain. The servers fail if it does.
+    pub fn on_ready(mut self, on_ready: impl Fn() -> Result<()> + Send + Sync + 'static) -> Self {
+        self.on_ready = Some(Arc::new(on_ready));
+        self
+    }
+
+    /// Controls the servers once they run.
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Runs the servers, the only one on the calling thread, until they're shut down. Fails if
+    /// any worker fails. Switches to the user and group of the config once all of them are
+    /// bound, and tells the server the listeners have been taken over from to drain then.
+    pub fn run(self) -> Result<()> {
+        // Looked up first so that a missing account doesn't get as far as binding.
+        let privileges =
+            Privileges::resolve(self.config.user.as_deref(), self.config.group.as_deref())?;
+
+        if self.handle_signals {
+            // Must be done before spawning any threads so that they inherit the signal mask.
+            signal::block(&HANDLED_SIGNALS)?;
+        }
+
+        if self.config.workers == 0 {
+            bail!("At least one worker is required");
+        }
+
+        let handover = match self.config.handover_socket {
+            Some(ref path) => Some(Arc::new(
+                Handover::take_over(path, self.config.workers)
+                    .with_context(|| format!("Handover socket {}", path.display()))?,
+            )),
+            None => None,
+        };
+
+        match self.config.workers {
+            1 => self.run_single(privileges, handover),
+            workers => self.run_workers(workers, privileges, handover),
+        }
+    }
+
+    fn run_single(
+        &self,
+        privileges: Option<Privileges>,
+        handover: Option<Arc<Handover>>,
+    ) -> Result<()> {
+        let remote = Remote::new()?;
+        self.handle.attach(remote.clone());
+
+        let mut server = Server::bind(&self.config, 0, handover.clone())?
+            .with_remote(remote)
+            .with_handle(self.handle.clone());
+
+        if let Some(ref make_serve) = self.handler {
+            server = server.with_handler(make_serve());
+        }
+
+        if !self.middleware.is_empty() {
+            server = server.with_middleware(self.middleware.as_slice().into());
+        }
+
+        if self.handle_signals {
+            server = server.with_signals(signal::signalfd(&HANDLED_SIGNALS, true)?);
+        }
+
+        if let Some(config_loader) = self.worker_config_loader() {
+            server = server.with_config_loader(config_loader);
+        }
+
+        if let Some(ref handover) = handover {
+            handover.bound();
+        }
+
+        self.set_up(privileges.as_ref(), handover.as_deref())?;
+        server.run()
+    }
+
+    fn run_workers(
+        &self,
+        workers: usize,
+        privileges: Option<Privileges>,
+        handover: Option<Arc<Handover>>,
+    ) -> Result<()> {
+        let (tx, rx) = mpsc::channel();
+        let mesh = Mesh::new(workers);
+        let metrics = Arc::new(Metrics::new(workers));
+        let connections_per_ip = ConnectionsPerIp::default();
+        // The workers wait for each other to bind, then for the privileges to be dropped and
+        // the handover to be completed.
+        let bound = (privileges.is_some() || handover.is_some() || self.on_ready.is_some())
+            .then(|| Arc::new(Barrier::new(workers + 1)));
+        let set_up = Arc::new(AtomicBool::new(false));
+
+        for worker_id in 0..workers {
+            let config = self.config.clone();
+            let config_loader = self.config_loader.clone();
+            let handler = self.handler.clone();
+            let middleware = self.middleware.clone();
+            let tx = tx.clone();
+            let mesh = mesh.clone();
+            let metrics = Arc::clone(&metrics);
+            let connections_per_ip = connections_per_ip.clone();
+            let remote = Remote::new()?;
+            let handle = self.handle.clone();
+            let bound = bound.clone();
+            let set_up = Arc::clone(&set_up);
+            let handover = handover.clone();
+            self.handle.attach(remote.clone());
+
+            thread::Builder::new()
+                .name(format!("worker-{worker_id}"))
+                .spawn(move || {
+                    let mut result = Server::bind(&config, worker_id, handover.clone());
+
+                    if let (Ok(_), Some(handover)) = (&result, handover) {
+                        handover.bound();
+                    }
+
+                    // Even if failed to bind, as the others wait for it.
+                    if let Some(bound) = bound {
+                        bound.wait();
+                        bound.wait();
+
+                        if !set_up.load(Ordering::Acquire) {
+                            result = result.and(Err(anyhow!("Not set up")));
+                        }
+                    }
+
+                    let result = result.and_then(|server| {
+                        let mut server = server
+                            .with_remote(remote)
+                            .with_handle(handle)
+                            .with_metrics(metrics)
+                            .with_connections_per_ip(connections_per_ip)
+                            .with_mesh(mesh)?;
+
+                        if let Some(make_serve) = handler {
+                            server = server.with_handler(make_serve());
+                        }
+
+                        if !middleware.is_empty() {
+                            server = server.with_middleware(middleware.into());
+                        }
+
+                        if let Some(config_loader) = config_loader {
+                            server = server.with_config_loader(Box::new(move || config_loader()));
+                        }
+
+                        server.run()
+                    });
+
+                    tx.send((worker_id, result)).ok();
+                })
+                .context("Spawn worker")?;
+        }
+
+        if let Some(bound) = bound {
+            bound.wait();
+            let result = self.set_up(privileges.as_ref(), handover.as_deref());
+            set_up.store(result.is_ok(), Ordering::Release);
+            bound.wait();
+            result?;
+        }
+
+        if self.handle_signals {
+            // Signals are delivered to just one thread so relay them to every worker.
+            let signalfd = signal::signalfd(&HANDLED_SIGNALS, false)?;
+            let handle = self.handle.clone();
+
+            thread::Builder::new()
+                .name("signals".into())
+                .spawn(move || {
+                    if let Err(err) = signal::forward(signalfd, handle) {
+                        error!("Signal forwarding failed: {err:#}");
+                    }
+                })
+                .context("Spawn signal forwarder")?;
+        }
+
+        // Any worker failure is fatal for the whole process.
+        for _ in 0..workers {
+            let (worker_id, result) = rx.recv().context("Receive worker result")?;
+            result.with_context(|| format!("Worker #{worker_id}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Drops the privileges once the workers have bound, then tells the previous server to
+    /// drain if all of them have and calls back that the servers are ready.
+    fn set_up(&self, privileges: Option<&Privileges>, handover: Option<&Handover>) -> Result<()> {
+        if let Some(privileges) = privileges {
+            self.drop_privileges(privileges)?;
+        }
+
+        if let Some(handover) = handover {
+            handover.complete(self.handle.clone())?;
+        }
+
+        if let Some(ref on_ready) = self.on_ready {
+            on_ready()?;
+        }
+
+        Ok(())
+    }
+
+    /// Switches the whole process to the account, handing the admin and handover sockets over
+    /// to it first for the account to be able to use and remove them.
+    fn drop_privileges(&self, privileges: &Privileges) -> Result<()> {
+        for path in [&self.config.admin_socket, &self.config.handover_socket]
+            .into_iter()
+            .flatten()
+        {
+            privileges.chown(path)?;
+        }
+
+        privileges.apply().context("Drop privileges")?;
+        info!("Switched to {privileges}");
+        Ok(())
+    }
+
+    fn worker_config_loader(&self) -> Option<ConfigLoader> {
+        let config_loader = self.config_loader.clone()?;
+        Some(Box::new(move || config_loader()))
+    }
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Controls the servers of a [`ServerBuilder`] from any thread. Commands reach the servers
+/// which have started running by the time they're sent.
+#[derive(Clone, Default)]
+pub struct Handle {
+    remotes: Arc<Mutex<Vec<Remote>>>,
+    drained: Arc<AtomicBool>,
+}
+
+impl Handle {
+    /// Shuts the servers down gracefully like SIGTERM; a repeated call stops them right away.
+    pub fn shutdown(&self) -> Result<()> {
+        self.send(Command::Signal(libc::SIGTERM as u32))
+    }
+
+    /// Drains the servers like [`DRAIN_SIGNAL`]; a repeated call stops them right away.
+    pub fn drain(&self) -> Result<()> {
+        self.send(Command::Signal(DRAIN_SIGNAL as u32))
+    }
+
+    /// Whether the servers have been drained rather than shut down, for the process to exit
+    /// with a status telling so.
+    pub fn is_drained(&self) -> bool {
+        self.drained.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_drained(&self) {
+        self.drained.store(true, Ordering::Release);
+    }
+
+    /// Makes the servers reload their runtime config like [`RELOAD_SIGNAL`].
+    pub fn reload(&self) -> Result<()> {
+        self.send(Command::Signal(RELOAD_SIGNAL as u32))
+    }
+
+    /// Makes the servers log a summary of their state like [`STATS_SIGNAL`].
+    pub fn report_stats(&self) -> Result<()> {
+        self.send(Command::Signal(STATS_SIGNAL as u32))
+    }
+
+    pub(crate) fn send(&self, command: Command) -> Result<()> {
+        for remote in self.remotes().iter() {
+            remote.send(command.clone())?;
+        }
+
+        Ok(())
+    }
+
+    /// Number of the servers the commands reach.
+    pub(crate) fn workers(&self) -> usize {
+        self.remotes().len()
+    }
+
+    fn attach(&self, remote: Remote) {
+        self.remotes().push(remote);
+    }
+
+    fn remotes(&self) -> std::sync::MutexGuard<'_, Vec<Remote>> {
+        self.remotes.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
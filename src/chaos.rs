@@ -0,0 +1,95 @@
+use std::time::Duration;
+
+use crate::config::Chaos;
+use crate::utils;
+
+/// What goes wrong with a write of [`Chaos`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    Drop,
+    /// Write this many bytes from the start only.
+    Truncate(usize),
+    /// Flip the bit of the byte at the index.
+    Corrupt {
+        index: usize,
+        bit: u8,
+    },
+    /// Write this many bytes from the start, then the rest after the pause.
+    Stall(usize, Duration),
+    Reset,
+}
+
+/// The fault a write of `len` bytes gets if any.
+pub fn pick(chaos: &Chaos, len: usize) -> Option<Fault> {
+    if len == 0 {
+        return None;
+    }
+
+    let probabilities = [
+        chaos.drop,
+        chaos.truncate,
+        chaos.corrupt,
+        chaos.stall,
+        chaos.reset,
+    ];
+
+    let mut roll = unit();
+    let fault = probabilities.iter().position(|&probability| {
+        roll -= probability;
+        roll < 0.0
+    })?;
+
+    let at = utils::random() as usize % len;
+
+    Some(match fault {
+        0 => Fault::Drop,
+        1 => Fault::Truncate(at),
+        2 => Fault::Corrupt {
+            index: at,
+            bit: (utils::random() % 8) as u8,
+        },
+        3 => Fault::Stall(at, Duration::from_millis(chaos.stall_ms)),
+        _ => Fault::Reset,
+    })
+}
+
+/// Uniformly distributed in `[0, 1)`.
+fn unit() -> f64 {
+    (utils::random() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn picks_by_probability() {
+        let chaos = Chaos::default();
+        assert!((0..100).all(|_| pick(&chaos, 10).is_none()));
+
+        let chaos = Chaos {
+            corrupt: 1.0,
+            ..Chaos::default()
+        };
+
+        for _ in 0..100 {
+            match pick(&chaos, 10) {
+                Some(Fault::Corrupt { index, bit }) => assert!(index < 10 && bit < 8),
+                fault => panic!("Expected corruption, got {fault:?}"),
+            }
+        }
+
+        assert_eq!(pick(&chaos, 0), None);
+
+        let chaos = Chaos {
+            drop: 0.5,
+            reset: 0.5,
+            ..Chaos::default()
+        };
+
+        let drops = (0..1000)
+            .filter(|_| pick(&chaos, 10) == Some(Fault::Drop))
+            .count();
+        assert!((350..650).contains(&drops), "{drops} drops of 1000");
+    }
+}
@@ -0,0 +1,667 @@
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU64;
+use std::path::PathBuf;
+
+use anyhow::Result;
+use clap::Parser;
+
+use uring::config::{
+    AllocatorKind, BackendKind, BufferClass, Cidr, Delay, Ipv6Mode, ServerConfig, Service,
+    SlowConsumer, TransformKind, VsockAddr,
+};
+use uring::{Framing, LogLevel};
+
+/// TCP echo server with io_uring.
+///
+/// Options given on the command line take precedence over the config file.
+#[derive(Clone, Debug, Parser)]
+#[command(version, about)]
+pub struct Args {
+    /// Path to a TOML config file.
+    #[arg(short, long)]
+    pub config: Option<PathBuf>,
+    /// Address to listen on [default: 0.0.0.0].
+    #[arg(short, long)]
+    pub address: Option<IpAddr>,
+    /// Port to listen on [default: 3456].
+    #[arg(short, long)]
+    pub port: Option<u16>,
+    /// Additional address to listen on; may be given multiple times.
+    #[arg(short, long = "listen", value_name = "ADDRESS")]
+    pub listen: Vec<SocketAddr>,
+    /// vsock address to listen on as CID:PORT, the CID being `any` to listen on whichever the
+    /// machine has; may be given multiple times.
+    #[arg(long = "vsock", value_name = "CID:PORT")]
+    pub vsock: Vec<VsockAddr>,
+    /// Path of a UNIX socket to listen on; may be given multiple times.
+    #[arg(long = "unix", value_name = "PATH")]
+    pub unix: Vec<PathBuf>,
+    /// How to listen on IPv6 addresses [default: dual-stack].
+    #[arg(long, value_enum)]
+    pub ipv6_mode: Option<Ipv6Mode>,
+    /// What runs the I/O operations [default: io-uring].
+    #[arg(long, value_enum)]
+    pub backend: Option<BackendKind>,
+    /// Number of io_uring submission queue entries [default: 1024].
+    #[arg(long)]
+    pub ring_entries: Option<u32>,
+    /// Poll the submission queue with a kernel thread which sleeps after being idle for this
+    /// many milliseconds [default: submit with syscalls].
+    #[arg(long)]
+    pub sqpoll_idle_ms: Option<u32>,
+    /// Let the kernel run completion work on the next syscall instead of interrupting the
+    /// thread; disabled if the kernel doesn't support it.
+    #[arg(long)]
+    pub coop_taskrun: bool,
+    /// Run completion work only when waiting for events; implies --single-issuer and disabled if
+    /// the kernel doesn't support it.
+    #[arg(long)]
+    pub defer_taskrun: bool,
+    /// Tell the kernel that only one thread submits to the ring; disabled if the kernel doesn't
+    /// support it.
+    #[arg(long)]
+    pub single_issuer: bool,
+    /// Busy-poll the NAPI contexts of the sockets for up to this many microseconds when waiting
+    /// for events; disabled if the kernel doesn't support it [default: don't busy-poll].
+    #[arg(long)]
+    pub napi_busy_poll_us: Option<u32>,
+    /// Prefer busy-polling NAPI contexts over NIC interrupts.
+    #[arg(long)]
+    pub napi_prefer_busy_poll: bool,
+    /// Poll the completion queue for up to this many microseconds before blocking when waiting
+    /// for events, unless with --coop-taskrun or --defer-taskrun [default: block right away].
+    #[arg(long)]
+    pub spin_us: Option<u64>,
+    /// Number of buffers in the pool, i.e. the maximum number of reads in progress
+    /// [default: 8192].
+    #[arg(long)]
+    pub buffers_count: Option<u16>,
+    /// Size of each buffer in bytes [default: 32768].
+    #[arg(long)]
+    pub buffer_size: Option<u32>,
+    /// Let the pool grow by the number of buffers above at a time up to this many when the
+    /// kernel runs out of them [default: never].
+    #[arg(long)]
+    pub buffers_max_count: Option<u16>,
+    /// Release buffers the pool has grown by once they've been unused for this long
+    /// [default: 30000].
+    #[arg(long)]
+    pub buffers_shrink_delay_ms: Option<u64>,
+    /// Size class of buffers in the pool as SIZE:COUNT[:MAX_COUNT] instead of the buffers above;
+    /// may be given multiple times.
+    #[arg(long = "buffer-class", value_name = "SIZE:COUNT[:MAX_COUNT]")]
+    pub buffer_classes: Vec<BufferClass>,
+    /// Report buffers held by a client for longer than this [default: never].
+    #[arg(long)]
+    pub buffer_hold_warn_ms: Option<u64>,
+    /// Zero buffers once they're released; debug builds poison them regardless.
+    #[arg(long)]
+    pub zero_buffers: bool,
+    /// Where the memory of the buffer pool comes from [default: mmap].
+    #[arg(long, value_enum)]
+    pub buffer_allocator: Option<AllocatorKind>,
+    /// Back the buffer pool with huge pages, falling back to transparent huge pages if none are
+    /// reserved. Applies to the mmap allocator only.
+    #[arg(long)]
+    pub huge_pages: bool,
+    /// Listen backlog for pending connections [default: 1024].
+    #[arg(long)]
+    pub backlog: Option<i32>,
+    /// Listen with Multipath TCP, falling back to plain TCP if the kernel doesn't support it.
+    #[arg(long)]
+    pub mptcp: bool,
+    /// What to serve TCP connections with [default: echo].
+    #[arg(long, value_enum)]
+    pub service: Option<Service>,
+    /// How to split the TCP stream into messages to echo [default: raw].
+    #[arg(short, long, value_enum)]
+    pub framing: Option<Framing>,
+    /// Maximum size of a single message in bytes with lines or length-prefixed framing
+    /// [default: 16777216].
+    #[arg(long)]
+    pub max_message_size: Option<usize>,
+    /// Rewrite each echoed message: every frame with framing, every chunk otherwise
+    /// [default: identity].
+    #[arg(long, value_enum)]
+    pub transform: Option<TransformKind>,
+    /// The byte the xor transform flips the bits with [default: 255].
+    #[arg(long, value_name = "BYTE")]
+    pub transform_key: Option<u8>,
+    /// Forward connections to this host:port instead of echoing.
+    #[arg(long, value_name = "HOST:PORT")]
+    pub forward: Option<String>,
+    /// Write messages received from a client to all the other clients instead of echoing.
+    #[arg(short, long)]
+    pub broadcast: bool,
+    /// Bytes a client may have waiting to be written to it, forwarded or broadcast, before its
+    /// source is held off or it's treated as a slow consumer [default: 1048576].
+    #[arg(long, value_name = "BYTES")]
+    pub output_high_watermark: Option<usize>,
+    /// What to do with a client which broadcasts pile up for beyond --output-high-watermark
+    /// [default: disconnect].
+    #[arg(long, value_enum)]
+    pub slow_consumer: Option<SlowConsumer>,
+    /// Respond to every message with the contents of this file instead of echoing it.
+    #[arg(long, value_name = "PATH")]
+    pub response_file: Option<PathBuf>,
+    /// Also echo UDP datagrams on the same address.
+    #[arg(long)]
+    pub udp: bool,
+    /// Number of worker threads with their own rings sharing the listening ports [default: 1].
+    #[arg(short, long)]
+    pub workers: Option<usize>,
+    /// CPU to pin an event loop thread to, the Nth worker to the Nth one wrapping around, with
+    /// its buffer pool on the CPU's NUMA node; may be given multiple times.
+    #[arg(long = "cpu", value_name = "CPU")]
+    pub cpus: Vec<usize>,
+    /// CPU to pin a SQPOLL kernel thread to likewise; may be given multiple times.
+    #[arg(long = "sqpoll-cpu", value_name = "CPU")]
+    pub sqpoll_cpus: Vec<u32>,
+    /// How long to let clients finish their exchanges on shutdown in milliseconds
+    /// [default: 5000].
+    #[arg(long)]
+    pub shutdown_timeout_ms: Option<u64>,
+    /// How long to let clients finish their exchanges on a drain, on SIGQUIT or the admin
+    /// `drain` command, in milliseconds [default: 30000].
+    #[arg(long)]
+    pub drain_timeout_ms: Option<u64>,
+    /// Message to send to the clients still connected when the drain deadline passes, before
+    /// disconnecting them.
+    #[arg(long)]
+    pub drain_goodbye: Option<String>,
+    /// How often to wake the event loop up for periodic maintenance in milliseconds
+    /// [default: 1000].
+    #[arg(long)]
+    pub tick_interval_ms: Option<u64>,
+    /// Maximum number of simultaneous connections per worker [default: unlimited].
+    #[arg(long)]
+    pub max_connections: Option<usize>,
+    /// Maximum number of simultaneous connections from the same address across the workers
+    /// [default: unlimited].
+    #[arg(long)]
+    pub max_connections_per_ip: Option<usize>,
+    /// Accept connections only from this network, such as 10.0.0.0/8, or the others allowed;
+    /// may be given multiple times [default: from anywhere].
+    #[arg(long = "allow", value_name = "CIDR")]
+    pub allow: Vec<Cidr>,
+    /// Close connections from this network right away, even if allowed; may be given multiple
+    /// times.
+    #[arg(long = "deny", value_name = "CIDR")]
+    pub deny: Vec<Cidr>,
+    /// Stop accepting for --accept-cooldown-ms once a worker accepts more connections per
+    /// second than this on average [default: unlimited].
+    #[arg(long, value_name = "CONNECTIONS")]
+    pub max_accept_rate: Option<NonZeroU64>,
+    /// How long to stop accepting for when over --max-accept-rate in milliseconds
+    /// [default: 1000].
+    #[arg(long)]
+    pub accept_cooldown_ms: Option<u64>,
+    /// Hand clients beyond --max-connections off to another worker instead of rejecting them.
+    #[arg(long)]
+    pub handoff: bool,
+    /// Disconnect clients which send nothing for this long in milliseconds [default: never].
+    #[arg(long)]
+    pub idle_timeout_ms: Option<u64>,
+    /// Disconnect clients which take longer than this to complete a frame they have started in
+    /// milliseconds, however often they send something [default: never].
+    #[arg(long)]
+    pub frame_timeout_ms: Option<u64>,
+    /// Disconnect clients which send fewer bytes per second than this in the middle of a frame,
+    /// after the first second [default: unlimited].
+    #[arg(long)]
+    pub min_frame_rate: Option<NonZeroU64>,
+    /// Keep a multishot receive on each socket instead of submitting a read after each message.
+    #[arg(long)]
+    pub multishot_recv: bool,
+    /// Write at least this many bytes at once with zero-copy sends [default: never].
+    #[arg(long)]
+    pub zerocopy_threshold: Option<usize>,
+    /// Submit each read linked with a write of the same buffer when echoing raw data.
+    #[arg(long)]
+    pub linked_echo: bool,
+    /// Echo raw data by splicing it through a pipe of each client's instead of reading it into
+    /// buffers.
+    #[arg(long)]
+    pub splice_echo: bool,
+    /// Hold reads from a client off while it's over this many bytes per second on average,
+    /// allowing bursts of a second's worth.
+    #[arg(long, value_name = "BYTES")]
+    pub rate_limit_bytes: Option<NonZeroU64>,
+    /// Same for messages: frames with framing, chunks as they arrive otherwise.
+    #[arg(long, value_name = "MESSAGES")]
+    pub rate_limit_messages: Option<NonZeroU64>,
+    /// Hold each write back to a client off for MS milliseconds, give or take up to JITTER.
+    #[arg(long, value_name = "MS[:JITTER]")]
+    pub delay: Option<Delay>,
+    /// Probability of a write to a client being dropped in chaos mode [default: 0].
+    #[arg(long, value_name = "P")]
+    pub chaos_drop: Option<f64>,
+    /// Probability of a write being cut short at a random byte [default: 0].
+    #[arg(long, value_name = "P")]
+    pub chaos_truncate: Option<f64>,
+    /// Probability of a bit of a random byte being flipped [default: 0].
+    #[arg(long, value_name = "P")]
+    pub chaos_corrupt: Option<f64>,
+    /// Probability of a write stalling at a random byte for --chaos-stall-ms [default: 0].
+    #[arg(long, value_name = "P")]
+    pub chaos_stall: Option<f64>,
+    /// Probability of the connection being reset instead of written to [default: 0].
+    #[arg(long, value_name = "P")]
+    pub chaos_reset: Option<f64>,
+    /// How long stalled writes pause for in milliseconds [default: 1000].
+    #[arg(long, value_name = "MS")]
+    pub chaos_stall_ms: Option<u64>,
+    /// Set `TCP_NODELAY` on accepted sockets.
+    #[arg(long)]
+    pub nodelay: bool,
+    /// Detach from the terminal and run in the background.
+    #[arg(short, long)]
+    pub daemon: bool,
+    /// File to write the process id to.
+    #[arg(long)]
+    pub pid_file: Option<PathBuf>,
+    /// File to redirect stdout and stderr to in daemon mode; discarded otherwise.
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+    /// User to switch to, by name or id, once the listeners are bound.
+    #[arg(long, value_name = "USER")]
+    pub user: Option<String>,
+    /// Group to switch to [default: the primary group of the user].
+    #[arg(long, value_name = "GROUP")]
+    pub group: Option<String>,
+    /// Unix socket to serve admin commands on: stats, list-clients, kick, set-log-level, set
+    /// and drain.
+    #[arg(long, value_name = "PATH")]
+    pub admin_socket: Option<PathBuf>,
+    /// Unix socket to take the listeners over from the running server at, which drains then,
+    /// and to hand them over to the next one at.
+    #[arg(long, value_name = "PATH")]
+    pub handover_socket: Option<PathBuf>,
+    /// Confine the workers to the syscalls serving clients takes once they're set up, and their
+    /// rings to the operations the server submits.
+    #[arg(long)]
+    pub seccomp: bool,
+    /// The most verbose events to log: connections at info, reads and writes at debug, payloads
+    /// at trace [default: info].
+    #[arg(long, value_enum)]
+    pub log_level: Option<LogLevel>,
+    /// Log only one in this many message payloads at the trace level [default: 1].
+    #[arg(long, value_name = "N")]
+    pub payload_log_every: Option<u32>,
+    /// Truncate logged message payloads to this many bytes [default: 256].
+    #[arg(long, value_name = "BYTES")]
+    pub payload_log_max_bytes: Option<usize>,
+    /// Log a summary of each worker every this many milliseconds as well as on SIGUSR1
+    /// [default: on SIGUSR1 only].
+    #[arg(long)]
+    pub stats_interval_ms: Option<u64>,
+    /// Check the accounting invariants of each worker every this many milliseconds, stopping the
+    /// server if they don't hold, and log the growth of its memory and descriptors.
+    #[arg(long)]
+    pub soak_interval_ms: Option<u64>,
+    /// Export metrics to statsd at this host:port.
+    #[arg(long, value_name = "HOST:PORT")]
+    pub statsd: Option<String>,
+    /// Prepended to the names of the exported metrics [default: uring].
+    #[arg(long, value_name = "PREFIX")]
+    pub statsd_prefix: Option<String>,
+    /// How often to flush the metrics to statsd in milliseconds [default: 10000].
+    #[arg(long)]
+    pub statsd_interval_ms: Option<u64>,
+    /// DogStatsD tag such as env:prod to add to the exported metrics; may be given multiple
+    /// times.
+    #[arg(long = "statsd-tag", value_name = "TAG")]
+    pub statsd_tags: Vec<String>,
+}
+
+impl Args {
+    /// Loads the config file if any and applies the command line options on top of it.
+    pub fn into_config(self) -> Result<ServerConfig> {
+        let mut config = match self.config {
+            Some(ref path) => ServerConfig::load(path)?,
+            None => ServerConfig::default(),
+        };
+
+        if let Some(address) = self.address {
+            config.address.set_ip(address);
+        }
+
+        if let Some(port) = self.port {
+            config.address.set_port(port);
+        }
+
+        if !self.listen.is_empty() {
+            config.listen = self.listen;
+        }
+
+        if !self.vsock.is_empty() {
+            config.vsock = self.vsock;
+        }
+
+        if !self.unix.is_empty() {
+            config.unix = self.unix;
+        }
+
+        if let Some(ipv6_mode) = self.ipv6_mode {
+            config.ipv6_mode = ipv6_mode;
+        }
+
+        if let Some(backend) = self.backend {
+            config.backend = backend;
+        }
+
+        if let Some(ring_entries) = self.ring_entries {
+            config.ring_entries = ring_entries;
+        }
+
+        if let Some(sqpoll_idle_ms) = self.sqpoll_idle_ms {
+            config.sqpoll_idle_ms = Some(sqpoll_idle_ms);
+        }
+
+        if self.coop_taskrun {
+            config.coop_taskrun = true;
+        }
+
+        if self.defer_taskrun {
+            config.defer_taskrun = true;
+        }
+
+        if self.single_issuer {
+            config.single_issuer = true;
+        }
+
+        if let Some(napi_busy_poll_us) = self.napi_busy_poll_us {
+            config.napi_busy_poll_us = Some(napi_busy_poll_us);
+        }
+
+        if self.napi_prefer_busy_poll {
+            config.napi_prefer_busy_poll = true;
+        }
+
+        if let Some(spin_us) = self.spin_us {
+            config.spin_us = Some(spin_us);
+        }
+
+        if let Some(buffers_count) = self.buffers_count {
+            config.buffers_count = buffers_count;
+        }
+
+        if let Some(buffer_size) = self.buffer_size {
+            config.buffer_size = buffer_size;
+        }
+
+        if let Some(buffers_max_count) = self.buffers_max_count {
+            config.buffers_max_count = Some(buffers_max_count);
+        }
+
+        if let Some(buffers_shrink_delay_ms) = self.buffers_shrink_delay_ms {
+            config.buffers_shrink_delay_ms = buffers_shrink_delay_ms;
+        }
+
+        if !self.buffer_classes.is_empty() {
+            config.buffer_classes = self.buffer_classes;
+        }
+
+        if let Some(buffer_hold_warn_ms) = self.buffer_hold_warn_ms {
+            config.buffer_hold_warn_ms = Some(buffer_hold_warn_ms);
+        }
+
+        if self.zero_buffers {
+            config.zero_buffers = true;
+        }
+
+        if let Some(buffer_allocator) = self.buffer_allocator {
+            config.buffer_allocator = buffer_allocator;
+        }
+
+        if self.huge_pages {
+            config.huge_pages = true;
+        }
+
+        if let Some(backlog) = self.backlog {
+            config.backlog = backlog;
+        }
+
+        if self.mptcp {
+            config.mptcp = true;
+        }
+
+        if let Some(service) = self.service {
+            config.service = service;
+        }
+
+        if let Some(framing) = self.framing {
+            config.framing = framing;
+        }
+
+        if let Some(max_message_size) = self.max_message_size {
+            config.max_message_size = max_message_size;
+        }
+
+        if let Some(transform) = self.transform {
+            config.transform = transform;
+        }
+
+        if let Some(transform_key) = self.transform_key {
+            config.transform_key = transform_key;
+        }
+
+        if let Some(forward) = self.forward {
+            config.forward = Some(forward);
+        }
+
+        if self.broadcast {
+            config.broadcast = true;
+        }
+
+        if let Some(output_high_watermark) = self.output_high_watermark {
+            config.output_high_watermark = output_high_watermark;
+        }
+
+        if let Some(slow_consumer) = self.slow_consumer {
+            config.slow_consumer = slow_consumer;
+        }
+
+        if let Some(response_file) = self.response_file {
+            config.response_file = Some(response_file);
+        }
+
+        if self.udp {
+            config.udp = true;
+        }
+
+        if let Some(workers) = self.workers {
+            config.workers = workers;
+        }
+
+        if !self.cpus.is_empty() {
+            config.cpus = self.cpus;
+        }
+
+        if !self.sqpoll_cpus.is_empty() {
+            config.sqpoll_cpus = self.sqpoll_cpus;
+        }
+
+        if let Some(shutdown_timeout_ms) = self.shutdown_timeout_ms {
+            config.shutdown_timeout_ms = shutdown_timeout_ms;
+        }
+
+        if let Some(drain_timeout_ms) = self.drain_timeout_ms {
+            config.drain_timeout_ms = drain_timeout_ms;
+        }
+
+        if let Some(drain_goodbye) = self.drain_goodbye {
+            config.drain_goodbye = Some(drain_goodbye);
+        }
+
+        if let Some(tick_interval_ms) = self.tick_interval_ms {
+            config.tick_interval_ms = tick_interval_ms;
+        }
+
+        if let Some(max_connections) = self.max_connections {
+            config.max_connections = Some(max_connections);
+        }
+
+        if let Some(max_connections_per_ip) = self.max_connections_per_ip {
+            config.max_connections_per_ip = Some(max_connections_per_ip);
+        }
+
+        if !self.allow.is_empty() {
+            config.allow = self.allow;
+        }
+
+        if !self.deny.is_empty() {
+            config.deny = self.deny;
+        }
+
+        if let Some(max_accept_rate) = self.max_accept_rate {
+            config.max_accept_rate = Some(max_accept_rate);
+        }
+
+        if let Some(accept_cooldown_ms) = self.accept_cooldown_ms {
+            config.accept_cooldown_ms = accept_cooldown_ms;
+        }
+
+        if self.handoff {
+            config.handoff = true;
+        }
+
+        if let Some(idle_timeout_ms) = self.idle_timeout_ms {
+            config.idle_timeout_ms = Some(idle_timeout_ms);
+        }
+
+        if let Some(frame_timeout_ms) = self.frame_timeout_ms {
+            config.frame_timeout_ms = Some(frame_timeout_ms);
+        }
+
+        if let Some(min_frame_rate) = self.min_frame_rate {
+            config.min_frame_rate = Some(min_frame_rate);
+        }
+
+        if self.multishot_recv {
+            config.multishot_recv = true;
+        }
+
+        if let Some(zerocopy_threshold) = self.zerocopy_threshold {
+            config.zerocopy_threshold = Some(zerocopy_threshold);
+        }
+
+        if self.linked_echo {
+            config.linked_echo = true;
+        }
+
+        if self.splice_echo {
+            config.splice_echo = true;
+        }
+
+        if let Some(rate_limit_bytes) = self.rate_limit_bytes {
+            config.rate_limit_bytes = Some(rate_limit_bytes);
+        }
+
+        if let Some(rate_limit_messages) = self.rate_limit_messages {
+            config.rate_limit_messages = Some(rate_limit_messages);
+        }
+
+        if let Some(delay) = self.delay {
+            config.delay = Some(delay);
+        }
+
+        if self.nodelay {
+            config.socket_options.nodelay = true;
+        }
+
+        if let Some(drop) = self.chaos_drop {
+            config.chaos.drop = drop;
+        }
+
+        if let Some(truncate) = self.chaos_truncate {
+            config.chaos.truncate = truncate;
+        }
+
+        if let Some(corrupt) = self.chaos_corrupt {
+            config.chaos.corrupt = corrupt;
+        }
+
+        if let Some(stall) = self.chaos_stall {
+            config.chaos.stall = stall;
+        }
+
+        if let Some(reset) = self.chaos_reset {
+            config.chaos.reset = reset;
+        }
+
+        if let Some(stall_ms) = self.chaos_stall_ms {
+            config.chaos.stall_ms = stall_ms;
+        }
+
+        if self.daemon {
+            config.daemon = true;
+        }
+
+        if let Some(pid_file) = self.pid_file {
+            config.pid_file = Some(pid_file);
+        }
+
+        if let Some(log_file) = self.log_file {
+            config.log_file = Some(log_file);
+        }
+
+        if let Some(user) = self.user {
+            config.user = Some(user);
+        }
+
+        if let Some(group) = self.group {
+            config.group = Some(group);
+        }
+
+        if let Some(admin_socket) = self.admin_socket {
+            config.admin_socket = Some(admin_socket);
+        }
+
+        if let Some(handover_socket) = self.handover_socket {
+            config.handover_socket = Some(handover_socket);
+        }
+
+        if self.seccomp {
+            config.seccomp = true;
+        }
+
+        if let Some(log_level) = self.log_level {
+            config.log_level = log_level;
+        }
+
+        if let Some(payload_log_every) = self.payload_log_every {
+            config.payload_log_every = payload_log_every;
+        }
+
+        if let Some(payload_log_max_bytes) = self.payload_log_max_bytes {
+            config.payload_log_max_bytes = payload_log_max_bytes;
+        }
+
+        if let Some(stats_interval_ms) = self.stats_interval_ms {
+            config.stats_interval_ms = Some(stats_interval_ms);
+        }
+
+        if let Some(soak_interval_ms) = self.soak_interval_ms {
+            config.soak_interval_ms = Some(soak_interval_ms);
+        }
+
+        if let Some(statsd) = self.statsd {
+            config.statsd = Some(statsd);
+        }
+
+        if let Some(statsd_prefix) = self.statsd_prefix {
+            config.statsd_prefix = statsd_prefix;
+        }
+
+        if let Some(statsd_interval_ms) = self.statsd_interval_ms {
+            config.statsd_interval_ms = statsd_interval_ms;
+        }
+
+        if !self.statsd_tags.is_empty() {
+            config.statsd_tags = self.statsd_tags;
+        }
+
+        Ok(config)
+    }
+}
@@ -1,143 +1,2065 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::future::Future;
-use std::os::fd::{AsRawFd, OwnedFd};
-use std::pin::Pin;
+use std::net::SocketAddr;
+use std::num::NonZeroU64;
+use std::ops::Deref;
+use std::os::fd::{AsRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::pin::pin;
 use std::rc::Rc;
-use std::task::{Context, Poll};
+use std::slice::SliceIndex;
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
 
 use anyhow::{Context as _, Result};
+use futures::future::{self, Either};
+use futures::FutureExt as _;
 use io_uring::cqueue::Entry as Cqe;
-use io_uring::opcode::{ReadFixed, WriteFixed};
-use io_uring::types::Fd;
-use io_uring::IoUring;
+use io_uring::opcode::{
+    Close, Connect, ReadFixed, Recv, RecvMulti, SendZc, Shutdown, Splice, Write, WriteFixed, Writev,
+};
+use io_uring::squeue::{Entry as Sqe, Flags};
+use io_uring::types::{Fd, Fixed, Timespec};
+use socket2::{Domain, SockAddr, SockRef, Socket, Type};
 
-use crate::buffer::Guard as Buffer;
-use crate::common::{Id, Route};
-use crate::utils::Errno;
+use crate::buffer::{BufferRing, Chunk, Guard as Buffer, Holder, Loan};
+use crate::chaos::{self, Fault};
+use crate::common::Id;
+use crate::config::{Chaos, Delay, SlowConsumer, TransformKind};
+use crate::error::Error;
+use crate::framing::{Decoder, Framing, Message};
+use crate::io::{Elapsed, Io, Stream};
+use crate::metrics::{self, Counters};
+use crate::middleware::Hooks;
+use crate::outbox::Outbox;
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::response::{Response, RESPONSE_FILE_INDEX};
+use crate::transform::{self, Transform};
+use crate::utils::{self, print_message};
+
+/// Connected clients to broadcast messages to.
+pub type Peers = Rc<RefCell<HashMap<Id, Rc<Broadcasts>>>>;
+
+/// Messages broadcast to a client, written by the client itself.
+pub type Broadcasts = Outbox<Rc<[u8]>>;
+
+/// Most bytes to splice from a client at once, which a pipe holds by default.
+const SPLICE_SIZE: u32 = 65_536;
+
+/// How long to wait before retrying a read when all the buffers are in use.
+const BUFFERS_RETRY_DELAY: Duration = Duration::from_millis(1);
+
+/// Stop a multishot receive when this many chunks are not consumed yet so that a client sending
+/// faster than it reads doesn't take all the buffers.
+const MULTISHOT_MAX_QUEUED: usize = 8;
+
+/// Most messages to coalesce into a vectored write, well within `IOV_MAX`.
+const MAX_COALESCED: usize = 64;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClientOptions {
+    pub framing: Framing,
+    pub max_message_size: usize,
+    /// Disconnect the client if it sends nothing for this long.
+    pub idle_timeout: Option<Duration>,
+    /// Disconnect the client if it takes longer than this to complete a frame it has started.
+    pub frame_timeout: Option<Duration>,
+    /// Disconnect the client if it sends fewer bytes per second than this while in the middle
+    /// of a frame, after the first second.
+    pub min_frame_rate: Option<NonZeroU64>,
+    /// Keep a multishot receive on the sockets instead of submitting a read after each message.
+    pub multishot: bool,
+    /// Write at least this many bytes at once with zero-copy sends.
+    pub zerocopy_threshold: Option<usize>,
+    /// Submit each read linked with a write of the same buffer.
+    pub linked_echo: bool,
+    /// Echo by splicing through a pipe instead of reading into buffers.
+    pub splice_echo: bool,
+    /// Rewrite echoed and broadcast messages, with the byte the xor transform takes.
+    pub transform: TransformKind,
+    pub transform_key: u8,
+    /// Hold reads off while the client is over these rates.
+    pub rate_limit: RateLimit,
+    /// Hold each write to the client off for this long.
+    pub delay: Option<Delay>,
+    /// Faults to inject into writes to the client.
+    pub chaos: Chaos,
+    /// Bytes to have waiting to be written to the client, or forwarded from it, before holding
+    /// the source off or treating the client as a slow consumer.
+    pub output_high_watermark: usize,
+    pub slow_consumer: SlowConsumer,
+}
+
+/// The other end of a forwarded connection.
+pub struct Upstream {
+    address: SocketAddr,
+    io: Io,
+    multishot: Option<Multishot>,
+    read_size: Cell<usize>,
+}
+
+impl Upstream {
+    pub fn new(address: SocketAddr, io: Io, multishot: Option<Multishot>) -> Self {
+        Self {
+            address,
+            io,
+            multishot,
+            read_size: Cell::new(0),
+        }
+    }
+}
 
 pub struct Client {
     id: Id,
     socket: OwnedFd,
-    buffer: Buffer,
-    ring: Rc<RefCell<IoUring>>,
-    cqe: Rc<RefCell<Option<Cqe>>>,
+    buffers: BufferRing,
+    io: Io,
+    options: ClientOptions,
+    /// Size hint for picking the buffer to read into.
+    read_size: Cell<usize>,
+    multishot: Option<Multishot>,
+    upstream: Option<Upstream>,
+    peers: Option<Peers>,
+    /// What the peers broadcast to the client.
+    broadcasts: Option<Rc<Broadcasts>>,
+    response: Option<Rc<Response>>,
+    hooks: Option<Hooks>,
+    draining: Rc<Cell<bool>>,
+    /// Of the worker, counting the bytes read and written.
+    counters: Arc<Counters>,
+    /// Counts the socket as open for as long as the client is there.
+    _open: OpenSocket,
+    stats: Rc<Stats>,
+    limits: Rc<Limits>,
+    /// None for the identity.
+    transform: Option<Box<dyn Transform>>,
+}
+
+/// Keeps the open sockets of the worker counted, so that a client which outlives its task,
+/// e.g. in a reference cycle, fails the soak check rather than holds the socket unnoticed.
+struct OpenSocket(Arc<Counters>);
+
+impl OpenSocket {
+    fn new(counters: &Arc<Counters>) -> Self {
+        metrics::add(&counters.sockets, 1);
+        Self(Arc::clone(counters))
+    }
+}
+
+impl Drop for OpenSocket {
+    fn drop(&mut self) {
+        metrics::sub(&self.0.sockets, 1);
+    }
+}
+
+/// What a connection has done so far, for the access log.
+#[derive(Debug)]
+pub struct Stats {
+    pub started: Instant,
+    /// Read from and written to the client, not counting the traffic with the upstream.
+    pub bytes_read: Cell<u64>,
+    pub bytes_written: Cell<u64>,
+    /// Received from the client: frames with framing, chunks as they arrive otherwise.
+    pub messages: Cell<u64>,
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            started: Instant::now(),
+            bytes_read: Cell::new(0),
+            bytes_written: Cell::new(0),
+            messages: Cell::new(0),
+        }
+    }
+}
+
+/// The limits of a connection which the admin may change while it's served, starting from the
+/// options it was accepted with. A read already waiting keeps the idle timeout it has started
+/// with.
+#[derive(Debug)]
+pub struct Limits {
+    idle_timeout: Cell<Option<Duration>>,
+    limiter: RateLimiter,
+}
+
+impl Limits {
+    fn new(options: &ClientOptions) -> Self {
+        Self {
+            idle_timeout: Cell::new(options.idle_timeout),
+            limiter: RateLimiter::new(options.rate_limit, Instant::now()),
+        }
+    }
+
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.get()
+    }
+
+    pub fn set_idle_timeout(&self, idle_timeout: Option<Duration>) {
+        self.idle_timeout.set(idle_timeout);
+    }
+
+    pub fn rate_limit(&self) -> RateLimit {
+        self.limiter.limit()
+    }
+
+    pub fn set_rate_limit(&self, limit: RateLimit) {
+        self.limiter.set_limit(limit, Instant::now());
+    }
+}
+
+/// How far the frame the client is in the middle of has come, so that clients trickling frames
+/// in are disconnected however often they send something, e.g. a byte at a time just within the
+/// idle timeout.
+#[derive(Debug, Default)]
+struct FrameProgress {
+    /// When a read has left a frame incomplete.
+    started: Option<Instant>,
+    /// Read since, including that read.
+    bytes: u64,
+}
+
+impl FrameProgress {
+    /// Counts a chunk of `len` bytes after which the framing is `at_boundary` or not.
+    fn update(&mut self, len: usize, at_boundary: bool) {
+        match at_boundary {
+            true => *self = Self::default(),
+            false => {
+                self.started.get_or_insert_with(Instant::now);
+                self.bytes += len as u64;
+            }
+        }
+    }
+
+    /// By when the frame in progress is to be complete, if there's one and a limit to it.
+    fn deadline(&self, options: &ClientOptions) -> Option<Instant> {
+        let started = self.started?;
+        let timeout = options.frame_timeout.map(|timeout| started + timeout);
+
+        // A second of grace and then as long as the bytes so far take at the minimum rate.
+        let rate = options.min_frame_rate.map(|rate| {
+            started
+                + Duration::from_secs(1)
+                + Duration::from_secs_f64(self.bytes as f64 / rate.get() as f64)
+        });
+
+        timeout.into_iter().chain(rate).min()
+    }
+}
+
+/// Where the bytes going through a socket are counted.
+#[derive(Clone, Copy)]
+struct Tally<'a> {
+    worker: &'a Counters,
+    /// Of the connection if the socket is the client's.
+    connection: Option<&'a Stats>,
+    /// Of the client if the socket is its.
+    limiter: Option<&'a RateLimiter>,
+}
+
+impl Tally<'_> {
+    fn read(&self, len: usize) {
+        metrics::add(&self.worker.bytes_read, len as u64);
+
+        if let Some(stats) = self.connection {
+            stats.bytes_read.set(stats.bytes_read.get() + len as u64);
+        }
+
+        if let Some(limiter) = self.limiter {
+            limiter.read(len, Instant::now());
+        }
+    }
+
+    /// Counts a message received from the client.
+    fn message(&self) {
+        if let Some(stats) = self.connection {
+            stats.messages.set(stats.messages.get() + 1);
+        }
+
+        if let Some(limiter) = self.limiter {
+            limiter.message(Instant::now());
+        }
+    }
+
+    fn written(&self, len: usize) {
+        metrics::add(&self.worker.bytes_written, len as u64);
+
+        if let Some(stats) = self.connection {
+            stats
+                .bytes_written
+                .set(stats.bytes_written.get() + len as u64);
+        }
+    }
 }
 
 impl Client {
+    /// Once `draining` is set, the client disconnects after finishing the current exchange.
     pub fn new(
         id: Id,
         socket: OwnedFd,
-        buffer: Buffer,
-        ring: Rc<RefCell<IoUring>>,
-        cqe: Rc<RefCell<Option<Cqe>>>,
+        buffers: BufferRing,
+        io: Io,
+        options: ClientOptions,
+        draining: Rc<Cell<bool>>,
+        counters: Arc<Counters>,
     ) -> Self {
         Self {
             id,
             socket,
-            buffer,
-            ring,
-            cqe,
+            buffers,
+            io,
+            options,
+            read_size: Cell::new(0),
+            multishot: None,
+            upstream: None,
+            peers: None,
+            broadcasts: None,
+            response: None,
+            hooks: None,
+            draining,
+            _open: OpenSocket::new(&counters),
+            counters,
+            stats: Rc::new(Stats::new()),
+            limits: Rc::new(Limits::new(&options)),
+            transform: (options.transform != TransformKind::Identity)
+                .then(|| transform::transform(options.transform, options.transform_key)),
         }
     }
 
+    /// Shared with the server to log once the client has finished.
+    pub fn stats(&self) -> Rc<Stats> {
+        Rc::clone(&self.stats)
+    }
+
+    /// Shared with the server for the admin to change.
+    pub fn limits(&self) -> Rc<Limits> {
+        Rc::clone(&self.limits)
+    }
+
+    /// Makes the client receive with a standing multishot operation.
+    pub fn with_multishot(mut self, multishot: Multishot) -> Self {
+        self.multishot = Some(multishot);
+        self
+    }
+
+    /// Makes the client forward data to and from `upstream` instead of echoing it.
+    pub fn with_upstream(mut self, upstream: Upstream) -> Self {
+        self.upstream = Some(upstream);
+        self
+    }
+
+    /// Makes the client write received messages to all the other `peers` instead of echoing,
+    /// joining them to get theirs.
+    pub fn with_peers(mut self, peers: Peers) -> Self {
+        let broadcasts = Rc::new(Outbox::new(self.options.output_high_watermark));
+        peers.borrow_mut().insert(self.id, Rc::clone(&broadcasts));
+        self.peers = Some(peers);
+        self.broadcasts = Some(broadcasts);
+        self
+    }
+
+    /// Makes the client respond to every message with the `response` instead of echoing it.
+    pub fn with_response(mut self, response: Rc<Response>) -> Self {
+        self.response = Some(response);
+        self
+    }
+
+    /// Runs the middleware `hooks` around reads from and writes to the client.
+    pub fn with_hooks(mut self, hooks: Hooks) -> Self {
+        self.hooks = Some(hooks);
+        self
+    }
+
     pub async fn handle(&mut self) -> Result<()> {
+        if let Some(ref upstream) = self.upstream {
+            return self.forward(upstream).await;
+        }
+
+        match self.response.as_deref() {
+            Some(&Response::Spliced(len)) => return self.respond_spliced(len).await,
+            Some(Response::Copied(contents)) => return self.respond_copied(contents).await,
+            None => (),
+        }
+
+        if let Some(ref broadcasts) = self.broadcasts {
+            return self.chat(broadcasts).await;
+        }
+
+        match self.options.framing {
+            Framing::Raw if self.is_spliced() => self.echo_spliced().await,
+            Framing::Raw if self.is_linked() => self.echo_linked().await,
+            Framing::Raw => self.echo_duplex().await,
+            framing if self.transform.is_some() => self.echo_framed(framing).await,
+            framing => self.echo_streamed(framing).await,
+        }
+    }
+
+    /// Broadcasts what the client sends to the peers while writing what they broadcast to it,
+    /// until either fails or the client finishes.
+    async fn chat(&self, broadcasts: &Broadcasts) -> Result<()> {
+        let send = async {
+            match self.options.framing {
+                Framing::Raw => self.echo_raw().await,
+                framing => self.echo_framed(framing).await,
+            }
+        };
+
+        let mut send = pin!(send);
+        let mut receive = pin!(self.receive_broadcasts(broadcasts));
+
+        // Whichever ends first ends the other, which is awaited still as the kernel uses its
+        // buffers until its operations complete: the writes of broadcasts by aborting them, the
+        // reads from the client by shutting the socket down.
+        match future::select(send.as_mut(), receive.as_mut()).await {
+            Either::Left((result, _)) => {
+                broadcasts.abort();
+                let _ = receive.await;
+                result
+            }
+            Either::Right((result, _)) => {
+                let _ = SockRef::from(&self.socket).shutdown(std::net::Shutdown::Both);
+                let _ = send.await;
+                result
+            }
+        }
+    }
+
+    /// Writes the messages of the peers to the client, those piled up meanwhile at once, until
+    /// it's cut off as too slow.
+    async fn receive_broadcasts(&self, broadcasts: &Broadcasts) -> Result<()> {
         loop {
-            let buffer = self.read().await?;
-
-            if let Ok(message) = std::str::from_utf8(buffer) {
-                println!(
-                    "Unicode message from client #{} of {} bytes: {}",
-                    self.id,
-                    buffer.len(),
-                    message
-                );
-            } else {
-                println!(
-                    "Binary message from client #{} of {} bytes: {:02x?}",
-                    self.id,
-                    buffer.len(),
-                    buffer
-                );
+            let messages = broadcasts.pop_many(MAX_COALESCED).await;
+
+            if messages.is_empty() {
+                break;
+            }
+
+            let parts = messages
+                .iter()
+                .map(|message| Payload::shared(Rc::clone(message)))
+                .collect::<Vec<_>>();
+
+            let mut write = pin!(self.write_many(&parts));
+
+            let aborted = match future::select(write.as_mut(), pin!(broadcasts.aborted())).await {
+                Either::Left((written, _)) => {
+                    written?;
+                    false
+                }
+                Either::Right(((), _)) => true,
+            };
+
+            // A write waiting for room in the socket buffer is failed by shutting the socket
+            // down, and awaited still as the kernel reads the message until it completes.
+            if aborted {
+                let _ = SockRef::from(&self.socket).shutdown(std::net::Shutdown::Both);
+                let _ = write.await;
+                break;
             }
 
-            self.write(buffer).await?;
+            broadcasts.written(messages.iter().map(|message| message.len()).sum());
         }
+
+        bail!(Error::SlowConsumer)
     }
 
-    async fn read(&self) -> Result<&[u8]> {
-        let sqe = ReadFixed::new(
-            Fd(self.socket.as_raw_fd()),
-            self.buffer.as_ref() as *const _ as *mut _,
-            self.buffer.as_ref().len() as u32,
-            self.buffer.idx(),
-        )
-        .build()
-        .user_data(Route::Client(self.id).into());
+    async fn echo_raw(&self) -> Result<()> {
+        loop {
+            let Some(mut chunk) = self.read().await? else {
+                return self.shutdown().await;
+            };
 
-        {
-            let mut ring = self.ring.borrow_mut();
-            unsafe { ring.submission().push(&sqe) }.context("Push read")?;
-            ring.submit().context("Submit read")?;
+            self.receive_frames(Framing::Raw, &mut chunk);
+            self.deliver(chunk.buffer(), &chunk).await?;
+
+            if self.draining.get() {
+                return Ok(());
+            }
         }
+    }
 
-        let cqe = WaitEventFuture::new(Rc::clone(&self.cqe)).await;
+    /// Echoes each chunk while reading the next one into another buffer, so that the data
+    /// keeps flowing both ways instead of reads and writes taking turns. Chunks a multishot
+    /// receive has queued meanwhile, e.g. of a client pipelining small messages, are echoed
+    /// along in a single vectored write.
+    async fn echo_duplex(&self) -> Result<()> {
+        let mut next = self.read().await?;
 
-        match cqe.result() {
-            errno if errno < 0 => bail!("Read error: {}", Errno(-errno)),
-            0 => bail!("Disconnected"),
-            len => Ok(&self.buffer.as_ref()[..(len as usize)]),
+        loop {
+            let Some(chunk) = next else {
+                return self.shutdown().await;
+            };
+
+            let mut chunks = vec![chunk];
+            let mut ended = false;
+
+            while !ended && chunks.len() < MAX_COALESCED && self.has_received() {
+                // Not ready if the completion queued is of the idle timer.
+                match self.read().now_or_never().transpose()? {
+                    Some(Some(chunk)) => chunks.push(chunk),
+                    Some(None) => ended = true,
+                    None => (),
+                }
+            }
+
+            for chunk in &mut chunks {
+                self.receive_frames(Framing::Raw, chunk);
+            }
+
+            if ended {
+                self.write_chunks(&chunks).await?;
+                return self.shutdown().await;
+            }
+
+            if self.draining.get() {
+                return self.write_chunks(&chunks).await;
+            }
+
+            // The buffers go back to the pool as soon as they're written rather than once the
+            // next read completes, or idle clients would hold one each.
+            let write = async move { self.write_chunks(&chunks).await };
+
+            // Both run to completion so that neither operation is left in flight on failure.
+            let (written, read) = future::join(write, self.read()).await;
+
+            written?;
+            next = read?;
         }
     }
 
-    async fn write(&self, buffer: &[u8]) -> Result<()> {
-        let sqe = WriteFixed::new(
-            Fd(self.socket.as_raw_fd()),
-            buffer as *const _ as *mut _,
-            buffer.len() as u32,
-            self.buffer.idx(),
-        )
-        .build()
-        .user_data(Route::Client(self.id).into());
+    /// Whether the next read completes right away, with a chunk the multishot receive has
+    /// queued and neither a hook nor the rate limit holding it off.
+    fn has_received(&self) -> bool {
+        self.hooks.is_none()
+            && self.limits.limiter.delay(Instant::now()).is_none()
+            && self.multishot.as_ref().is_some_and(Multishot::has_queued)
+    }
 
-        {
-            let mut ring = self.ring.borrow_mut();
-            unsafe { ring.submission().push(&sqe) }.context("Push write")?;
-            ring.submit().context("Submit write")?;
+    async fn write_chunks(&self, chunks: &[Chunk]) -> Result<()> {
+        let parts = chunks
+            .iter()
+            .map(|chunk| Payload::new(Some(chunk.buffer()), chunk))
+            .collect::<Vec<_>>();
+
+        self.write_many(&parts).await
+    }
+
+    /// Closes the socket through the ring once the client has finished.
+    pub async fn close(mut self) -> Result<()> {
+        // Nobody is to broadcast to the client once it's gone.
+        if let Some(ref peers) = self.peers {
+            peers.borrow_mut().remove(&self.id);
         }
 
-        let cqe = WaitEventFuture::new(Rc::clone(&self.cqe)).await;
+        // Cancels receiving before the socket goes away.
+        self.multishot = None;
+        close(&self.io, self.socket).await
+    }
 
-        match cqe.result() {
-            errno if errno < 0 => bail!("Write error: {}", Errno(-errno)),
-            0 => bail!("Disconnected"),
-            len if len as usize == buffer.len() => Ok(()),
-            len => bail!(
-                "Incomplete message written: {} of {} bytes",
-                len,
-                buffer.len()
-            ),
+    /// Linked echo replaces plain reads only, a timeout can't be linked to a read followed by a
+    /// write, and the kernel reads and writes without running the hooks, holding off reads over
+    /// the rate limit, transforming the data or delaying the writes and injecting faults.
+    fn is_linked(&self) -> bool {
+        self.options.linked_echo
+            && self.multishot.is_none()
+            && self.peers.is_none()
+            && self.hooks.is_none()
+            && !self.limits.limiter.is_limited()
+            && self.transform.is_none()
+            && self.options.delay.is_none()
+            && !self.options.chaos.is_enabled()
+            && self.limits.idle_timeout().is_none()
+    }
+
+    /// The data never reaches userspace when spliced, so it can't go through the hooks, be
+    /// transformed, broadcast or have faults injected, and reads over the rate limit or delayed
+    /// writes are left to the buffer-copy path.
+    fn is_spliced(&self) -> bool {
+        self.options.splice_echo
+            && self.peers.is_none()
+            && self.hooks.is_none()
+            && !self.limits.limiter.is_limited()
+            && self.transform.is_none()
+            && self.options.delay.is_none()
+            && !self.options.chaos.is_enabled()
+    }
+
+    /// Echoes with two splices per round trip through a pipe of the client's own, from the
+    /// socket into the pipe and from the pipe back to the socket, so that the kernel moves the
+    /// pages without copying them into the buffers at all. Each splice into the pipe counts as
+    /// a message.
+    async fn echo_spliced(&self) -> Result<()> {
+        let (pipe_out, pipe_in) = utils::pipe().context("Splice pipe")?;
+        let socket = Fd(self.socket.as_raw_fd());
+
+        loop {
+            let sqe = Splice::new(socket, -1, Fd(pipe_in.as_raw_fd()), -1, SPLICE_SIZE)
+                .flags(libc::SPLICE_F_MOVE)
+                .build();
+
+            let idle_timeout = self.limits.idle_timeout();
+            let read = self
+                .io
+                .submit_with_timeout(sqe, idle_timeout, "splice from client");
+
+            // Like reads, the splice goes first so that data arrived along with draining is
+            // echoed.
+            let cqe = match future::select(pin!(read), drained(&self.draining, Some(&self.stats)))
+                .await
+            {
+                Either::Left((cqe, _)) => cqe?,
+                Either::Right(((), _)) => return Ok(()),
+            };
+
+            let len = match cqe.result() {
+                errno if errno == -libc::ECANCELED && idle_timeout.is_some() => {
+                    bail!(Error::IdleTimeout)
+                }
+                errno if errno < 0 => bail!(Error::from_errno("Splice", -errno)),
+                0 => return self.shutdown().await,
+                len => len as usize,
+            };
+
+            self.tally().read(len);
+            self.tally().message();
+            debug!("Spliced {len} bytes");
+
+            self.splice_to_client(&pipe_out, len).await?;
+
+            if self.draining.get() {
+                return Ok(());
+            }
         }
     }
-}
 
-struct WaitEventFuture {
-    cqe: Rc<RefCell<Option<Cqe>>>,
-}
+    /// Splices the `len` bytes in the pipe to the client.
+    async fn splice_to_client(&self, pipe_out: &OwnedFd, len: usize) -> Result<()> {
+        let mut rest = len;
+
+        while rest > 0 {
+            let sqe = Splice::new(
+                Fd(pipe_out.as_raw_fd()),
+                -1,
+                Fd(self.socket.as_raw_fd()),
+                -1,
+                rest as u32,
+            )
+            .flags(libc::SPLICE_F_MOVE)
+            .build();
+
+            match self.io.submit(sqe, "splice to client").await?.result() {
+                errno if errno < 0 => bail!(Error::from_errno("Splice", -errno)),
+                0 => bail!(Error::Disconnected),
+                written => {
+                    self.tally().written(written as usize);
+                    rest -= written as usize;
+                }
+            }
+        }
 
-impl WaitEventFuture {
-    fn new(cqe: Rc<RefCell<Option<Cqe>>>) -> Self {
-        *cqe.borrow_mut() = None;
-        Self { cqe }
+        Ok(())
     }
-}
 
-impl Future for WaitEventFuture {
-    type Output = Cqe;
+    /// Responds to each message with the `len` bytes of the registered response file, spliced
+    /// into a pipe of the client's from the file and from the pipe to the socket a pipeful at a
+    /// time, so that the contents are never copied to userspace.
+    async fn respond_spliced(&self, len: u64) -> Result<()> {
+        let (pipe_out, pipe_in) = utils::pipe().context("Splice pipe")?;
+        let mut decoder = Decoder::new(self.options.framing, self.options.max_message_size);
+        let mut progress = FrameProgress::default();
+
+        while let Some(messages) = self.read_messages(&mut decoder, &mut progress).await? {
+            for _ in 0..messages {
+                let mut offset = 0;
+
+                while offset < len {
+                    let size = (len - offset).min(u64::from(SPLICE_SIZE)) as u32;
+                    let file = Fixed(RESPONSE_FILE_INDEX);
+                    let sqe =
+                        Splice::new(file, offset as i64, Fd(pipe_in.as_raw_fd()), -1, size).build();
+
+                    let spliced = match self.io.submit(sqe, "splice response").await?.result() {
+                        errno if errno < 0 => bail!(Error::from_errno("Splice", -errno)),
+                        0 => bail!("The response file was truncated"),
+                        spliced => spliced as usize,
+                    };
+
+                    self.splice_to_client(&pipe_out, spliced).await?;
+                    offset += spliced as u64;
+                }
+            }
+
+            if self.draining.get() && decoder.at_boundary() {
+                return Ok(());
+            }
+        }
+
+        self.shutdown().await
+    }
+
+    /// Same as [`Client::respond_spliced`] with the `contents` of the response file in memory.
+    async fn respond_copied(&self, contents: &Rc<[u8]>) -> Result<()> {
+        let mut decoder = Decoder::new(self.options.framing, self.options.max_message_size);
+        let mut progress = FrameProgress::default();
+
+        while let Some(messages) = self.read_messages(&mut decoder, &mut progress).await? {
+            for _ in 0..messages {
+                self.write_payload(Payload::shared(Rc::clone(contents)))
+                    .await?;
+            }
+
+            if self.draining.get() && decoder.at_boundary() {
+                return Ok(());
+            }
+        }
+
+        self.shutdown().await
+    }
+
+    /// Reads the next chunk and returns the number of messages completed in it, counting them,
+    /// or `None` at the end of the stream.
+    async fn read_messages(
+        &self,
+        decoder: &mut Decoder,
+        progress: &mut FrameProgress,
+    ) -> Result<Option<usize>> {
+        let Some(chunk) = self.read_within(progress).await? else {
+            return Ok(None);
+        };
+
+        let messages = decoder.feed(&chunk)?;
+        progress.update(chunk.len(), decoder.at_boundary());
+
+        for message in &messages {
+            match *message {
+                Message::Whole(payload) => self.received(payload),
+                Message::Streamed(len) => {
+                    self.tally().message();
+                    trace!("Streamed message from client #{} of {len} bytes", self.id)
+                }
+            }
+        }
+
+        Ok(Some(messages.len()))
+    }
+
+    /// Echoes into the buffer of the first chunk which is kept afterwards: each read into it is
+    /// linked with a write of the whole buffer, so when the read fills it the kernel writes the
+    /// data back right away without another submission. Shorter reads break the link and are
+    /// written as usual. Draining doesn't interrupt waiting for the chain, which would leave the
+    /// kernel reading into the buffer after it's returned to the pool.
+    async fn echo_linked(&self) -> Result<()> {
+        let Some(chunk) = self.read().await? else {
+            return self.shutdown().await;
+        };
+
+        self.received(&chunk);
+        self.write(Some(chunk.buffer()), &chunk).await?;
+
+        let mut buffer = chunk.into_buffer();
+        let size = buffer.as_ref().len() as u32;
+        let fd = Fd(self.socket.as_raw_fd());
+
+        while !self.draining.get() {
+            let ptr = buffer.as_mut_ptr();
+
+            // Unlike receiving without `MSG_WAITALL`, a short read fails the link.
+            let sqes = vec![
+                ReadFixed::new(fd, ptr, size, buffer.idx()).build(),
+                WriteFixed::new(fd, ptr, size, buffer.idx()).build(),
+            ];
 
-    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
-        match self.cqe.borrow_mut().take() {
-            None => Poll::Pending,
-            Some(cqe) => Poll::Ready(cqe),
+            let cqes = self
+                .io
+                .submit_linked(sqes, buffer.lend(), "linked echo")
+                .await?;
+            let data = buffer.as_ref();
+
+            let len = match cqes[0].result() {
+                errno if errno < 0 => bail!(Error::from_errno("Read", -errno)),
+                0 => return self.shutdown().await,
+                len => len as usize,
+            };
+
+            self.tally().read(len);
+
+            self.received(&data[..len]);
+
+            // The write may still be short when it has run.
+            let rest = match cqes[1].result() {
+                errno if errno == -libc::ECANCELED => &data[..len],
+                errno if errno < 0 => bail!(Error::from_errno("Write", -errno)),
+                0 => bail!(Error::Disconnected),
+                written => {
+                    self.tally().written(written as usize);
+                    &data[(written as usize)..]
+                }
+            };
+
+            self.write(Some(&buffer), rest).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Echoes data as it arrives, so that frames don't have to fit in the buffer, and only
+    /// checks frame boundaries to log messages and stop draining between frames.
+    async fn echo_streamed(&self, framing: Framing) -> Result<()> {
+        let mut decoder = Decoder::new(framing, self.options.max_message_size);
+        let mut progress = FrameProgress::default();
+
+        loop {
+            let Some(chunk) = self.read_within(&progress).await? else {
+                return self.shutdown().await;
+            };
+
+            for message in decoder.feed(&chunk)? {
+                match message {
+                    Message::Whole(payload) => self.received(payload),
+                    Message::Streamed(len) => {
+                        self.tally().message();
+                        trace!("Streamed message from client #{} of {len} bytes", self.id)
+                    }
+                }
+            }
+
+            progress.update(chunk.len(), decoder.at_boundary());
+
+            self.write(Some(chunk.buffer()), &chunk).await?;
+
+            if self.draining.get() && decoder.at_boundary() {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Delivers complete frames only, accumulating partial ones across reads, so that frames
+    /// of different clients don't interleave when broadcasting and are transformed whole.
+    async fn echo_framed(&self, framing: Framing) -> Result<()> {
+        let mut partial = Vec::new();
+        let mut progress = FrameProgress::default();
+
+        loop {
+            let Some(mut chunk) = self.read_within(&progress).await? else {
+                return self.shutdown().await;
+            };
+
+            if partial.is_empty() {
+                // Fast path: echo complete frames right from the fixed buffer.
+                let len = framing.complete_len(&chunk, self.options.max_message_size)?;
+                self.receive_frames(framing, &mut chunk[..len]);
+                self.echo_frames(chunk.buffer(), &chunk[..len]).await?;
+                partial.extend_from_slice(&chunk[len..]);
+            } else {
+                partial.extend_from_slice(&chunk);
+                let len = framing.complete_len(&partial, self.options.max_message_size)?;
+                self.receive_frames(framing, &mut partial[..len]);
+                self.echo_frames(chunk.buffer(), &partial[..len]).await?;
+                partial.drain(..len);
+            }
+
+            progress.update(chunk.len(), partial.is_empty());
+
+            if self.draining.get() {
+                return Ok(());
+            }
+        }
+    }
+
+    async fn echo_frames(&self, buffer: &Buffer, frames: &[u8]) -> Result<()> {
+        match frames.is_empty() {
+            true => Ok(()),
+            false => self.deliver(buffer, frames).await,
+        }
+    }
+
+    /// Logs and counts the messages of the complete `frames`, then transforms them in place.
+    fn receive_frames(&self, framing: Framing, frames: &mut [u8]) {
+        for payload in framing.payloads_mut(frames) {
+            self.received(payload);
+
+            if let Some(ref transform) = self.transform {
+                transform.apply(payload);
+            }
+        }
+    }
+
+    /// Echoes the message back or broadcasts it to peers depending on the mode. The `buffer` is
+    /// where the message is if it's not copied elsewhere.
+    async fn deliver(&self, buffer: &Buffer, message: &[u8]) -> Result<()> {
+        match self.peers {
+            Some(ref peers) => {
+                self.broadcast(peers, message);
+                Ok(())
+            }
+            None => self.write(Some(buffer), message).await,
+        }
+    }
+
+    /// Queues the message for the peers to write, which count what's written to them in their
+    /// own stats, except for those which have too much queued already.
+    fn broadcast(&self, peers: &Peers, message: &[u8]) {
+        let message = Rc::<[u8]>::from(message);
+
+        for (&id, broadcasts) in peers.borrow().iter().filter(|(&id, _)| id != self.id) {
+            if !broadcasts.is_full() {
+                broadcasts.push(Rc::clone(&message), message.len());
+                continue;
+            }
+
+            match self.options.slow_consumer {
+                SlowConsumer::Drop => {
+                    debug!("Dropped a broadcast to slow client #{id}");
+                    metrics::add(&self.counters.broadcasts_dropped, 1);
+                }
+                SlowConsumer::Disconnect => {
+                    // Closed once, after which the client is no longer full.
+                    broadcasts.abort();
+                    metrics::add(&self.counters.slow_consumers, 1);
+                }
+            }
+        }
+    }
+
+    /// Connects to the upstream and pumps data in both directions until both sides finish
+    /// sending or either fails.
+    async fn forward(&self, upstream: &Upstream) -> Result<()> {
+        let socket = Socket::new(Domain::for_address(upstream.address), Type::STREAM, None)
+            .context("Upstream socket")?;
+
+        let address = Box::new(SockAddr::from(upstream.address));
+        let sqe = Connect::new(
+            Fd(socket.as_raw_fd()),
+            address.as_ptr().cast(),
+            address.len(),
+        );
+
+        let connect = async {
+            let (cqe, _) = self
+                .io
+                .submit_keeping(sqe.build(), address, "connect")
+                .await?;
+            anyhow::Ok(cqe)
+        };
+
+        // Nothing is relayed meanwhile, so it counts as idling.
+        let cqe = match self.limits.idle_timeout() {
+            Some(timeout) => self
+                .io
+                .timeout(timeout, connect)
+                .await
+                .with_context(|| format!("Connect to {}", upstream.address))??,
+            None => connect.await?,
+        };
+
+        if cqe.result() < 0 {
+            return Err(Error::from_errno("Connect", -cqe.result()))
+                .with_context(|| format!("Connect to {}", upstream.address));
         }
+
+        let upstream_socket = OwnedFd::from(socket);
+
+        let client_name = format!("client #{}", self.id);
+        let zerocopy_threshold = self.options.zerocopy_threshold;
+
+        let upstream_tally = Tally {
+            worker: &self.counters,
+            connection: None,
+            limiter: None,
+        };
+
+        let high_watermark = self.options.output_high_watermark;
+
+        let outbound = pump(
+            self.reader(),
+            (&upstream_socket, upstream_tally),
+            None,
+            &client_name,
+            &self.draining,
+            (zerocopy_threshold, high_watermark),
+        );
+
+        let upstream_reader = Reader {
+            io: &upstream.io,
+            socket: &upstream_socket,
+            buffers: &self.buffers,
+            read_size: &upstream.read_size,
+            multishot: upstream.multishot.as_ref(),
+            limits: None,
+            holder: Holder::Upstream(self.id),
+            hooks: None,
+            draining: &self.draining,
+            tally: upstream_tally,
+        };
+
+        let upstream_name = format!("upstream of client #{}", self.id);
+        let inbound = async {
+            pump(
+                upstream_reader,
+                (&self.socket, self.tally()),
+                self.hooks.as_ref(),
+                &upstream_name,
+                &self.draining,
+                (zerocopy_threshold, high_watermark),
+            )
+            .await
+            .context("Upstream")
+        };
+
+        let pumped = future::try_join(outbound, inbound).await;
+        let closed = close(&self.io, upstream_socket).await.context("Upstream");
+        pumped?;
+        closed
+    }
+
+    fn reader(&self) -> Reader<'_> {
+        Reader {
+            io: &self.io,
+            socket: &self.socket,
+            buffers: &self.buffers,
+            read_size: &self.read_size,
+            multishot: self.multishot.as_ref(),
+            limits: Some(&self.limits),
+            holder: Holder::Client(self.id),
+            hooks: self.hooks.as_ref(),
+            draining: &self.draining,
+            tally: self.tally(),
+        }
+    }
+
+    fn tally(&self) -> Tally<'_> {
+        Tally {
+            worker: &self.counters,
+            connection: Some(&self.stats),
+            limiter: Some(&self.limits.limiter),
+        }
+    }
+
+    /// Logs the message received from the client and counts it.
+    fn received(&self, message: &[u8]) {
+        self.tally().message();
+        print_message(format_args!("client #{}", self.id), message);
+    }
+
+    pub fn id(&self) -> Id {
+        self.id
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.get()
+    }
+
+    pub async fn read(&self) -> Result<Option<Chunk>> {
+        self.reader().read().await
+    }
+
+    /// Reads the next chunk before the deadline of the frame in `progress` if there's one.
+    async fn read_within(&self, progress: &FrameProgress) -> Result<Option<Chunk>> {
+        let Some(deadline) = progress.deadline(&self.options) else {
+            return self.read().await;
+        };
+
+        let timeout = deadline.saturating_duration_since(Instant::now());
+
+        match self.io.timeout(timeout, self.read()).await {
+            Err(err) if err.is::<Elapsed>() => bail!(Error::FrameTimeout),
+            result => result?,
+        }
+    }
+
+    pub async fn shutdown(&self) -> Result<()> {
+        shutdown(&self.io, &self.socket).await
+    }
+
+    /// The `buffer` is where the data is if it's not elsewhere, in which case it's copied.
+    pub async fn write(&self, buffer: Option<&Buffer>, data: &[u8]) -> Result<()> {
+        self.write_payload(Payload::new(buffer, data)).await
+    }
+
+    async fn write_payload(&self, data: Payload) -> Result<()> {
+        if let Some(ref hooks) = self.hooks {
+            delay(&self.io, hooks.before_write(&data)?).await?;
+        }
+
+        delay(&self.io, self.options.delay.map(|delay| delay.pick())).await?;
+
+        let fault = match self.options.chaos.is_enabled() {
+            true => chaos::pick(&self.options.chaos, data.len()),
+            false => None,
+        };
+
+        let Some(fault) = fault else {
+            return self.write_now(data).await;
+        };
+
+        debug!("Injecting {fault:?} into a write of {} bytes", data.len());
+
+        match fault {
+            Fault::Drop => Ok(()),
+            Fault::Truncate(len) => self.write_now(data.part(..len)).await,
+            Fault::Corrupt { index, bit } => {
+                let mut data = data.to_vec();
+                data[index] ^= 1 << bit;
+                self.write_now(Payload::shared(data.into())).await
+            }
+            Fault::Stall(len, pause) => {
+                self.write_now(data.part(..len)).await?;
+                self.io.sleep(pause).await?;
+                self.write_now(data.part(len..)).await
+            }
+            Fault::Reset => {
+                let socket = SockRef::from(&self.socket);
+                socket
+                    .set_linger(Some(Duration::ZERO))
+                    .context("Set linger")?;
+                // Ends a read in flight alongside without sending anything so that the socket is
+                // closed, resetting the connection, right away.
+                socket
+                    .shutdown(std::net::Shutdown::Read)
+                    .context("Shut down")?;
+                bail!(Error::ChaosReset)
+            }
+        }
+    }
+
+    /// Writes the parts, each in its fixed buffer if any, with a single vectored write unless
+    /// there's just one. Hooks, delays and faults apply to each write, so with any of them the
+    /// parts are written one by one.
+    async fn write_many(&self, parts: &[Payload]) -> Result<()> {
+        let vectored = parts.len() > 1
+            && self.hooks.is_none()
+            && self.options.delay.is_none()
+            && !self.options.chaos.is_enabled();
+
+        if !vectored {
+            for part in parts {
+                self.write_payload(part.clone()).await?;
+            }
+
+            return Ok(());
+        }
+
+        write_vectored(&self.io, &self.socket, parts, self.tally()).await
+    }
+
+    async fn write_now(&self, data: Payload) -> Result<()> {
+        let zerocopy_threshold = self.options.zerocopy_threshold;
+        let tally = self.tally();
+        write(&self.io, &self.socket, data, zerocopy_threshold, tally).await
+    }
+}
+
+/// Copies data from one socket to another, counted with its tally, until the end of the stream
+/// which is passed on by shutting down the writing side of the other socket, an error or
+/// draining. The `hooks` run before writes if the other socket is the client's. Reading goes on
+/// while the chunks read before are written until they hold `high_watermark` bytes of buffers,
+/// and pauses then until the other socket catches up.
+async fn pump(
+    from: Reader<'_>,
+    (to, to_tally): (&OwnedFd, Tally<'_>),
+    hooks: Option<&Hooks>,
+    from_name: &str,
+    draining: &Cell<bool>,
+    (zerocopy_threshold, high_watermark): (Option<usize>, usize),
+) -> Result<()> {
+    let outbox = Outbox::<Chunk>::new(high_watermark);
+
+    // Whether the stream has ended rather than draining has started.
+    let read = async {
+        loop {
+            if outbox.is_full() {
+                debug!("Too much to write from the {from_name}, pausing");
+                metrics::add(&from.tally.worker.paused, 1);
+                outbox.room().await;
+            }
+
+            let Some(chunk) = from.read().await? else {
+                outbox.close();
+                return anyhow::Ok(true);
+            };
+
+            from.tally.message();
+            print_message(from_name, &chunk);
+
+            let size = chunk.buffer().as_ref().len();
+            outbox.push(chunk, size);
+
+            if draining.get() {
+                outbox.close();
+                return Ok(false);
+            }
+        }
+    };
+
+    let write = async {
+        while let Some(chunk) = outbox.pop().await {
+            if let Some(hooks) = hooks {
+                delay(from.io, hooks.before_write(&chunk)?).await?;
+            }
+
+            write(
+                from.io,
+                to,
+                Payload::new(Some(chunk.buffer()), &chunk),
+                zerocopy_threshold,
+                to_tally,
+            )
+            .await?;
+
+            outbox.written(chunk.buffer().as_ref().len());
+        }
+
+        anyhow::Ok(())
+    };
+
+    let mut write = pin!(write);
+
+    let ended = match future::select(pin!(read), write.as_mut()).await {
+        Either::Left((Ok(ended), _)) => {
+            write.await?;
+            ended
+        }
+        // A write waiting for room in the socket buffer is failed by shutting the socket down,
+        // and awaited still as the kernel reads the chunk until it completes.
+        Either::Left((Err(err), _)) => {
+            outbox.abort();
+            let _ = SockRef::from(to).shutdown(std::net::Shutdown::Both);
+            let _ = write.await;
+            return Err(err);
+        }
+        Either::Right((written, read)) => {
+            written?;
+            read.await?
+        }
+    };
+
+    match ended {
+        true => shutdown(from.io, to).await,
+        false => Ok(()),
+    }
+}
+
+/// A socket to read from into buffers picked from the ring.
+struct Reader<'a> {
+    io: &'a Io,
+    socket: &'a OwnedFd,
+    buffers: &'a BufferRing,
+    /// How much the previous read has got.
+    read_size: &'a Cell<usize>,
+    multishot: Option<&'a Multishot>,
+    /// Of the client if the socket is its.
+    limits: Option<&'a Limits>,
+    /// Who holds the buffers read into.
+    holder: Holder,
+    /// Run before reads from the client.
+    hooks: Option<&'a Hooks>,
+    draining: &'a Cell<bool>,
+    tally: Tally<'a>,
+}
+
+impl Reader<'_> {
+    /// Reads the next chunk; `None` means the end of the stream, or that draining has started
+    /// while waiting for it as there's no exchange to finish then.
+    async fn read(&self) -> Result<Option<Chunk>> {
+        if let Some(hooks) = self.hooks {
+            delay(self.io, hooks.before_read()?).await?;
+        }
+
+        let delay = |limiter: &RateLimiter| limiter.delay(Instant::now());
+
+        if let Some(limit) = self.tally.limiter.and_then(delay) {
+            debug!("Over the rate limit, reading in {limit:?}");
+            metrics::add(&self.tally.worker.throttled, 1);
+            self.io.sleep(limit).await?;
+        }
+
+        let read = async {
+            match self.multishot {
+                Some(multishot) => multishot.read(self.io, self.socket).await,
+                None => self.read_once().await,
+            }
+        };
+
+        // The read goes first so that a chunk which has arrived along with draining is echoed.
+        let chunk =
+            match future::select(pin!(read), drained(self.draining, self.tally.connection)).await {
+                Either::Left((chunk, _)) => chunk?,
+                Either::Right(((), _)) => None,
+            };
+
+        if let Some(ref chunk) = chunk {
+            chunk.buffer().hand_to(self.holder);
+            self.tally.read(chunk.len());
+
+            match self.holder {
+                Holder::Upstream(_) => debug!("Read {} bytes from the upstream", chunk.len()),
+                _ => debug!("Read {} bytes", chunk.len()),
+            }
+        }
+
+        Ok(chunk)
+    }
+
+    async fn read_once(&self) -> Result<Option<Chunk>> {
+        loop {
+            let sqe = Recv::new(Fd(self.socket.as_raw_fd()), std::ptr::null_mut(), 0)
+                .buf_group(self.buffers.group(self.read_size.get()))
+                .build()
+                .flags(Flags::BUFFER_SELECT);
+
+            let idle_timeout = self.limits.and_then(Limits::idle_timeout);
+            let cqe = self
+                .io
+                .submit_with_timeout(sqe, idle_timeout, "read")
+                .await?;
+
+            // Take the buffer back whatever the result is so that it returns to the pool.
+            let chunk = take_buffer(self.buffers, &cqe)?;
+
+            match cqe.result() {
+                errno if errno == -libc::ENOBUFS => wait_for_buffers(self.io).await?,
+                errno if errno == -libc::ECANCELED && idle_timeout.is_some() => {
+                    bail!(Error::IdleTimeout)
+                }
+                errno if errno < 0 => bail!(Error::from_errno("Read", -errno)),
+                0 => return Ok(None),
+                _ => {
+                    let chunk = chunk.context("No buffer selected")?;
+                    self.read_size.set(chunk.size_hint());
+                    return Ok(Some(chunk));
+                }
+            }
+        }
+    }
+}
+
+/// A standing multishot receive producing a completion per arriving chunk instead of a read
+/// submitted for each. It's rearmed when terminated by the kernel, e.g. when out of buffers.
+///
+/// A linked timeout would cancel the whole operation, so instead a timer checks whether anything
+/// has been received since it was armed and a client is disconnected idle for one to two
+/// idle timeout periods.
+pub struct Multishot {
+    stream: Stream,
+    buffers: BufferRing,
+    /// Of the client if it's subject to the idle timeout.
+    limits: Option<Rc<Limits>>,
+    /// The period of the timer, boxed for the kernel to read it at a stable address.
+    timespec: RefCell<Box<Timespec>>,
+    /// Size hint for picking the buffers to receive into when rearming.
+    read_size: Cell<usize>,
+    armed: Cell<bool>,
+    cancelling: Cell<bool>,
+    timer_armed: Cell<bool>,
+    received: Cell<bool>,
+}
+
+impl Multishot {
+    pub fn new(stream: Stream, buffers: BufferRing, limits: Option<Rc<Limits>>) -> Self {
+        Self {
+            stream,
+            buffers,
+            limits,
+            timespec: RefCell::default(),
+            read_size: Cell::new(0),
+            armed: Cell::new(false),
+            cancelling: Cell::new(false),
+            timer_armed: Cell::new(false),
+            received: Cell::new(false),
+        }
+    }
+
+    async fn read(&self, io: &Io, socket: &impl AsRawFd) -> Result<Option<Chunk>> {
+        loop {
+            // Don't rearm until the chunks received so far are consumed to keep them in order.
+            if !self.armed.get() && self.stream.queued() == 0 {
+                let group = self.buffers.group(self.read_size.get());
+                let sqe = RecvMulti::new(Fd(socket.as_raw_fd()), group).build();
+                self.stream.submit(sqe, "multishot receive")?;
+                self.armed.set(true);
+            }
+
+            let idle_timeout = self.limits.as_deref().and_then(Limits::idle_timeout);
+
+            if let Some(idle_timeout) = idle_timeout {
+                if !self.timer_armed.get() {
+                    // The previous timer has completed, so the kernel is done with the period.
+                    **self.timespec.borrow_mut() = Timespec::from(idle_timeout);
+                    self.stream
+                        .submit_timer(&self.timespec.borrow(), "idle timer")?;
+                    self.timer_armed.set(true);
+                    self.received.set(false);
+                }
+            }
+
+            let cqe = self.stream.next().await;
+
+            // Receiving never fails with ETIME so it's the timer.
+            if cqe.result() == -libc::ETIME {
+                self.timer_armed.set(false);
+
+                if !self.received.get() {
+                    bail!(Error::IdleTimeout);
+                }
+
+                continue;
+            }
+
+            self.received.set(true);
+            let cancelled = !io_uring::cqueue::more(cqe.flags()) && self.cancelling.replace(false);
+
+            if !io_uring::cqueue::more(cqe.flags()) {
+                self.armed.set(false);
+            }
+
+            let chunk = take_buffer(&self.buffers, &cqe)?;
+
+            match cqe.result() {
+                errno if errno == -libc::ECANCELED && cancelled => (),
+                errno if errno == -libc::ENOBUFS => wait_for_buffers(io).await?,
+                errno if errno < 0 => bail!(Error::from_errno("Read", -errno)),
+                0 => return Ok(None),
+                _ => {
+                    if self.stream.queued() >= MULTISHOT_MAX_QUEUED && self.armed.get() {
+                        self.pause()?;
+                    }
+
+                    let chunk = chunk.context("No buffer selected")?;
+                    self.read_size.set(chunk.size_hint());
+                    return Ok(Some(chunk));
+                }
+            }
+        }
+    }
+
+    /// Whether completions have arrived which the next read takes without waiting.
+    fn has_queued(&self) -> bool {
+        self.stream.queued() > 0
+    }
+
+    /// Cancels receiving until the queued chunks are consumed.
+    fn pause(&self) -> Result<()> {
+        if self.cancelling.replace(true) {
+            return Ok(());
+        }
+
+        self.stream.cancel("multishot receive cancellation")
+    }
+}
+
+impl Drop for Multishot {
+    fn drop(&mut self) {
+        if self.armed.get() {
+            if let Err(err) = self.stream.cancel("multishot receive cancellation") {
+                error!("{err:#}");
+            }
+        }
+
+        if self.timer_armed.get() {
+            if let Err(err) = self.stream.cancel_timer("idle timer cancellation") {
+                error!("{err:#}");
+            }
+        }
+
+        // Return the buffers of the chunks nobody is going to consume; those received later
+        // are returned by the server.
+        for cqe in self.stream.drain() {
+            take_buffer(&self.buffers, &cqe).ok();
+        }
+    }
+}
+
+/// Takes back the buffer the kernel has picked for the completed read if any.
+pub fn take_buffer(buffers: &BufferRing, cqe: &Cqe) -> Result<Option<Chunk>> {
+    match io_uring::cqueue::buffer_select(cqe.flags()) {
+        Some(bid) => Ok(Some(buffers.take(bid, cqe.result().max(0) as usize)?)),
+        None => Ok(None),
+    }
+}
+
+/// Completes once draining has started. The server polls all the clients then, so there's no
+/// waker to keep. A client which hasn't sent anything yet according to its `stats` may have
+/// connected just before, e.g. while the listeners were handed over to the next server, so it
+/// gets to send its first message until the deadline instead.
+fn drained<'a>(
+    draining: &'a Cell<bool>,
+    stats: Option<&'a Stats>,
+) -> impl Future<Output = ()> + Unpin + 'a {
+    let fresh = move || stats.is_some_and(|stats| stats.bytes_read.get() == 0);
+
+    future::poll_fn(move |_| match draining.get() && !fresh() {
+        true => Poll::Ready(()),
+        false => Poll::Pending,
+    })
+}
+
+/// Waits for as long as a hook has asked.
+async fn delay(io: &Io, delay: Option<Duration>) -> Result<()> {
+    match delay {
+        Some(delay) => io.sleep(delay).await,
+        None => Ok(()),
+    }
+}
+
+/// Lets the server replenish the ring after the kernel has run out of buffers to pick from.
+async fn wait_for_buffers(io: &Io) -> Result<()> {
+    io.sleep(BUFFERS_RETRY_DELAY).await
+}
+
+/// Shuts down the writing side of the socket so that the peer reads the end of the stream.
+async fn shutdown(io: &Io, socket: &impl AsRawFd) -> Result<()> {
+    let sqe = Shutdown::new(Fd(socket.as_raw_fd()), libc::SHUT_WR);
+
+    match io.submit(sqe.build(), "shutdown").await?.result() {
+        errno if errno < 0 => bail!(Error::from_errno("Shutdown", -errno)),
+        _ => Ok(()),
+    }
+}
+
+/// Closes the socket with an operation submitted along with the others instead of a syscall of
+/// its own, or right away if it can't be pushed.
+async fn close(io: &Io, socket: OwnedFd) -> Result<()> {
+    let operation = io.start(Close::new(Fd(socket.as_raw_fd())).build(), "close")?;
+
+    // The kernel closes it from now on.
+    let _ = socket.into_raw_fd();
+
+    match operation.next().await.result() {
+        errno if errno < 0 => bail!(Error::from_errno("Close", -errno)),
+        _ => Ok(()),
+    }
+}
+
+/// Writes all the `data` resubmitting the rest after short writes. Writes of at least
+/// `zerocopy_threshold` bytes are zero-copy sends.
+async fn write(
+    io: &Io,
+    socket: &impl AsRawFd,
+    data: Payload,
+    zerocopy_threshold: Option<usize>,
+    tally: Tally<'_>,
+) -> Result<()> {
+    let total = data.len();
+    let mut rest = data;
+
+    while !rest.is_empty() {
+        let zerocopy = zerocopy_threshold.is_some_and(|threshold| rest.len() >= threshold);
+
+        let sqe = if zerocopy {
+            send_zc_sqe(socket.as_raw_fd(), &rest)
+        } else {
+            write_sqe(socket.as_raw_fd(), &rest)
+        };
+
+        let operation = io.start_keeping(sqe, rest.clone(), "write")?;
+        let cqe = operation.next().await;
+
+        // The kernel posts a notification once it doesn't need the data anymore, so neither
+        // the buffer may be released nor the next read may overwrite it until then.
+        if zerocopy && io_uring::cqueue::more(cqe.flags()) {
+            operation.next().await;
+        }
+
+        match cqe.result() {
+            errno if errno < 0 => bail!(Error::from_errno("Write", -errno)),
+            0 => bail!(Error::Disconnected),
+            len => {
+                tally.written(len as usize);
+                rest = rest.part((len as usize)..);
+            }
+        }
+    }
+
+    debug!("Wrote {total} bytes");
+    Ok(())
+}
+
+/// Writes the `parts` one after another with vectored writes, resuming after short ones.
+async fn write_vectored(
+    io: &Io,
+    socket: &impl AsRawFd,
+    parts: &[Payload],
+    tally: Tally<'_>,
+) -> Result<()> {
+    let parts = Rc::<[Payload]>::from(parts);
+
+    let mut iovecs = parts
+        .iter()
+        .filter(|part| !part.is_empty())
+        .map(|part| libc::iovec {
+            iov_base: part.as_ptr() as *mut libc::c_void,
+            iov_len: part.len(),
+        })
+        .collect::<Vec<_>>();
+
+    let total = iovecs.iter().map(|iovec| iovec.iov_len).sum::<usize>();
+    let mut first = 0;
+
+    while first < iovecs.len() {
+        // The kernel may read the vectors as late as the data, so they're kept along.
+        let rest = iovecs[first..].to_vec();
+        let sqe = Writev::new(Fd(socket.as_raw_fd()), rest.as_ptr(), rest.len() as u32).build();
+        let kept = (rest, Rc::clone(&parts));
+
+        let mut len = match io
+            .submit_keeping(sqe, kept, "vectored write")
+            .await?
+            .0
+            .result()
+        {
+            errno if errno < 0 => bail!(Error::from_errno("Write", -errno)),
+            0 => bail!(Error::Disconnected),
+            len => len as usize,
+        };
+
+        tally.written(len);
+
+        // Skips what's been written, the slices written whole and a part of the next one.
+        while len > 0 {
+            let iovec = &mut iovecs[first];
+
+            if len < iovec.iov_len {
+                iovec.iov_base = unsafe { iovec.iov_base.add(len) };
+                iovec.iov_len -= len;
+                break;
+            }
+
+            len -= iovec.iov_len;
+            first += 1;
+        }
+    }
+
+    debug!("Wrote {total} bytes of {} messages at once", parts.len());
+    Ok(())
+}
+
+/// Builds a zero-copy send of `data`, from its fixed buffer if it's in one.
+fn send_zc_sqe(fd: RawFd, data: &Payload) -> Sqe {
+    SendZc::new(Fd(fd), data.as_ptr(), data.len() as u32)
+        .buf_index(data.fixed_index())
+        .build()
+}
+
+/// Builds a write of `data`, from its fixed buffer if it's in one.
+fn write_sqe(fd: RawFd, data: &Payload) -> Sqe {
+    match data.fixed_index() {
+        Some(idx) => WriteFixed::new(Fd(fd), data.as_ptr(), data.len() as u32, idx).build(),
+        None => Write::new(Fd(fd), data.as_ptr(), data.len() as u32).build(),
+    }
+}
+
+/// Data to write, which the operations writing it keep until the kernel is done with it so
+/// that a write dropped in flight doesn't free the memory under the kernel.
+#[derive(Clone)]
+struct Payload {
+    owner: Owner,
+    /// Where the data is in the owner's memory.
+    start: usize,
+    len: usize,
+}
+
+#[derive(Clone)]
+enum Owner {
+    /// The fixed buffer the data is a part of.
+    Fixed(Loan),
+    Shared(Rc<[u8]>),
+}
+
+impl Payload {
+    /// Of `data` which may be either a part of the fixed `buffer` or any other memory, which is
+    /// copied then.
+    fn new(buffer: Option<&Buffer>, data: &[u8]) -> Self {
+        let memory = buffer.map(|buffer| buffer.as_ref().as_ptr_range());
+
+        match buffer.zip(memory) {
+            Some((buffer, memory)) if memory.contains(&data.as_ptr()) => Self {
+                owner: Owner::Fixed(buffer.lend()),
+                start: data.as_ptr() as usize - memory.start as usize,
+                len: data.len(),
+            },
+            _ => Self::shared(Rc::from(data)),
+        }
+    }
+
+    fn shared(data: Rc<[u8]>) -> Self {
+        Self {
+            start: 0,
+            len: data.len(),
+            owner: Owner::Shared(data),
+        }
+    }
+
+    /// The `range` of the data, owned the same way.
+    fn part(&self, range: impl SliceIndex<[u8], Output = [u8]>) -> Self {
+        let part = &self[range];
+
+        Self {
+            owner: self.owner.clone(),
+            start: self.start + (part.as_ptr() as usize - self.as_ptr() as usize),
+            len: part.len(),
+        }
+    }
+
+    fn fixed_index(&self) -> Option<u16> {
+        match self.owner {
+            Owner::Fixed(ref loan) => Some(loan.idx()),
+            Owner::Shared(_) => None,
+        }
+    }
+}
+
+impl Deref for Payload {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let memory = match self.owner {
+            Owner::Fixed(ref loan) => loan.as_ref(),
+            Owner::Shared(ref data) => data,
+        };
+
+        &memory[self.start..][..self.len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixStream;
+
+    use io_uring::opcode::Timeout;
+
+    use super::*;
+    use crate::mock::Reactor;
+
+    fn options() -> ClientOptions {
+        ClientOptions {
+            max_message_size: 16,
+            ..ClientOptions::default()
+        }
+    }
+
+    fn client(reactor: &Reactor, options: ClientOptions) -> Client {
+        // Never used as the operations on it only pretend to run.
+        let (socket, _) = UnixStream::pair().unwrap();
+        let io = reactor.io();
+        let counters = Arc::default();
+        Client::new(
+            0,
+            socket.into(),
+            reactor.buffers(),
+            io,
+            options,
+            Rc::default(),
+            counters,
+        )
+    }
+
+    #[test]
+    fn short_writes_are_resumed() {
+        let reactor = Reactor::new(64, 4);
+        let mut client = client(&reactor, options());
+        let stats = client.stats();
+        let mut handle = pin!(client.handle());
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let read = reactor.mock().take(Recv::CODE);
+        reactor.mock().receive(&read, b"hello, world");
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let write = reactor.mock().take(WriteFixed::CODE);
+        assert_eq!(write.data(), b"hello, world");
+        reactor.mock().complete(&write, 5);
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let write = reactor.mock().take(WriteFixed::CODE);
+        assert_eq!(write.data(), b", world");
+        reactor.mock().complete(&write, 7);
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        // The next read has been submitted along with the first write.
+        let read = reactor.mock().take(Recv::CODE);
+        reactor.mock().complete(&read, 0);
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let shutdown = reactor.mock().take(Shutdown::CODE);
+        reactor.mock().complete(&shutdown, 0);
+        assert!(matches!(reactor.run(handle), Poll::Ready(Ok(()))));
+
+        assert_eq!(stats.bytes_read.get(), 12);
+        assert_eq!(stats.bytes_written.get(), 12);
+        assert_eq!(stats.messages.get(), 1);
+    }
+
+    #[test]
+    fn reads_wait_for_buffers() {
+        let reactor = Reactor::new(64, 1);
+        let mut client = client(&reactor, options());
+        let mut handle = pin!(client.handle());
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let read = reactor.mock().take(Recv::CODE);
+        reactor.mock().complete(&read, -libc::ENOBUFS);
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let sleep = reactor.mock().take(Timeout::CODE);
+        assert_eq!(sleep.duration(), BUFFERS_RETRY_DELAY);
+        reactor.mock().complete(&sleep, -libc::ETIME);
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let read = reactor.mock().take(Recv::CODE);
+        reactor.mock().complete(&read, -libc::ECONNRESET);
+
+        let Poll::Ready(Err(err)) = reactor.run(handle) else {
+            panic!("Not failed");
+        };
+
+        assert!(Error::of(&err).is_some_and(Error::is_disconnect));
+    }
+
+    #[test]
+    fn idle_clients_time_out() {
+        let reactor = Reactor::new(64, 1);
+        let options = ClientOptions {
+            idle_timeout: Some(Duration::from_secs(5)),
+            ..options()
+        };
+
+        let mut client = client(&reactor, options);
+        let mut handle = pin!(client.handle());
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let read = reactor.mock().take(Recv::CODE);
+        reactor.mock().expire(&read);
+
+        let Poll::Ready(Err(err)) = reactor.run(handle) else {
+            panic!("Not timed out");
+        };
+
+        assert!(matches!(Error::of(&err), Some(Error::IdleTimeout)));
+    }
+
+    #[test]
+    fn slow_frames_time_out() {
+        let reactor = Reactor::new(64, 2);
+        let options = ClientOptions {
+            framing: Framing::Lines,
+            idle_timeout: Some(Duration::from_secs(60)),
+            frame_timeout: Some(Duration::from_secs(5)),
+            ..options()
+        };
+
+        let mut client = client(&reactor, options);
+        let mut handle = pin!(client.handle());
+
+        // Complete lines leave no frame in progress.
+        assert!(reactor.run(handle.as_mut()).is_pending());
+        let read = reactor.mock().take(Recv::CODE);
+        reactor.mock().receive(&read, b"foo\nba");
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let write = reactor.mock().take(WriteFixed::CODE);
+        reactor.mock().complete(&write, 6);
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        // Sending within the idle timeout doesn't stretch the frame timeout.
+        let sleep = reactor.mock().take(Timeout::CODE);
+        assert!(sleep.duration() <= Duration::from_secs(5));
+        let read = reactor.mock().take(Recv::CODE);
+        reactor.mock().receive(&read, b"r");
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let write = reactor.mock().take(WriteFixed::CODE);
+        reactor.mock().complete(&write, 1);
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let sleep = reactor.mock().take(Timeout::CODE);
+        assert!(sleep.duration() < Duration::from_secs(5));
+        reactor.mock().complete(&sleep, -libc::ETIME);
+
+        let Poll::Ready(Err(err)) = reactor.run(handle) else {
+            panic!("Not timed out");
+        };
+
+        assert!(matches!(Error::of(&err), Some(Error::FrameTimeout)));
+    }
+
+    #[test]
+    fn frames_keep_up_with_the_minimum_rate() {
+        let options = ClientOptions {
+            min_frame_rate: NonZeroU64::new(100),
+            ..options()
+        };
+
+        let mut progress = FrameProgress::default();
+        progress.update(10, true);
+        assert_eq!(progress.deadline(&options), None);
+
+        progress.update(10, false);
+        let started = progress.started.unwrap();
+        let deadline = started + Duration::from_millis(1100);
+        assert_eq!(progress.deadline(&options), Some(deadline));
+
+        // Each byte buys another hundredth of a second.
+        progress.update(50, false);
+        let deadline = started + Duration::from_millis(1600);
+        assert_eq!(progress.deadline(&options), Some(deadline));
+
+        let options = ClientOptions {
+            frame_timeout: Some(Duration::from_secs(1)),
+            ..options
+        };
+
+        assert_eq!(
+            progress.deadline(&options),
+            Some(started + Duration::from_secs(1))
+        );
+    }
+
+    #[test]
+    fn slow_consumers_are_cut_off() {
+        let reactor = Reactor::new(64, 2);
+        let options = ClientOptions {
+            output_high_watermark: 5,
+            ..options()
+        };
+
+        let peers = Peers::default();
+        let peer = Rc::new(Outbox::new(5));
+        peers.borrow_mut().insert(1, Rc::clone(&peer));
+
+        let mut client = client(&reactor, options).with_peers(Rc::clone(&peers));
+        let mut handle = pin!(client.handle());
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let read = reactor.mock().take(Recv::CODE);
+        reactor.mock().receive(&read, b"hello");
+        assert!(reactor.run(handle.as_mut()).is_pending());
+        assert!(peer.is_full());
+
+        // The peer hasn't written the first message yet.
+        let read = reactor.mock().take(Recv::CODE);
+        reactor.mock().receive(&read, b"world");
+        assert!(reactor.run(handle.as_mut()).is_pending());
+        assert_eq!(reactor.run(pin!(peer.pop())), Poll::Ready(None));
+    }
+
+    #[test]
+    fn broadcasts_piled_up_are_written_at_once() {
+        let reactor = Reactor::new(64, 2);
+        let peers = Peers::default();
+        let mut client = client(&reactor, options()).with_peers(Rc::clone(&peers));
+        let stats = client.stats();
+        let broadcasts = Rc::clone(&peers.borrow()[&0]);
+
+        for message in [&b"hello"[..], b", ", b"world"] {
+            broadcasts.push(message.into(), message.len());
+        }
+
+        let mut handle = pin!(client.handle());
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let write = reactor.mock().take(Writev::CODE);
+        assert_eq!(write.data(), b"hello, world");
+        reactor.mock().complete(&write, 6);
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let write = reactor.mock().take(Writev::CODE);
+        assert_eq!(write.data(), b" world");
+        reactor.mock().complete(&write, 6);
+        assert!(reactor.run(handle.as_mut()).is_pending());
+        assert!(!broadcasts.is_full());
+        assert_eq!(stats.bytes_written.get(), 12);
+    }
+
+    #[test]
+    fn broadcast_written_when_the_client_finishes_is_awaited() {
+        let reactor = Reactor::new(64, 2);
+        let peers = Peers::default();
+        let mut client = client(&reactor, options()).with_peers(Rc::clone(&peers));
+        let broadcasts = Rc::clone(&peers.borrow()[&0]);
+        broadcasts.push(b"hello"[..].into(), 5);
+
+        let mut handle = pin!(client.handle());
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let read = reactor.mock().take(Recv::CODE);
+        reactor.mock().complete(&read, 0);
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let shutdown = reactor.mock().take(Shutdown::CODE);
+        reactor.mock().complete(&shutdown, 0);
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        // Until the write fails on the socket shut down.
+        let write = reactor.mock().take(Write::CODE);
+        assert_eq!(write.data(), b"hello");
+        reactor.mock().complete(&write, -libc::EPIPE);
+        assert!(matches!(reactor.run(handle), Poll::Ready(Ok(()))));
+    }
+
+    #[test]
+    fn limits_change_from_the_next_read() {
+        let reactor = Reactor::new(64, 2);
+        let mut client = client(&reactor, options());
+        let limits = client.limits();
+        let mut handle = pin!(client.handle());
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let read = reactor.mock().take(Recv::CODE);
+        assert!(read.timeout.is_none());
+
+        limits.set_idle_timeout(Some(Duration::from_secs(5)));
+        reactor.mock().receive(&read, b"hello");
+        assert!(reactor.run(handle.as_mut()).is_pending());
+
+        let write = reactor.mock().take(WriteFixed::CODE);
+        reactor.mock().complete(&write, 5);
+
+        let read = reactor.mock().take(Recv::CODE);
+        assert!(read.timeout.is_some());
+        reactor.mock().expire(&read);
+
+        let Poll::Ready(Err(err)) = reactor.run(handle) else {
+            panic!("Not timed out");
+        };
+
+        assert!(matches!(Error::of(&err), Some(Error::IdleTimeout)));
+    }
+
+    #[test]
+    fn lines_stream_across_reads() {
+        let reactor = Reactor::new(64, 4);
+        let options = ClientOptions {
+            framing: Framing::Lines,
+            ..options()
+        };
+
+        let mut client = client(&reactor, options);
+        let stats = client.stats();
+        let mut handle = pin!(client.handle());
+
+        for data in [&b"foo\nba"[..], b"r\n", b"0123456789abcdef"] {
+            assert!(reactor.run(handle.as_mut()).is_pending());
+            let read = reactor.mock().take(Recv::CODE);
+            reactor.mock().receive(&read, data);
+            assert!(reactor.run(handle.as_mut()).is_pending());
+
+            let write = reactor.mock().take(WriteFixed::CODE);
+            assert_eq!(write.data(), data);
+            reactor.mock().complete(&write, data.len() as i32);
+        }
+
+        assert_eq!(stats.messages.get(), 2);
+
+        // The line has gone over the maximum size once more of it arrives.
+        assert!(reactor.run(handle.as_mut()).is_pending());
+        let read = reactor.mock().take(Recv::CODE);
+        reactor.mock().receive(&read, b"g");
+
+        let Poll::Ready(Err(err)) = reactor.run(handle) else {
+            panic!("Not failed");
+        };
+
+        assert!(matches!(Error::of(&err), Some(Error::Protocol(_))));
     }
 }
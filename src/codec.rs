@@ -0,0 +1,200 @@
+//! Codecs turning the byte stream of a [`Connection`] into frames and back, so that handlers
+//! don't have to care how the frames are split across reads.
+
+use anyhow::{Context as _, Result};
+
+use crate::error::Error;
+use crate::framing::Framing;
+use crate::handler::Connection;
+
+pub trait Codec {
+    type Frame;
+
+    /// Decodes the frame at the start of `data` returning it along with the number of bytes it
+    /// takes, or `None` if it's not complete yet.
+    fn decode(&mut self, data: &[u8]) -> Result<Option<(Self::Frame, usize)>>;
+
+    /// Appends the encoded `frame` to `out`.
+    fn encode(&mut self, frame: &Self::Frame, out: &mut Vec<u8>) -> Result<()>;
+}
+
+/// Whatever each read returns as is.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Raw;
+
+impl Codec for Raw {
+    type Frame = Vec<u8>;
+
+    fn decode(&mut self, data: &[u8]) -> Result<Option<(Vec<u8>, usize)>> {
+        decode(Framing::Raw, data, usize::MAX)
+    }
+
+    fn encode(&mut self, frame: &Vec<u8>, out: &mut Vec<u8>) -> Result<()> {
+        out.extend_from_slice(frame);
+        Ok(())
+    }
+}
+
+/// `\n`-terminated lines without the terminator.
+#[derive(Clone, Copy, Debug)]
+pub struct Lines {
+    pub max_line_size: usize,
+}
+
+impl Codec for Lines {
+    type Frame = Vec<u8>;
+
+    fn decode(&mut self, data: &[u8]) -> Result<Option<(Vec<u8>, usize)>> {
+        decode(Framing::Lines, data, self.max_line_size)
+    }
+
+    fn encode(&mut self, frame: &Vec<u8>, out: &mut Vec<u8>) -> Result<()> {
+        if frame.contains(&b'\n') {
+            bail!("Line contains a line feed");
+        }
+
+        out.extend_from_slice(frame);
+        out.push(b'\n');
+        Ok(())
+    }
+}
+
+/// Messages preceded by a big-endian u32 length header.
+#[derive(Clone, Copy, Debug)]
+pub struct LengthPrefixed {
+    pub max_message_size: usize,
+}
+
+impl Codec for LengthPrefixed {
+    type Frame = Vec<u8>;
+
+    fn decode(&mut self, data: &[u8]) -> Result<Option<(Vec<u8>, usize)>> {
+        decode(Framing::LengthPrefixed, data, self.max_message_size)
+    }
+
+    fn encode(&mut self, frame: &Vec<u8>, out: &mut Vec<u8>) -> Result<()> {
+        let header = u32::try_from(frame.len()).context("Message too large")?;
+        out.extend_from_slice(&header.to_be_bytes());
+        out.extend_from_slice(frame);
+        Ok(())
+    }
+}
+
+/// Decodes the frame the way the echo finds it, copying the payload.
+fn decode(framing: Framing, data: &[u8], max_size: usize) -> Result<Option<(Vec<u8>, usize)>> {
+    let frame = framing.frame(data, max_size)?;
+    Ok(frame.map(|(payload, len)| (data[payload].to_vec(), len)))
+}
+
+/// Reads and writes frames of a connection with the codec, keeping data read past the last
+/// frame for the next one.
+pub struct Framed<'a, C> {
+    conn: &'a Connection,
+    codec: C,
+    /// Data read but not decoded yet starting at `start`.
+    read_buffer: Vec<u8>,
+    start: usize,
+    write_buffer: Vec<u8>,
+}
+
+impl<'a, C: Codec> Framed<'a, C> {
+    pub fn new(conn: &'a Connection, codec: C) -> Self {
+        Self {
+            conn,
+            codec,
+            read_buffer: Vec::new(),
+            start: 0,
+            write_buffer: Vec::new(),
+        }
+    }
+
+    /// Reads the next frame; `None` means the end of the stream between frames, or that the
+    /// server is shutting down.
+    pub async fn read(&mut self) -> Result<Option<C::Frame>> {
+        loop {
+            if let Some((frame, len)) = self.codec.decode(&self.read_buffer[self.start..])? {
+                self.start += len;
+                return Ok(Some(frame));
+            }
+
+            self.read_buffer.drain(..self.start);
+            self.start = 0;
+
+            let Some(chunk) = self.conn.read().await? else {
+                if self.read_buffer.is_empty() || self.conn.is_draining() {
+                    return Ok(None);
+                }
+
+                bail!(Error::protocol("Stream ended mid-frame"));
+            };
+
+            self.read_buffer.extend_from_slice(&chunk);
+        }
+    }
+
+    pub async fn write(&mut self, frame: &C::Frame) -> Result<()> {
+        self.write_buffer.clear();
+        self.codec.encode(frame, &mut self.write_buffer)?;
+        self.conn.write(&self.write_buffer).await
+    }
+
+    pub fn codec(&self) -> &C {
+        &self.codec
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_all<C: Codec>(codec: &mut C, mut data: &[u8]) -> Result<Vec<C::Frame>> {
+        let mut frames = Vec::new();
+
+        while let Some((frame, len)) = codec.decode(data)? {
+            frames.push(frame);
+            data = &data[len..];
+        }
+
+        Ok(frames)
+    }
+
+    #[test]
+    fn round_trip() {
+        let frames = vec![b"foo".to_vec(), Vec::new(), b"bar".to_vec()];
+        let mut lines = Lines { max_line_size: 16 };
+        let mut length_prefixed = LengthPrefixed {
+            max_message_size: 16,
+        };
+
+        let mut data = Vec::new();
+        for frame in &frames {
+            lines.encode(frame, &mut data).unwrap();
+        }
+        assert_eq!(data, b"foo\n\nbar\n");
+        assert_eq!(decode_all(&mut lines, &data).unwrap(), frames);
+
+        let mut data = Vec::new();
+        for frame in &frames {
+            length_prefixed.encode(frame, &mut data).unwrap();
+        }
+        assert_eq!(decode_all(&mut length_prefixed, &data).unwrap(), frames);
+
+        // A partial frame is left for later.
+        assert_eq!(
+            decode_all(&mut length_prefixed, &data[..5]).unwrap().len(),
+            0
+        );
+    }
+
+    #[test]
+    fn oversize_frames() {
+        assert!(Lines { max_line_size: 4 }.decode(b"abcde").is_err());
+        assert!(Lines { max_line_size: 4 }.decode(b"abcd\n").is_ok());
+
+        let mut codec = LengthPrefixed {
+            max_message_size: 4,
+        };
+        assert!(codec.decode(&5u32.to_be_bytes()).is_err());
+        assert!(codec.decode(&4u32.to_be_bytes()).unwrap().is_none());
+    }
+}
@@ -1,20 +1,410 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll, Waker};
+
+use io_uring::cqueue::Entry as Cqe;
+
 pub type Id = u32;
+pub type ListenerId = u32;
+
+/// Tells apart the users of a recycled id or slot, so that a late completion of the previous
+/// one isn't delivered to the next.
+pub type Generation = u16;
 
-#[derive(Debug)]
-#[repr(u32)]
+/// What a completion is for, packed into its `user_data`: the kind in the top byte, the
+/// generation if any in the 16 bits starting with the 32nd and the id if any in the lower half.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Route {
-    Accept,
-    Client(Id),
+    Accept(ListenerId),
+    Operation(Generation, u32),
+    Receive(Generation, Id),
+    UpstreamReceive(Generation, Id),
+    IdleTimer(Generation, Id),
+    Signal,
+    Tick,
+    ProvideBuffer(u32),
+    Cancel,
+    Timeout,
+    /// A client handed off by another worker with its fd as the result.
+    Handoff,
+    /// Commands sent from other threads.
+    Remote,
+    /// A connection to the admin socket.
+    Admin,
+    /// Tasks woken from other threads.
+    Wake,
 }
 
+const KIND_SHIFT: u32 = 56;
+const GENERATION_SHIFT: u32 = 32;
+
 impl From<Route> for u64 {
     fn from(route: Route) -> Self {
-        unsafe { std::mem::transmute(route) }
+        let (kind, generation, id): (u8, Generation, u32) = match route {
+            Route::Accept(id) => (0, 0, id),
+            Route::Operation(generation, key) => (1, generation, key),
+            Route::Receive(generation, id) => (2, generation, id),
+            Route::UpstreamReceive(generation, id) => (3, generation, id),
+            Route::IdleTimer(generation, id) => (4, generation, id),
+            Route::Signal => (5, 0, 0),
+            Route::Tick => (6, 0, 0),
+            Route::ProvideBuffer(bid) => (7, 0, bid),
+            Route::Cancel => (8, 0, 0),
+            Route::Timeout => (9, 0, 0),
+            Route::Handoff => (10, 0, 0),
+            Route::Remote => (11, 0, 0),
+            Route::Admin => (12, 0, 0),
+            Route::Wake => (13, 0, 0),
+        };
+
+        (kind as u64) << KIND_SHIFT | (generation as u64) << GENERATION_SHIFT | id as u64
+    }
+}
+
+impl TryFrom<u64> for Route {
+    type Error = InvalidRoute;
+
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        let generation = (value >> GENERATION_SHIFT) as Generation;
+        let id = value as u32;
+
+        let route = match value >> KIND_SHIFT {
+            0 => Route::Accept(id),
+            1 => Route::Operation(generation, id),
+            2 => Route::Receive(generation, id),
+            3 => Route::UpstreamReceive(generation, id),
+            4 => Route::IdleTimer(generation, id),
+            5 => Route::Signal,
+            6 => Route::Tick,
+            7 => Route::ProvideBuffer(id),
+            8 => Route::Cancel,
+            9 => Route::Timeout,
+            10 => Route::Handoff,
+            11 => Route::Remote,
+            12 => Route::Admin,
+            13 => Route::Wake,
+            _ => return Err(InvalidRoute(value)),
+        };
+
+        // The bits the kind doesn't use have to be clear.
+        match u64::from(route) == value {
+            true => Ok(route),
+            false => Err(InvalidRoute(value)),
+        }
     }
 }
 
-impl From<u64> for Route {
-    fn from(value: u64) -> Self {
-        unsafe { std::mem::transmute(value) }
+/// `user_data` which no [`Route`] encodes to.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InvalidRoute(pub u64);
+
+impl std::fmt::Display for InvalidRoute {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid route {:#018x}", self.0)
+    }
+}
+
+impl std::error::Error for InvalidRoute {}
+
+/// Completions of a multishot operation not consumed yet.
+pub type CqeQueue = Rc<RefCell<VecDeque<Cqe>>>;
+
+/// Completion slots of the operations in flight keyed by the [`Route::Operation`] they're
+/// submitted with, so that each operation completes into a slot of its own and wakes up the task
+/// awaiting it.
+#[derive(Clone, Default)]
+pub struct Operations(Rc<RefCell<OperationTable>>);
+
+#[derive(Default)]
+struct OperationTable {
+    slots: Vec<Slot>,
+    free: Vec<u32>,
+    /// Completions nobody awaits anymore, whose buffers are to be taken back.
+    stale: Vec<Cqe>,
+    /// Routes of the operations abandoned in flight, to be cancelled.
+    abandoned: Vec<u64>,
+}
+
+#[derive(Default)]
+struct Slot {
+    /// Bumped each time the slot is freed.
+    generation: Generation,
+    state: SlotState,
+    cqes: VecDeque<Cqe>,
+    waker: Option<Waker>,
+    /// What the entry of the operation points at, dropped along with the slot once the last
+    /// completion has arrived rather than with the operation, so that the kernel never uses
+    /// memory freed meanwhile.
+    kept: Option<Box<dyn Any>>,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+enum SlotState {
+    #[default]
+    Free,
+    InFlight,
+    /// The last completion has arrived.
+    Done,
+    /// Dropped while in flight, so the slot is freed once the last completion arrives.
+    Abandoned,
+}
+
+impl Operations {
+    /// Takes a slot for an operation about to be submitted.
+    pub fn start(&self) -> Operation {
+        let mut table = self.0.borrow_mut();
+
+        let key = match table.free.pop() {
+            Some(key) => key,
+            None => {
+                table.slots.push(Slot::default());
+                (table.slots.len() - 1) as u32
+            }
+        };
+
+        let slot = &mut table.slots[key as usize];
+        slot.state = SlotState::InFlight;
+
+        Operation {
+            operations: self.clone(),
+            key,
+            generation: slot.generation,
+        }
+    }
+
+    /// Puts the completion into the slot of its operation and wakes up the task awaiting it.
+    /// Completions of operations which have taken the slot before are discarded.
+    pub fn complete(&self, key: u32, generation: Generation, cqe: Cqe) {
+        let mut table = self.0.borrow_mut();
+        let table = &mut *table;
+        let more = io_uring::cqueue::more(cqe.flags());
+
+        let slot = match table.slots.get_mut(key as usize) {
+            Some(slot) if slot.generation == generation => slot,
+            _ => {
+                error!("Stale completion of operation #{key}");
+                table.stale.push(cqe);
+                return;
+            }
+        };
+
+        let waker = match slot.state {
+            SlotState::InFlight => {
+                if !more {
+                    slot.state = SlotState::Done;
+                }
+
+                slot.cqes.push_back(cqe);
+                slot.waker.take()
+            }
+            SlotState::Abandoned => {
+                if !more {
+                    slot.free();
+                    table.free.push(key);
+                }
+
+                table.stale.push(cqe);
+                None
+            }
+            SlotState::Free | SlotState::Done => {
+                error!("Unexpected completion of operation #{key}");
+                table.stale.push(cqe);
+                None
+            }
+        };
+
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// Takes the completions of the operations dropped before consuming them.
+    pub fn take_stale(&self) -> Vec<Cqe> {
+        std::mem::take(&mut self.0.borrow_mut().stale)
+    }
+
+    /// Takes the routes of the operations dropped in flight since the last call, e.g. along with
+    /// the task of a finished client, so that they're cancelled instead of holding on to the
+    /// slots and sockets until they complete by themselves.
+    pub fn take_abandoned(&self) -> Vec<u64> {
+        std::mem::take(&mut self.0.borrow_mut().abandoned)
+    }
+}
+
+impl Slot {
+    fn free(&mut self) {
+        self.state = SlotState::Free;
+        self.generation = self.generation.wrapping_add(1);
+        self.kept = None;
+    }
+}
+
+/// An operation taking a completion slot until it's dropped.
+pub struct Operation {
+    operations: Operations,
+    key: u32,
+    generation: Generation,
+}
+
+impl Operation {
+    pub fn route(&self) -> Route {
+        Route::Operation(self.generation, self.key)
+    }
+
+    /// Waits for the next completion of the operation.
+    pub fn next(&self) -> CompletionFuture<'_> {
+        CompletionFuture { operation: self }
+    }
+
+    /// Keeps `kept` in the slot until the last completion arrives, even if the operation is
+    /// dropped before.
+    pub fn keep(&self, kept: impl Any) {
+        let mut table = self.operations.0.borrow_mut();
+        table.slots[self.key as usize].kept = Some(Box::new(kept));
+    }
+
+    /// Takes back what the slot keeps, once the kernel is done with it.
+    pub fn take_kept<T: Any>(&self) -> Option<T> {
+        let mut table = self.operations.0.borrow_mut();
+        let kept = table.slots[self.key as usize].kept.take()?;
+        kept.downcast().ok().map(|kept| *kept)
+    }
+
+    /// Marks the operation as never submitted, e.g. when pushing it has failed, so that its
+    /// slot is freed right away on drop.
+    pub fn discard(&self) {
+        let mut table = self.operations.0.borrow_mut();
+        table.slots[self.key as usize].state = SlotState::Done;
+    }
+}
+
+impl Drop for Operation {
+    fn drop(&mut self) {
+        let mut table = self.operations.0.borrow_mut();
+        let table = &mut *table;
+        let slot = &mut table.slots[self.key as usize];
+
+        slot.waker = None;
+        table.stale.extend(slot.cqes.drain(..));
+
+        match slot.state {
+            SlotState::Done => {
+                slot.free();
+                table.free.push(self.key);
+            }
+            SlotState::InFlight => {
+                slot.state = SlotState::Abandoned;
+                table.abandoned.push(self.route().into());
+            }
+            SlotState::Free | SlotState::Abandoned => (),
+        }
+    }
+}
+
+pub struct CompletionFuture<'a> {
+    operation: &'a Operation,
+}
+
+impl Future for CompletionFuture<'_> {
+    type Output = Cqe;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut table = self.operation.operations.0.borrow_mut();
+        let slot = &mut table.slots[self.operation.key as usize];
+
+        match slot.cqes.pop_front() {
+            Some(cqe) => Poll::Ready(cqe),
+            None => {
+                slot.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Waits for the next completion in the queue.
+pub struct NextEventFuture {
+    cqes: CqeQueue,
+}
+
+impl NextEventFuture {
+    pub fn new(cqes: CqeQueue) -> Self {
+        Self { cqes }
+    }
+}
+
+impl Future for NextEventFuture {
+    type Output = Cqe;
+
+    fn poll(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Self::Output> {
+        match self.cqes.borrow_mut().pop_front() {
+            None => Poll::Pending,
+            Some(cqe) => Poll::Ready(cqe),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn routes_round_trip() {
+        let routes = [
+            Route::Accept(0),
+            Route::Accept(ListenerId::MAX),
+            Route::Operation(0, 0),
+            Route::Operation(Generation::MAX, u32::MAX),
+            Route::Receive(1, 2),
+            Route::UpstreamReceive(Generation::MAX, Id::MAX),
+            Route::IdleTimer(3, 4),
+            Route::Signal,
+            Route::Tick,
+            Route::ProvideBuffer(u16::MAX as u32),
+            Route::Cancel,
+            Route::Timeout,
+            Route::Handoff,
+            Route::Remote,
+            Route::Admin,
+            Route::Wake,
+        ];
+
+        for route in routes {
+            assert_eq!(Route::try_from(u64::from(route)), Ok(route));
+        }
+    }
+
+    #[test]
+    fn routes_keep_fields_apart() {
+        assert_eq!(u64::from(Route::Accept(1)), 1);
+        assert_eq!(u64::from(Route::Operation(2, 3)), 1 << 56 | 2 << 32 | 3);
+        assert_ne!(
+            u64::from(Route::Receive(1, 0)),
+            u64::from(Route::Receive(0, 1 << 16))
+        );
+    }
+
+    #[test]
+    fn invalid_routes() {
+        let values = [
+            // Unknown kinds.
+            14 << 56,
+            u64::MAX,
+            // Reserved bits between the generation and the kind.
+            1 << 48 | 1,
+            1 << 56 | 1 << 55,
+            // Generations or ids of kinds without them.
+            1 << 32 | 7,
+            5 << 56 | 1,
+            6 << 56 | 1 << 32,
+        ];
+
+        for value in values {
+            assert_eq!(Route::try_from(value), Err(InvalidRoute(value)));
+        }
     }
 }
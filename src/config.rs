@@ -0,0 +1,735 @@
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::num::NonZeroU64;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use serde::Deserialize;
+
+use crate::framing::Framing;
+use crate::log::LogLevel;
+use crate::utils;
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Address to listen on.
+    pub address: SocketAddr,
+    /// Additional addresses to listen on.
+    pub listen: Vec<SocketAddr>,
+    /// `AF_VSOCK` addresses to listen on as well, for clients in virtual machines or their host.
+    pub vsock: Vec<VsockAddr>,
+    /// Paths of UNIX sockets to listen on as well, for local clients known by their
+    /// credentials.
+    pub unix: Vec<PathBuf>,
+    /// How to listen on IPv6 addresses.
+    pub ipv6_mode: Ipv6Mode,
+    /// What runs the I/O operations.
+    pub backend: BackendKind,
+    /// Number of io_uring submission queue entries.
+    pub ring_entries: u32,
+    /// Poll the submission queue with a kernel thread which sleeps after being idle for this
+    /// long instead of submitting with syscalls.
+    pub sqpoll_idle_ms: Option<u32>,
+    /// Set up the ring with `IORING_SETUP_COOP_TASKRUN` so that the kernel runs completion work
+    /// on the next syscall instead of interrupting the thread.
+    pub coop_taskrun: bool,
+    /// Set up the ring with `IORING_SETUP_DEFER_TASKRUN` so that completion work is only run
+    /// when waiting for events. Implies `single_issuer`; incompatible with SQPOLL.
+    pub defer_taskrun: bool,
+    /// Set up the ring with `IORING_SETUP_SINGLE_ISSUER` as it's only used by one thread.
+    pub single_issuer: bool,
+    /// Busy-poll the NAPI contexts of the sockets for up to this long when waiting for events,
+    /// which needs a kernel built with `CONFIG_NET_RX_BUSY_POLL`; disabled if not set.
+    pub napi_busy_poll_us: Option<u32>,
+    /// Keep busy-polling even when the NIC interrupts are deferred for it.
+    pub napi_prefer_busy_poll: bool,
+    /// Poll the completion queue for up to this long before blocking when waiting for events;
+    /// never if not set, or with `coop_taskrun` or `defer_taskrun`.
+    pub spin_us: Option<u64>,
+    /// Number of buffers in the pool, i.e. the maximum number of reads in progress. Idle clients
+    /// hold no buffers.
+    pub buffers_count: u16,
+    /// Size of each buffer in bytes.
+    pub buffer_size: u32,
+    /// Let the pool grow by `buffers_count` buffers at a time up to this many when the kernel
+    /// runs out of them; never if not set.
+    pub buffers_max_count: Option<u16>,
+    /// Release buffers the pool has grown by once they've been unused for this long.
+    pub buffers_shrink_delay_ms: u64,
+    /// Size classes of buffers in the pool instead of `buffers_count` buffers of `buffer_size`,
+    /// so that small reads don't hold large buffers.
+    pub buffer_classes: Vec<BufferClass>,
+    /// Report buffers held by a client for longer than this, e.g. by a stuck future; never if
+    /// not set.
+    pub buffer_hold_warn_ms: Option<u64>,
+    /// Zero buffers once they're released so that no data of one client may end up echoed to
+    /// another. Debug builds fill them with a poison pattern regardless.
+    pub zero_buffers: bool,
+    /// Where the memory of the buffer pool comes from.
+    pub buffer_allocator: AllocatorKind,
+    /// Back the buffer pool with huge pages to cut TLB misses, falling back to transparent huge
+    /// pages if none are reserved. Applies to the mmap allocator only.
+    pub huge_pages: bool,
+    /// Listen backlog for pending connections.
+    pub backlog: i32,
+    /// Listen with Multipath TCP so that clients may spread a connection over several paths,
+    /// falling back to plain TCP if the kernel doesn't support it.
+    pub mptcp: bool,
+    /// What to serve TCP connections with.
+    pub service: Service,
+    /// How to split the TCP stream into messages to echo.
+    pub framing: Framing,
+    /// Maximum size of a single message in bytes with lines or length-prefixed framing.
+    pub max_message_size: usize,
+    /// Rewrite each message echoed or broadcast over TCP: every frame with framing, which are
+    /// accumulated whole then, or every chunk as it arrives otherwise.
+    pub transform: TransformKind,
+    /// The byte the `xor` transform flips the bits with.
+    pub transform_key: u8,
+    /// Forward connections to this `host:port` instead of echoing.
+    pub forward: Option<String>,
+    /// Write messages received from a client to all the other clients instead of echoing.
+    pub broadcast: bool,
+    /// Bytes a client may have waiting to be written to it, forwarded or broadcast, before its
+    /// source is held off or it's treated as a slow consumer.
+    pub output_high_watermark: usize,
+    /// What to do with a client which broadcasts pile up for beyond `output_high_watermark`.
+    pub slow_consumer: SlowConsumer,
+    /// Respond to every message with the contents of this file instead of echoing it, spliced
+    /// from the file registered with the ring.
+    pub response_file: Option<PathBuf>,
+    /// Whether to also echo UDP datagrams on the same address.
+    pub udp: bool,
+    /// Number of worker threads, each with its own ring, buffer pool and listeners bound with
+    /// `SO_REUSEPORT` so that the kernel balances connections between them.
+    pub workers: usize,
+    /// CPUs to pin the event loop threads to, the Nth worker to the Nth one wrapping around;
+    /// not pinned if empty. The buffer pool of a pinned worker is allocated on its CPU's NUMA
+    /// node.
+    pub cpus: Vec<usize>,
+    /// CPUs to pin the SQPOLL kernel threads of the workers to likewise.
+    pub sqpoll_cpus: Vec<u32>,
+    /// How long to let clients finish their exchanges on shutdown before disconnecting them.
+    pub shutdown_timeout_ms: u64,
+    /// How long to let clients finish their exchanges on a drain before disconnecting them.
+    pub drain_timeout_ms: u64,
+    /// Sent to the clients still connected when the drain deadline passes, before they're
+    /// disconnected; they're just disconnected if not set.
+    pub drain_goodbye: Option<String>,
+    /// How often to wake the event loop up for periodic maintenance.
+    pub tick_interval_ms: u64,
+    /// Maximum number of simultaneous connections per worker; unlimited if not set.
+    pub max_connections: Option<usize>,
+    /// Maximum number of simultaneous connections from the same address across the workers;
+    /// unlimited if not set.
+    pub max_connections_per_ip: Option<usize>,
+    /// Accept connections from these networks only if any, checked before `max_connections`.
+    pub allow: Vec<Cidr>,
+    /// Close connections from these networks right away, even if allowed.
+    pub deny: Vec<Cidr>,
+    /// Stop accepting for `accept_cooldown_ms` once a worker accepts more connections per second
+    /// than this on average; unlimited if not set.
+    pub max_accept_rate: Option<NonZeroU64>,
+    pub accept_cooldown_ms: u64,
+    /// Hand clients beyond `max_connections` off to another worker instead of rejecting them.
+    pub handoff: bool,
+    /// Disconnect clients which send nothing for this long; never if not set.
+    pub idle_timeout_ms: Option<u64>,
+    /// Disconnect clients which take longer than this to complete a frame they have started,
+    /// however often they send something; never if not set. Needs framing.
+    pub frame_timeout_ms: Option<u64>,
+    /// Disconnect clients which send fewer bytes per second than this in the middle of a frame,
+    /// after the first second; never if not set. Needs framing.
+    pub min_frame_rate: Option<NonZeroU64>,
+    /// Keep a multishot receive on each socket instead of submitting a read after each message.
+    pub multishot_recv: bool,
+    /// Write at least this many bytes at once with zero-copy sends; never if not set.
+    pub zerocopy_threshold: Option<usize>,
+    /// Submit each read linked with a write of the same buffer so that a full echo round trip
+    /// takes a single submission.
+    pub linked_echo: bool,
+    /// Echo raw data with two splices through a pipe of each client's, from the socket and back
+    /// to it, so that it's never copied to the buffers at all.
+    pub splice_echo: bool,
+    /// Hold reads from a client off while it's over this many bytes per second on average.
+    pub rate_limit_bytes: Option<NonZeroU64>,
+    /// Same for messages: frames with framing, chunks as they arrive otherwise.
+    pub rate_limit_messages: Option<NonZeroU64>,
+    /// Hold each write back to a client off for this long with a timer to play a slow server.
+    pub delay: Option<Delay>,
+    /// Whether to detach from the terminal and run in the background.
+    pub daemon: bool,
+    /// File to write the process id to.
+    pub pid_file: Option<PathBuf>,
+    /// File to redirect stdout and stderr to in daemon mode; discarded otherwise.
+    pub log_file: Option<PathBuf>,
+    /// User to switch to, by name or id, once the listeners are bound, e.g. as root to port 7.
+    pub user: Option<String>,
+    /// Group to switch to; the primary group of the `user` if not set.
+    pub group: Option<String>,
+    /// Unix socket to serve admin commands on; none if not set.
+    pub admin_socket: Option<PathBuf>,
+    /// Unix socket to take the listeners over from the running server at on start, and to hand
+    /// them over to the next one at; none if not set.
+    pub handover_socket: Option<PathBuf>,
+    /// Confine the workers to the syscalls serving clients takes once they're set up, on x86_64
+    /// and aarch64, and their rings to the operations the server submits.
+    pub seccomp: bool,
+    /// The most verbose events to log: connections at `info`, reads and writes at `debug`,
+    /// payloads at `trace`.
+    pub log_level: LogLevel,
+    /// Log only one in this many message payloads at the `trace` level.
+    pub payload_log_every: u32,
+    /// Truncate logged message payloads to this many bytes.
+    pub payload_log_max_bytes: usize,
+    /// Log a summary of each worker this often, as well as on `SIGUSR1`; never if not set.
+    pub stats_interval_ms: Option<u64>,
+    /// Check the accounting invariants of each worker this often, stopping the server if they
+    /// don't hold, and log how much the memory and descriptors of the process have grown since
+    /// the first check, to find leaks in long runs; never if not set.
+    pub soak_interval_ms: Option<u64>,
+    /// Export metrics to statsd at this `host:port`; never if not set.
+    pub statsd: Option<String>,
+    /// Prepended to the names of the exported metrics.
+    pub statsd_prefix: String,
+    /// How often to flush the metrics to statsd.
+    pub statsd_interval_ms: u64,
+    /// DogStatsD tags such as `env:prod` to add to the exported metrics.
+    pub statsd_tags: Vec<String>,
+    /// Options set on accepted sockets.
+    pub socket_options: SocketOptions,
+    /// Faults to inject into writes back to the clients.
+    pub chaos: Chaos,
+}
+
+impl ServerConfig {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("Read config file {}", path.display()))?;
+
+        toml::from_str(&content).with_context(|| format!("Parse config file {}", path.display()))
+    }
+
+    /// Size classes of the buffer pool.
+    pub fn buffer_classes(&self) -> Vec<BufferClass> {
+        match self.buffer_classes.is_empty() {
+            true => vec![BufferClass {
+                size: self.buffer_size,
+                count: self.buffers_count,
+                max_count: self.buffers_max_count,
+            }],
+            false => self.buffer_classes.clone(),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            address: SocketAddr::from(([0, 0, 0, 0], 3456)),
+            listen: Vec::new(),
+            vsock: Vec::new(),
+            unix: Vec::new(),
+            ipv6_mode: Ipv6Mode::default(),
+            backend: BackendKind::default(),
+            ring_entries: 1024,
+            sqpoll_idle_ms: None,
+            coop_taskrun: false,
+            defer_taskrun: false,
+            single_issuer: false,
+            napi_busy_poll_us: None,
+            napi_prefer_busy_poll: false,
+            spin_us: None,
+            buffers_count: 8192,
+            buffer_size: 32_768,
+            buffers_max_count: None,
+            buffers_shrink_delay_ms: 30_000,
+            buffer_classes: Vec::new(),
+            buffer_hold_warn_ms: None,
+            zero_buffers: false,
+            buffer_allocator: AllocatorKind::default(),
+            huge_pages: false,
+            backlog: 1024,
+            mptcp: false,
+            service: Service::default(),
+            framing: Framing::default(),
+            max_message_size: 16 * 1024 * 1024,
+            transform: TransformKind::default(),
+            transform_key: 0xff,
+            forward: None,
+            broadcast: false,
+            output_high_watermark: 1 << 20,
+            slow_consumer: SlowConsumer::default(),
+            response_file: None,
+            udp: false,
+            workers: 1,
+            cpus: Vec::new(),
+            sqpoll_cpus: Vec::new(),
+            shutdown_timeout_ms: 5000,
+            drain_timeout_ms: 30000,
+            drain_goodbye: None,
+            tick_interval_ms: 1000,
+            max_connections: None,
+            max_connections_per_ip: None,
+            allow: Vec::new(),
+            deny: Vec::new(),
+            max_accept_rate: None,
+            accept_cooldown_ms: 1000,
+            handoff: false,
+            idle_timeout_ms: None,
+            frame_timeout_ms: None,
+            min_frame_rate: None,
+            multishot_recv: false,
+            zerocopy_threshold: None,
+            linked_echo: false,
+            splice_echo: false,
+            rate_limit_bytes: None,
+            rate_limit_messages: None,
+            delay: None,
+            daemon: false,
+            pid_file: None,
+            log_file: None,
+            user: None,
+            group: None,
+            admin_socket: None,
+            handover_socket: None,
+            seccomp: false,
+            log_level: LogLevel::default(),
+            payload_log_every: 1,
+            payload_log_max_bytes: 256,
+            stats_interval_ms: None,
+            soak_interval_ms: None,
+            statsd: None,
+            statsd_prefix: "uring".into(),
+            statsd_interval_ms: 10000,
+            statsd_tags: Vec::new(),
+            socket_options: SocketOptions::default(),
+            chaos: Chaos::default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SocketOptions {
+    /// Whether to set `TCP_NODELAY` to send small messages without delay.
+    pub nodelay: bool,
+    /// Whether to set `SO_KEEPALIVE` to detect dead peers.
+    pub keepalive: bool,
+    /// Idle time before sending keepalive probes; the system default if not set.
+    pub keepalive_idle_secs: Option<u64>,
+    /// Interval between keepalive probes; the system default if not set.
+    pub keepalive_interval_secs: Option<u64>,
+    /// Number of unanswered keepalive probes before dropping the connection; the system default
+    /// if not set.
+    pub keepalive_count: Option<u32>,
+    /// Set `SO_LINGER` to wait for unsent data on close for this long; 0 resets the connection.
+    pub linger_secs: Option<u64>,
+    /// `SO_RCVBUF` size in bytes; the system default if not set.
+    pub recv_buffer_size: Option<usize>,
+    /// `SO_SNDBUF` size in bytes; the system default if not set.
+    pub send_buffer_size: Option<usize>,
+}
+
+/// Faults injected into the writes back to clients to test how they cope with a misbehaving
+/// server, each with the probability of a write getting it. A write gets one fault at most, tried
+/// in the order of the fields, and none by default.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Chaos {
+    /// Don't write the data at all.
+    pub drop: f64,
+    /// Write a random part from the start only.
+    pub truncate: f64,
+    /// Flip a bit of a random byte.
+    pub corrupt: f64,
+    /// Write a random part from the start, then the rest after `stall_ms`.
+    pub stall: f64,
+    /// Reset the connection instead of writing.
+    pub reset: f64,
+    pub stall_ms: u64,
+}
+
+impl Chaos {
+    pub fn is_enabled(&self) -> bool {
+        self.drop + self.truncate + self.corrupt + self.stall + self.reset > 0.0
+    }
+}
+
+impl Default for Chaos {
+    fn default() -> Self {
+        Self {
+            drop: 0.0,
+            truncate: 0.0,
+            corrupt: 0.0,
+            stall: 0.0,
+            reset: 0.0,
+            stall_ms: 1000,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct BufferClass {
+    /// Size of each buffer in bytes.
+    pub size: u32,
+    /// Number of buffers of the size.
+    pub count: u16,
+    /// Let the class grow by `count` buffers at a time up to this many; never if not set.
+    #[serde(default)]
+    pub max_count: Option<u16>,
+}
+
+impl FromStr for BufferClass {
+    type Err = anyhow::Error;
+
+    /// Parses `SIZE:COUNT[:MAX_COUNT]`.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split(':');
+
+        let (Some(size), Some(count), max_count, None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            bail!("Expected SIZE:COUNT[:MAX_COUNT]");
+        };
+
+        Ok(Self {
+            size: size.parse().context("Invalid buffer size")?,
+            count: count.parse().context("Invalid buffer count")?,
+            max_count: max_count
+                .map(str::parse)
+                .transpose()
+                .context("Invalid maximum buffer count")?,
+        })
+    }
+}
+
+/// A block of IPv4 or IPv6 addresses such as `10.0.0.0/8`, or a single address without the
+/// prefix length.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network: IpAddr,
+    prefix_len: u8,
+}
+
+impl Cidr {
+    /// IPv4-mapped IPv6 addresses of clients accepted on dual-stack sockets count as IPv4.
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(
+                u32::from(network).into(),
+                u32::from(ip).into(),
+                32,
+                self.prefix_len,
+            ),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                prefix_matches(network.into(), ip.into(), 128, self.prefix_len)
+            }
+            _ => false,
+        }
+    }
+}
+
+fn prefix_matches(network: u128, ip: u128, bits: u32, prefix_len: u8) -> bool {
+    (network ^ ip)
+        .checked_shr(bits - prefix_len as u32)
+        .unwrap_or(0)
+        == 0
+}
+
+impl FromStr for Cidr {
+    type Err = anyhow::Error;
+
+    /// Parses `ADDRESS[/PREFIX_LEN]`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (network, prefix_len) = match s.split_once('/') {
+            Some((network, prefix_len)) => (network, Some(prefix_len)),
+            None => (s, None),
+        };
+
+        let network = network
+            .parse::<IpAddr>()
+            .context("Invalid network address")?;
+
+        let bits = match network {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse().context("Invalid prefix length")?,
+            None => bits,
+        };
+
+        if prefix_len > bits {
+            bail!("Prefix length over {bits}");
+        }
+
+        Ok(Self {
+            network,
+            prefix_len,
+        })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Cidr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix_len)
+    }
+}
+
+/// A vsock address such as `3:3456`: the context id of a virtual machine, 2 for the host, or
+/// `any`, and the port.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct VsockAddr {
+    pub cid: u32,
+    pub port: u32,
+}
+
+impl FromStr for VsockAddr {
+    type Err = anyhow::Error;
+
+    /// Parses `CID:PORT`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (cid, port) = s.split_once(':').context("Expected CID:PORT")?;
+
+        let cid = match cid {
+            "any" => libc::VMADDR_CID_ANY,
+            cid => cid.parse().context("Invalid context id")?,
+        };
+
+        Ok(Self {
+            cid,
+            port: port.parse().context("Invalid port")?,
+        })
+    }
+}
+
+impl TryFrom<String> for VsockAddr {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for VsockAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.cid {
+            libc::VMADDR_CID_ANY => write!(f, "any:{}", self.port),
+            cid => write!(f, "{cid}:{}", self.port),
+        }
+    }
+}
+
+/// How long to hold writes back to clients off: `MS[:JITTER]`, the jitter spreading the delays
+/// evenly over that many milliseconds either way.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(try_from = "String")]
+pub struct Delay {
+    base: Duration,
+    jitter: Duration,
+}
+
+impl Delay {
+    /// The delay of the next write.
+    pub fn pick(&self) -> Duration {
+        let jitter = self.jitter.as_nanos() as u64;
+
+        match jitter {
+            0 => self.base,
+            _ => (self.base + Duration::from_nanos(utils::random() % (2 * jitter + 1)))
+                .saturating_sub(self.jitter),
+        }
+    }
+}
+
+impl FromStr for Delay {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (base, jitter) = match s.split_once(':') {
+            Some((base, jitter)) => (base, Some(jitter)),
+            None => (s, None),
+        };
+
+        let base = base.parse().context("Invalid delay")?;
+        let jitter = match jitter {
+            Some(jitter) => jitter.parse().context("Invalid jitter")?,
+            None => 0,
+        };
+
+        Ok(Self {
+            base: Duration::from_millis(base),
+            jitter: Duration::from_millis(jitter),
+        })
+    }
+}
+
+impl TryFrom<String> for Delay {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Delay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.base.as_millis(), self.jitter.as_millis())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Ipv6Mode {
+    /// Accept IPv6 connections only (`IPV6_V6ONLY` set).
+    Only,
+    /// Accept both IPv6 and IPv4-mapped connections on a single socket.
+    #[default]
+    DualStack,
+    /// Listen on the IPv6 address with `IPV6_V6ONLY` set and additionally on its IPv4
+    /// counterpart (0.0.0.0 for `::`, 127.0.0.1 for `::1`) with a separate socket.
+    Separate,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum BackendKind {
+    /// Submit the operations to an io_uring instance.
+    #[default]
+    IoUring,
+    /// Do the operations with plain syscalls on epoll readiness, for systems without io_uring.
+    /// The ring settings don't apply then.
+    Epoll,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum AllocatorKind {
+    /// Map each block of buffers with their own pages, on the NUMA node of the worker's CPU.
+    #[default]
+    Mmap,
+    /// Allocate the buffers from the heap packed together, wasting less memory on small ones.
+    Heap,
+}
+
+/// What the server does with TCP connections, see [`ServerConfig::service`].
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Service {
+    /// Echo the data back, or forward or broadcast it.
+    #[default]
+    Echo,
+    /// Read and throw away whatever the client sends (RFC 863).
+    Discard,
+    /// Write lines of rotating printable characters until the client goes away (RFC 864).
+    Chargen,
+    /// Write the current date and time, then close the connection (RFC 867).
+    Daytime,
+}
+
+/// How echoed messages are rewritten, see [`ServerConfig::transform`].
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransformKind {
+    /// Echo the messages as they are.
+    #[default]
+    Identity,
+    /// Uppercase ASCII letters.
+    Uppercase,
+    /// Echo the bytes of each message in reverse order.
+    Reverse,
+    /// Flip the bits of each byte with the `transform_key`.
+    Xor,
+    /// Rotate ASCII letters by 13 places.
+    Rot13,
+}
+
+/// What happens to a client too slow to keep up with broadcasts, see
+/// [`ServerConfig::slow_consumer`].
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum SlowConsumer {
+    /// Disconnect the client.
+    #[default]
+    Disconnect,
+    /// Skip the broadcasts to the client until it catches up.
+    Drop,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cidr_contains() {
+        let cidr = "10.1.0.0/16".parse::<Cidr>().unwrap();
+        assert!(cidr.contains("10.1.2.3".parse().unwrap()));
+        assert!(cidr.contains("::ffff:10.1.2.3".parse().unwrap()));
+        assert!(!cidr.contains("10.2.0.1".parse().unwrap()));
+        assert!(!cidr.contains("::1".parse().unwrap()));
+
+        let cidr = "2001:db8::/32".parse::<Cidr>().unwrap();
+        assert!(cidr.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!cidr.contains("2001:db9::1".parse().unwrap()));
+
+        let any = "0.0.0.0/0".parse::<Cidr>().unwrap();
+        assert!(any.contains("192.0.2.1".parse().unwrap()));
+
+        let single = "192.0.2.1".parse::<Cidr>().unwrap();
+        assert_eq!(single.to_string(), "192.0.2.1/32");
+        assert!(!single.contains("192.0.2.2".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0/8".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn vsock_addr() {
+        let addr = "3:3456".parse::<VsockAddr>().unwrap();
+        assert_eq!(addr, VsockAddr { cid: 3, port: 3456 });
+        assert_eq!(addr.to_string(), "3:3456");
+
+        let any = "any:7".parse::<VsockAddr>().unwrap();
+        assert_eq!(any.cid, libc::VMADDR_CID_ANY);
+        assert_eq!(any.to_string(), "any:7");
+
+        assert!("3".parse::<VsockAddr>().is_err());
+        assert!("host:7".parse::<VsockAddr>().is_err());
+    }
+
+    #[test]
+    fn delay_within_jitter() {
+        let delay = "100".parse::<Delay>().unwrap();
+        assert_eq!(delay.pick(), Duration::from_millis(100));
+
+        let delay = "100:20".parse::<Delay>().unwrap();
+        assert_eq!(delay.to_string(), "100:20");
+
+        for _ in 0..100 {
+            let picked = delay.pick();
+            assert!(picked >= Duration::from_millis(80) && picked <= Duration::from_millis(120));
+        }
+
+        let delay = "10:20".parse::<Delay>().unwrap();
+        assert!(delay.pick() <= Duration::from_millis(30));
+
+        assert!("10:".parse::<Delay>().is_err());
+        assert!("-1".parse::<Delay>().is_err());
+    }
+}
@@ -0,0 +1,73 @@
+//! Connections counted by the address of the peer across all the workers, so that a host is
+//! capped however `SO_REUSEPORT` spreads its connections.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Clients of each peer address, shared by the workers. Only addresses with clients are kept.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionsPerIp {
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionsPerIp {
+    /// Counts a connection from the `ip` unless there are `max` of them already.
+    pub fn add(&self, ip: IpAddr, max: Option<usize>) -> bool {
+        let mut counts = self.counts();
+        let count = counts.get(&ip).copied().unwrap_or(0);
+
+        if max.is_some_and(|max| count >= max) {
+            return false;
+        }
+
+        counts.insert(ip, count + 1);
+        true
+    }
+
+    pub fn remove(&self, ip: IpAddr) {
+        let mut counts = self.counts();
+
+        if let Some(count) = counts.get_mut(&ip) {
+            *count -= 1;
+
+            if *count == 0 {
+                counts.remove(&ip);
+            }
+        }
+    }
+
+    fn counts(&self) -> MutexGuard<'_, HashMap<IpAddr, usize>> {
+        self.counts.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn caps_each_address() {
+        let connections = ConnectionsPerIp::default();
+        let worker = connections.clone();
+        let ip = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+
+        assert!(connections.add(ip, Some(2)));
+        assert!(worker.add(ip, Some(2)));
+        assert!(!connections.add(ip, Some(2)));
+        assert!(worker.add(other, Some(2)));
+
+        worker.remove(ip);
+        assert!(connections.add(ip, Some(2)));
+
+        // Rejected addresses aren't kept.
+        assert!(!connections.add(other, Some(0)));
+        connections.remove(ip);
+        connections.remove(ip);
+        worker.remove(other);
+        assert!(connections.counts().is_empty());
+    }
+}
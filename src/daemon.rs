@@ -0,0 +1,157 @@
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use anyhow::{bail, Context as _, Result};
+
+/// Detaches the process from the terminal with a double fork and redirects stdout and stderr
+/// to `log_file` (or discards them). Must be called before spawning any threads.
+pub fn daemonize(log_file: Option<&Path>) -> Result<()> {
+    let log = match log_file {
+        Some(path) => OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Open log file {}", path.display()))?,
+        None => OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .context("Open /dev/null")?,
+    };
+
+    let null = File::open("/dev/null").context("Open /dev/null")?;
+
+    fork()?;
+
+    if unsafe { libc::setsid() } < 0 {
+        return Err(std::io::Error::last_os_error()).context("setsid");
+    }
+
+    // Fork again so that the daemon is not a session leader and never acquires a terminal.
+    fork()?;
+
+    std::env::set_current_dir("/").context("Change directory to /")?;
+
+    for (fd, target) in [
+        (&null, libc::STDIN_FILENO),
+        (&log, libc::STDOUT_FILENO),
+        (&log, libc::STDERR_FILENO),
+    ] {
+        if unsafe { libc::dup2(fd.as_raw_fd(), target) } < 0 {
+            return Err(std::io::Error::last_os_error()).context("Redirect stdio");
+        }
+    }
+
+    Ok(())
+}
+
+/// Forks the process, exits the parent and returns in the child.
+fn fork() -> Result<()> {
+    match unsafe { libc::fork() } {
+        pid if pid < 0 => Err(std::io::Error::last_os_error()).context("fork"),
+        0 => Ok(()),
+        _ => unsafe { libc::_exit(0) },
+    }
+}
+
+/// A locked file with the process id which is removed on drop unless the listeners have been
+/// handed over to a new server, which has written its own id there then.
+pub struct PidFile {
+    path: PathBuf,
+    file: File,
+    /// Whether the file has the id of this process rather than of the previous server.
+    written: AtomicBool,
+}
+
+impl PidFile {
+    pub fn create(path: &Path) -> Result<Self> {
+        let pid_file = Self::open(path)?;
+
+        if !lock(&pid_file.file, false)? {
+            bail!("Lock PID file {}; already running?", path.display());
+        }
+
+        pid_file.write()?;
+        Ok(pid_file)
+    }
+
+    /// Takes the file over from the server the listeners are taken over from if it's running,
+    /// which holds the lock until it exits: the id is written by [`PidFile::write`] once the
+    /// new server is ready, and the lock taken once the previous one has exited.
+    pub fn take_over(path: &Path) -> Result<Self> {
+        let pid_file = Self::open(path)?;
+
+        // Nothing to take over from.
+        if lock(&pid_file.file, false)? {
+            pid_file.write()?;
+        }
+
+        Ok(pid_file)
+    }
+
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("Open PID file {}", path.display()))?;
+
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            written: AtomicBool::new(false),
+        })
+    }
+
+    /// Writes the id of the process unless already written, locking the file in the
+    /// background if the previous server still holds the lock.
+    pub fn write(&self) -> Result<()> {
+        if self.written.swap(true, Ordering::AcqRel) {
+            return Ok(());
+        }
+
+        self.file.set_len(0).context("Truncate PID file")?;
+        writeln!(&self.file, "{}", std::process::id()).context("Write PID file")?;
+
+        if !lock(&self.file, false)? {
+            // Shares the lock with the original, being a duplicate.
+            let file = self.file.try_clone().context("Duplicate PID file")?;
+
+            thread::Builder::new()
+                .name("pid-file".into())
+                .spawn(move || lock(&file, true))
+                .context("Spawn PID file locker")?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        if self.written.load(Ordering::Acquire) && !uring::is_handed_over() {
+            std::fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+/// Whether the `file` has been locked exclusively, waiting for it if `block`.
+fn lock(file: &File, block: bool) -> Result<bool> {
+    let operation = match block {
+        true => libc::LOCK_EX,
+        false => libc::LOCK_EX | libc::LOCK_NB,
+    };
+
+    if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+        return Ok(true);
+    }
+
+    match std::io::Error::last_os_error() {
+        err if err.kind() == std::io::ErrorKind::WouldBlock => Ok(false),
+        err => Err(err).context("Lock PID file"),
+    }
+}
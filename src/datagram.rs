@@ -0,0 +1,273 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, UdpSocket};
+use std::os::fd::AsRawFd;
+
+use anyhow::Result;
+use io_uring::opcode::{RecvMsg, SendMsg};
+use io_uring::types::Fd;
+
+use crate::buffer::Guard as Buffer;
+use crate::common::ListenerId;
+use crate::io::Io;
+use crate::utils::{print_message, socket_addr, Errno};
+
+/// Room for a single control message with the packet info of either IP version, aligned for
+/// the headers.
+type Control = [u64; 8];
+
+/// Echoes datagrams received on a UDP socket back to their source address.
+pub struct Datagram {
+    id: ListenerId,
+    socket: UdpSocket,
+    buffer: Buffer,
+    io: Io,
+}
+
+/// A message header along with what it points at but the buffer, boxed and kept by the
+/// operations so that it stays where the kernel reads and writes it until it's done.
+struct Header {
+    address: libc::sockaddr_storage,
+    iovec: libc::iovec,
+    control: Control,
+    msg: libc::msghdr,
+}
+
+/// Where a datagram has arrived, which the kernel tells in a control message once the socket
+/// has `IP_PKTINFO` or `IPV6_RECVPKTINFO` set.
+#[derive(Clone, Copy)]
+enum PacketInfo {
+    V4(libc::in_pktinfo),
+    V6(libc::in6_pktinfo),
+}
+
+impl Datagram {
+    pub fn new(id: ListenerId, socket: UdpSocket, buffer: Buffer, io: Io) -> Self {
+        Self {
+            id,
+            socket,
+            buffer,
+            io,
+        }
+    }
+
+    pub async fn handle(&mut self) -> Result<()> {
+        let mut header = Box::new(Header {
+            address: unsafe { std::mem::zeroed() },
+            iovec: unsafe { std::mem::zeroed() },
+            control: [0; 8],
+            msg: unsafe { std::mem::zeroed() },
+        });
+
+        loop {
+            let buffer = self.buffer.as_mut_slice();
+            let Header {
+                address,
+                iovec,
+                control,
+                msg,
+            } = &mut *header;
+
+            *iovec = libc::iovec {
+                iov_base: buffer.as_mut_ptr().cast(),
+                iov_len: buffer.len(),
+            };
+
+            *msg = msghdr(address, iovec);
+            msg.msg_control = control.as_mut_ptr().cast();
+            msg.msg_controllen = std::mem::size_of_val(control);
+
+            let sqe = RecvMsg::new(Fd(self.socket.as_raw_fd()), msg).build();
+            let kept = (header, self.buffer.lend());
+            let cqe;
+            (cqe, (header, _)) = self
+                .io
+                .submit_keeping(sqe, kept, "receive datagram")
+                .await?;
+
+            let len = match cqe.result() {
+                errno if errno < 0 => {
+                    error!("Receive datagram #{} error: {}", self.id, Errno(-errno));
+                    continue;
+                }
+                len => len as usize,
+            };
+
+            let message = &self.buffer.as_ref()[..len];
+            let info = packet_info(&header.msg);
+            let local = info.map_or_else(|| "unknown address".into(), |info| info.ip().to_string());
+
+            match socket_addr(&header.address) {
+                Some(peer) => {
+                    print_message(format_args!("datagram peer {peer} to {local}"), message)
+                }
+                None => print_message(format_args!("unknown datagram peer to {local}"), message),
+            }
+
+            let Header {
+                address,
+                iovec,
+                control,
+                msg,
+            } = &mut *header;
+
+            let address_len = msg.msg_namelen;
+            iovec.iov_len = len;
+            *msg = msghdr(address, iovec);
+            msg.msg_namelen = address_len;
+
+            // Sent from the address the datagram has arrived at rather than the one the kernel
+            // would route from, which differ with a wildcard address on a host with several.
+            if let Some(info) = info {
+                info.reply_from(msg, control);
+            }
+
+            let sqe = SendMsg::new(Fd(self.socket.as_raw_fd()), msg).build();
+            let kept = (header, self.buffer.lend());
+            let cqe;
+            (cqe, (header, _)) = self.io.submit_keeping(sqe, kept, "send datagram").await?;
+
+            match cqe.result() {
+                errno if errno < 0 => {
+                    error!("Send datagram #{} error: {}", self.id, Errno(-errno))
+                }
+                sent if sent as usize != len => {
+                    warn!("Incomplete datagram sent: {sent} of {len} bytes")
+                }
+                _ => (),
+            }
+        }
+    }
+}
+
+impl PacketInfo {
+    /// The destination address of the datagram.
+    fn ip(&self) -> IpAddr {
+        match self {
+            Self::V4(info) => Ipv4Addr::from(u32::from_be(info.ipi_addr.s_addr)).into(),
+            Self::V6(info) => Ipv6Addr::from(info.ipi6_addr.s6_addr).into(),
+        }
+    }
+
+    /// Sets the control message of `msg` in `control` for the datagram to be sent from the
+    /// destination address. That's left to the kernel for broadcasts and multicasts, which
+    /// can't be sent from.
+    fn reply_from(self, msg: &mut libc::msghdr, control: &mut Control) {
+        let (level, ty, len) = match self {
+            Self::V4(_) => (
+                libc::IPPROTO_IP,
+                libc::IP_PKTINFO,
+                std::mem::size_of::<libc::in_pktinfo>(),
+            ),
+            Self::V6(_) => (
+                libc::IPPROTO_IPV6,
+                libc::IPV6_PKTINFO,
+                std::mem::size_of::<libc::in6_pktinfo>(),
+            ),
+        };
+
+        let ip = self.ip();
+
+        if ip.is_multicast() || ip == Ipv4Addr::BROADCAST {
+            return;
+        }
+
+        *control = [0; 8];
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(len as u32) } as usize;
+
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(msg);
+            (*cmsg).cmsg_level = level;
+            (*cmsg).cmsg_type = ty;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(len as u32) as usize;
+            let data = libc::CMSG_DATA(cmsg);
+
+            match self {
+                // The interface the datagram has arrived on is left for the routing to pick.
+                Self::V4(info) => {
+                    data.cast::<libc::in_pktinfo>()
+                        .write_unaligned(libc::in_pktinfo {
+                            ipi_ifindex: 0,
+                            ipi_spec_dst: info.ipi_addr,
+                            ipi_addr: libc::in_addr { s_addr: 0 },
+                        })
+                }
+                // Link-local addresses are ambiguous without the interface though.
+                Self::V6(info) => data.cast::<libc::in6_pktinfo>().write_unaligned(info),
+            }
+        }
+    }
+}
+
+fn msghdr(address: &mut libc::sockaddr_storage, iovec: &mut libc::iovec) -> libc::msghdr {
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_name = address as *mut _ as *mut libc::c_void;
+    msg.msg_namelen = std::mem::size_of_val(address) as libc::socklen_t;
+    msg.msg_iov = iovec;
+    msg.msg_iovlen = 1;
+    msg
+}
+
+/// Finds the packet info among the control messages received with `msg`.
+fn packet_info(msg: &libc::msghdr) -> Option<PacketInfo> {
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(msg) };
+
+    while !cmsg.is_null() {
+        let (level, ty) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+        let data = unsafe { libc::CMSG_DATA(cmsg) };
+
+        match (level, ty) {
+            (libc::IPPROTO_IP, libc::IP_PKTINFO) => {
+                let info = unsafe { data.cast::<libc::in_pktinfo>().read_unaligned() };
+                return Some(PacketInfo::V4(info));
+            }
+            (libc::IPPROTO_IPV6, libc::IPV6_PKTINFO) => {
+                let info = unsafe { data.cast::<libc::in6_pktinfo>().read_unaligned() };
+                return Some(PacketInfo::V6(info));
+            }
+            _ => cmsg = unsafe { libc::CMSG_NXTHDR(msg, cmsg) },
+        }
+    }
+
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reply_from_destination() {
+        let mut address: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+        let mut iovec: libc::iovec = unsafe { std::mem::zeroed() };
+        let mut control: Control = [0; 8];
+        let mut msg = msghdr(&mut address, &mut iovec);
+
+        let destination = u32::from(Ipv4Addr::new(10, 0, 0, 2)).to_be();
+        let received = PacketInfo::V4(libc::in_pktinfo {
+            ipi_ifindex: 2,
+            ipi_spec_dst: libc::in_addr { s_addr: 0 },
+            ipi_addr: libc::in_addr {
+                s_addr: destination,
+            },
+        });
+
+        received.reply_from(&mut msg, &mut control);
+
+        let Some(PacketInfo::V4(sent)) = packet_info(&msg) else {
+            panic!("No IPv4 packet info");
+        };
+
+        assert_eq!(sent.ipi_ifindex, 0);
+        assert_eq!(sent.ipi_spec_dst.s_addr, destination);
+
+        let mut msg = msghdr(&mut address, &mut iovec);
+        let broadcast = PacketInfo::V4(libc::in_pktinfo {
+            ipi_ifindex: 2,
+            ipi_spec_dst: libc::in_addr { s_addr: 0 },
+            ipi_addr: libc::in_addr { s_addr: u32::MAX },
+        });
+
+        broadcast.reply_from(&mut msg, &mut control);
+        assert!(msg.msg_control.is_null());
+    }
+}
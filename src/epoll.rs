@@ -0,0 +1,652 @@
+use std::collections::{BTreeSet, HashMap, VecDeque};
+use std::os::fd::{AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::time::{Duration, Instant};
+
+use io_uring::cqueue::Entry as Cqe;
+use io_uring::opcode::{
+    Accept, AsyncCancel, Close, Connect, LinkTimeout, ProvideBuffers, Read, ReadFixed, Recv,
+    RecvMsg, Send, SendMsg, Shutdown, Timeout, TimeoutRemove, Write, WriteFixed, Writev,
+};
+use io_uring::squeue::{Entry as Sqe, Flags};
+use io_uring::types::Timespec;
+
+use crate::backend::{
+    Backend, KernelTimespec, RawCqe, RawSqe, IORING_CQE_BUFFER_SHIFT, IORING_CQE_F_BUFFER,
+};
+use crate::probe::Features;
+
+/// None of the optional io_uring features are emulated.
+pub const FEATURES: Features = Features {
+    accept_multi: false,
+    recv_multi: false,
+    send_zc: false,
+    buf_ring: false,
+    msg_ring: false,
+    splice: false,
+};
+
+/// Maximum number of readiness events to take at once.
+const MAX_EVENTS: usize = 256;
+
+/// A backend doing the io_uring operations with plain syscalls on epoll readiness, for kernels
+/// without io_uring or where it's forbidden, e.g. by seccomp in containers.
+///
+/// It follows the io_uring semantics the server relies on: operations complete with the same
+/// results, links run one after another and cancel the rest on failure, and buffers are picked
+/// from the provided ones only when data arrives. Operations waiting for readiness hold a
+/// duplicate of their fd, as the kernel does with a reference to the file, so that a closed and
+/// reused fd can't be mistaken for theirs.
+pub struct Epoll {
+    epoll: OwnedFd,
+    /// Entries pushed since the last submission.
+    queued: Vec<RawSqe>,
+    /// Operations waiting for readiness or a deadline by their ids.
+    ops: HashMap<u64, Op>,
+    next_id: u64,
+    /// Deadlines of the operations in `ops` with ones.
+    timers: BTreeSet<(Instant, u64)>,
+    /// Provided buffers by their groups.
+    groups: HashMap<u16, Vec<ProvidedBuffer>>,
+    completed: Vec<RawCqe>,
+}
+
+/// An operation in progress.
+struct Op {
+    sqe: RawSqe,
+    /// Duplicate of the fd while waiting for readiness.
+    fd: Option<OwnedFd>,
+    /// When a timeout expires or the linked timeout cancels the operation.
+    deadline: Option<Instant>,
+    /// User data of the linked timeout.
+    timeout: Option<u64>,
+    /// The rest of the chain to start once the operation succeeds.
+    chain: VecDeque<Link>,
+    /// Whether the connection is being established.
+    connecting: bool,
+}
+
+/// An operation of a chain along with its linked timeout.
+struct Link {
+    sqe: RawSqe,
+    timeout: Option<RawSqe>,
+}
+
+struct ProvidedBuffer {
+    addr: u64,
+    len: u32,
+    bid: u16,
+}
+
+enum Attempt {
+    /// The operation has completed with the result and flags.
+    Done(i32, u32),
+    /// The operation waits for the epoll events on its fd.
+    Wait(u32),
+    /// The operation waits for its deadline.
+    Sleep,
+}
+
+impl Epoll {
+    pub fn new() -> std::io::Result<Self> {
+        let fd = unsafe { libc::epoll_create1(libc::EPOLL_CLOEXEC) };
+
+        if fd < 0 {
+            return Err(std::io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            epoll: unsafe { OwnedFd::from_raw_fd(fd) },
+            queued: Vec::new(),
+            ops: HashMap::new(),
+            next_id: 0,
+            timers: BTreeSet::new(),
+            groups: HashMap::new(),
+            completed: Vec::new(),
+        })
+    }
+
+    /// Starts the queued operations splitting them into chains. Returns how many there were.
+    fn submit(&mut self) -> usize {
+        let queued = std::mem::take(&mut self.queued);
+        let count = queued.len();
+        let mut sqes = queued.into_iter().peekable();
+        let mut chain = VecDeque::new();
+
+        while let Some(sqe) = sqes.next() {
+            let mut link = Link { sqe, timeout: None };
+            let mut linked = is_linked(&sqe);
+
+            // A linked timeout applies to the operation before it.
+            if linked {
+                if let Some(timeout) = sqes.next_if(|next| next.opcode == LinkTimeout::CODE) {
+                    linked = is_linked(&timeout);
+                    link.timeout = Some(timeout);
+                }
+            }
+
+            chain.push_back(link);
+
+            if !linked {
+                self.start(std::mem::take(&mut chain));
+            }
+        }
+
+        // The kernel also ends a link left dangling at the end of a submission.
+        if !chain.is_empty() {
+            self.start(chain);
+        }
+
+        count
+    }
+
+    /// Starts the first operation of the chain.
+    fn start(&mut self, mut chain: VecDeque<Link>) {
+        let Some(link) = chain.pop_front() else {
+            return;
+        };
+
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let deadline = match (link.sqe.opcode, link.timeout) {
+            (Timeout::CODE, _) => Some(deadline(&link.sqe)),
+            (_, Some(ref timeout)) => Some(deadline(timeout)),
+            (_, None) => None,
+        };
+
+        if let Some(deadline) = deadline {
+            self.timers.insert((deadline, id));
+        }
+
+        let op = Op {
+            sqe: link.sqe,
+            fd: None,
+            deadline,
+            timeout: link.timeout.map(|timeout| timeout.user_data),
+            chain,
+            connecting: false,
+        };
+
+        self.attempt(id, op);
+    }
+
+    /// Tries to do the operation and makes it wait if it can't complete yet.
+    fn attempt(&mut self, id: u64, mut op: Op) {
+        match self.perform(&mut op) {
+            Attempt::Done(res, flags) => self.finish(id, op, res, flags, false),
+            Attempt::Wait(events) => match self.watch(id, &mut op, events) {
+                Ok(()) => {
+                    self.ops.insert(id, op);
+                }
+                Err(err) => {
+                    let errno = err.raw_os_error().unwrap_or(libc::EIO);
+                    self.finish(id, op, -errno, 0, false);
+                }
+            },
+            Attempt::Sleep => {
+                self.ops.insert(id, op);
+            }
+        }
+    }
+
+    /// Waits for the `events` on a duplicate of the operation's fd, once per call.
+    fn watch(&mut self, id: u64, op: &mut Op, events: u32) -> std::io::Result<()> {
+        let mut event = libc::epoll_event {
+            events: events | libc::EPOLLONESHOT as u32,
+            u64: id,
+        };
+
+        let ctl = match op.fd {
+            Some(_) => libc::EPOLL_CTL_MOD,
+            None => {
+                let fd = unsafe { libc::fcntl(op.sqe.fd, libc::F_DUPFD_CLOEXEC, 0) };
+
+                if fd < 0 {
+                    return Err(std::io::Error::last_os_error());
+                }
+
+                op.fd = Some(unsafe { OwnedFd::from_raw_fd(fd) });
+                libc::EPOLL_CTL_ADD
+            }
+        };
+
+        let fd = op.fd.as_ref().map_or(op.sqe.fd, AsRawFd::as_raw_fd);
+
+        match unsafe { libc::epoll_ctl(self.epoll.as_raw_fd(), ctl, fd, &mut event) } {
+            0 => Ok(()),
+            _ => Err(std::io::Error::last_os_error()),
+        }
+    }
+
+    /// Posts the completion of the operation, and of its linked timeout which has `expired` or
+    /// is cancelled. Then either starts the rest of the chain or cancels it if the operation
+    /// has failed.
+    fn finish(&mut self, id: u64, op: Op, res: i32, flags: u32, expired: bool) {
+        if let Some(fd) = op.fd {
+            let epoll = self.epoll.as_raw_fd();
+            unsafe {
+                libc::epoll_ctl(
+                    epoll,
+                    libc::EPOLL_CTL_DEL,
+                    fd.as_raw_fd(),
+                    std::ptr::null_mut(),
+                )
+            };
+        }
+
+        if let Some(deadline) = op.deadline {
+            self.timers.remove(&(deadline, id));
+        }
+
+        self.post(op.sqe.user_data, res, flags);
+
+        if let Some(timeout) = op.timeout {
+            let res = if expired {
+                -libc::ETIME
+            } else {
+                -libc::ECANCELED
+            };
+            self.post(timeout, res, 0);
+        }
+
+        // Like with io_uring, reads and writes also break the link when short.
+        let failed = match op.sqe.opcode {
+            Read::CODE | ReadFixed::CODE | Write::CODE | WriteFixed::CODE => {
+                res != op.sqe.len as i32
+            }
+            _ => res < 0,
+        };
+
+        if !failed {
+            self.start(op.chain);
+            return;
+        }
+
+        for link in op.chain {
+            self.post(link.sqe.user_data, -libc::ECANCELED, 0);
+
+            if let Some(timeout) = link.timeout {
+                self.post(timeout.user_data, -libc::ECANCELED, 0);
+            }
+        }
+    }
+
+    fn post(&mut self, user_data: u64, res: i32, flags: u32) {
+        self.completed.push(RawCqe {
+            user_data,
+            res,
+            flags,
+        });
+    }
+
+    fn perform(&mut self, op: &mut Op) -> Attempt {
+        let sqe = op.sqe;
+        let fd = op.fd.as_ref().map_or(sqe.fd, AsRawFd::as_raw_fd);
+        let addr = sqe.addr as *mut libc::c_void;
+        let len = sqe.len as usize;
+
+        match sqe.opcode {
+            Accept::CODE => {
+                let flags = sqe.op_flags as libc::c_int;
+                let addrlen = sqe.off as *mut libc::socklen_t;
+
+                syscall(libc::EPOLLIN, || unsafe {
+                    set_nonblocking(fd)?;
+                    Ok(libc::accept4(fd, addr as _, addrlen, flags) as isize)
+                })
+            }
+            Connect::CODE if op.connecting => match socket_error(fd) {
+                Ok(0) => Attempt::Done(0, 0),
+                Ok(errno) => Attempt::Done(-errno, 0),
+                Err(err) => Attempt::Done(-err.raw_os_error().unwrap_or(libc::EIO), 0),
+            },
+            Connect::CODE => {
+                let addrlen = sqe.off as libc::socklen_t;
+
+                let attempt = syscall(libc::EPOLLOUT, || unsafe {
+                    set_nonblocking(fd)?;
+                    Ok(libc::connect(fd, addr as _, addrlen) as isize)
+                });
+
+                match attempt {
+                    Attempt::Done(res, _) if res == -libc::EINPROGRESS => {
+                        op.connecting = true;
+                        Attempt::Wait(libc::EPOLLOUT as u32)
+                    }
+                    attempt => attempt,
+                }
+            }
+            Recv::CODE if sqe.flags & Flags::BUFFER_SELECT.bits() != 0 => self.recv_select(op, fd),
+            Recv::CODE => {
+                let flags = sqe.op_flags as libc::c_int | libc::MSG_DONTWAIT;
+                syscall(libc::EPOLLIN, || unsafe {
+                    Ok(libc::recv(fd, addr, len, flags))
+                })
+            }
+            Read::CODE | ReadFixed::CODE => syscall(libc::EPOLLIN, || unsafe {
+                set_nonblocking(fd)?;
+                Ok(libc::read(fd, addr, len))
+            }),
+            Write::CODE | WriteFixed::CODE => syscall(libc::EPOLLOUT, || unsafe {
+                set_nonblocking(fd)?;
+                Ok(libc::write(fd, addr, len))
+            }),
+            Writev::CODE => syscall(libc::EPOLLOUT, || unsafe {
+                set_nonblocking(fd)?;
+                Ok(libc::writev(fd, addr as _, len as libc::c_int))
+            }),
+            RecvMsg::CODE => {
+                let flags = sqe.op_flags as libc::c_int | libc::MSG_DONTWAIT;
+                syscall(libc::EPOLLIN, || unsafe {
+                    Ok(libc::recvmsg(fd, addr as _, flags))
+                })
+            }
+            Send::CODE => {
+                let flags = sqe.op_flags as libc::c_int | libc::MSG_DONTWAIT;
+                syscall(libc::EPOLLOUT, || unsafe {
+                    Ok(libc::send(fd, addr, len, flags))
+                })
+            }
+            SendMsg::CODE => {
+                let flags = sqe.op_flags as libc::c_int | libc::MSG_DONTWAIT;
+                syscall(libc::EPOLLOUT, || unsafe {
+                    Ok(libc::sendmsg(fd, addr as _, flags))
+                })
+            }
+            Close::CODE => syscall(0, || unsafe { Ok(libc::close(fd) as isize) }),
+            Shutdown::CODE => syscall(0, || unsafe {
+                Ok(libc::shutdown(fd, sqe.len as libc::c_int) as isize)
+            }),
+            Timeout::CODE => Attempt::Sleep,
+            TimeoutRemove::CODE => self.cancel(sqe.addr, true),
+            AsyncCancel::CODE => self.cancel(sqe.addr, false),
+            ProvideBuffers::CODE => {
+                let buffers = self.groups.entry(sqe.buf_index).or_default();
+
+                // The fd is the number of buffers and the offset is the id of the first one.
+                for i in 0..sqe.fd as u64 {
+                    buffers.push(ProvidedBuffer {
+                        addr: sqe.addr + i * sqe.len as u64,
+                        len: sqe.len,
+                        bid: (sqe.off + i) as u16,
+                    });
+                }
+
+                Attempt::Done(0, 0)
+            }
+            _ => Attempt::Done(-libc::EINVAL, 0),
+        }
+    }
+
+    /// Receives into a buffer picked from the group when data arrives.
+    fn recv_select(&mut self, op: &Op, fd: RawFd) -> Attempt {
+        let Some(buffers) = self.groups.get_mut(&op.sqe.buf_index) else {
+            return Attempt::Done(-libc::ENOBUFS, 0);
+        };
+
+        let Some(buffer) = buffers.pop() else {
+            return Attempt::Done(-libc::ENOBUFS, 0);
+        };
+
+        let flags = op.sqe.op_flags as libc::c_int | libc::MSG_DONTWAIT;
+
+        // A zero length means the whole buffer.
+        let len = match op.sqe.len {
+            0 => buffer.len,
+            len => len.min(buffer.len),
+        } as usize;
+
+        let attempt = syscall(libc::EPOLLIN, || unsafe {
+            Ok(libc::recv(fd, buffer.addr as *mut _, len, flags))
+        });
+
+        match attempt {
+            Attempt::Done(res, _) if res > 0 => {
+                let flags = IORING_CQE_F_BUFFER | (buffer.bid as u32) << IORING_CQE_BUFFER_SHIFT;
+                Attempt::Done(res, flags)
+            }
+            attempt => {
+                buffers.push(buffer);
+                attempt
+            }
+        }
+    }
+
+    /// Cancels the first operation in progress with the `user_data`, only a timeout if
+    /// `timeouts` is set.
+    fn cancel(&mut self, user_data: u64, timeouts: bool) -> Attempt {
+        let id = self
+            .ops
+            .iter()
+            .filter(|(_, op)| op.sqe.user_data == user_data)
+            .filter(|(_, op)| !timeouts || op.sqe.opcode == Timeout::CODE)
+            .map(|(&id, _)| id)
+            .min();
+
+        let Some((id, op)) = id.and_then(|id| self.ops.remove_entry(&id)) else {
+            return Attempt::Done(-libc::ENOENT, 0);
+        };
+
+        self.finish(id, op, -libc::ECANCELED, 0, false);
+        Attempt::Done(0, 0)
+    }
+
+    /// Completes the operations whose deadlines have passed.
+    fn expire(&mut self) {
+        let now = Instant::now();
+
+        while let Some(&(deadline, id)) = self.timers.first() {
+            if deadline > now {
+                break;
+            }
+
+            self.timers.pop_first();
+
+            let Some(op) = self.ops.remove(&id) else {
+                continue;
+            };
+
+            match op.sqe.opcode {
+                Timeout::CODE => self.finish(id, op, -libc::ETIME, 0, false),
+                _ => self.finish(id, op, -libc::ECANCELED, 0, true),
+            }
+        }
+    }
+
+    /// Waits for at least `want` completions, for up to `timeout` if set.
+    fn wait(&mut self, want: usize, timeout: Option<Duration>) -> std::io::Result<()> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut events = [libc::epoll_event { events: 0, u64: 0 }; MAX_EVENTS];
+
+        loop {
+            self.expire();
+
+            if self.completed.len() >= want {
+                return Ok(());
+            }
+
+            let now = Instant::now();
+
+            if deadline.is_some_and(|deadline| now >= deadline) {
+                return Err(std::io::Error::from_raw_os_error(libc::ETIME));
+            }
+
+            let next = self.timers.first().map(|&(deadline, _)| deadline);
+
+            let timeout_ms = match next.into_iter().chain(deadline).min() {
+                Some(next) => {
+                    let nanos = next.saturating_duration_since(now).as_nanos();
+                    nanos.div_ceil(1_000_000).min(i32::MAX as u128) as libc::c_int
+                }
+                None => -1,
+            };
+
+            let count = unsafe {
+                libc::epoll_wait(
+                    self.epoll.as_raw_fd(),
+                    events.as_mut_ptr(),
+                    MAX_EVENTS as libc::c_int,
+                    timeout_ms,
+                )
+            };
+
+            if count < 0 {
+                let err = std::io::Error::last_os_error();
+
+                match err.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    _ => return Err(err),
+                }
+            }
+
+            for event in &events[..count as usize] {
+                let id = event.u64;
+
+                if let Some(op) = self.ops.remove(&id) {
+                    self.attempt(id, op);
+                }
+            }
+        }
+    }
+}
+
+impl Backend for Epoll {
+    /// The queue is unbounded so there's always room.
+    unsafe fn push(&mut self, sqes: &[Sqe]) -> std::io::Result<()> {
+        let sqes = sqes
+            .iter()
+            .map(|sqe| unsafe { std::mem::transmute_copy::<Sqe, RawSqe>(sqe) });
+
+        self.queued.extend(sqes);
+        Ok(())
+    }
+
+    fn submit_and_wait(&mut self, want: usize) -> std::io::Result<usize> {
+        let count = self.submit();
+        self.wait(want, None)?;
+        Ok(count)
+    }
+
+    fn submit_with_timeout(&mut self, want: usize, timeout: &Timespec) -> std::io::Result<usize> {
+        let timeout = unsafe { &*(timeout as *const Timespec as *const KernelTimespec) };
+        let timeout = Duration::new(timeout.tv_sec as u64, timeout.tv_nsec as u32);
+
+        let count = self.submit();
+        self.wait(want, Some(timeout))?;
+        Ok(count)
+    }
+
+    fn queued(&mut self) -> usize {
+        self.queued.len()
+    }
+
+    fn is_ready(&mut self) -> bool {
+        !self.completed.is_empty()
+    }
+
+    /// Never overflows as completions are kept in a growing queue.
+    fn complete(&mut self, cqes: &mut Vec<Cqe>) -> std::io::Result<bool> {
+        let completed = self.completed.drain(..);
+        cqes.extend(completed.map(|cqe| unsafe { std::mem::transmute::<RawCqe, Cqe>(cqe) }));
+        Ok(false)
+    }
+
+    fn overflows(&self) -> u64 {
+        0
+    }
+
+    fn dropped(&mut self) -> u32 {
+        0
+    }
+
+    fn ring_fd(&self) -> Option<BorrowedFd<'_>> {
+        None
+    }
+
+    unsafe fn register_buf_ring(&mut self, _: u64, _: u16, _: u16) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    fn unregister_buf_ring(&mut self, _: u16) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    /// Fixed operations are done with the addresses, so there's nothing to register.
+    unsafe fn register_buffers(&mut self, _: &[libc::iovec]) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    unsafe fn update_buffers(&mut self, _: u32, _: &[libc::iovec]) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn register_files(&mut self, _: &[RawFd]) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+}
+
+fn is_linked(sqe: &RawSqe) -> bool {
+    sqe.flags & Flags::IO_LINK.bits() != 0
+}
+
+/// When the timeout of a timer or a linked timeout expires.
+fn deadline(sqe: &RawSqe) -> Instant {
+    let timespec = unsafe { &*(sqe.addr as *const KernelTimespec) };
+    Instant::now() + Duration::new(timespec.tv_sec as u64, timespec.tv_nsec as u32)
+}
+
+/// Makes a syscall turning its result into a completion unless it would block, in which
+/// case the operation waits for the `events`.
+fn syscall(events: libc::c_int, f: impl FnOnce() -> std::io::Result<isize>) -> Attempt {
+    let err = match f() {
+        Ok(res) if res >= 0 => return Attempt::Done(res as i32, 0),
+        Ok(_) => std::io::Error::last_os_error(),
+        Err(err) => err,
+    };
+
+    match err.raw_os_error() {
+        Some(libc::EAGAIN) if events != 0 => Attempt::Wait(events as u32),
+        Some(errno) => Attempt::Done(-errno, 0),
+        None => Attempt::Done(-libc::EIO, 0),
+    }
+}
+
+/// Sets `O_NONBLOCK` on the file unless it's already set, which is shared by all the fds
+/// referring to it.
+unsafe fn set_nonblocking(fd: RawFd) -> std::io::Result<()> {
+    let flags = libc::fcntl(fd, libc::F_GETFL);
+
+    if flags < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    if flags & libc::O_NONBLOCK != 0 {
+        return Ok(());
+    }
+
+    match libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Takes the error of an asynchronous connect.
+fn socket_error(fd: RawFd) -> std::io::Result<libc::c_int> {
+    let mut errno: libc::c_int = 0;
+    let mut len = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ERROR,
+            &mut errno as *mut _ as *mut _,
+            &mut len,
+        )
+    };
+
+    match result {
+        0 => Ok(errno),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+
+use crate::utils::Errno;
+
+/// What has gone wrong with a connection, found under the context of an [`anyhow::Error`] with
+/// [`Error::of`] to tell a client going away from the server running out of memory.
+#[derive(Debug)]
+pub enum Error {
+    /// An operation on a socket has failed with the errno.
+    Transport { operation: &'static str, errno: i32 },
+    /// The peer has closed the connection while being written to.
+    Disconnected,
+    /// The peer has broken the framing, e.g. sent a frame larger than allowed.
+    Protocol(String),
+    /// The peer has sent nothing for longer than the idle timeout.
+    IdleTimeout,
+    /// The peer has sent a frame too slowly, taking longer than the frame timeout or falling
+    /// below the minimum rate.
+    FrameTimeout,
+    /// The peer has read broadcasts slower than the others sent them until too many piled up.
+    SlowConsumer,
+    /// The server has reset the connection on purpose in chaos mode.
+    ChaosReset,
+    /// The kernel has run out of memory for an operation.
+    Exhausted { operation: &'static str },
+    /// The ring has failed to take an operation.
+    Ring(std::io::Error),
+}
+
+impl Error {
+    /// Tells apart running out of memory from other failures of the `operation`.
+    pub fn from_errno(operation: &'static str, errno: i32) -> Self {
+        match errno {
+            libc::ENOMEM => Self::Exhausted { operation },
+            errno => Self::Transport { operation, errno },
+        }
+    }
+
+    pub fn protocol(message: impl fmt::Display) -> Self {
+        Self::Protocol(message.to_string())
+    }
+
+    /// Finds the error anywhere in the chain of `err`.
+    pub fn of(err: &anyhow::Error) -> Option<&Self> {
+        err.chain().find_map(|err| err.downcast_ref())
+    }
+
+    /// Whether it's the usual way for a client to go away rather than a failure.
+    pub fn is_disconnect(&self) -> bool {
+        match *self {
+            Self::Transport { errno, .. } => matches!(
+                errno,
+                libc::ECONNRESET | libc::EPIPE | libc::ECONNABORTED | libc::ETIMEDOUT
+            ),
+            Self::Disconnected | Self::IdleTimeout | Self::FrameTimeout | Self::SlowConsumer => {
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the server is in no state to go on serving.
+    pub fn is_fatal(&self) -> bool {
+        matches!(self, Self::Exhausted { .. })
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Self::Transport { operation, errno } => {
+                write!(f, "{operation} error: {}", Errno(errno))
+            }
+            Self::Disconnected => write!(f, "Disconnected"),
+            Self::Protocol(ref message) => write!(f, "{message}"),
+            Self::IdleTimeout => write!(f, "Idle timeout"),
+            Self::FrameTimeout => write!(f, "Frame timeout"),
+            Self::SlowConsumer => write!(f, "Slow consumer"),
+            Self::ChaosReset => write!(f, "Reset by chaos mode"),
+            Self::Exhausted { operation } => {
+                write!(f, "{operation} error: {}", Errno(libc::ENOMEM))
+            }
+            Self::Ring(_) => write!(f, "Ring error"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match *self {
+            Self::Ring(ref err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Context as _;
+
+    use super::*;
+
+    #[test]
+    fn found_under_context() {
+        let reset = Err::<(), _>(anyhow::Error::new(Error::from_errno(
+            "Read",
+            libc::ECONNRESET,
+        )))
+        .context("Upstream")
+        .unwrap_err();
+        assert!(Error::of(&reset).is_some_and(Error::is_disconnect));
+
+        let exhausted = anyhow::Error::new(Error::from_errno("Write", libc::ENOMEM));
+        assert!(Error::of(&exhausted).is_some_and(Error::is_fatal));
+
+        assert!(Error::of(&anyhow!("Other")).is_none());
+    }
+}
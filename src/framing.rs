@@ -0,0 +1,278 @@
+use std::ops::Range;
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::error::Error;
+
+const LENGTH_HEADER_SIZE: usize = std::mem::size_of::<u32>();
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Framing {
+    /// Echo whatever each read returns.
+    #[default]
+    Raw,
+    /// Echo `\n`-terminated lines, streaming ones larger than a buffer.
+    Lines,
+    /// Echo messages preceded by a big-endian u32 length header, streaming large ones.
+    LengthPrefixed,
+}
+
+impl Framing {
+    /// Finds the frame at the start of `data`, returning the range of its payload and the
+    /// number of bytes it takes, or `None` if it's not complete yet. Fails as soon as the data
+    /// shows a payload larger than `max_message_size`.
+    pub fn frame(
+        self,
+        data: &[u8],
+        max_message_size: usize,
+    ) -> Result<Option<(Range<usize>, usize)>> {
+        match self {
+            Self::Raw => match data.is_empty() {
+                true => Ok(None),
+                false => Ok(Some((0..data.len(), data.len()))),
+            },
+            Self::Lines => {
+                let len = data.iter().position(|&byte| byte == b'\n');
+                check_line(len.unwrap_or(data.len()), max_message_size)?;
+                Ok(len.map(|len| (0..len, len + 1)))
+            }
+            Self::LengthPrefixed => {
+                let Some(header) = data.first_chunk::<LENGTH_HEADER_SIZE>() else {
+                    return Ok(None);
+                };
+
+                let frame_len = LENGTH_HEADER_SIZE + message_size(*header, max_message_size)?;
+
+                match data.len() < frame_len {
+                    true => Ok(None),
+                    false => Ok(Some((LENGTH_HEADER_SIZE..frame_len, frame_len))),
+                }
+            }
+        }
+    }
+
+    /// Returns the length of the longest prefix of `data` consisting of complete frames.
+    pub fn complete_len(self, data: &[u8], max_message_size: usize) -> Result<usize> {
+        let mut len = 0;
+
+        while let Some((_, frame_len)) = self.frame(&data[len..], max_message_size)? {
+            len += frame_len;
+        }
+
+        Ok(len)
+    }
+
+    /// Splits `data` consisting of complete frames into message payloads.
+    pub fn payloads(self, mut data: &[u8]) -> impl Iterator<Item = &[u8]> {
+        std::iter::from_fn(move || {
+            let (payload, len) = self.frame(data, usize::MAX).ok()??;
+            let (frame, rest) = data.split_at(len);
+            data = rest;
+            Some(&frame[payload])
+        })
+    }
+
+    /// Like [`Framing::payloads`], for rewriting them in place.
+    pub fn payloads_mut(self, mut data: &mut [u8]) -> impl Iterator<Item = &mut [u8]> {
+        std::iter::from_fn(move || {
+            let (payload, len) = self.frame(data, usize::MAX).ok()??;
+            let (frame, rest) = std::mem::take(&mut data).split_at_mut(len);
+            data = rest;
+            Some(&mut frame[payload])
+        })
+    }
+}
+
+fn check_line(len: usize, max_message_size: usize) -> Result<()> {
+    if len > max_message_size {
+        bail!(Error::protocol(format_args!(
+            "Line exceeds {max_message_size} bytes"
+        )));
+    }
+
+    Ok(())
+}
+
+fn message_size(header: [u8; LENGTH_HEADER_SIZE], max_message_size: usize) -> Result<usize> {
+    let message_size = u32::from_be_bytes(header) as usize;
+
+    if message_size > max_message_size {
+        bail!(Error::protocol(format_args!(
+            "Message of {message_size} bytes exceeds {max_message_size} bytes"
+        )));
+    }
+
+    Ok(message_size)
+}
+
+/// A message found by [`Decoder::feed`].
+#[derive(Debug, PartialEq, Eq)]
+pub enum Message<'a> {
+    /// The payload of a frame which is entirely in the fed data.
+    Whole(&'a [u8]),
+    /// The payload size of a frame which started in previously fed data and ended in this one.
+    Streamed(usize),
+}
+
+/// Tracks frame boundaries across reads so that data may be echoed as it arrives instead of
+/// accumulating frames larger than a buffer first.
+pub struct Decoder {
+    framing: Framing,
+    max_message_size: usize,
+    /// Bytes of the current frame including the header fed so far.
+    seen: usize,
+    header: [u8; LENGTH_HEADER_SIZE],
+}
+
+impl Decoder {
+    /// Fails on frames with payloads larger than `max_message_size`.
+    pub fn new(framing: Framing, max_message_size: usize) -> Self {
+        Self {
+            framing,
+            max_message_size,
+            seen: 0,
+            header: [0; LENGTH_HEADER_SIZE],
+        }
+    }
+
+    /// Whether all the fed data consists of complete frames.
+    pub fn at_boundary(&self) -> bool {
+        self.seen == 0
+    }
+
+    /// Consumes the next chunk of the stream and returns the messages completed in it.
+    pub fn feed<'a>(&mut self, mut data: &'a [u8]) -> Result<Vec<Message<'a>>> {
+        let mut messages = Vec::new();
+
+        while !data.is_empty() {
+            if self.seen == 0 {
+                if let Some((payload, len)) = self.framing.frame(data, self.max_message_size)? {
+                    messages.push(Message::Whole(&data[payload]));
+                    data = &data[len..];
+                    continue;
+                }
+            }
+
+            // The rest of the data is of a frame it doesn't complete, or completes a frame
+            // started in previously fed data.
+            let len = match self.framing {
+                Framing::Raw => {
+                    self.seen = data.len();
+                    data.len()
+                }
+                Framing::Lines => {
+                    let len = data.iter().position(|&byte| byte == b'\n');
+                    self.seen += len.unwrap_or(data.len());
+                    check_line(self.seen, self.max_message_size)?;
+
+                    let Some(len) = len else {
+                        break;
+                    };
+
+                    len + 1
+                }
+                Framing::LengthPrefixed => {
+                    if self.seen < LENGTH_HEADER_SIZE {
+                        let len = data.len().min(LENGTH_HEADER_SIZE - self.seen);
+                        self.header[self.seen..(self.seen + len)].copy_from_slice(&data[..len]);
+                        self.seen += len;
+                        data = &data[len..];
+
+                        if self.seen < LENGTH_HEADER_SIZE {
+                            break;
+                        }
+                    }
+
+                    let message_size = message_size(self.header, self.max_message_size)?;
+                    let missing = LENGTH_HEADER_SIZE + message_size - self.seen;
+
+                    if data.len() < missing {
+                        self.seen += data.len();
+                        break;
+                    }
+
+                    self.seen = message_size;
+                    missing
+                }
+            };
+
+            messages.push(Message::Streamed(self.seen));
+            self.seen = 0;
+            data = &data[len..];
+        }
+
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn length_prefixed(payload: &[u8]) -> Vec<u8> {
+        let mut frame = (payload.len() as u32).to_be_bytes().to_vec();
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn whole_frames_in_one_chunk() {
+        let mut decoder = Decoder::new(Framing::Lines, 16);
+        let messages = decoder.feed(b"foo\n\nbar\nba").unwrap();
+        let expected = [
+            Message::Whole(b"foo"),
+            Message::Whole(b""),
+            Message::Whole(b"bar"),
+        ];
+        assert_eq!(messages, expected);
+        assert!(!decoder.at_boundary());
+
+        let mut decoder = Decoder::new(Framing::LengthPrefixed, 16);
+        let data = [length_prefixed(b"foo"), length_prefixed(b"")].concat();
+        let messages = decoder.feed(&data).unwrap();
+        assert_eq!(messages, [Message::Whole(b"foo"), Message::Whole(b"")]);
+        assert!(decoder.at_boundary());
+    }
+
+    #[test]
+    fn multi_megabyte_frames_in_small_chunks() {
+        const SIZE: usize = 5 * 1024 * 1024;
+
+        for framing in [Framing::Lines, Framing::LengthPrefixed] {
+            let mut data = match framing {
+                Framing::LengthPrefixed => length_prefixed(&vec![b'x'; SIZE]),
+                _ => [vec![b'x'; SIZE], b"\n".to_vec()].concat(),
+            };
+
+            data.extend_from_slice(&data.clone());
+            let mut decoder = Decoder::new(framing, SIZE);
+            let mut messages = Vec::new();
+
+            // An odd chunk size to split headers and cross frame boundaries mid-chunk.
+            for chunk in data.chunks(4093) {
+                messages.extend(decoder.feed(chunk).unwrap());
+            }
+
+            assert_eq!(messages, [Message::Streamed(SIZE), Message::Streamed(SIZE)]);
+            assert!(decoder.at_boundary());
+        }
+    }
+
+    #[test]
+    fn oversize_frames() {
+        let mut decoder = Decoder::new(Framing::Lines, 4);
+        decoder.feed(b"abc").unwrap();
+        assert!(decoder.feed(b"de").is_err());
+
+        let mut decoder = Decoder::new(Framing::LengthPrefixed, 4);
+        assert!(decoder.feed(&5u32.to_be_bytes()[..2]).is_ok());
+        assert!(decoder.feed(&5u32.to_be_bytes()[2..]).is_err());
+
+        // Whether the frame is complete or not.
+        assert!(Framing::Lines.complete_len(b"abcde\n", 4).is_err());
+        assert!(Decoder::new(Framing::Lines, 4).feed(b"abcde\n").is_err());
+        assert!(Decoder::new(Framing::Lines, 4).feed(b"abcd\n").is_ok());
+    }
+}
@@ -0,0 +1,103 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::Arc;
+
+use anyhow::Result;
+
+use crate::buffer::Chunk;
+use crate::client::Client;
+use crate::codec::{Codec, Framed};
+use crate::middleware::ConnectionInfo;
+
+/// Serves an accepted connection, a clone of the handler given to the
+/// [`ServerBuilder`](crate::ServerBuilder) per connection. The connection is closed once the
+/// handler returns.
+pub trait Handler {
+    fn handle(&mut self, conn: &mut Connection) -> impl Future<Output = Result<()>>;
+}
+
+/// Echoes the data back, or forwards or broadcasts it as configured. That's what the server
+/// does unless given another handler.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Echo;
+
+impl Handler for Echo {
+    fn handle(&mut self, conn: &mut Connection) -> impl Future<Output = Result<()>> {
+        conn.client.handle()
+    }
+}
+
+/// A connected client reading into the buffers of the server and writing through its ring.
+pub struct Connection {
+    client: Client,
+    info: ConnectionInfo,
+}
+
+impl Connection {
+    pub(crate) fn new(client: Client, info: ConnectionInfo) -> Self {
+        Self { client, info }
+    }
+
+    pub fn id(&self) -> u32 {
+        self.client.id()
+    }
+
+    /// Who the client is, the same as the middleware gets.
+    pub fn info(&self) -> &ConnectionInfo {
+        &self.info
+    }
+
+    /// Reads the next chunk subject to the idle timeout; `None` means the end of the stream, or
+    /// that the server is shutting down while there's nothing to finish.
+    pub async fn read(&self) -> Result<Option<Chunk>> {
+        self.client.read().await
+    }
+
+    /// Writes all the `data` which is copied by the kernel unless it's a part of a chunk.
+    pub async fn write(&self, data: &[u8]) -> Result<()> {
+        self.client.write(None, data).await
+    }
+
+    /// Writes the chunk right from the fixed buffer it's been read into.
+    pub async fn write_chunk(&self, chunk: &Chunk) -> Result<()> {
+        self.client.write(Some(chunk.buffer()), chunk).await
+    }
+
+    /// Reads and writes frames with the `codec` instead of chunks as they arrive.
+    pub fn framed<C: Codec>(&self, codec: C) -> Framed<'_, C> {
+        Framed::new(self, codec)
+    }
+
+    /// Shuts down the writing side so that the client reads the end of the stream.
+    pub async fn shutdown(&self) -> Result<()> {
+        self.client.shutdown().await
+    }
+
+    /// Whether the server is shutting down and the handler is to finish the current exchange.
+    pub fn is_draining(&self) -> bool {
+        self.client.is_draining()
+    }
+}
+
+/// Makes the future serving a connection with a handler of its own, closing it afterwards.
+pub(crate) type Serve = Rc<dyn Fn(Connection) -> Pin<Box<dyn Future<Output = Result<()>>>>>;
+
+/// Lets [`Serve`] be made on each worker for the handler shared by them.
+pub(crate) type MakeServe = Arc<dyn Fn() -> Serve + Send + Sync>;
+
+pub(crate) fn make_serve<H: Handler + Clone + Send + Sync + 'static>(handler: H) -> MakeServe {
+    Arc::new(move || serve(handler.clone()))
+}
+
+pub(crate) fn serve<H: Handler + Clone + 'static>(handler: H) -> Serve {
+    Rc::new(move |mut conn| {
+        let mut handler = handler.clone();
+
+        Box::pin(async move {
+            let result = handler.handle(&mut conn).await;
+            let closed = conn.client.close().await;
+            result.and(closed)
+        })
+    })
+}
@@ -0,0 +1,413 @@
+//! Upgrades without dropping connections: a new server connects to the handover socket of the
+//! running one and takes its listening sockets over with `SCM_RIGHTS`, so that the connections
+//! waiting in their accept queues are accepted by the new server instead of being reset. Once
+//! the new server has set up all its workers it tells the old one, which stops accepting and
+//! drains like on SIGTERM.
+//!
+//! The socket is a sequenced packet one: the old server sends a packet per socket naming it,
+//! e.g. `tcp 0.0.0.0:7`, with the descriptor attached, then `end`, and the new one answers
+//! `ready`. Going away without answering leaves the old server serving as before.
+
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use socket2::{Domain, SockAddr, Socket, Type};
+
+use crate::builder::Handle;
+
+/// Longer packets are truncated, and names are much shorter.
+const MAX_PACKET: usize = 4096;
+
+/// How long the new server waits for the old one to send its sockets.
+const RECEIVE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Set once the sockets are handed over, after which the paths of the UNIX sockets belong to
+/// the new server and aren't to be removed.
+static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+
+/// Whether the sockets have been handed over to a new server, which then owns the files the
+/// process would otherwise remove on exit.
+pub fn is_handed_over() -> bool {
+    HANDED_OVER.load(Ordering::Acquire)
+}
+
+/// The sockets taken over from the previous server for the workers to adopt, and those they
+/// listen on to hand over to the next one.
+pub struct Handover {
+    path: PathBuf,
+    listener: Socket,
+    workers: usize,
+    /// By their names in the order received, `None` once adopted.
+    inherited: Mutex<Vec<(String, Option<OwnedFd>)>>,
+    /// Duplicates of the sockets the workers listen on, until they stop accepting.
+    offered: Arc<Mutex<Vec<(String, OwnedFd)>>>,
+    /// Workers which have bound.
+    bound: AtomicUsize,
+    /// To tell once the workers have bound.
+    previous: Mutex<Option<Socket>>,
+}
+
+impl Handover {
+    /// Takes the sockets over from the server listening at the `path` if there's one, or
+    /// listens there itself otherwise, for the `workers` to adopt.
+    pub fn take_over(path: &Path, workers: usize) -> Result<Self> {
+        let mut inherited = Vec::new();
+        let mut previous = None;
+
+        match connect(path) {
+            Ok(socket) => {
+                inherited = receive_all(&socket).context("Receive sockets")?;
+                info!(
+                    "Took {} sockets over from the server at {}",
+                    inherited.len(),
+                    path.display()
+                );
+                previous = Some(socket);
+            }
+            // A socket left by a server which hasn't exited cleanly.
+            Err(_) if path.exists() => {
+                std::fs::remove_file(path).context("Remove stale handover socket")?;
+            }
+            Err(_) => (),
+        }
+
+        let name = format!("handover {}", path.display());
+        let position = inherited
+            .iter()
+            .position(|(inherited, _)| *inherited == name);
+
+        let listener = match position {
+            Some(position) => Socket::from(inherited.remove(position).1),
+            None => listen(path)?,
+        };
+
+        Ok(Self {
+            path: path.to_owned(),
+            listener,
+            workers,
+            inherited: Mutex::new(
+                inherited
+                    .into_iter()
+                    .map(|(name, fd)| (name, Some(fd)))
+                    .collect(),
+            ),
+            offered: Arc::default(),
+            bound: AtomicUsize::new(0),
+            previous: Mutex::new(previous),
+        })
+    }
+
+    /// The inherited sockets with the `name` which are the worker's: the workers take turns
+    /// in the order the sockets have been received, so that a server with fewer workers than
+    /// the previous one adopts all of them and one with more binds the rest anew.
+    pub fn adopt(&self, name: &str, worker_id: usize) -> Vec<OwnedFd> {
+        lock(&self.inherited)
+            .iter_mut()
+            .filter(|(inherited, _)| inherited == name)
+            .enumerate()
+            .filter(|(i, _)| i % self.workers == worker_id)
+            .filter_map(|(_, (_, fd))| fd.take())
+            .collect()
+    }
+
+    /// Hands the `socket` over to the next server under the `name` along with the others.
+    pub fn offer(&self, name: &str, socket: BorrowedFd<'_>) -> Result<()> {
+        let fd = socket
+            .try_clone_to_owned()
+            .with_context(|| format!("Duplicate {name}"))?;
+
+        lock(&self.offered).push((name.to_owned(), fd));
+        Ok(())
+    }
+
+    /// Stops handing the sockets over once the workers stop accepting, so that the kernel
+    /// refuses connections instead of queueing them for nobody.
+    pub fn withdraw(&self) {
+        lock(&self.offered).clear();
+    }
+
+    pub fn bound(&self) {
+        self.bound.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Once all the workers have bound, tells the previous server to drain and starts handing
+    /// the sockets over to the next server connecting, shutting the servers of the `handle`
+    /// down then.
+    pub fn complete(&self, handle: Handle) -> Result<()> {
+        if self.bound.load(Ordering::Acquire) < self.workers {
+            return Ok(());
+        }
+
+        for (name, fd) in lock(&self.inherited).drain(..) {
+            if fd.is_some() {
+                warn!("Closing {name} taken over but not listened on anymore");
+            }
+        }
+
+        // Both serve if it fails, which is what the previous one does without the new one.
+        if let Some(previous) = lock(&self.previous).take() {
+            match send(&previous, "ready", None) {
+                Ok(()) => info!("Told the previous server to drain"),
+                Err(err) => warn!("Tell the previous server to drain: {err}"),
+            }
+        }
+
+        let listener = self
+            .listener
+            .try_clone()
+            .context("Duplicate handover socket")?;
+        let name = format!("handover {}", self.path.display());
+        let offered = Arc::clone(&self.offered);
+
+        thread::Builder::new()
+            .name("handover".into())
+            .spawn(move || serve(listener, &name, &offered, handle))
+            .context("Spawn handover")?;
+
+        info!("Handing the sockets over at {}", self.path.display());
+        Ok(())
+    }
+}
+
+impl Drop for Handover {
+    fn drop(&mut self) {
+        if !is_handed_over() {
+            std::fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+/// Hands the sockets over to the first server which takes them, then shuts the servers down.
+fn serve(listener: Socket, name: &str, offered: &Mutex<Vec<(String, OwnedFd)>>, handle: Handle) {
+    loop {
+        let socket = match listener.accept() {
+            Ok((socket, _)) => socket,
+            Err(err) => {
+                error!("Accept handover: {err}");
+                return;
+            }
+        };
+
+        info!("Handing the sockets over to the next server");
+
+        match hand_over(&socket, &listener, name, offered) {
+            Ok(true) => break,
+            Ok(false) => warn!("The next server has gone away, keeping on serving"),
+            Err(err) => warn!("Handover failed, keeping on serving: {err:#}"),
+        }
+    }
+
+    HANDED_OVER.store(true, Ordering::Release);
+    info!("The next server is ready, draining");
+
+    if let Err(err) = handle.shutdown() {
+        error!("Shut down after handover: {err:#}");
+    }
+}
+
+/// Whether the next server has taken the sockets over and is ready.
+fn hand_over(
+    socket: &Socket,
+    listener: &Socket,
+    name: &str,
+    offered: &Mutex<Vec<(String, OwnedFd)>>,
+) -> Result<bool> {
+    // The workers may stop accepting meanwhile, but the duplicates keep the sockets open.
+    let offered = lock(offered)
+        .iter()
+        .map(|(name, fd)| Ok((name.clone(), fd.try_clone()?)))
+        .collect::<std::io::Result<Vec<_>>>()
+        .context("Duplicate sockets")?;
+
+    if offered.is_empty() {
+        bail!("Not accepting anymore");
+    }
+
+    for (name, fd) in &offered {
+        send(socket, name, Some(fd.as_fd())).with_context(|| format!("Send {name}"))?;
+    }
+
+    send(socket, name, Some(listener.as_fd())).context("Send handover socket")?;
+    send(socket, "end", None).context("Send end")?;
+
+    match receive(socket).context("Receive ready")? {
+        Some((packet, _)) if packet == "ready" => Ok(true),
+        Some((packet, _)) => bail!("Unexpected {packet:?}"),
+        None => Ok(false),
+    }
+}
+
+fn connect(path: &Path) -> std::io::Result<Socket> {
+    let socket = Socket::new(Domain::UNIX, Type::SEQPACKET, None)?;
+    socket.connect(&SockAddr::unix(path)?)?;
+    socket.set_read_timeout(Some(RECEIVE_TIMEOUT))?;
+    Ok(socket)
+}
+
+fn listen(path: &Path) -> Result<Socket> {
+    let socket = Socket::new(Domain::UNIX, Type::SEQPACKET, None).context("Socket")?;
+
+    socket
+        .bind(&SockAddr::unix(path).context("Address")?)
+        .context("Bind handover socket")?;
+
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o600))
+        .context("Restrict handover socket")?;
+
+    socket.listen(1).context("Listen")?;
+    Ok(socket)
+}
+
+/// The named sockets sent until `end`.
+fn receive_all(socket: &Socket) -> Result<Vec<(String, OwnedFd)>> {
+    let mut sockets = Vec::new();
+
+    loop {
+        match receive(socket)? {
+            Some((packet, None)) if packet == "end" => return Ok(sockets),
+            Some((name, Some(fd))) => sockets.push((name, fd)),
+            Some((packet, None)) => bail!("Unexpected {packet:?}"),
+            None => bail!("The previous server has gone away"),
+        }
+    }
+}
+
+/// Sends the `packet` with the descriptor attached if any.
+fn send(socket: &Socket, packet: &str, fd: Option<BorrowedFd<'_>>) -> std::io::Result<()> {
+    let mut iovec = libc::iovec {
+        iov_base: packet.as_ptr() as *mut libc::c_void,
+        iov_len: packet.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iovec;
+    msg.msg_iovlen = 1;
+
+    // Aligned for the header.
+    let mut control = [0u64; 4];
+
+    if let Some(fd) = fd {
+        let len = std::mem::size_of::<libc::c_int>() as u32;
+        msg.msg_control = control.as_mut_ptr().cast();
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(len) } as usize;
+
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(len) as usize;
+            libc::CMSG_DATA(cmsg)
+                .cast::<libc::c_int>()
+                .write_unaligned(fd.as_raw_fd());
+        }
+    }
+
+    match unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) } {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// The next packet with the descriptor attached if any, `None` at the end of the stream.
+fn receive(socket: &Socket) -> std::io::Result<Option<(String, Option<OwnedFd>)>> {
+    let mut packet = vec![0u8; MAX_PACKET];
+    let mut iovec = libc::iovec {
+        iov_base: packet.as_mut_ptr().cast(),
+        iov_len: packet.len(),
+    };
+
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iovec;
+    msg.msg_iovlen = 1;
+
+    let mut control = [0u64; 4];
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = std::mem::size_of_val(&control);
+
+    let len = match unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) } {
+        -1 => return Err(std::io::Error::last_os_error()),
+        0 => return Ok(None),
+        len => len as usize,
+    };
+
+    let mut fd = None;
+    let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+
+    while !cmsg.is_null() {
+        let (level, ty) = unsafe { ((*cmsg).cmsg_level, (*cmsg).cmsg_type) };
+
+        if (level, ty) == (libc::SOL_SOCKET, libc::SCM_RIGHTS) {
+            let raw = unsafe { libc::CMSG_DATA(cmsg).cast::<libc::c_int>().read_unaligned() };
+            fd = Some(unsafe { OwnedFd::from_raw_fd(raw) });
+        }
+
+        cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+    }
+
+    packet.truncate(len);
+    Ok(Some((String::from_utf8_lossy(&packet).into_owned(), fd)))
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn sockets_pass_with_their_names() {
+        let (old, new) = Socket::pair(Domain::UNIX, Type::SEQPACKET, None).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+
+        send(&old, &format!("tcp {address}"), Some(listener.as_fd())).unwrap();
+        send(&old, "end", None).unwrap();
+
+        let sockets = receive_all(&new).unwrap();
+        assert_eq!(sockets.len(), 1);
+        assert_eq!(sockets[0].0, format!("tcp {address}"));
+
+        let adopted = TcpListener::from(sockets[0].1.try_clone().unwrap());
+        assert_eq!(adopted.local_addr().unwrap(), address);
+
+        drop(old);
+        assert!(receive(&new).unwrap().is_none());
+    }
+
+    #[test]
+    fn workers_take_turns() {
+        let fd = || std::fs::File::open("/dev/null").unwrap().into();
+        let (listener, _) = Socket::pair(Domain::UNIX, Type::SEQPACKET, None).unwrap();
+
+        let handover = Handover {
+            path: PathBuf::new(),
+            listener,
+            workers: 2,
+            inherited: Mutex::new(vec![
+                ("tcp a".into(), Some(fd())),
+                ("tcp b".into(), Some(fd())),
+                ("tcp a".into(), Some(fd())),
+                ("tcp a".into(), Some(fd())),
+            ]),
+            offered: Arc::default(),
+            bound: AtomicUsize::new(0),
+            previous: Mutex::new(None),
+        };
+
+        assert_eq!(handover.adopt("tcp a", 0).len(), 2);
+        assert_eq!(handover.adopt("tcp a", 1).len(), 1);
+        assert_eq!(handover.adopt("tcp a", 0).len(), 0);
+        assert_eq!(handover.adopt("tcp b", 1).len(), 0);
+        assert_eq!(handover.adopt("tcp b", 0).len(), 1);
+    }
+}
@@ -0,0 +1,350 @@
+use std::any::Any;
+use std::cell::RefCell;
+use std::fmt;
+use std::future::Future;
+use std::pin::pin;
+use std::rc::Rc;
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use futures::future::{self, Either};
+use io_uring::cqueue::Entry as Cqe;
+use io_uring::opcode::{AsyncCancel, LinkTimeout, Timeout, TimeoutRemove};
+use io_uring::squeue::{Entry as Sqe, Flags};
+use io_uring::types::Timespec;
+
+use crate::backend::Backend;
+use crate::common::{CqeQueue, NextEventFuture, Operation, Operations, Route};
+use crate::error::Error;
+
+/// Submits operations completing into slots of their own, so that any number of them may be in
+/// flight at a time.
+pub struct Io {
+    ring: Rc<RefCell<dyn Backend>>,
+    operations: Operations,
+}
+
+impl Io {
+    pub fn new(ring: Rc<RefCell<dyn Backend>>, operations: Operations) -> Self {
+        Self { ring, operations }
+    }
+
+    /// Submits the operation (named `what` for errors) and waits for its completion.
+    pub async fn submit(&self, sqe: Sqe, what: &str) -> Result<Cqe> {
+        Ok(self.start(sqe, what)?.next().await)
+    }
+
+    /// Submits the operation without waiting, so that operations with more than one completion,
+    /// e.g. with a notification, may be awaited further.
+    pub fn start(&self, sqe: Sqe, what: &str) -> Result<Operation> {
+        let operation = self.operations.start();
+        self.push(
+            &[sqe.user_data(operation.route().into())],
+            &[&operation],
+            what,
+        )?;
+        Ok(operation)
+    }
+
+    /// Same as [`Io::submit`] with `kept`, what the entry points at, kept by the slot of the
+    /// operation until the kernel is done with it even if the operation is dropped before, and
+    /// handed back along with the completion.
+    pub async fn submit_keeping<T: Any>(&self, sqe: Sqe, kept: T, what: &str) -> Result<(Cqe, T)> {
+        let operation = self.start_keeping(sqe, kept, what)?;
+        let cqe = operation.next().await;
+        let kept = operation.take_kept().context("Nothing kept")?;
+        Ok((cqe, kept))
+    }
+
+    /// Same as [`Io::start`] keeping `kept` as [`Io::submit_keeping`] does.
+    pub fn start_keeping(&self, sqe: Sqe, kept: impl Any, what: &str) -> Result<Operation> {
+        let operation = self.start(sqe, what)?;
+        operation.keep(kept);
+        Ok(operation)
+    }
+
+    /// Same as [`Io::submit`] but cancels the operation if it doesn't complete in `timeout`
+    /// in which case it fails with `ECANCELED`.
+    pub async fn submit_with_timeout(
+        &self,
+        sqe: Sqe,
+        timeout: Option<Duration>,
+        what: &str,
+    ) -> Result<Cqe> {
+        let Some(timeout) = timeout else {
+            return self.submit(sqe, what).await;
+        };
+
+        // The kernel reads the timeout as it submits the entries, which may be once the
+        // operation has been dropped, or asynchronously with SQPOLL, so it's kept by the slot.
+        let timespec = Box::new(Timespec::from(timeout));
+        let operation = self.operations.start();
+
+        let sqes = [
+            sqe.user_data(operation.route().into())
+                .flags(Flags::IO_LINK),
+            LinkTimeout::new(&*timespec)
+                .build()
+                .user_data(Route::Timeout.into()),
+        ];
+
+        self.push(&sqes, &[&operation], what)?;
+        operation.keep(timespec);
+        Ok(operation.next().await)
+    }
+
+    /// Waits for `duration` to pass without blocking the other tasks.
+    pub async fn sleep(&self, duration: Duration) -> Result<()> {
+        // Kept by the slot rather than the sleep, which may be dropped before the kernel has
+        // read it, as it does asynchronously with SQPOLL.
+        let timespec = Box::new(Timespec::from(duration));
+        let sqe = Timeout::new(&*timespec).build();
+        let (cqe, _) = self.submit_keeping(sqe, timespec, "sleep").await?;
+
+        match cqe.result() {
+            errno if errno == -libc::ETIME => Ok(()),
+            errno if errno < 0 => Err(Error::from_errno("Sleep", -errno).into()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Runs the future for at most `duration`, failing with [`Elapsed`] if it takes longer.
+    /// Unlike [`Io::submit_with_timeout`] covers any number of operations, which are cancelled
+    /// once the future is dropped.
+    pub async fn timeout<F: Future>(&self, duration: Duration, fut: F) -> Result<F::Output> {
+        let sleep = pin!(self.sleep(duration));
+
+        match future::select(pin!(fut), sleep).await {
+            Either::Left((output, _)) => Ok(output),
+            Either::Right((Ok(()), _)) => Err(Elapsed(duration).into()),
+            Either::Right((Err(err), _)) => Err(err),
+        }
+    }
+
+    /// Pushes the entries of the `operations` to the submission queue together for the event
+    /// loop to submit them along with the others at once.
+    fn push(&self, sqes: &[Sqe], operations: &[&Operation], what: &str) -> Result<()> {
+        let mut ring = self.ring.borrow_mut();
+        let result = unsafe { ring.push(sqes) }
+            .map_err(Error::Ring)
+            .with_context(|| format!("Push {what}"));
+
+        if result.is_err() {
+            for operation in operations {
+                operation.discard();
+            }
+        }
+
+        result
+    }
+
+    /// Submits the operations as a chain which the kernel runs one after another, and waits for
+    /// a completion of each in order. Once one of them fails, the rest complete with `ECANCELED`.
+    /// Each of them keeps a clone of `kept` as [`Io::submit_keeping`] does.
+    pub async fn submit_linked(
+        &self,
+        sqes: Vec<Sqe>,
+        kept: impl Any + Clone,
+        what: &str,
+    ) -> Result<Vec<Cqe>> {
+        let count = sqes.len();
+        let operations = (0..count)
+            .map(|_| self.operations.start())
+            .collect::<Vec<_>>();
+
+        let sqes = sqes
+            .into_iter()
+            .zip(&operations)
+            .enumerate()
+            .map(|(i, (sqe, operation))| {
+                let sqe = sqe.user_data(operation.route().into());
+
+                match i + 1 < count {
+                    true => sqe.flags(Flags::IO_LINK),
+                    false => sqe,
+                }
+            })
+            .collect::<Vec<_>>();
+
+        // All at once as the chain must be contiguous in the submission queue.
+        self.push(&sqes, &operations.iter().collect::<Vec<_>>(), what)?;
+
+        for operation in &operations {
+            operation.keep(kept.clone());
+        }
+
+        let mut cqes = Vec::with_capacity(count);
+
+        for operation in &operations {
+            cqes.push(operation.next().await);
+        }
+
+        Ok(cqes)
+    }
+}
+
+/// A lane for multishot operations whose completions are queued until consumed, so that any
+/// number of them may be in flight. Timers are routed separately so that cancelling the
+/// operations doesn't hit them.
+pub struct Stream {
+    ring: Rc<RefCell<dyn Backend>>,
+    cqes: CqeQueue,
+    route: u64,
+    timer_route: Option<u64>,
+}
+
+impl Stream {
+    pub fn new(ring: Rc<RefCell<dyn Backend>>, cqes: CqeQueue, route: Route) -> Self {
+        Self {
+            ring,
+            cqes,
+            route: route.into(),
+            timer_route: None,
+        }
+    }
+
+    /// Routes timers with `route` which has to end up in the same queue.
+    pub fn with_timer_route(mut self, route: Route) -> Self {
+        self.timer_route = Some(route.into());
+        self
+    }
+
+    /// Submits the operation (named `what` for errors) without waiting for its completions.
+    pub fn submit(&self, sqe: Sqe, what: &str) -> Result<()> {
+        self.push(sqe.user_data(self.route), what)
+    }
+
+    pub fn submit_timer(&self, timespec: &Timespec, what: &str) -> Result<()> {
+        let route = self.timer_route.context("No timer route")?;
+        self.push(Timeout::new(timespec).build().user_data(route), what)
+    }
+
+    /// Cancels the operations submitted so far except for timers. The cancellation's own
+    /// completion isn't routed to the stream so that it can't be mistaken for theirs.
+    pub fn cancel(&self, what: &str) -> Result<()> {
+        let sqe = AsyncCancel::new(self.route).build();
+        self.push(sqe.user_data(Route::Cancel.into()), what)
+    }
+
+    pub fn cancel_timer(&self, what: &str) -> Result<()> {
+        let route = self.timer_route.context("No timer route")?;
+        let sqe = TimeoutRemove::new(route).build();
+        self.push(sqe.user_data(Route::Cancel.into()), what)
+    }
+
+    fn push(&self, sqe: Sqe, what: &str) -> Result<()> {
+        let mut ring = self.ring.borrow_mut();
+        unsafe { ring.push(&[sqe]) }
+            .map_err(Error::Ring)
+            .with_context(|| format!("Push {what}"))
+    }
+
+    /// Waits for the next completion of any of the submitted operations.
+    pub async fn next(&self) -> Cqe {
+        NextEventFuture::new(Rc::clone(&self.cqes)).await
+    }
+
+    /// Number of completions not consumed yet.
+    pub fn queued(&self) -> usize {
+        self.cqes.borrow().len()
+    }
+
+    /// Takes the completions not consumed yet.
+    pub fn drain(&self) -> Vec<Cqe> {
+        self.cqes.borrow_mut().drain(..).collect()
+    }
+}
+
+/// The error of [`Io::timeout`] when the future hasn't completed in time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Elapsed(pub Duration);
+
+impl fmt::Display for Elapsed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Timed out after {:?}", self.0)
+    }
+}
+
+impl std::error::Error for Elapsed {}
+
+#[cfg(test)]
+mod tests {
+    use std::task::Poll;
+
+    use io_uring::opcode::Nop;
+
+    use super::*;
+    use crate::mock::Reactor;
+
+    #[test]
+    fn timeout_elapses() {
+        let reactor = Reactor::new(64, 1);
+        let io = reactor.io();
+        let nop = io.submit(Nop::new().build(), "nop");
+        let mut fut = pin!(io.timeout(Duration::from_secs(1), nop));
+        assert!(reactor.run(fut.as_mut()).is_pending());
+
+        let sleep = reactor.mock().take(Timeout::CODE);
+        assert_eq!(sleep.duration(), Duration::from_secs(1));
+        reactor.mock().complete(&sleep, -libc::ETIME);
+
+        let Poll::Ready(Err(err)) = reactor.run(fut.as_mut()) else {
+            panic!("Not timed out");
+        };
+
+        assert_eq!(err.downcast_ref(), Some(&Elapsed(Duration::from_secs(1))));
+
+        // The operation has been cancelled.
+        assert_eq!(reactor.mock().submitted(), 0);
+    }
+
+    #[test]
+    fn timeout_cancels_sleep() {
+        let reactor = Reactor::new(64, 1);
+        let io = reactor.io();
+        let nop = io.submit(Nop::new().build(), "nop");
+        let mut fut = pin!(io.timeout(Duration::from_secs(1), nop));
+        assert!(reactor.run(fut.as_mut()).is_pending());
+
+        let nop = reactor.mock().take(Nop::CODE);
+        reactor.mock().complete(&nop, 0);
+
+        let Poll::Ready(Ok(Ok(cqe))) = reactor.run(fut.as_mut()) else {
+            panic!("Not completed");
+        };
+
+        assert_eq!(cqe.result(), 0);
+        assert_eq!(reactor.mock().submitted(), 0);
+    }
+
+    #[test]
+    fn sleep_outlives_drop() {
+        let reactor = Reactor::new(64, 1);
+        let io = reactor.io();
+        let mut fut = Box::pin(io.sleep(Duration::from_secs(1)));
+        assert!(reactor.run(fut.as_mut()).is_pending());
+        drop(fut);
+
+        // Still there for the kernel to read.
+        let sleep = reactor.mock().take(Timeout::CODE);
+        assert_eq!(sleep.duration(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn kept_until_cancelled() {
+        let reactor = Reactor::new(64, 1);
+        let io = reactor.io();
+        let kept = Rc::new(());
+        let mut fut = Box::pin(io.submit_keeping(Nop::new().build(), Rc::clone(&kept), "nop"));
+        assert!(reactor.run(fut.as_mut()).is_pending());
+
+        // The kernel may still use it.
+        drop(fut);
+        assert_eq!(Rc::strong_count(&kept), 2);
+
+        // Until the cancellation completes the operation.
+        let mut other = pin!(io.submit(Nop::new().build(), "other nop"));
+        assert!(reactor.run(other.as_mut()).is_pending());
+        assert_eq!(Rc::strong_count(&kept), 1);
+    }
+}
@@ -0,0 +1,58 @@
+//! An io_uring TCP and UDP echo server, also proxying and broadcasting, with a runtime for
+//! embedding configured with a [`ServerBuilder`].
+
+#[macro_use]
+extern crate anyhow;
+
+#[macro_use]
+mod log;
+
+mod admin;
+mod allocator;
+mod backend;
+mod buffer;
+mod builder;
+mod chaos;
+mod client;
+pub mod codec;
+mod common;
+pub mod config;
+mod connection_limit;
+mod datagram;
+mod epoll;
+mod error;
+mod framing;
+mod handler;
+mod handover;
+mod io;
+mod mesh;
+mod metrics;
+mod middleware;
+#[cfg(test)]
+mod mock;
+mod outbox;
+mod privileges;
+mod probe;
+mod rate_limit;
+mod remote;
+mod response;
+mod ring;
+mod seccomp;
+mod server;
+mod services;
+mod signal;
+mod slab;
+mod soak;
+mod statsd;
+mod transform;
+mod utils;
+
+pub use self::buffer::Chunk;
+pub use self::builder::{Handle, ServerBuilder, SharedConfigLoader};
+pub use self::error::Error;
+pub use self::framing::{Decoder, Framing, Message};
+pub use self::handler::{Connection, Echo, Handler};
+pub use self::handover::is_handed_over;
+pub use self::log::LogLevel;
+pub use self::middleware::{ConnectionInfo, Credentials, Middleware};
+pub use self::services::{Chargen, Daytime, Discard};
@@ -0,0 +1,129 @@
+//! Leveled logging to stdout, or to stderr for warnings and errors, with the span of the task
+//! being polled so that the events of a connection carry its id and peer.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU32, AtomicU8, AtomicUsize, Ordering};
+
+use serde::Deserialize;
+
+#[derive(
+    Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, PartialOrd, Ord, clap::ValueEnum,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum LogLevel {
+    Error,
+    Warn,
+    /// Connections coming and going along with notices of the server.
+    #[default]
+    Info,
+    /// Every read and write.
+    Debug,
+    /// Message payloads too.
+    Trace,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Error => "ERROR",
+            Self::Warn => "WARN",
+            Self::Info => "INFO",
+            Self::Debug => "DEBUG",
+            Self::Trace => "TRACE",
+        };
+
+        f.pad(name)
+    }
+}
+
+/// Shared by all the workers like the payload settings.
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static PAYLOAD_EVERY: AtomicU32 = AtomicU32::new(1);
+static PAYLOAD_MAX_BYTES: AtomicUsize = AtomicUsize::new(256);
+
+thread_local! {
+    static SPAN: RefCell<Option<Span>> = const { RefCell::new(None) };
+    /// Payloads skipped since the last one logged.
+    static PAYLOADS_SKIPPED: Cell<u32> = const { Cell::new(0) };
+}
+
+/// What the events logged in it are about, e.g. `client{id=1 peer=127.0.0.1:52000}`.
+pub type Span = Rc<str>;
+
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn enabled(level: LogLevel) -> bool {
+    level as u8 <= LEVEL.load(Ordering::Relaxed)
+}
+
+/// Logs only one in `every` payloads, truncated to `max_bytes`.
+pub fn set_payloads(every: u32, max_bytes: usize) {
+    PAYLOAD_EVERY.store(every.max(1), Ordering::Relaxed);
+    PAYLOAD_MAX_BYTES.store(max_bytes, Ordering::Relaxed);
+}
+
+/// Picks the part of the next payload to log if it's to be logged at all.
+pub fn sample_payload(payload: &[u8]) -> Option<&[u8]> {
+    if !enabled(LogLevel::Trace) {
+        return None;
+    }
+
+    let skipped = PAYLOADS_SKIPPED.get() + 1;
+
+    if skipped < PAYLOAD_EVERY.load(Ordering::Relaxed) {
+        PAYLOADS_SKIPPED.set(skipped);
+        return None;
+    }
+
+    PAYLOADS_SKIPPED.set(0);
+    Some(&payload[..payload.len().min(PAYLOAD_MAX_BYTES.load(Ordering::Relaxed))])
+}
+
+/// Runs `f` with the events it logs in the `span`.
+pub fn in_span<R>(span: Option<&Span>, f: impl FnOnce() -> R) -> R {
+    let outer = SPAN.with(|current| current.replace(span.cloned()));
+    let result = f();
+    SPAN.with(|current| current.replace(outer));
+    result
+}
+
+pub fn write(level: LogLevel, args: fmt::Arguments<'_>) {
+    SPAN.with(|span| match (level, span.borrow().as_deref()) {
+        (LogLevel::Error | LogLevel::Warn, Some(span)) => eprintln!("{level:>5} {span}: {args}"),
+        (LogLevel::Error | LogLevel::Warn, None) => eprintln!("{level:>5} {args}"),
+        (_, Some(span)) => println!("{level:>5} {span}: {args}"),
+        (_, None) => println!("{level:>5} {args}"),
+    })
+}
+
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => {
+        if $crate::log::enabled($level) {
+            $crate::log::write($level, format_args!($($arg)*));
+        }
+    };
+}
+
+macro_rules! error {
+    ($($arg:tt)*) => { log!($crate::log::LogLevel::Error, $($arg)*) };
+}
+
+macro_rules! warn {
+    ($($arg:tt)*) => { log!($crate::log::LogLevel::Warn, $($arg)*) };
+}
+
+macro_rules! info {
+    ($($arg:tt)*) => { log!($crate::log::LogLevel::Info, $($arg)*) };
+}
+
+macro_rules! debug {
+    ($($arg:tt)*) => { log!($crate::log::LogLevel::Debug, $($arg)*) };
+}
+
+macro_rules! trace {
+    ($($arg:tt)*) => { log!($crate::log::LogLevel::Trace, $($arg)*) };
+}
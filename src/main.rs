@@ -1,17 +1,76 @@
-#[macro_use]
-extern crate anyhow;
+mod cli;
+mod daemon;
 
-mod buffer;
-mod client;
-mod common;
-mod server;
-mod utils;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{Context as _, Result};
+use clap::Parser;
+use uring::config::ServerConfig;
+use uring::ServerBuilder;
 
-use self::server::Server;
+use self::cli::Args;
+use self::daemon::PidFile;
 
-fn main() -> Result<()> {
-    let server = Server::bind("0.0.0.0:3456")?;
-    server.run()
+/// Exit status after a drain, for supervisors to tell it from a shutdown or a failure.
+const DRAINED: u8 = 3;
+
+fn main() -> Result<ExitCode> {
+    let mut args = Args::parse();
+    let config = args.clone().into_config()?;
+
+    if config.daemon {
+        // The working directory changes so make the config path absolute for reloading.
+        if let Some(ref mut path) = args.config {
+            *path = std::fs::canonicalize(&*path).context("Resolve config path")?;
+        }
+
+        let log_file = config.log_file.as_deref().map(absolute).transpose()?;
+        let pid_file = config.pid_file.as_deref().map(absolute).transpose()?;
+        daemon::daemonize(log_file.as_deref())?;
+        let pid_file = pid_file.as_deref().map(|path| pid_file_for(path, &config));
+        return run(args, config, pid_file.transpose()?);
+    }
+
+    let pid_file = config
+        .pid_file
+        .as_deref()
+        .map(|path| pid_file_for(path, &config));
+    run(args, config, pid_file.transpose()?)
+}
+
+/// With a handover socket the PID file is taken over from the running server along with the
+/// listeners, as it's normally the same for both.
+fn pid_file_for(path: &Path, config: &ServerConfig) -> Result<PidFile> {
+    match config.handover_socket {
+        Some(_) => PidFile::take_over(path),
+        None => PidFile::create(path),
+    }
+}
+
+fn absolute(path: &Path) -> Result<PathBuf> {
+    std::path::absolute(path).with_context(|| format!("Resolve path {}", path.display()))
+}
+
+fn run(args: Args, config: ServerConfig, pid_file: Option<PidFile>) -> Result<ExitCode> {
+    let mut builder = ServerBuilder::from_config(config)
+        .config_loader(Arc::new(move || args.clone().into_config()))
+        .handle_signals(true);
+
+    let pid_file = pid_file.map(Arc::new);
+
+    if let Some(ref pid_file) = pid_file {
+        let pid_file = Arc::clone(pid_file);
+
+        builder = builder.on_ready(move || pid_file.write());
+    }
+
+    let handle = builder.handle();
+    builder.run()?;
+
+    match handle.is_drained() {
+        true => Ok(ExitCode::from(DRAINED)),
+        false => Ok(ExitCode::SUCCESS),
+    }
 }
@@ -0,0 +1,51 @@
+use std::os::fd::{AsRawFd, BorrowedFd, OwnedFd, RawFd};
+use std::sync::{Arc, OnceLock};
+
+use anyhow::{Context as _, Result};
+
+/// Rings of all the workers for them to message each other with `IORING_OP_MSG_RING`, which
+/// posts a completion right into the queue of another ring without locks or wakeup fds.
+///
+/// Each worker joins with a duplicate of its ring fd, so the rings stay valid to post to while
+/// any worker is left even if their own ones have stopped.
+///
+/// Only connections are handed off this way. Shutdown and the other commands come from threads
+/// without a ring of their own, such as the signal forwarder or an embedder's, so they keep
+/// reaching each worker through its [`Remote`](crate::remote::Remote) eventfd. A completion
+/// carries no more than its user data and result, so the stats are summed from the shared
+/// atomic counters of [`Metrics`](crate::metrics::Metrics) instead.
+#[derive(Clone)]
+pub struct Mesh {
+    rings: Arc<[OnceLock<OwnedFd>]>,
+}
+
+impl Mesh {
+    pub fn new(workers: usize) -> Self {
+        Self {
+            rings: (0..workers).map(|_| OnceLock::new()).collect(),
+        }
+    }
+
+    /// Makes the `ring` of the worker reachable for the others.
+    pub fn join(&self, worker_id: usize, ring: BorrowedFd<'_>) -> Result<()> {
+        let slot = self.rings.get(worker_id).context("No such worker")?;
+        let fd = ring.try_clone_to_owned().context("Duplicate ring fd")?;
+
+        if slot.set(fd).is_err() {
+            bail!("Worker #{worker_id} has already joined");
+        }
+
+        Ok(())
+    }
+
+    /// Picks the next worker other than the one with `worker_id` which has joined, starting
+    /// after `last` and returning it along with its ring.
+    pub fn next_peer(&self, worker_id: usize, last: usize) -> Option<(usize, RawFd)> {
+        let count = self.rings.len();
+
+        (1..=count)
+            .map(|i| (last + i) % count)
+            .filter(|&peer| peer != worker_id)
+            .find_map(|peer| Some((peer, self.rings[peer].get()?.as_raw_fd())))
+    }
+}
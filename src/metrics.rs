@@ -0,0 +1,194 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// Counters and gauges of all the workers, each updating its own so that they don't contend,
+/// for exporters to sum up.
+pub struct Metrics {
+    workers: Box<[Arc<Counters>]>,
+}
+
+impl Metrics {
+    pub fn new(workers: usize) -> Self {
+        Self {
+            workers: (0..workers).map(|_| Arc::default()).collect(),
+        }
+    }
+
+    /// The counters updated by the worker with the id.
+    pub fn worker(&self, worker_id: usize) -> Arc<Counters> {
+        Arc::clone(&self.workers[worker_id])
+    }
+
+    /// Sums the metrics of the workers up, except for the peaks which are the highest of them.
+    pub fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::default();
+
+        for counters in self.workers.iter() {
+            snapshot.accepted += get(&counters.accepted);
+            snapshot.rejected += get(&counters.rejected);
+            snapshot.denied += get(&counters.denied);
+            snapshot.accept_cooldowns += get(&counters.accept_cooldowns);
+            snapshot.active += get(&counters.active);
+            snapshot.bytes_read += get(&counters.bytes_read);
+            snapshot.bytes_written += get(&counters.bytes_written);
+            snapshot.errors += get(&counters.errors);
+            snapshot.throttled += get(&counters.throttled);
+            snapshot.paused += get(&counters.paused);
+            snapshot.broadcasts_dropped += get(&counters.broadcasts_dropped);
+            snapshot.slow_consumers += get(&counters.slow_consumers);
+            snapshot.buffers += get(&counters.buffers);
+            snapshot.buffers_in_use += get(&counters.buffers_in_use);
+            snapshot.wakeups += get(&counters.wakeups);
+            snapshot.submitted += get(&counters.submitted);
+            snapshot.completions += get(&counters.completions);
+            snapshot.overflows += get(&counters.overflows);
+            snapshot.dropped += get(&counters.dropped);
+            snapshot.sq_peak = snapshot.sq_peak.max(get(&counters.sq_peak));
+            snapshot.cq_peak = snapshot.cq_peak.max(get(&counters.cq_peak));
+        }
+
+        snapshot
+    }
+}
+
+/// Metrics of a worker, updated with relaxed ordering as they're only read for reporting.
+#[derive(Debug, Default)]
+pub struct Counters {
+    /// Connections served since the start.
+    pub accepted: AtomicU64,
+    /// Connections closed right away as the worker is full or middleware refuses them.
+    pub rejected: AtomicU64,
+    /// Connections closed right away as their peers aren't allowed.
+    pub denied: AtomicU64,
+    /// Times accepting has been paused as connections arrive faster than allowed.
+    pub accept_cooldowns: AtomicU64,
+    /// Connections being served; a gauge.
+    pub active: AtomicU64,
+    /// Sockets of the clients still there, which the soak check compares with the connections
+    /// being served rather than exported; a gauge.
+    pub sockets: AtomicU64,
+    pub bytes_read: AtomicU64,
+    pub bytes_written: AtomicU64,
+    /// Connections which have failed rather than been closed by the client.
+    pub errors: AtomicU64,
+    /// Reads held off as the clients are over the rate limit.
+    pub throttled: AtomicU64,
+    /// Reads held off as what's been read already waits to be written to the other side.
+    pub paused: AtomicU64,
+    /// Broadcasts skipped for clients too slow to keep up.
+    pub broadcasts_dropped: AtomicU64,
+    /// Clients disconnected as too slow to keep up with broadcasts.
+    pub slow_consumers: AtomicU64,
+    /// Buffers the pool has; a gauge updated on maintenance.
+    pub buffers: AtomicU64,
+    /// Buffers held by connections and datagram sockets; a gauge updated on maintenance.
+    pub buffers_in_use: AtomicU64,
+    /// Times the event loop has waited for events.
+    pub wakeups: AtomicU64,
+    /// Entries the event loop has submitted when waiting, not counting those submitted when
+    /// the submission queue is full.
+    pub submitted: AtomicU64,
+    /// Completions the event loop has taken.
+    pub completions: AtomicU64,
+    /// Times the completion queue has overflowed, updated on maintenance.
+    pub overflows: AtomicU64,
+    /// Completions dropped by kernels which can't keep them when the queue overflows, updated
+    /// on maintenance.
+    pub dropped: AtomicU64,
+    /// Most entries submitted at once since the previous maintenance; a gauge.
+    pub sq_peak: AtomicU64,
+    /// Most completions taken at once since the previous maintenance; a gauge.
+    pub cq_peak: AtomicU64,
+}
+
+/// Adds to a counter or gauge of [`Counters`].
+pub fn add(metric: &AtomicU64, value: u64) {
+    metric.fetch_add(value, Ordering::Relaxed);
+}
+
+/// Subtracts from a gauge of [`Counters`].
+pub fn sub(metric: &AtomicU64, value: u64) {
+    metric.fetch_sub(value, Ordering::Relaxed);
+}
+
+pub fn set(metric: &AtomicU64, value: u64) {
+    metric.store(value, Ordering::Relaxed);
+}
+
+pub fn get(metric: &AtomicU64) -> u64 {
+    metric.load(Ordering::Relaxed)
+}
+
+/// Metrics of all the workers at a point in time.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Snapshot {
+    pub accepted: u64,
+    pub rejected: u64,
+    pub denied: u64,
+    pub accept_cooldowns: u64,
+    pub active: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub errors: u64,
+    pub throttled: u64,
+    pub paused: u64,
+    pub broadcasts_dropped: u64,
+    pub slow_consumers: u64,
+    pub buffers: u64,
+    pub buffers_in_use: u64,
+    pub wakeups: u64,
+    pub submitted: u64,
+    pub completions: u64,
+    pub overflows: u64,
+    pub dropped: u64,
+    pub sq_peak: u64,
+    pub cq_peak: u64,
+}
+
+/// Whether a metric only grows or goes up and down.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    Counter,
+    Gauge,
+}
+
+impl Snapshot {
+    /// Names, kinds and values of the metrics for exporters.
+    pub fn metrics(&self) -> [(&'static str, Kind, u64); 21] {
+        [
+            ("connections.accepted", Kind::Counter, self.accepted),
+            ("connections.rejected", Kind::Counter, self.rejected),
+            ("connections.denied", Kind::Counter, self.denied),
+            (
+                "connections.accept_cooldowns",
+                Kind::Counter,
+                self.accept_cooldowns,
+            ),
+            ("connections.active", Kind::Gauge, self.active),
+            ("bytes.read", Kind::Counter, self.bytes_read),
+            ("bytes.written", Kind::Counter, self.bytes_written),
+            ("errors", Kind::Counter, self.errors),
+            ("reads.throttled", Kind::Counter, self.throttled),
+            ("reads.paused", Kind::Counter, self.paused),
+            ("broadcasts.dropped", Kind::Counter, self.broadcasts_dropped),
+            ("connections.slow", Kind::Counter, self.slow_consumers),
+            ("buffers.total", Kind::Gauge, self.buffers),
+            ("buffers.in_use", Kind::Gauge, self.buffers_in_use),
+            ("ring.wakeups", Kind::Counter, self.wakeups),
+            ("ring.submitted", Kind::Counter, self.submitted),
+            ("ring.completions", Kind::Counter, self.completions),
+            ("ring.overflows", Kind::Counter, self.overflows),
+            ("ring.dropped", Kind::Counter, self.dropped),
+            ("ring.sq_peak", Kind::Gauge, self.sq_peak),
+            ("ring.cq_peak", Kind::Gauge, self.cq_peak),
+        ]
+    }
+
+    /// Averages per wakeup since the `last` snapshot: entries submitted and completions taken.
+    pub fn per_wakeup(&self, last: &Snapshot) -> (f64, f64) {
+        let wakeups = self.wakeups.saturating_sub(last.wakeups).max(1) as f64;
+        let submitted = self.submitted.saturating_sub(last.submitted) as f64;
+        let completions = self.completions.saturating_sub(last.completions) as f64;
+        (submitted / wakeups, completions / wakeups)
+    }
+}
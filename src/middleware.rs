@@ -0,0 +1,93 @@
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+
+/// Hooks into the life of TCP connections to layer filtering, logging, metrics or shaping over
+/// any handler. The middleware given to the [`ServerBuilder`](crate::ServerBuilder) is shared by
+/// the workers and runs in the order it's added; all the hooks do nothing by default.
+///
+/// The hooks run on the event loop so they're not to block.
+pub trait Middleware: Send + Sync {
+    /// Decides whether to serve the connection from the `peer` just accepted before anything is
+    /// set up for it. It's closed right away unless every middleware accepts it.
+    fn accept(&self, _peer: Option<SocketAddr>) -> bool {
+        true
+    }
+
+    /// Runs once the accepted connection is set up.
+    fn connected(&self, _conn: &ConnectionInfo) {}
+
+    /// Runs before each read from the client, which is delayed by the returned duration if
+    /// any. An error disconnects the client.
+    fn before_read(&self, _conn: &ConnectionInfo) -> Result<Option<Duration>> {
+        Ok(None)
+    }
+
+    /// Runs before each write of the `data` to the client like [`Middleware::before_read`].
+    /// Broadcasts to the client from its peers don't go through it.
+    fn before_write(&self, _conn: &ConnectionInfo, _data: &[u8]) -> Result<Option<Duration>> {
+        Ok(None)
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionInfo {
+    /// Unique among the connections of the worker at the same time.
+    pub id: u32,
+    pub worker_id: usize,
+    /// Unknown if the peer has disconnected by the time it's asked for.
+    pub peer: Option<SocketAddr>,
+    /// Of the process which has connected to a UNIX socket.
+    pub credentials: Option<Credentials>,
+}
+
+/// Who the process at the other end of a UNIX socket was when it connected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Credentials {
+    pub pid: u32,
+    pub uid: u32,
+    pub gid: u32,
+}
+
+/// Middleware of a server in order.
+pub type Chain = Rc<[Arc<dyn Middleware>]>;
+
+/// The middleware chain bound to a connection.
+pub struct Hooks {
+    chain: Chain,
+    info: ConnectionInfo,
+}
+
+impl Hooks {
+    pub fn new(chain: Chain, info: ConnectionInfo) -> Self {
+        Self { chain, info }
+    }
+
+    pub fn connected(&self) {
+        for middleware in self.chain.iter() {
+            middleware.connected(&self.info);
+        }
+    }
+
+    /// The longest of the delays asked for.
+    pub fn before_read(&self) -> Result<Option<Duration>> {
+        self.chain.iter().try_fold(None, |delay, middleware| {
+            Ok(delay.max(middleware.before_read(&self.info)?))
+        })
+    }
+
+    /// The longest of the delays asked for.
+    pub fn before_write(&self, data: &[u8]) -> Result<Option<Duration>> {
+        self.chain.iter().try_fold(None, |delay, middleware| {
+            Ok(delay.max(middleware.before_write(&self.info, data)?))
+        })
+    }
+}
+
+/// Whether every middleware of the `chain` accepts the connection from the `peer`.
+pub fn accept(chain: &Chain, peer: Option<SocketAddr>) -> bool {
+    chain.iter().all(|middleware| middleware.accept(peer))
+}
@@ -0,0 +1,397 @@
+//! A backend leaving the operations for tests to complete instead of running them, along with
+//! an executor driving a future over it, so that the futures doing I/O are tested step by step
+//! without a kernel: a test takes the operations submitted so far and completes each whenever
+//! it chooses with whatever result, e.g. a short read or write, an error or an expired timeout.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::future::Future;
+use std::os::fd::{BorrowedFd, RawFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use io_uring::cqueue::Entry as Cqe;
+use io_uring::opcode::{AsyncCancel, LinkTimeout, ProvideBuffers, TimeoutRemove, Writev};
+use io_uring::squeue::{Entry as Sqe, Flags};
+use io_uring::types::Timespec;
+
+use crate::allocator::HeapAllocator;
+use crate::backend::{
+    Backend, KernelTimespec, RawCqe, RawSqe, IORING_CQE_BUFFER_SHIFT, IORING_CQE_F_BUFFER,
+};
+use crate::buffer::{BufferPool, BufferRing};
+use crate::client;
+use crate::common::{Operations, Route};
+use crate::config::BufferClass;
+use crate::io::Io;
+
+/// The buffer group reads pick from.
+const READ_BUFFER_GROUP: u16 = 0;
+
+/// Keeps the submitted operations until the test completes them. Buffers are provided and
+/// operations cancelled right away as the kernel would.
+#[derive(Default)]
+pub struct Mock {
+    /// Entries pushed since the last submission.
+    queued: Vec<RawSqe>,
+    /// Operations submitted and not completed yet, in the order of submission.
+    submitted: Vec<Op>,
+    completed: Vec<RawCqe>,
+    /// Provided buffers by their groups.
+    groups: HashMap<u16, Vec<ProvidedBuffer>>,
+    /// Buffers registered for fixed operations by their indexes.
+    registered: Vec<libc::iovec>,
+}
+
+/// An operation waiting for the test to complete it.
+#[derive(Clone, Copy, Debug)]
+pub struct Op {
+    pub sqe: RawSqe,
+    /// The linked timeout if any.
+    pub timeout: Option<RawSqe>,
+}
+
+struct ProvidedBuffer {
+    addr: u64,
+    len: u32,
+    bid: u16,
+}
+
+impl Op {
+    /// The data of a write or a send, of all the buffers of a vectored write. The future
+    /// awaiting it keeps the data alive until the operation completes.
+    pub fn data(&self) -> Vec<u8> {
+        if self.sqe.opcode == Writev::CODE {
+            let iovecs = self.sqe.addr as *const libc::iovec;
+            let iovecs = unsafe { std::slice::from_raw_parts(iovecs, self.sqe.len as usize) };
+
+            return iovecs
+                .iter()
+                .flat_map(|iovec| unsafe {
+                    std::slice::from_raw_parts(iovec.iov_base as *const u8, iovec.iov_len)
+                })
+                .copied()
+                .collect();
+        }
+
+        let data = self.sqe.addr as *const u8;
+        unsafe { std::slice::from_raw_parts(data, self.sqe.len as usize) }.to_vec()
+    }
+
+    /// The duration of a timeout.
+    pub fn duration(&self) -> Duration {
+        timespec_duration(&self.sqe)
+    }
+}
+
+impl Mock {
+    fn submit(&mut self) -> usize {
+        let queued = std::mem::take(&mut self.queued);
+        let count = queued.len();
+        let mut sqes = queued.into_iter().peekable();
+
+        while let Some(sqe) = sqes.next() {
+            match sqe.opcode {
+                ProvideBuffers::CODE => {
+                    let buffers = self.groups.entry(sqe.buf_index).or_default();
+
+                    // The fd is the number of buffers and the offset is the id of the first one.
+                    for i in 0..sqe.fd as u64 {
+                        buffers.push(ProvidedBuffer {
+                            addr: sqe.addr + i * sqe.len as u64,
+                            len: sqe.len,
+                            bid: (sqe.off + i) as u16,
+                        });
+                    }
+
+                    self.push_completion(sqe.user_data, 0, 0);
+                }
+                AsyncCancel::CODE => self.cancel(&sqe, false),
+                TimeoutRemove::CODE => self.cancel(&sqe, true),
+                _ => {
+                    let linked = sqe.flags & Flags::IO_LINK.bits() != 0;
+
+                    let timeout = match linked {
+                        true => sqes.next_if(|next| next.opcode == LinkTimeout::CODE),
+                        false => None,
+                    };
+
+                    self.submitted.push(Op { sqe, timeout });
+                }
+            }
+        }
+
+        count
+    }
+
+    /// Completes the first operation with the `user_data` the cancellation targets with
+    /// `ECANCELED`, only a timeout if `timeouts` is set.
+    fn cancel(&mut self, sqe: &RawSqe, timeouts: bool) {
+        let position = self.submitted.iter().position(|op| {
+            op.sqe.user_data == sqe.addr
+                && (!timeouts || op.sqe.opcode == io_uring::opcode::Timeout::CODE)
+        });
+
+        let res = match position {
+            Some(position) => {
+                let op = self.submitted.remove(position);
+                self.push_completion(op.sqe.user_data, -libc::ECANCELED, 0);
+                0
+            }
+            None => -libc::ENOENT,
+        };
+
+        self.push_completion(sqe.user_data, res, 0);
+    }
+
+    fn push_completion(&mut self, user_data: u64, res: i32, flags: u32) {
+        self.completed.push(RawCqe {
+            user_data,
+            res,
+            flags,
+        });
+    }
+
+    /// Takes the first submitted operation with the `opcode` for the test to complete.
+    pub fn take(&mut self, opcode: u8) -> Op {
+        let Some(position) = self.submitted.iter().position(|op| op.sqe.opcode == opcode) else {
+            let submitted = self
+                .submitted
+                .iter()
+                .map(|op| op.sqe.opcode)
+                .collect::<Vec<_>>();
+
+            panic!("No operation {opcode} submitted, only {submitted:?}");
+        };
+
+        self.submitted.remove(position)
+    }
+
+    /// Number of operations submitted and not taken yet.
+    pub fn submitted(&self) -> usize {
+        self.submitted.len()
+    }
+
+    /// Completes the operation with the result.
+    pub fn complete(&mut self, op: &Op, res: i32) {
+        self.push_completion(op.sqe.user_data, res, 0);
+    }
+
+    /// Completes a read selecting a buffer with the `data` put into a provided buffer, or with
+    /// `ENOBUFS` if there's none. The end of the stream is a completion with 0 instead.
+    pub fn receive(&mut self, op: &Op, data: &[u8]) {
+        let buffer = self.groups.get_mut(&op.sqe.buf_index).and_then(Vec::pop);
+
+        let Some(buffer) = buffer else {
+            return self.complete(op, -libc::ENOBUFS);
+        };
+
+        assert!(
+            data.len() <= buffer.len as usize,
+            "Data larger than the buffer"
+        );
+
+        let addr = buffer.addr as *mut u8;
+        unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), addr, data.len()) };
+
+        let flags = IORING_CQE_F_BUFFER | (buffer.bid as u32) << IORING_CQE_BUFFER_SHIFT;
+        self.push_completion(op.sqe.user_data, data.len() as i32, flags);
+    }
+
+    /// The buffers registered for fixed operations by their indexes, null where there are none.
+    pub fn registered(&self) -> &[libc::iovec] {
+        &self.registered
+    }
+
+    /// Completes the operation as its linked timeout expiring does.
+    pub fn expire(&mut self, op: &Op) {
+        let timeout = op.timeout.expect("No linked timeout");
+        self.push_completion(op.sqe.user_data, -libc::ECANCELED, 0);
+        self.push_completion(timeout.user_data, -libc::ETIME, 0);
+    }
+}
+
+impl Backend for Mock {
+    unsafe fn push(&mut self, sqes: &[Sqe]) -> std::io::Result<()> {
+        let sqes = sqes
+            .iter()
+            .map(|sqe| unsafe { std::mem::transmute_copy::<Sqe, RawSqe>(sqe) });
+
+        self.queued.extend(sqes);
+        Ok(())
+    }
+
+    /// Never waits as only the test completes the operations.
+    fn submit_and_wait(&mut self, _: usize) -> std::io::Result<usize> {
+        Ok(self.submit())
+    }
+
+    fn submit_with_timeout(&mut self, _: usize, _: &Timespec) -> std::io::Result<usize> {
+        Ok(self.submit())
+    }
+
+    fn queued(&mut self) -> usize {
+        self.queued.len()
+    }
+
+    fn is_ready(&mut self) -> bool {
+        !self.completed.is_empty()
+    }
+
+    fn complete(&mut self, cqes: &mut Vec<Cqe>) -> std::io::Result<bool> {
+        let completed = self.completed.drain(..);
+        cqes.extend(completed.map(|cqe| unsafe { std::mem::transmute::<RawCqe, Cqe>(cqe) }));
+        Ok(false)
+    }
+
+    fn overflows(&self) -> u64 {
+        0
+    }
+
+    fn dropped(&mut self) -> u32 {
+        0
+    }
+
+    fn ring_fd(&self) -> Option<BorrowedFd<'_>> {
+        None
+    }
+
+    unsafe fn register_buf_ring(&mut self, _: u64, _: u16, _: u16) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    fn unregister_buf_ring(&mut self, _: u16) -> std::io::Result<()> {
+        Err(std::io::ErrorKind::Unsupported.into())
+    }
+
+    unsafe fn register_buffers(&mut self, iovecs: &[libc::iovec]) -> std::io::Result<()> {
+        self.registered = iovecs.to_vec();
+        Ok(())
+    }
+
+    unsafe fn update_buffers(
+        &mut self,
+        offset: u32,
+        iovecs: &[libc::iovec],
+    ) -> std::io::Result<()> {
+        let offset = offset as usize;
+
+        self.registered
+            .get_mut(offset..(offset + iovecs.len()))
+            .ok_or(std::io::Error::from_raw_os_error(libc::EINVAL))?
+            .copy_from_slice(iovecs);
+
+        Ok(())
+    }
+
+    fn register_files(&mut self, _: &[RawFd]) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Runs futures over a [`Mock`] the way the server does over a ring: delivers completions to
+/// the operations awaiting them, provides the free buffers again, cancels the abandoned
+/// operations and takes back the buffers of the stale completions.
+pub struct Reactor {
+    mock: Rc<RefCell<Mock>>,
+    operations: Operations,
+    pool: BufferPool,
+    buffers: BufferRing,
+}
+
+impl Reactor {
+    /// With `count` buffers of `size` bytes to read into.
+    pub fn new(size: u32, count: u16) -> Self {
+        let classes = [BufferClass {
+            size,
+            count,
+            max_count: None,
+        }];
+
+        let pool = BufferPool::new(&classes, Box::new(HeapAllocator)).unwrap();
+        let mock = Rc::new(RefCell::new(Mock::default()));
+        let buffers = BufferRing::provide(mock.clone(), &pool, READ_BUFFER_GROUP);
+
+        Self {
+            mock,
+            operations: Operations::default(),
+            pool,
+            buffers,
+        }
+    }
+
+    pub fn io(&self) -> Io {
+        Io::new(self.mock.clone(), self.operations.clone())
+    }
+
+    pub fn buffers(&self) -> BufferRing {
+        self.buffers.clone()
+    }
+
+    pub fn mock(&self) -> std::cell::RefMut<'_, Mock> {
+        self.mock.borrow_mut()
+    }
+
+    /// Polls the future and delivers the completions until it's done or waits for operations
+    /// the test is yet to complete.
+    pub fn run<F: Future>(&self, mut fut: Pin<&mut F>) -> Poll<F::Output> {
+        let mut cx = Context::from_waker(futures::task::noop_waker_ref());
+        let mut cqes = Vec::new();
+
+        loop {
+            let poll = fut.as_mut().poll(&mut cx);
+            self.maintain();
+
+            if poll.is_ready() {
+                // The operations the future has left in flight are cancelled.
+                self.mock.borrow_mut().submit();
+                return poll;
+            }
+
+            {
+                let mut mock = self.mock.borrow_mut();
+                mock.submit();
+                Backend::complete(&mut *mock, &mut cqes).unwrap();
+            }
+
+            if cqes.is_empty() {
+                return Poll::Pending;
+            }
+
+            for cqe in cqes.drain(..) {
+                self.dispatch(cqe);
+            }
+        }
+    }
+
+    fn dispatch(&self, cqe: Cqe) {
+        match Route::try_from(cqe.user_data()).unwrap() {
+            Route::Operation(generation, key) => self.operations.complete(key, generation, cqe),
+            Route::ProvideBuffer(_) | Route::Cancel | Route::Timeout => (),
+            route => panic!("Unexpected completion of {route:?}"),
+        }
+    }
+
+    fn maintain(&self) {
+        for cqe in self.operations.take_stale() {
+            client::take_buffer(&self.buffers, &cqe).unwrap();
+        }
+
+        for route in self.operations.take_abandoned() {
+            let sqe = AsyncCancel::new(route)
+                .build()
+                .user_data(Route::Cancel.into());
+
+            unsafe { self.mock.borrow_mut().push(&[sqe]) }.unwrap();
+        }
+
+        self.buffers.replenish(&self.pool);
+    }
+}
+
+fn timespec_duration(sqe: &RawSqe) -> Duration {
+    let timespec = unsafe { &*(sqe.addr as *const KernelTimespec) };
+    Duration::new(timespec.tv_sec as u64, timespec.tv_nsec as u32)
+}
@@ -0,0 +1,226 @@
+//! Data waiting to be written to a socket while whoever produces it goes on, bounded by a high
+//! watermark so that a receiver slower than the sender doesn't pile the data up in memory.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::future;
+use std::task::{Poll, Waker};
+
+/// A queue between a producer and the writer of a socket, with each item counting as many bytes
+/// as it holds. Producers check [`Outbox::is_full`] and wait for room, give up on the item or
+/// abort the outbox altogether then.
+pub struct Outbox<T> {
+    queue: RefCell<VecDeque<(T, usize)>>,
+    /// Bytes pushed and not written yet, including those being written.
+    queued: Cell<usize>,
+    high_watermark: usize,
+    closed: Cell<bool>,
+    aborted: Cell<bool>,
+    /// Waiting for an item to write or for the outbox to be aborted.
+    writer: Cell<Option<Waker>>,
+    /// Waiting for room.
+    producer: Cell<Option<Waker>>,
+}
+
+impl<T> Outbox<T> {
+    pub fn new(high_watermark: usize) -> Self {
+        Self {
+            queue: RefCell::default(),
+            queued: Cell::new(0),
+            high_watermark,
+            closed: Cell::new(false),
+            aborted: Cell::new(false),
+            writer: Cell::new(None),
+            producer: Cell::new(None),
+        }
+    }
+
+    /// Whether the bytes not written yet have reached the high watermark. Never when nothing is
+    /// queued, so an item larger than the watermark still goes through on its own.
+    pub fn is_full(&self) -> bool {
+        let queued = self.queued.get();
+        queued > 0 && queued >= self.high_watermark
+    }
+
+    /// Queues the `item` of `len` bytes for the writer, however full the outbox is; dropped if
+    /// the outbox is closed.
+    pub fn push(&self, item: T, len: usize) {
+        if self.closed.get() {
+            return;
+        }
+
+        self.queue.borrow_mut().push_back((item, len));
+        self.queued.set(self.queued.get() + len);
+        wake(&self.writer);
+    }
+
+    /// Takes the next item to write, waiting for one, or `None` once the outbox is closed and
+    /// everything queued before has been taken.
+    pub async fn pop(&self) -> Option<T> {
+        future::poll_fn(|cx| {
+            if let Some((item, _)) = self.queue.borrow_mut().pop_front() {
+                return Poll::Ready(Some(item));
+            }
+
+            if self.closed.get() {
+                return Poll::Ready(None);
+            }
+
+            self.writer.set(Some(cx.waker().clone()));
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Takes up to `max` items queued, waiting for at least one, for the writer to write them
+    /// at once; none once the outbox is closed and everything queued before has been taken.
+    pub async fn pop_many(&self, max: usize) -> Vec<T> {
+        let Some(first) = self.pop().await else {
+            return Vec::new();
+        };
+
+        let mut queue = self.queue.borrow_mut();
+        let rest = queue.len().min(max.saturating_sub(1));
+        let items = queue.drain(..rest).map(|(item, _)| item);
+        std::iter::once(first).chain(items).collect()
+    }
+
+    /// Counts `len` bytes of a taken item as written, making room for the producer.
+    pub fn written(&self, len: usize) {
+        self.queued.set(self.queued.get().saturating_sub(len));
+
+        if !self.is_full() {
+            wake(&self.producer);
+        }
+    }
+
+    /// Waits until the outbox is below the high watermark.
+    pub async fn room(&self) {
+        future::poll_fn(|cx| {
+            if !self.is_full() {
+                return Poll::Ready(());
+            }
+
+            self.producer.set(Some(cx.waker().clone()));
+            Poll::Pending
+        })
+        .await
+    }
+
+    /// Takes no more items, leaving those queued for the writer to finish with.
+    pub fn close(&self) {
+        self.closed.set(true);
+        wake(&self.writer);
+    }
+
+    /// Discards the items queued and closes the outbox, so that the writer stops right away.
+    pub fn abort(&self) {
+        let discarded = self
+            .queue
+            .take()
+            .into_iter()
+            .map(|(_, len)| len)
+            .sum::<usize>();
+        self.queued.set(self.queued.get() - discarded);
+        self.aborted.set(true);
+        self.close();
+    }
+
+    /// Waits until the outbox is aborted, for the writer to give up on the item it's writing.
+    pub async fn aborted(&self) {
+        future::poll_fn(|cx| {
+            if self.aborted.get() {
+                return Poll::Ready(());
+            }
+
+            self.writer.set(Some(cx.waker().clone()));
+            Poll::Pending
+        })
+        .await
+    }
+}
+
+fn wake(waker: &Cell<Option<Waker>>) {
+    if let Some(waker) = waker.take() {
+        waker.wake();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::pin::{pin, Pin};
+    use std::task::Context;
+
+    use super::*;
+
+    fn poll<F: Future>(fut: Pin<&mut F>) -> Poll<F::Output> {
+        fut.poll(&mut Context::from_waker(futures::task::noop_waker_ref()))
+    }
+
+    #[test]
+    fn producers_wait_for_room() {
+        let outbox = Outbox::new(10);
+        outbox.push("first", 6);
+        assert!(!outbox.is_full());
+        outbox.push("second", 6);
+        assert!(outbox.is_full());
+
+        let mut room = pin!(outbox.room());
+        assert!(poll(room.as_mut()).is_pending());
+
+        // Taken but not written yet still counts.
+        assert_eq!(poll(pin!(outbox.pop())), Poll::Ready(Some("first")));
+        assert!(poll(room.as_mut()).is_pending());
+
+        outbox.written(6);
+        assert!(poll(room).is_ready());
+    }
+
+    #[test]
+    fn writers_take_what_has_piled_up() {
+        let outbox = Outbox::new(10);
+
+        for item in ["a", "b", "c"] {
+            outbox.push(item, 1);
+        }
+
+        assert_eq!(poll(pin!(outbox.pop_many(2))), Poll::Ready(vec!["a", "b"]));
+        assert_eq!(poll(pin!(outbox.pop_many(2))), Poll::Ready(vec!["c"]));
+        assert!(poll(pin!(outbox.pop_many(2))).is_pending());
+
+        outbox.close();
+        assert_eq!(poll(pin!(outbox.pop_many(2))), Poll::Ready(Vec::new()));
+    }
+
+    #[test]
+    fn large_items_go_through_alone() {
+        let outbox = Outbox::new(0);
+        assert!(!outbox.is_full());
+        outbox.push("large", 100);
+        assert!(outbox.is_full());
+    }
+
+    #[test]
+    fn closing_lets_the_writer_finish() {
+        let outbox = Outbox::new(10);
+        let mut pop = pin!(outbox.pop());
+        assert!(poll(pop.as_mut()).is_pending());
+
+        outbox.push("last", 4);
+        outbox.close();
+        outbox.push("too late", 8);
+        assert_eq!(poll(pop), Poll::Ready(Some("last")));
+        assert_eq!(poll(pin!(outbox.pop())), Poll::Ready(None));
+
+        let outbox = Outbox::new(10);
+        let mut aborted = pin!(outbox.aborted());
+        assert!(poll(aborted.as_mut()).is_pending());
+
+        outbox.push("discarded", 12);
+        outbox.abort();
+        assert!(!outbox.is_full());
+        assert!(poll(aborted).is_ready());
+        assert_eq!(poll(pin!(outbox.pop())), Poll::Ready(None));
+    }
+}
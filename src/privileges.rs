@@ -0,0 +1,161 @@
+//! Switching to an unprivileged account once the server holds what takes root, such as
+//! listeners on ports below 1024. The rings and their registered buffers stay as they are.
+
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+
+/// The account to switch to.
+#[derive(Debug)]
+pub struct Privileges {
+    /// With the name of the user to take its supplementary groups of, if it has an entry.
+    uid: Option<(libc::uid_t, Option<CString>)>,
+    gid: libc::gid_t,
+}
+
+impl Privileges {
+    /// Looks the `user` and the `group` up by name or id, defaulting the group to the primary
+    /// one of the user. None if neither is given.
+    pub fn resolve(user: Option<&str>, group: Option<&str>) -> Result<Option<Self>> {
+        let user = user
+            .map(|user| lookup_user(user).with_context(|| format!("User {user}")))
+            .transpose()?;
+
+        let group = group
+            .map(|group| lookup_group(group).with_context(|| format!("Group {group}")))
+            .transpose()?;
+
+        Ok(match (user, group) {
+            (None, None) => None,
+            (Some((uid, name, primary)), group) => Some(Self {
+                gid: match (group, primary) {
+                    (Some(gid), _) | (None, Some(gid)) => gid,
+                    (None, None) => bail!("No primary group of user {uid}, give the group"),
+                },
+                uid: Some((uid, name)),
+            }),
+            (None, Some(gid)) => Some(Self { uid: None, gid }),
+        })
+    }
+
+    /// Gives the file to the account so that it can still use and remove it.
+    pub fn chown(&self, path: &Path) -> Result<()> {
+        std::os::unix::fs::chown(path, self.uid.as_ref().map(|&(uid, _)| uid), Some(self.gid))
+            .with_context(|| format!("Change owner of {}", path.display()))
+    }
+
+    /// Switches every thread of the process: the supplementary groups first, then the group
+    /// and the user last as nothing can be switched back after it.
+    pub fn apply(&self) -> Result<()> {
+        let groups = match self.uid {
+            Some((_, Some(ref name))) => unsafe { libc::initgroups(name.as_ptr(), self.gid) },
+            _ => unsafe { libc::setgroups(1, &self.gid) },
+        };
+
+        check(groups).context("Set supplementary groups")?;
+        check(unsafe { libc::setgid(self.gid) }).context("Set group")?;
+
+        if let Some((uid, _)) = self.uid {
+            check(unsafe { libc::setuid(uid) }).context("Set user")?;
+
+            if uid != 0 && unsafe { libc::setuid(0) } == 0 {
+                bail!("Root can still be regained");
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for Privileges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.uid {
+            Some((uid, _)) => write!(f, "uid {uid} gid {}", self.gid),
+            None => write!(f, "gid {}", self.gid),
+        }
+    }
+}
+
+/// The id, name and primary group of the user, the last two missing if it's an id without an
+/// entry.
+fn lookup_user(user: &str) -> Result<(libc::uid_t, Option<CString>, Option<libc::gid_t>)> {
+    let name = CString::new(user).context("Invalid name")?;
+    let mut entry: libc::passwd = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0; 4096];
+    let mut found = std::ptr::null_mut();
+
+    let mut result = unsafe {
+        libc::getpwnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+
+    if found.is_null() {
+        if let Ok(uid) = user.parse() {
+            result = unsafe {
+                libc::getpwuid_r(
+                    uid,
+                    &mut entry,
+                    buffer.as_mut_ptr(),
+                    buffer.len(),
+                    &mut found,
+                )
+            };
+
+            if found.is_null() {
+                return Ok((uid, None, None));
+            }
+        }
+    }
+
+    match found.is_null() {
+        true if result != 0 => Err(std::io::Error::from_raw_os_error(result)).context("Look up"),
+        true => bail!("No such user"),
+        false => Ok((
+            entry.pw_uid,
+            Some(unsafe { CStr::from_ptr(entry.pw_name) }.to_owned()),
+            Some(entry.pw_gid),
+        )),
+    }
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t> {
+    let name = CString::new(group).context("Invalid name")?;
+    let mut entry: libc::group = unsafe { std::mem::zeroed() };
+    let mut buffer = vec![0; 4096];
+    let mut found = std::ptr::null_mut();
+
+    let result = unsafe {
+        libc::getgrnam_r(
+            name.as_ptr(),
+            &mut entry,
+            buffer.as_mut_ptr(),
+            buffer.len(),
+            &mut found,
+        )
+    };
+
+    match found.is_null() {
+        false => Ok(entry.gr_gid),
+        true => match group.parse() {
+            Ok(gid) => Ok(gid),
+            Err(_) if result != 0 => {
+                Err(std::io::Error::from_raw_os_error(result)).context("Look up")
+            }
+            Err(_) => bail!("No such group"),
+        },
+    }
+}
+
+fn check(result: libc::c_int) -> std::io::Result<()> {
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
@@ -0,0 +1,142 @@
+use std::alloc::{self, Layout};
+
+use anyhow::{Context as _, Result};
+use io_uring::opcode::{
+    Accept, AsyncCancel, Close, Connect, LinkTimeout, MsgRingData, ProvideBuffers, Read, ReadFixed,
+    Recv, RecvMsg, SendMsg, SendZc, Shutdown, Socket, Splice, Timeout, TimeoutRemove, Write,
+    WriteFixed, Writev,
+};
+use io_uring::types::BufRingEntry;
+use io_uring::{IoUring, Probe};
+
+/// Operations the server can't do without.
+const REQUIRED: &[(u8, &str)] = &[
+    (Accept::CODE, "accept"),
+    (AsyncCancel::CODE, "cancel"),
+    (Close::CODE, "close"),
+    (Connect::CODE, "connect"),
+    (LinkTimeout::CODE, "link timeout"),
+    (Read::CODE, "read"),
+    (ReadFixed::CODE, "fixed read"),
+    (Recv::CODE, "receive"),
+    (RecvMsg::CODE, "receive message"),
+    (SendMsg::CODE, "send message"),
+    (Shutdown::CODE, "shutdown"),
+    (Timeout::CODE, "timeout"),
+    (TimeoutRemove::CODE, "timeout remove"),
+    (Write::CODE, "write"),
+    (WriteFixed::CODE, "fixed write"),
+    (Writev::CODE, "vectored write"),
+];
+
+/// Buffer group used to check whether the kernel supports buffer rings.
+const PROBE_BUFFER_GROUP: u16 = u16::MAX;
+
+/// Optional io_uring features the kernel supports. The server falls back to what's available
+/// instead of failing on kernels lacking any of them.
+#[derive(Clone, Copy, Debug)]
+pub struct Features {
+    /// Multishot accepts, otherwise accepts are submitted one at a time.
+    pub accept_multi: bool,
+    /// Multishot receives, otherwise `multishot_recv` is ignored.
+    pub recv_multi: bool,
+    /// Zero-copy sends, otherwise `zerocopy_threshold` is ignored.
+    pub send_zc: bool,
+    /// Provided buffer rings, otherwise buffers are provided with an operation each.
+    pub buf_ring: bool,
+    /// Messages between rings, otherwise `handoff` is ignored.
+    pub msg_ring: bool,
+    /// Splicing between sockets and pipes, otherwise `splice_echo` is ignored.
+    pub splice: bool,
+}
+
+impl Features {
+    /// Detects the features of the kernel the `ring` runs on and reports the missing ones.
+    pub fn probe(ring: &IoUring) -> Result<Self> {
+        let mut probe = Probe::new();
+
+        ring.submitter()
+            .register_probe(&mut probe)
+            .context("Probe io_uring operations")?;
+
+        for &(code, name) in REQUIRED {
+            if !probe.is_supported(code) {
+                bail!("The kernel doesn't support io_uring {name} operations");
+            }
+        }
+
+        // Multishot flavours of operations have no opcodes of their own, so they're detected by
+        // opcodes which have come with the same kernel versions: multishot accepts and sockets
+        // with 5.19, multishot receives and zero-copy sends with 6.0.
+        let features = Self {
+            accept_multi: probe.is_supported(Socket::CODE),
+            recv_multi: probe.is_supported(SendZc::CODE),
+            send_zc: probe.is_supported(SendZc::CODE),
+            buf_ring: supports_buf_ring(ring)?,
+            msg_ring: probe.is_supported(MsgRingData::CODE),
+            splice: probe.is_supported(Splice::CODE),
+        };
+
+        if !features.buf_ring && !probe.is_supported(ProvideBuffers::CODE) {
+            bail!("The kernel doesn't support io_uring provided buffers");
+        }
+
+        let fallbacks = [
+            (
+                features.accept_multi,
+                "multishot accepts, accepting one at a time",
+            ),
+            (
+                features.recv_multi,
+                "multishot receives, ignoring multishot_recv",
+            ),
+            (
+                features.send_zc,
+                "zero-copy sends, ignoring zerocopy_threshold",
+            ),
+            (
+                features.buf_ring,
+                "buffer rings, providing buffers one by one",
+            ),
+            (
+                features.msg_ring,
+                "messages between rings, ignoring handoff",
+            ),
+            (features.splice, "splice, ignoring splice_echo"),
+        ];
+
+        for (supported, fallback) in fallbacks {
+            if !supported {
+                error!("The kernel doesn't support {fallback}");
+            }
+        }
+
+        Ok(features)
+    }
+}
+
+/// Checks by registering a single entry buffer ring and unregistering it right away.
+fn supports_buf_ring(ring: &IoUring) -> Result<bool> {
+    let layout = Layout::from_size_align(std::mem::size_of::<BufRingEntry>(), 4096)
+        .context("Probe buffer ring layout")?;
+
+    let entries = unsafe { alloc::alloc_zeroed(layout) };
+
+    if entries.is_null() {
+        bail!("Allocate probe buffer ring");
+    }
+
+    let submitter = ring.submitter();
+    let result = unsafe { submitter.register_buf_ring(entries as u64, 1, PROBE_BUFFER_GROUP) };
+
+    if result.is_ok() {
+        if let Err(err) = submitter.unregister_buf_ring(PROBE_BUFFER_GROUP) {
+            // Leak the ring as the kernel may still touch it.
+            error!("Unregister probe buffer ring: {err}");
+            return Ok(true);
+        }
+    }
+
+    unsafe { alloc::dealloc(entries, layout) };
+    Ok(result.is_ok())
+}
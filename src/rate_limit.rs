@@ -0,0 +1,202 @@
+use std::cell::{Cell, RefCell};
+use std::num::NonZeroU64;
+use std::time::{Duration, Instant};
+
+/// Rates a client is limited to on average, with bursts of up to a second's worth.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RateLimit {
+    pub bytes_per_sec: Option<NonZeroU64>,
+    pub messages_per_sec: Option<NonZeroU64>,
+}
+
+/// Buckets of a client refilled at the rates of a [`RateLimit`], which may change while the
+/// client is served. Takes the time of each call, normally [`Instant::now`], for the refills.
+#[derive(Debug)]
+pub struct RateLimiter {
+    bytes: RefCell<Option<TokenBucket>>,
+    messages: RefCell<Option<TokenBucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(limit: RateLimit, now: Instant) -> Self {
+        let bucket = |rate| TokenBucket::new(rate, now);
+
+        Self {
+            bytes: RefCell::new(limit.bytes_per_sec.map(bucket)),
+            messages: RefCell::new(limit.messages_per_sec.map(bucket)),
+        }
+    }
+
+    pub fn is_limited(&self) -> bool {
+        self.bytes.borrow().is_some() || self.messages.borrow().is_some()
+    }
+
+    pub fn limit(&self) -> RateLimit {
+        RateLimit {
+            bytes_per_sec: self.bytes.borrow().as_ref().map(TokenBucket::rate),
+            messages_per_sec: self.messages.borrow().as_ref().map(TokenBucket::rate),
+        }
+    }
+
+    /// Switches to the rates of the `limit`, keeping the tokens of those which stay the same.
+    pub fn set_limit(&self, limit: RateLimit, now: Instant) {
+        for (bucket, rate) in [
+            (&self.bytes, limit.bytes_per_sec),
+            (&self.messages, limit.messages_per_sec),
+        ] {
+            let mut bucket = bucket.borrow_mut();
+
+            if bucket.as_ref().map(TokenBucket::rate) != rate {
+                *bucket = rate.map(|rate| TokenBucket::new(rate, now));
+            }
+        }
+    }
+
+    pub fn read(&self, len: usize, now: Instant) {
+        if let Some(ref bytes) = *self.bytes.borrow() {
+            bytes.take(len as f64, now);
+        }
+    }
+
+    pub fn message(&self, now: Instant) {
+        if let Some(ref messages) = *self.messages.borrow() {
+            messages.take(1.0, now);
+        }
+    }
+
+    /// How long to hold the next read off for the client to get back within its budget.
+    pub fn delay(&self, now: Instant) -> Option<Duration> {
+        let delay = |bucket: &Option<TokenBucket>| bucket.as_ref()?.delay(now);
+        let bytes = delay(&self.bytes.borrow());
+        let messages = delay(&self.messages.borrow());
+        bytes.max(messages)
+    }
+}
+
+/// Tokens refilled at a rate up to a second's worth, going into debt when taken beyond what's
+/// there, as reads can't be split to fit.
+#[derive(Debug)]
+pub struct TokenBucket {
+    /// Tokens per second.
+    rate: NonZeroU64,
+    tokens: Cell<f64>,
+    refilled: Cell<Instant>,
+}
+
+impl TokenBucket {
+    /// Starts full at `now`.
+    pub fn new(rate: NonZeroU64, now: Instant) -> Self {
+        Self {
+            rate,
+            tokens: Cell::new(rate.get() as f64),
+            refilled: Cell::new(now),
+        }
+    }
+
+    pub fn rate(&self) -> NonZeroU64 {
+        self.rate
+    }
+
+    pub fn take(&self, tokens: f64, now: Instant) {
+        self.refill(now);
+        self.tokens.set(self.tokens.get() - tokens);
+    }
+
+    /// Until the debt is paid off if there's one.
+    pub fn delay(&self, now: Instant) -> Option<Duration> {
+        self.refill(now);
+
+        match self.tokens.get() {
+            tokens if tokens < 0.0 => {
+                Some(Duration::from_secs_f64(-tokens / self.rate.get() as f64))
+            }
+            _ => None,
+        }
+    }
+
+    /// Adds the tokens for the time since the last refill, none if `now` is earlier.
+    fn refill(&self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.refilled.replace(now))
+            .as_secs_f64();
+        let rate = self.rate.get() as f64;
+        self.tokens
+            .set((self.tokens.get() + elapsed * rate).min(rate));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn delays_for_the_debt() {
+        let start = Instant::now();
+        let limiter = RateLimiter::new(
+            RateLimit {
+                bytes_per_sec: NonZeroU64::new(1000),
+                messages_per_sec: NonZeroU64::new(10),
+            },
+            start,
+        );
+
+        // A second's worth passes right away.
+        limiter.read(1000, start);
+        assert_eq!(limiter.delay(start), None);
+
+        limiter.read(500, start);
+        assert_eq!(limiter.delay(start), Some(Duration::from_millis(500)));
+
+        // Paid off over time.
+        let later = start + Duration::from_millis(200);
+        assert_eq!(limiter.delay(later), Some(Duration::from_millis(300)));
+
+        for _ in 0..30 {
+            limiter.message(later);
+        }
+
+        assert_eq!(limiter.delay(later), Some(Duration::from_secs(2)));
+
+        let paid_off = later + Duration::from_secs(2);
+        assert_eq!(limiter.delay(paid_off), None);
+    }
+
+    #[test]
+    fn changed_rates_start_over() {
+        let now = Instant::now();
+        let limiter = RateLimiter::new(
+            RateLimit {
+                bytes_per_sec: NonZeroU64::new(1000),
+                messages_per_sec: None,
+            },
+            now,
+        );
+
+        limiter.read(1500, now);
+        assert!(limiter.delay(now).is_some());
+
+        // The debt is kept while the rate stays the same.
+        limiter.set_limit(
+            RateLimit {
+                bytes_per_sec: NonZeroU64::new(1000),
+                messages_per_sec: NonZeroU64::new(10),
+            },
+            now,
+        );
+        assert!(limiter.delay(now).is_some());
+
+        limiter.set_limit(
+            RateLimit {
+                bytes_per_sec: NonZeroU64::new(2000),
+                messages_per_sec: NonZeroU64::new(10),
+            },
+            now,
+        );
+        assert_eq!(limiter.delay(now), None);
+
+        limiter.set_limit(RateLimit::default(), now);
+        assert!(!limiter.is_limited());
+        limiter.read(1_000_000, now);
+        assert_eq!(limiter.delay(now), None);
+    }
+}
@@ -0,0 +1,59 @@
+use std::os::fd::{AsRawFd, OwnedFd, RawFd};
+use std::sync::{Arc, Mutex, PoisonError};
+
+use anyhow::{Context as _, Result};
+
+use crate::admin::{Reply, Request};
+use crate::utils;
+
+/// Work for a server from outside of its event loop.
+#[derive(Clone)]
+pub enum Command {
+    /// Handle the signal as if the server has received it itself.
+    Signal(u32),
+    /// Answer the request of an admin session with a part of the reply.
+    Admin(Request, Arc<Reply>),
+}
+
+/// Sends commands to a server from other threads. The commands are queued and an eventfd the
+/// server keeps a read on is bumped, so that the event loop wakes up and runs them.
+#[derive(Clone)]
+pub struct Remote(Arc<Shared>);
+
+struct Shared {
+    eventfd: OwnedFd,
+    commands: Mutex<Vec<Command>>,
+}
+
+impl Remote {
+    pub fn new() -> Result<Self> {
+        let eventfd = utils::eventfd().context("Create eventfd")?;
+
+        Ok(Self(Arc::new(Shared {
+            eventfd,
+            commands: Mutex::new(Vec::new()),
+        })))
+    }
+
+    pub fn send(&self, command: Command) -> Result<()> {
+        self.commands().push(command);
+        utils::bump(&self.0.eventfd).context("Wake server up")
+    }
+
+    /// Takes the commands sent since the last call.
+    pub fn take(&self) -> Vec<Command> {
+        std::mem::take(&mut *self.commands())
+    }
+
+    /// The eventfd to read a `u64` from which tells that there are commands to take.
+    pub fn eventfd(&self) -> RawFd {
+        self.0.eventfd.as_raw_fd()
+    }
+
+    fn commands(&self) -> std::sync::MutexGuard<'_, Vec<Command>> {
+        self.0
+            .commands
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+}
@@ -0,0 +1,44 @@
+//! The file to respond to every message with instead of echoing, see
+//! [`crate::config::ServerConfig::response_file`].
+
+use std::fs::File;
+use std::io::Read as _;
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::rc::Rc;
+
+use anyhow::{Context as _, Result};
+
+use crate::backend::Backend;
+
+/// Index of the response file among the files registered with the ring.
+pub const RESPONSE_FILE_INDEX: u32 = 0;
+
+pub enum Response {
+    /// Spliced from the file registered with the ring, of this many bytes.
+    Spliced(u64),
+    /// Read into memory to be written from there where the backend can't splice, shared with
+    /// the writes which keep it until the kernel is done with them.
+    Copied(Rc<[u8]>),
+}
+
+impl Response {
+    /// Registers the file at `path` with the `ring` if it may `splice`, or reads it otherwise.
+    pub fn open(path: &Path, ring: &mut dyn Backend, splice: bool) -> Result<Self> {
+        let mut file = File::open(path).context("Open")?;
+
+        if !splice {
+            let mut contents = Vec::new();
+            file.read_to_end(&mut contents).context("Read")?;
+            return Ok(Self::Copied(contents.into()));
+        }
+
+        let len = file.metadata().context("Stat")?.len();
+
+        // The ring holds on to the file, so the descriptor is closed right away.
+        ring.register_files(&[file.as_raw_fd()])
+            .context("Register")?;
+
+        Ok(Self::Spliced(len))
+    }
+}
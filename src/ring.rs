@@ -0,0 +1,367 @@
+use std::ops::{Deref, DerefMut};
+use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+use std::sync::atomic::{self, Ordering};
+
+use io_uring::cqueue::Entry as Cqe;
+use io_uring::squeue::Entry as Sqe;
+use io_uring::types::Timespec;
+use io_uring::IoUring;
+
+use crate::backend::Backend;
+
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+const IORING_ENTER_SQ_WAKEUP: u32 = 1 << 1;
+const IORING_ENTER_EXT_ARG: u32 = 1 << 3;
+const IORING_ENTER_REGISTERED_RING: u32 = 1 << 4;
+
+const IORING_REGISTER_BUFFERS_UPDATE: libc::c_uint = 16;
+const IORING_REGISTER_RING_FDS: libc::c_uint = 20;
+const IORING_UNREGISTER_RING_FDS: libc::c_uint = 21;
+const IORING_REGISTER_NAPI: libc::c_uint = 27;
+
+#[repr(C)]
+struct RsrcUpdate {
+    offset: u32,
+    resv: u32,
+    data: u64,
+}
+
+#[repr(C)]
+struct RsrcUpdate2 {
+    offset: u32,
+    resv: u32,
+    data: u64,
+    tags: u64,
+    nr: u32,
+    resv2: u32,
+}
+
+#[repr(C)]
+struct Napi {
+    busy_poll_to: u32,
+    prefer_busy_poll: u8,
+    pad: [u8; 3],
+    resv: u64,
+}
+
+#[repr(C)]
+struct GeteventsArg {
+    sigmask: u64,
+    sigmask_sz: u32,
+    pad: u32,
+    ts: u64,
+}
+
+/// An io_uring instance entering the kernel by its registered ring fd if the kernel supports
+/// that, which saves looking the fd up on every enter. Registered fds are per thread, so
+/// the ring must be used on the thread which has created it.
+pub struct Ring {
+    inner: IoUring,
+    registered_fd: Option<u32>,
+    /// Number of times the completion queue has overflowed.
+    overflows: u64,
+}
+
+impl Ring {
+    pub fn new(inner: IoUring) -> Self {
+        let registered_fd = register_ring_fd(&inner);
+        Self {
+            inner,
+            registered_fd,
+            overflows: 0,
+        }
+    }
+
+    /// Makes waiting for events busy-poll the NAPI contexts of the sockets for up to `timeout`
+    /// microseconds, preferring that over interrupts if `prefer` is set.
+    pub fn register_napi(&self, timeout: u32, prefer: bool) -> std::io::Result<()> {
+        let mut napi = Napi {
+            busy_poll_to: timeout,
+            prefer_busy_poll: prefer as u8,
+            pad: [0; 3],
+            resv: 0,
+        };
+
+        register(&self.inner, IORING_REGISTER_NAPI, &mut napi, 1)
+    }
+
+    /// Submits all the queued entries without waiting for completions. With DEFER_TASKRUN this
+    /// doesn't run the completion work as it doesn't get events, only waiting does.
+    fn submit(&mut self) -> std::io::Result<usize> {
+        self.submit_and_wait(0)
+    }
+
+    /// Mirrors the flags `io_uring` passes to enter; `None` means there's no need to enter since
+    /// the SQPOLL thread is awake and picks the entries up by itself.
+    fn enter_flags(&mut self, want: usize) -> Option<u32> {
+        let params = self.inner.params();
+        let (is_sqpoll, is_iopoll) = (params.is_setup_sqpoll(), params.is_setup_iopoll());
+        let submission = self.inner.submission();
+        let mut flags = 0;
+
+        if want > 0 || is_iopoll || submission.cq_overflow() {
+            flags |= IORING_ENTER_GETEVENTS;
+        }
+
+        if is_sqpoll {
+            atomic::fence(Ordering::SeqCst);
+
+            if submission.need_wakeup() {
+                flags |= IORING_ENTER_SQ_WAKEUP;
+            } else if want == 0 {
+                return None;
+            }
+        }
+
+        Some(flags)
+    }
+
+    fn enter(
+        &mut self,
+        want: usize,
+        mut flags: u32,
+        arg: *const libc::c_void,
+        size: usize,
+    ) -> std::io::Result<usize> {
+        let to_submit = self.inner.submission().len();
+
+        let fd = match self.registered_fd {
+            Some(index) => {
+                flags |= IORING_ENTER_REGISTERED_RING;
+                index as libc::c_int
+            }
+            None => self.inner.as_raw_fd(),
+        };
+
+        let result = unsafe {
+            libc::syscall(
+                libc::SYS_io_uring_enter,
+                fd,
+                to_submit as libc::c_uint,
+                want as libc::c_uint,
+                flags,
+                arg,
+                size,
+            )
+        };
+
+        match result {
+            result if result < 0 => Err(std::io::Error::last_os_error()),
+            result => Ok(result as usize),
+        }
+    }
+}
+
+impl Backend for Ring {
+    /// Also waits for the SQPOLL thread to pick the entries up if necessary.
+    unsafe fn push(&mut self, sqes: &[Sqe]) -> std::io::Result<()> {
+        if sqes.len() > self.inner.submission().capacity() {
+            return Err(std::io::Error::other(
+                "Too many entries for the submission queue",
+            ));
+        }
+
+        while self.inner.submission().push_multiple(sqes).is_err() {
+            // Also wakes the SQPOLL thread up if it's asleep, otherwise there would be no room
+            // ever.
+            self.submit()?;
+
+            if self.inner.params().is_setup_sqpoll() {
+                self.inner.submitter().squeue_wait()?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn submit_and_wait(&mut self, want: usize) -> std::io::Result<usize> {
+        let Some(flags) = self.enter_flags(want) else {
+            return Ok(self.inner.submission().len());
+        };
+
+        self.enter(want, flags, std::ptr::null(), 0)
+    }
+
+    fn submit_with_timeout(&mut self, want: usize, timeout: &Timespec) -> std::io::Result<usize> {
+        let Some(flags) = self.enter_flags(want) else {
+            return Ok(self.inner.submission().len());
+        };
+
+        // `Timespec` is a transparent wrapper of `__kernel_timespec`.
+        let arg = GeteventsArg {
+            sigmask: 0,
+            sigmask_sz: 0,
+            pad: 0,
+            ts: timeout as *const Timespec as u64,
+        };
+
+        let size = std::mem::size_of::<GeteventsArg>();
+        self.enter(
+            want,
+            flags | IORING_ENTER_EXT_ARG,
+            &arg as *const _ as _,
+            size,
+        )
+    }
+
+    fn queued(&mut self) -> usize {
+        self.inner.submission().len()
+    }
+
+    fn is_ready(&mut self) -> bool {
+        !self.inner.completion().is_empty() || self.inner.submission().cq_overflow()
+    }
+
+    /// Includes the completions which didn't fit into the completion queue and were kept by
+    /// the kernel meanwhile.
+    fn complete(&mut self, cqes: &mut Vec<Cqe>) -> std::io::Result<bool> {
+        let mut overflowed = false;
+
+        loop {
+            cqes.extend(self.inner.completion());
+
+            if !self.inner.submission().cq_overflow() {
+                break;
+            }
+
+            // Entering to get events flushes the kept completions to the now empty queue.
+            overflowed = true;
+            self.enter(0, IORING_ENTER_GETEVENTS, std::ptr::null(), 0)?;
+        }
+
+        if overflowed {
+            self.overflows += 1;
+        }
+
+        Ok(overflowed)
+    }
+
+    fn overflows(&self) -> u64 {
+        self.overflows
+    }
+
+    /// Only kernels without `IORING_FEAT_NODROP` drop them, others keep them until there's room.
+    fn dropped(&mut self) -> u32 {
+        self.inner.completion().overflow()
+    }
+
+    fn ring_fd(&self) -> Option<BorrowedFd<'_>> {
+        // The ring keeps the fd open while it's borrowed.
+        Some(unsafe { BorrowedFd::borrow_raw(self.inner.as_raw_fd()) })
+    }
+
+    unsafe fn register_buf_ring(
+        &mut self,
+        addr: u64,
+        entries: u16,
+        group: u16,
+    ) -> std::io::Result<()> {
+        self.inner
+            .submitter()
+            .register_buf_ring(addr, entries, group)
+    }
+
+    fn unregister_buf_ring(&mut self, group: u16) -> std::io::Result<()> {
+        self.inner.submitter().unregister_buf_ring(group)
+    }
+
+    unsafe fn register_buffers(&mut self, iovecs: &[libc::iovec]) -> std::io::Result<()> {
+        self.inner.submitter().register_buffers(iovecs)
+    }
+
+    fn register_files(&mut self, fds: &[RawFd]) -> std::io::Result<()> {
+        self.inner.submitter().register_files(fds)
+    }
+
+    unsafe fn update_buffers(
+        &mut self,
+        offset: u32,
+        iovecs: &[libc::iovec],
+    ) -> std::io::Result<()> {
+        let mut update = RsrcUpdate2 {
+            offset,
+            resv: 0,
+            data: iovecs.as_ptr() as u64,
+            tags: 0,
+            nr: iovecs.len() as u32,
+            resv2: 0,
+        };
+
+        // Takes the size of the update instead of the number of them.
+        let size = std::mem::size_of::<RsrcUpdate2>() as libc::c_uint;
+        register(
+            &self.inner,
+            IORING_REGISTER_BUFFERS_UPDATE,
+            &mut update,
+            size,
+        )
+    }
+}
+
+impl Deref for Ring {
+    type Target = IoUring;
+
+    fn deref(&self) -> &Self::Target {
+        &self.inner
+    }
+}
+
+impl DerefMut for Ring {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.inner
+    }
+}
+
+impl Drop for Ring {
+    fn drop(&mut self) {
+        if let Some(index) = self.registered_fd {
+            let mut update = RsrcUpdate {
+                offset: index,
+                resv: 0,
+                data: 0,
+            };
+
+            if let Err(err) = register(&self.inner, IORING_UNREGISTER_RING_FDS, &mut update, 1) {
+                error!("Unregister ring fd: {err}");
+            }
+        }
+    }
+}
+
+/// Returns the registered ring fd index or `None` if the kernel doesn't support it.
+fn register_ring_fd(ring: &IoUring) -> Option<u32> {
+    let mut update = RsrcUpdate {
+        // Lets the kernel pick a free index.
+        offset: u32::MAX,
+        resv: 0,
+        data: ring.as_raw_fd() as u64,
+    };
+
+    register(ring, IORING_REGISTER_RING_FDS, &mut update, 1)
+        .ok()
+        .map(|_| update.offset)
+}
+
+/// Registers the argument with the `opcode`, `nr_args` being either the number of arguments or
+/// their size depending on the opcode.
+fn register<T>(
+    ring: &IoUring,
+    opcode: libc::c_uint,
+    arg: &mut T,
+    nr_args: libc::c_uint,
+) -> std::io::Result<()> {
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_io_uring_register,
+            ring.as_raw_fd(),
+            opcode,
+            arg as *mut T,
+            nr_args,
+        )
+    };
+
+    match result {
+        result if result < 0 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,281 @@
+//! A seccomp filter confining a worker thread to the syscalls serving clients takes once the
+//! server is set up. Other syscalls fail with `EPERM` so that a compromised worker can't open
+//! files for writing, spawn processes or map executable memory. The filter is the calling
+//! thread's: the main thread and those forwarding signals and handing the listeners over aren't
+//! confined.
+//!
+//! Operations submitted to io_uring don't go through the filter, so the rings of a confined
+//! worker are restricted to the operations the server submits, which opening files or creating
+//! sockets aren't.
+
+use anyhow::{Context as _, Result};
+use io_uring::opcode::{
+    Accept, AsyncCancel, Close, Connect, LinkTimeout, MsgRingData, ProvideBuffers, Read, ReadFixed,
+    Recv, RecvMsg, Send, SendMsg, SendZc, Shutdown, Splice, Timeout, TimeoutRemove, Write,
+    WriteFixed, Writev,
+};
+use io_uring::register::Restriction;
+use io_uring::IoUring;
+use libc::{sock_filter, sock_fprog, BPF_ABS, BPF_ALU, BPF_AND, BPF_JEQ, BPF_JMP, BPF_K};
+use libc::{BPF_LD, BPF_RET, BPF_W};
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xc000_003e;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xc000_00b7;
+
+/// Offsets into `seccomp_data`.
+const NR_OFFSET: u32 = 0;
+const ARCH_OFFSET: u32 = 4;
+/// The lower half of the third argument.
+const ARG2_OFFSET: u32 = if cfg!(target_endian = "little") {
+    32
+} else {
+    36
+};
+
+/// Neither the operations run by io_uring nor the setup done before the filter is installed go
+/// through it.
+const ALLOWED: &[libc::c_long] = &[
+    // The rings and the epoll backend.
+    libc::SYS_io_uring_enter,
+    libc::SYS_io_uring_register,
+    libc::SYS_epoll_pwait,
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_epoll_wait,
+    libc::SYS_epoll_ctl,
+    // Sockets, eventfds, splice pipes and logging.
+    libc::SYS_read,
+    libc::SYS_write,
+    libc::SYS_readv,
+    libc::SYS_writev,
+    libc::SYS_close,
+    libc::SYS_fcntl,
+    libc::SYS_eventfd2,
+    libc::SYS_pipe2,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    libc::SYS_recvfrom,
+    libc::SYS_sendto,
+    libc::SYS_recvmsg,
+    libc::SYS_sendmsg,
+    libc::SYS_shutdown,
+    libc::SYS_getsockopt,
+    libc::SYS_setsockopt,
+    libc::SYS_getsockname,
+    libc::SYS_getpeername,
+    // Upstreams and the statsd socket.
+    libc::SYS_socket,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    // Memory, including the buffers the pool grows by.
+    libc::SYS_brk,
+    libc::SYS_munmap,
+    libc::SYS_mremap,
+    libc::SYS_madvise,
+    libc::SYS_mbind,
+    // Threads, time and signals.
+    libc::SYS_futex,
+    libc::SYS_sched_yield,
+    libc::SYS_clock_gettime,
+    libc::SYS_clock_nanosleep,
+    libc::SYS_nanosleep,
+    libc::SYS_getrandom,
+    libc::SYS_rt_sigreturn,
+    libc::SYS_rt_sigprocmask,
+    libc::SYS_sigaltstack,
+    libc::SYS_restart_syscall,
+    libc::SYS_getpid,
+    libc::SYS_gettid,
+    libc::SYS_tgkill,
+    libc::SYS_exit,
+    libc::SYS_exit_group,
+    // Reloading the config file.
+    libc::SYS_fstat,
+    libc::SYS_newfstatat,
+    libc::SYS_statx,
+    libc::SYS_lseek,
+    // Counting the open descriptors in soak mode.
+    libc::SYS_getdents64,
+    // Removing the admin socket and the pid file on exit.
+    #[cfg(target_arch = "x86_64")]
+    libc::SYS_unlink,
+    libc::SYS_unlinkat,
+];
+
+/// Syscalls allowed if their third argument masked equals the value: files may only be opened for
+/// reading, which reloading the config takes, and memory may not be made executable.
+const CHECKED: &[(libc::c_long, libc::c_int, libc::c_int)] = &[
+    (libc::SYS_openat, libc::O_ACCMODE, libc::O_RDONLY),
+    (libc::SYS_mmap, libc::PROT_EXEC, 0),
+    (libc::SYS_mprotect, libc::PROT_EXEC, 0),
+];
+
+/// The operations the server submits to a ring, multishot flavours sharing the opcodes.
+const RING_OPS: &[u8] = &[
+    Accept::CODE,
+    AsyncCancel::CODE,
+    Close::CODE,
+    Connect::CODE,
+    LinkTimeout::CODE,
+    MsgRingData::CODE,
+    ProvideBuffers::CODE,
+    Read::CODE,
+    ReadFixed::CODE,
+    Recv::CODE,
+    RecvMsg::CODE,
+    Send::CODE,
+    SendMsg::CODE,
+    SendZc::CODE,
+    Shutdown::CODE,
+    Splice::CODE,
+    Timeout::CODE,
+    TimeoutRemove::CODE,
+    Write::CODE,
+    WriteFixed::CODE,
+    Writev::CODE,
+];
+
+/// What the server registers with a ring once it's built: fixed buffers and files, buffer rings
+/// the pool grows by, the ring fd, NAPI and the probe of the kernel features.
+const RING_REGISTER_OPS: &[u8] = &[
+    0,  // IORING_REGISTER_BUFFERS
+    1,  // IORING_UNREGISTER_BUFFERS
+    2,  // IORING_REGISTER_FILES
+    3,  // IORING_UNREGISTER_FILES
+    8,  // IORING_REGISTER_PROBE
+    16, // IORING_REGISTER_BUFFERS_UPDATE
+    20, // IORING_REGISTER_RING_FDS
+    21, // IORING_UNREGISTER_RING_FDS
+    22, // IORING_REGISTER_PBUF_RING
+    23, // IORING_UNREGISTER_PBUF_RING
+    27, // IORING_REGISTER_NAPI
+    28, // IORING_UNREGISTER_NAPI
+];
+
+/// Restricts the `ring`, built disabled, to [`RING_OPS`] and [`RING_REGISTER_OPS`] and enables
+/// it. Any flags of the operations are allowed.
+pub fn restrict_ring(ring: &IoUring) -> Result<()> {
+    let mut restrictions = RING_OPS
+        .iter()
+        .map(|&op| Restriction::sqe_op(op))
+        .chain(
+            RING_REGISTER_OPS
+                .iter()
+                .map(|&op| Restriction::register_op(op)),
+        )
+        .chain([Restriction::sqe_flags_allowed(u8::MAX)])
+        .collect::<Vec<_>>();
+
+    let submitter = ring.submitter();
+
+    submitter
+        .register_restrictions(&mut restrictions)
+        .context("Restrict io_uring operations")?;
+
+    submitter.register_enable_rings().context("Enable io_uring")
+}
+
+/// Confines the calling thread and the threads it spawns afterwards.
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+pub fn install() -> Result<()> {
+    let deny = libc::SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let mut filter = Vec::with_capacity(5 + ALLOWED.len() * 2 + CHECKED.len() * 6);
+
+    // Syscall numbers differ between architectures.
+    filter.push(load(ARCH_OFFSET));
+    filter.push(jump_if(AUDIT_ARCH, 1, 0));
+    filter.push(ret(libc::SECCOMP_RET_KILL_PROCESS));
+    filter.push(load(NR_OFFSET));
+
+    for &nr in ALLOWED {
+        filter.push(jump_if(nr as u32, 0, 1));
+        filter.push(ret(libc::SECCOMP_RET_ALLOW));
+    }
+
+    // Each check returns so the syscall number is only overwritten for the syscall it's about.
+    for &(nr, mask, value) in CHECKED {
+        filter.push(jump_if(nr as u32, 0, 5));
+        filter.push(load(ARG2_OFFSET));
+        filter.push(stmt(BPF_ALU | BPF_AND | BPF_K, mask as u32));
+        filter.push(jump_if(value as u32, 0, 1));
+        filter.push(ret(libc::SECCOMP_RET_ALLOW));
+        filter.push(ret(deny));
+    }
+
+    filter.push(ret(deny));
+
+    let program = sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+
+    // Lets an unprivileged process install the filter, and is required anyway.
+    if unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } != 0 {
+        return Err(std::io::Error::last_os_error()).context("Set no new privileges");
+    }
+
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_seccomp,
+            libc::SECCOMP_SET_MODE_FILTER,
+            0,
+            &program as *const sock_fprog,
+        )
+    };
+
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()).context("Install seccomp filter"),
+    }
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+pub fn install() -> Result<()> {
+    bail!("The seccomp filter is only available on x86_64 and aarch64")
+}
+
+fn load(offset: u32) -> sock_filter {
+    stmt(BPF_LD | BPF_W | BPF_ABS, offset)
+}
+
+fn ret(action: u32) -> sock_filter {
+    stmt(BPF_RET | BPF_K, action)
+}
+
+fn stmt(code: u32, k: u32) -> sock_filter {
+    unsafe { libc::BPF_STMT(code as u16, k) }
+}
+
+/// Skips `jt` instructions if the accumulator equals `k`, `jf` otherwise.
+fn jump_if(k: u32, jt: u8, jf: u8) -> sock_filter {
+    unsafe { libc::BPF_JUMP((BPF_JMP | BPF_JEQ | BPF_K) as u16, k, jt, jf) }
+}
+
+#[cfg(test)]
+mod tests {
+    use io_uring::opcode::{Nop, OpenAt};
+    use io_uring::types::Fd;
+
+    use super::*;
+
+    #[test]
+    fn rings_run_only_the_operations_of_the_server() {
+        let ring = IoUring::builder().setup_r_disabled().build(4).unwrap();
+        restrict_ring(&ring).unwrap();
+
+        let path = c"/etc/passwd";
+        let run = |sqe| {
+            unsafe { ring.submission_shared().push(&sqe).unwrap() };
+            ring.submit_and_wait(1).unwrap();
+            unsafe { ring.completion_shared() }.next().unwrap().result()
+        };
+
+        let open = OpenAt::new(Fd(libc::AT_FDCWD), path.as_ptr()).build();
+        assert_eq!(run(open), -libc::EACCES);
+        assert_eq!(run(Nop::new().build()), -libc::EACCES);
+
+        // Nothing to cancel.
+        assert_eq!(run(AsyncCancel::new(0).build()), -libc::ENOENT);
+    }
+}
@@ -1,165 +1,2525 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::HashSet;
 use std::future::Future;
-use std::net::{TcpListener, ToSocketAddrs};
-use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs, UdpSocket};
+use std::os::fd::{AsFd, AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::rc::Rc;
-use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::task::{Context, Poll, Wake, Waker};
+use std::time::{Duration, Instant};
 
 use anyhow::{Context as _, Result};
 use io_uring::cqueue::Entry as Cqe;
-use io_uring::opcode::AcceptMulti;
-use io_uring::types::Fd;
-use io_uring::IoUring;
-
-use crate::buffer::BufferPool;
-use crate::client::Client;
-use crate::common::{Id, Route};
-use crate::utils::Errno;
-
-const URING_BUFFER_SIZE: u32 = 1024;
-const BUFFERS_COUNT: u16 = 8192;
-const BUFFER_SIZE: u32 = 32_768;
-
-static VTABLE_STUB: RawWakerVTable = RawWakerVTable::new(
-    |ptr| RawWaker::new(ptr, &VTABLE_STUB),
-    |_| {},
-    |_| {},
-    |_| {},
-);
+use io_uring::opcode::{Accept, AcceptMulti, AsyncCancel, MsgRingData, Read, Timeout};
+use io_uring::types::{Fd, Timespec};
+use io_uring::{Builder, IoUring};
+use socket2::{Domain, Protocol, SockAddr, SockRef, Socket, TcpKeepalive, Type};
+
+use crate::admin::{AdminSocket, Request, Session, Setting};
+use crate::allocator::{BufferAllocator, HeapAllocator, MmapAllocator};
+use crate::backend::Backend;
+use crate::buffer::{BufferPool, BufferRing, Holder, Scrub};
+use crate::builder::Handle;
+use crate::client::{self, Client, ClientOptions, Limits, Multishot, Peers, Stats, Upstream};
+use crate::common::{CqeQueue, Generation, Id, ListenerId, Operations, Route};
+use crate::config::{
+    AllocatorKind, BackendKind, Cidr, Ipv6Mode, ServerConfig, Service, SocketOptions, VsockAddr,
+};
+use crate::connection_limit::ConnectionsPerIp;
+use crate::datagram::Datagram;
+use crate::epoll::{self, Epoll};
+use crate::error::Error;
+use crate::handler::{self, Connection, Echo, Serve};
+use crate::handover::{self, Handover};
+use crate::io::{Io, Stream};
+use crate::log::{self, Span};
+use crate::mesh::Mesh;
+use crate::metrics::{self, Counters, Metrics};
+use crate::middleware::{self, Chain, ConnectionInfo, Credentials, Hooks};
+use crate::probe::Features;
+use crate::rate_limit::{RateLimit, TokenBucket};
+use crate::remote::{Command, Remote};
+use crate::response::Response;
+use crate::ring::Ring;
+use crate::seccomp;
+use crate::services::{Chargen, Daytime, Discard};
+use crate::signal::{
+    self, SignalInfo, DRAIN_SIGNAL, RELOAD_SIGNAL, SHUTDOWN_SIGNALS, STATS_SIGNAL,
+};
+use crate::slab::Slab;
+use crate::soak::Soak;
+use crate::statsd::Statsd;
+use crate::utils::{self, Errno};
+
+pub type ConfigLoader = Box<dyn Fn() -> Result<ServerConfig>>;
+
+/// First of the provided buffer groups to read from sockets into, one per size class.
+const READ_BUFFER_GROUP: u16 = 0;
+
+/// Ways past the common failures of binding listeners.
+const BIND_HINTS: &[(libc::c_int, &str)] = &[
+    (
+        libc::EADDRINUSE,
+        "Another process listens on the address, stop it or pick another port",
+    ),
+    (
+        libc::EACCES,
+        "Ports below 1024 take root or CAP_NET_BIND_SERVICE",
+    ),
+    (libc::EADDRNOTAVAIL, "No network interface has the address"),
+];
+
+/// Ways past the failure of creating a vsock socket.
+const VSOCK_HINTS: &[(libc::c_int, &str)] = &[(
+    libc::EAFNOSUPPORT,
+    "The kernel has no vsock transport loaded, e.g. vhost_vsock on the host or \
+     virtio_vsock in the guest",
+)];
+
+/// Ways past the common failures of setting up a ring.
+const RING_HINTS: &[(libc::c_int, &str)] = &[
+    (
+        libc::ENOSYS,
+        "The kernel is built without io_uring or predates it, use the epoll backend",
+    ),
+    (
+        libc::EPERM,
+        "io_uring is disabled by the kernel.io_uring_disabled sysctl or a sandbox, use the epoll \
+         backend",
+    ),
+    (
+        libc::ENOMEM,
+        "The ring counts against RLIMIT_MEMLOCK on older kernels, raise it with `ulimit -l` or \
+         use fewer ring entries",
+    ),
+];
+
+/// Errors of creating an MPTCP socket where the kernel is built without it or it's disabled with
+/// the net.mptcp.enabled sysctl.
+const MPTCP_UNSUPPORTED: &[libc::c_int] = &[libc::EPROTONOSUPPORT, libc::ENOPROTOOPT, libc::EINVAL];
+
+/// Number of the clients with the most traffic to report.
+const TOP_TALKERS: usize = 3;
+
+/// Datagrams get the largest buffers, which may still be shorter and truncate them.
+const MAX_DATAGRAM_SIZE: usize = u16::MAX as usize;
 
 pub struct Server {
-    listener: TcpListener,
-    ring: Rc<RefCell<IoUring>>,
+    listeners: Vec<Socket>,
+    /// Of the UNIX sockets among the listeners, only held to remove them in the end.
+    _unix_paths: Vec<UnixPath>,
+    /// Hands the listeners over to the next server until this one stops accepting.
+    handover: Option<Arc<Handover>>,
+    /// Whether the multishot accept of the listener at the same index is in flight.
+    accept_armed: Vec<bool>,
+    /// Whether accepting is suspended until enough buffers are released or the cooldown is over.
+    accept_paused: bool,
+    /// Accepts allowed per second if limited.
+    accept_rate: Option<TokenBucket>,
+    accept_cooldown: Duration,
+    /// When to resume accepting after going over the accept rate.
+    cooldown_until: Option<Instant>,
+    /// Wakes the event loop up at the end of the cooldown.
+    cooldown_timer: Box<Timespec>,
+    udp_sockets: Vec<UdpSocket>,
+    ring: Rc<RefCell<dyn Backend>>,
+    operations: Operations,
+    features: Features,
     buffer_pool: BufferPool,
-    clients: HashMap<Id, Task>,
-    client_id_counter: u32,
+    buffer_ring: BufferRing,
+    /// How long grown buffers stay unused before they're released.
+    buffers_shrink_delay: Duration,
+    client_options: ClientOptions,
+    forward: Option<SocketAddr>,
+    peers: Option<Peers>,
+    /// What to respond to every message with instead of echoing, if anything.
+    response: Option<Rc<Response>>,
+    worker_id: usize,
+    mesh: Option<Mesh>,
+    handoff: bool,
+    /// The worker the last client has been handed off to.
+    handoff_peer: usize,
+    clients: Slab<Task>,
+    /// Spawned tasks along with their names for errors.
+    tasks: Slab<(String, Task)>,
+    /// Tasks woken by their wakers rather than by completions.
+    ready: Arc<ReadyQueue>,
+    /// Where the eventfd of the ready queue is read into.
+    ready_count: Box<u64>,
+    signals: Option<OwnedFd>,
+    signal_info: Box<SignalInfo>,
+    remote: Option<Remote>,
+    /// Where the eventfd of the remote is read into.
+    remote_count: Box<u64>,
+    shutdown_timeout: Duration,
+    drain_timeout: Duration,
+    drain_goodbye: Option<String>,
+    shutdown_deadline: Option<Instant>,
+    /// Whether the shutdown is a drain, which says goodbye to the clients left at the deadline.
+    drain: bool,
+    draining: Rc<Cell<bool>>,
+    serve: Serve,
+    middleware: Chain,
+    config_loader: Option<ConfigLoader>,
+    /// Stops the event loop right away, failing the server.
+    fatal: Option<anyhow::Error>,
+    max_connections: Option<usize>,
+    max_connections_per_ip: Option<usize>,
+    /// Clients of each peer address, of all the workers if shared.
+    connections_per_ip: ConnectionsPerIp,
+    /// Networks to accept connections from only if any, and those not to.
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    socket_options: SocketOptions,
+    /// Report buffers held by clients for longer than this.
+    buffer_hold_warning: Option<Duration>,
+    /// Completions dropped by the kernel so far as of the last check.
+    dropped_completions: u32,
+    /// How long to poll for completions before blocking when waiting for events.
+    spin: Option<Duration>,
+    /// Interval of the periodic maintenance.
+    tick: Box<Timespec>,
+    metrics: Arc<Metrics>,
+    /// The part of the `metrics` the worker updates.
+    counters: Arc<Counters>,
+    /// Where the first worker exports the metrics of all of them to.
+    statsd: Option<Statsd>,
+    /// Most entries submitted and completions taken at once since the last maintenance.
+    sq_peak: usize,
+    cq_peak: usize,
+    /// How often to log a summary of the worker on maintenance.
+    stats_interval: Option<Duration>,
+    /// When the previous summary was logged, along with the bytes read and written by then.
+    last_report: (Instant, u64, u64),
+    /// Checks of the invariants in soak mode.
+    soak: Option<Soak>,
+    /// Where the first worker serves admin commands.
+    admin: Option<AdminSocket>,
+    /// Reaches all the workers for the admin commands.
+    handle: Handle,
+    /// Whether to install the seccomp filter once set up.
+    seccomp: bool,
 }
 
 impl Server {
-    pub fn bind(bind_address: impl ToSocketAddrs) -> Result<Self> {
-        let listener = TcpListener::bind(bind_address).context("Bind")?;
+    /// Binds the listeners of the worker with the id, which picks the CPUs it's pinned to,
+    /// unless it adopts those taken over with the `handover`.
+    pub fn bind(
+        config: &ServerConfig,
+        worker_id: usize,
+        handover: Option<Arc<Handover>>,
+    ) -> Result<Self> {
+        let cpu = nth_wrapping(&config.cpus, worker_id).copied();
 
-        let ring = IoUring::builder()
-            .build(URING_BUFFER_SIZE)
-            .context("Build io_uring")?;
+        if let Some(cpu) = cpu {
+            utils::pin_thread(cpu).with_context(|| format!("Pin to CPU {cpu}"))?;
+        }
 
-        let buffer_pool = BufferPool::new(BUFFERS_COUNT, BUFFER_SIZE);
-        let iovecs = buffer_pool.iovecs();
-        unsafe { ring.submitter().register_buffers(&iovecs) }.context("Register buffers")?;
+        let mut listeners = Vec::new();
+        let mut udp_sockets = Vec::new();
+        let reuse_port = config.workers > 1;
 
-        Ok(Self {
-            listener,
-            ring: Rc::new(RefCell::new(ring)),
+        // The sockets taken over with the name, or the one bound with `bind` if there are none,
+        // each handed over to the next server in turn.
+        let adopt_or = |name: String, bind: &mut dyn FnMut() -> Result<Socket>| -> Result<_> {
+            let Some(ref handover) = handover else {
+                return Ok(vec![bind()?]);
+            };
+
+            let mut sockets = handover
+                .adopt(&name, worker_id)
+                .into_iter()
+                .map(Socket::from)
+                .collect::<Vec<_>>();
+
+            match sockets.is_empty() {
+                true => sockets.push(bind()?),
+                false => info!("Adopted {name}"),
+            }
+
+            for socket in &sockets {
+                handover.offer(&name, socket.as_fd())?;
+            }
+
+            Ok(sockets)
+        };
+
+        for &address in std::iter::once(&config.address).chain(&config.listen) {
+            for (address, only_v6) in bind_addresses(address, config.ipv6_mode)? {
+                listeners.extend(adopt_or(format!("tcp {address}"), &mut || {
+                    listen(address, only_v6, reuse_port, config.backlog, config.mptcp)
+                        .with_context(|| format!("Bind {address}"))
+                })?);
+
+                info!("Listening on {address}");
+
+                if config.udp {
+                    let sockets = adopt_or(format!("udp {address}"), &mut || {
+                        bind_udp(address, only_v6, reuse_port)
+                            .map(Socket::from)
+                            .with_context(|| format!("Bind UDP {address}"))
+                    })?;
+
+                    info!("Listening on UDP {address}");
+                    udp_sockets.extend(sockets.into_iter().map(UdpSocket::from));
+                }
+            }
+        }
+
+        let mut unix_paths = Vec::new();
+
+        // Only one socket may listen on a vsock port or a UNIX socket path.
+        if worker_id == 0 {
+            for &address in &config.vsock {
+                listeners.extend(adopt_or(format!("vsock {address}"), &mut || {
+                    listen_vsock(address, config.backlog)
+                        .with_context(|| format!("Bind vsock {address}"))
+                })?);
+
+                info!("Listening on vsock {address}");
+            }
+
+            for path in &config.unix {
+                listeners.extend(adopt_or(format!("unix {}", path.display()), &mut || {
+                    listen_unix(path, config.backlog)
+                        .with_context(|| format!("Bind UNIX socket {}", path.display()))
+                })?);
+
+                info!("Listening on UNIX socket {}", path.display());
+                unix_paths.push(UnixPath(path.clone()));
+            }
+        }
+
+        if config.broadcast && config.forward.is_some() {
+            bail!("Broadcast and forward modes are mutually exclusive");
+        }
+
+        if config.response_file.is_some() && (config.broadcast || config.forward.is_some()) {
+            bail!("Responding with a file excludes broadcast and forward modes");
+        }
+
+        if config.service != Service::Echo
+            && (config.broadcast || config.forward.is_some() || config.response_file.is_some())
+        {
+            bail!("Broadcast, forward and file response modes are the echo service's");
+        }
+
+        if config.service != Service::Echo && config.udp {
+            bail!("UDP datagrams are only echoed");
+        }
+
+        let forward = match config.forward {
+            Some(ref address) => Some(
+                address
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addresses| addresses.next())
+                    .with_context(|| format!("Resolve forward address {address}"))?,
+            ),
+            None => None,
+        };
+
+        if config.tick_interval_ms == 0 {
+            bail!("The maintenance tick interval must be positive");
+        }
+
+        let statsd = match config.statsd {
+            Some(ref address) => {
+                if config.statsd_interval_ms == 0 {
+                    bail!("The statsd flush interval must be positive");
+                }
+
+                Some(Statsd {
+                    address: address
+                        .to_socket_addrs()
+                        .ok()
+                        .and_then(|mut addresses| addresses.next())
+                        .with_context(|| format!("Resolve statsd address {address}"))?,
+                    prefix: config.statsd_prefix.clone(),
+                    interval: Duration::from_millis(config.statsd_interval_ms),
+                    tags: config.statsd_tags.clone(),
+                })
+            }
+            None => None,
+        };
+
+        let admin = match config.admin_socket {
+            Some(ref path) if worker_id == 0 => {
+                let sockets = adopt_or(format!("admin {}", path.display()), &mut || {
+                    AdminSocket::listen(path)
+                        .map(|listener| Socket::from(OwnedFd::from(listener)))
+                        .with_context(|| format!("Admin socket {}", path.display()))
+                })?;
+
+                info!("Serving admin commands on {}", path.display());
+                sockets
+                    .into_iter()
+                    .next()
+                    .map(|socket| AdminSocket::new(UnixListener::from(OwnedFd::from(socket)), path))
+            }
+            _ => None,
+        };
+
+        // Until shared with the other workers.
+        let metrics = Arc::new(Metrics::new(worker_id + 1));
+
+        let allocator: Box<dyn BufferAllocator> = match config.buffer_allocator {
+            AllocatorKind::Mmap => Box::new(MmapAllocator {
+                huge_pages: config.huge_pages,
+                // The buffers of a pinned worker come from the memory close to its CPU.
+                node: cpu.and_then(utils::cpu_node),
+            }),
+            AllocatorKind::Heap => {
+                if config.huge_pages {
+                    warn!("Huge pages apply to the mmap allocator only");
+                }
+
+                Box::new(HeapAllocator)
+            }
+        };
+
+        // Debug builds always poison the buffers to surface accounting bugs.
+        let scrub = match (cfg!(debug_assertions), config.zero_buffers) {
+            (true, _) => Scrub::Poison,
+            (false, true) => Scrub::Zero,
+            (false, false) => Scrub::None,
+        };
+
+        let buffer_pool = BufferPool::new(&config.buffer_classes(), allocator)?.with_scrub(scrub);
+
+        // Only completions of a ring may arrive without waiting, and with the completion work
+        // left to the thread they don't until it enters the kernel.
+        let spin = match config.backend {
+            BackendKind::IoUring if config.coop_taskrun || config.defer_taskrun => {
+                if config.spin_us.is_some() && worker_id == 0 {
+                    warn!("Not spinning, completions arrive on waiting with the taskrun flags");
+                }
+
+                None
+            }
+            BackendKind::IoUring => config.spin_us.map(Duration::from_micros),
+            BackendKind::Epoll => None,
+        };
+
+        let (ring, features): (Rc<RefCell<dyn Backend>>, _) = match config.backend {
+            BackendKind::IoUring => {
+                let sqpoll_cpu = nth_wrapping(&config.sqpoll_cpus, worker_id).copied();
+                let ring = build_ring(config, sqpoll_cpu)?;
+                let features = Features::probe(&ring)?;
+
+                if !ring.params().is_feature_nodrop() {
+                    warn!("The kernel drops completions when the completion queue overflows");
+                }
+
+                let ring = Ring::new(ring);
+
+                if let Some(timeout) = config.napi_busy_poll_us {
+                    match ring.register_napi(timeout, config.napi_prefer_busy_poll) {
+                        Ok(()) => (),
+                        Err(err) if err.raw_os_error() == Some(libc::EINVAL) => {
+                            warn!("The kernel doesn't support NAPI busy polling, disabling it");
+                        }
+                        Err(err) => return Err(err).context("Register NAPI"),
+                    }
+                }
+
+                (Rc::new(RefCell::new(ring)), features)
+            }
+            BackendKind::Epoll => {
+                let epoll = Epoll::new().context("Create epoll instance")?;
+                (Rc::new(RefCell::new(epoll)), epoll::FEATURES)
+            }
+        };
+        buffer_pool.register(&mut *ring.borrow_mut())?;
+
+        let response = match config.response_file {
+            Some(ref path) => {
+                let response = Response::open(path, &mut *ring.borrow_mut(), features.splice)
+                    .with_context(|| format!("Response file {}", path.display()))?;
+                Some(Rc::new(response))
+            }
+            None => None,
+        };
+
+        let buffer_ring = match features.buf_ring {
+            true => BufferRing::register(Rc::clone(&ring), &buffer_pool, READ_BUFFER_GROUP)?,
+            false => BufferRing::provide(Rc::clone(&ring), &buffer_pool, READ_BUFFER_GROUP),
+        };
+
+        let mut server = Self {
+            accept_armed: vec![false; listeners.len()],
+            accept_paused: false,
+            accept_rate: None,
+            accept_cooldown: Duration::ZERO,
+            cooldown_until: None,
+            cooldown_timer: Box::new(Timespec::new()),
+            listeners,
+            _unix_paths: unix_paths,
+            handover,
+            udp_sockets,
+            ring,
+            operations: Operations::default(),
+            features,
             buffer_pool,
-            clients: HashMap::new(),
-            client_id_counter: 0,
-        })
+            buffer_ring,
+            buffers_shrink_delay: Duration::from_millis(config.buffers_shrink_delay_ms),
+            client_options: ClientOptions::default(),
+            forward,
+            peers: config.broadcast.then(Peers::default),
+            response,
+            worker_id,
+            mesh: None,
+            handoff: false,
+            handoff_peer: worker_id,
+            clients: Slab::new(),
+            tasks: Slab::new(),
+            ready: Arc::new(ReadyQueue::new()?),
+            ready_count: Box::new(0),
+            signals: None,
+            signal_info: Box::new(unsafe { std::mem::zeroed() }),
+            remote: None,
+            remote_count: Box::new(0),
+            shutdown_timeout: Duration::ZERO,
+            drain_timeout: Duration::ZERO,
+            drain_goodbye: None,
+            shutdown_deadline: None,
+            drain: false,
+            draining: Rc::new(Cell::new(false)),
+            serve: match config.service {
+                Service::Echo => handler::serve(Echo),
+                Service::Discard => handler::serve(Discard),
+                Service::Chargen => handler::serve(Chargen),
+                Service::Daytime => handler::serve(Daytime),
+            },
+            middleware: Rc::new([]),
+            config_loader: None,
+            fatal: None,
+            max_connections: None,
+            max_connections_per_ip: None,
+            connections_per_ip: ConnectionsPerIp::default(),
+            allow: Vec::new(),
+            deny: Vec::new(),
+            socket_options: SocketOptions::default(),
+            buffer_hold_warning: None,
+            dropped_completions: 0,
+            spin,
+            tick: Box::new(Timespec::from(Duration::from_millis(
+                config.tick_interval_ms,
+            ))),
+            counters: metrics.worker(worker_id),
+            metrics,
+            statsd,
+            sq_peak: 0,
+            cq_peak: 0,
+            stats_interval: None,
+            last_report: (Instant::now(), 0, 0),
+            soak: None,
+            admin,
+            handle: Handle::default(),
+            seccomp: config.seccomp,
+        };
+
+        server.apply_runtime_config(config);
+        Ok(server)
+    }
+
+    /// Applies the part of the config which may change at runtime without a restart.
+    fn apply_runtime_config(&mut self, config: &ServerConfig) {
+        log::set_level(config.log_level);
+        log::set_payloads(config.payload_log_every, config.payload_log_max_bytes);
+
+        self.client_options = ClientOptions {
+            framing: config.framing,
+            max_message_size: config.max_message_size,
+            idle_timeout: config.idle_timeout_ms.map(Duration::from_millis),
+            frame_timeout: config.frame_timeout_ms.map(Duration::from_millis),
+            min_frame_rate: config.min_frame_rate,
+            output_high_watermark: config.output_high_watermark,
+            slow_consumer: config.slow_consumer,
+            multishot: config.multishot_recv && self.features.recv_multi,
+            zerocopy_threshold: config.zerocopy_threshold.filter(|_| self.features.send_zc),
+            linked_echo: config.linked_echo,
+            splice_echo: config.splice_echo && self.features.splice,
+            transform: config.transform,
+            transform_key: config.transform_key,
+            delay: config.delay,
+            chaos: config.chaos,
+            rate_limit: RateLimit {
+                bytes_per_sec: config.rate_limit_bytes,
+                messages_per_sec: config.rate_limit_messages,
+            },
+        };
+
+        self.shutdown_timeout = Duration::from_millis(config.shutdown_timeout_ms);
+        self.drain_timeout = Duration::from_millis(config.drain_timeout_ms);
+        self.drain_goodbye = config.drain_goodbye.clone();
+        self.max_connections = config.max_connections;
+        self.max_connections_per_ip = config.max_connections_per_ip;
+        self.allow = config.allow.clone();
+        self.deny = config.deny.clone();
+        self.accept_cooldown = Duration::from_millis(config.accept_cooldown_ms);
+
+        // Keeps the tokens across reloads unless the rate changes.
+        if self.accept_rate.as_ref().map(TokenBucket::rate) != config.max_accept_rate {
+            let bucket = |rate| TokenBucket::new(rate, Instant::now());
+            self.accept_rate = config.max_accept_rate.map(bucket);
+        }
+        self.handoff = config.handoff;
+        self.socket_options = config.socket_options.clone();
+        self.buffer_hold_warning = config.buffer_hold_warn_ms.map(Duration::from_millis);
+        self.stats_interval = config.stats_interval_ms.map(Duration::from_millis);
+
+        // Keeps the usage of the first check to report the growth since across reloads.
+        match (
+            config.soak_interval_ms.map(Duration::from_millis),
+            &mut self.soak,
+        ) {
+            (Some(interval), Some(soak)) => soak.interval = interval,
+            (interval, soak) => *soak = interval.map(Soak::new),
+        }
+    }
+
+    /// Makes the server read `signalfd_siginfo` records from the `signals` signalfd and shut
+    /// down gracefully on [`SHUTDOWN_SIGNALS`].
+    pub fn with_signals(mut self, signals: OwnedFd) -> Self {
+        self.signals = Some(signals);
+        self
+    }
+
+    /// Makes the server run the commands sent with the `remote` from other threads.
+    pub fn with_remote(mut self, remote: Remote) -> Self {
+        self.remote = Some(remote);
+        self
+    }
+
+    /// Lets the server hand clients off to the other workers of the `mesh` and take theirs.
+    pub fn with_mesh(mut self, mesh: Mesh) -> Result<Self> {
+        if !self.features.msg_ring {
+            return Ok(self);
+        }
+
+        if let Some(ring) = self.ring.borrow().ring_fd() {
+            mesh.join(self.worker_id, ring)?;
+        }
+
+        self.mesh = Some(mesh);
+        Ok(self)
+    }
+
+    /// Makes the server serve connections with `serve` instead of echoing.
+    pub fn with_handler(mut self, serve: Serve) -> Self {
+        self.serve = serve;
+        self
+    }
+
+    /// Runs the `middleware` hooks for each connection in order.
+    pub fn with_middleware(mut self, middleware: Chain) -> Self {
+        self.middleware = middleware;
+        self
+    }
+
+    /// Makes the server update its part of the `metrics` shared with the other workers.
+    pub fn with_metrics(mut self, metrics: Arc<Metrics>) -> Self {
+        self.counters = metrics.worker(self.worker_id);
+        self.metrics = metrics;
+        self
+    }
+
+    /// Caps the connections from an address together with the other workers sharing the
+    /// `connections_per_ip`.
+    pub fn with_connections_per_ip(mut self, connections_per_ip: ConnectionsPerIp) -> Self {
+        self.connections_per_ip = connections_per_ip;
+        self
+    }
+
+    /// Lets the admin commands reach the servers of the `handle`, this one included.
+    pub fn with_handle(mut self, handle: Handle) -> Self {
+        self.handle = handle;
+        self
+    }
+
+    /// Makes the server reload its runtime config with `config_loader` on [`RELOAD_SIGNAL`].
+    /// Existing connections keep their settings while new ones get the reloaded values.
+    pub fn with_config_loader(mut self, config_loader: ConfigLoader) -> Self {
+        self.config_loader = Some(config_loader);
+        self
     }
 
     pub fn run(mut self) -> Result<()> {
+        // Datagrams have buffers of their own and the rest are provided for reading.
+        self.start_datagrams()?;
+        self.replenish();
         self.start_accepting()?;
+        self.read_signal()?;
+        self.read_remote()?;
+        self.read_ready()?;
+        self.start_tick()?;
+        self.start_statsd();
+        self.accept_admin()?;
 
-        loop {
-            let cqe = match self.wait_event() {
-                Ok(cqe) => cqe,
-                Err(err) => {
-                    eprintln!("Wait event: {err:#}");
-                    continue;
-                }
-            };
+        if self.seccomp {
+            seccomp::install().context("Sandbox with seccomp")?;
+            info!("Sandboxed with seccomp");
+        }
+
+        // Reused between iterations to avoid allocating on each.
+        let mut cqes = Vec::new();
+
+        while !self.is_finished() && self.fatal.is_none() {
+            self.replenish();
+            self.update_accepting();
+
+            if let Err(err) = self.wait_events(&mut cqes) {
+                error!("Wait event: {err:#}");
+                continue;
+            }
+
+            // Woken tasks are polled before the next completion, e.g. of a shutdown signal, so
+            // that they see the events in order.
+            for cqe in cqes.drain(..) {
+                self.dispatch(cqe);
+                self.poll_ready();
+            }
+
+            self.poll_ready();
+            self.release_stale();
+            self.cancel_abandoned();
+        }
+
+        if !self.clients.is_empty() {
+            info!("Closing {} remaining connections", self.clients.len());
+
+            if self.drain {
+                self.say_goodbye();
+            }
+        }
+
+        let overflows = self.ring.borrow().overflows();
+
+        if overflows > 0 {
+            info!("The completion queue has overflowed {overflows} times");
+        }
+
+        match self.fatal.take() {
+            Some(err) => Err(err),
+            None => Ok(()),
+        }
+    }
+
+    fn dispatch(&mut self, cqe: Cqe) {
+        let route = match Route::try_from(cqe.user_data()) {
+            Ok(route) => route,
+            Err(err) => {
+                error!("Completion with a result of {}: {err}", cqe.result());
+                return;
+            }
+        };
+
+        match route {
+            Route::Accept(listener_id) => self.handle_accept(cqe, listener_id),
+            Route::Operation(generation, key) => self.operations.complete(key, generation, cqe),
+            Route::Receive(generation, id) => self.handle_receive(cqe, id, generation, false),
+            Route::UpstreamReceive(generation, id) => {
+                self.handle_receive(cqe, id, generation, true)
+            }
+            Route::IdleTimer(generation, id) => self.handle_receive(cqe, id, generation, false),
+            Route::Signal => self.handle_signal(cqe),
+            Route::Tick => self.handle_tick(cqe),
+            Route::ProvideBuffer(bid) => self.handle_provide_buffer(cqe, bid as u16),
+            Route::Handoff => self.handle_handoff(cqe),
+            Route::Remote => self.handle_remote(cqe),
+            Route::Wake => self.handle_wake(cqe),
+            Route::Admin => self.handle_admin(cqe),
+            Route::Cancel | Route::Timeout => (),
+        }
+    }
+
+    /// Polls the tasks woken since the last call. Those woken again meanwhile are polled on the
+    /// next iteration so that a task waking itself doesn't starve the rest.
+    fn poll_ready(&mut self) {
+        for id in self.ready.take() {
+            match id {
+                TaskId::Client(id, generation) => self.poll_client(id, generation),
+                TaskId::Spawned(id, generation) => self.poll_task(id, generation),
+            }
+        }
+    }
+
+    /// Takes back the buffers of the completions nobody awaits anymore.
+    fn release_stale(&mut self) {
+        for cqe in self.operations.take_stale() {
+            client::take_buffer(&self.buffer_ring, &cqe).ok();
+        }
+    }
+
+    /// Cancels the operations nobody awaits anymore. Their completions are discarded as stale.
+    fn cancel_abandoned(&mut self) {
+        let mut ring = self.ring.borrow_mut();
 
-            match cqe.user_data().into() {
-                Route::Accept => self.handle_accept(cqe),
-                Route::Client(id) => self.handle_client(cqe, id),
+        for route in self.operations.take_abandoned() {
+            let sqe = AsyncCancel::new(route)
+                .build()
+                .user_data(Route::Cancel.into());
+
+            if let Err(err) = unsafe { ring.push(&[sqe]) } {
+                error!("Push abandoned operation cancellation: {err}");
+            }
+        }
+    }
+
+    /// The waker of the task with `id` queueing it to be polled.
+    fn waker(&self, id: TaskId) -> Arc<TaskWaker> {
+        Arc::new(TaskWaker {
+            id,
+            ready: Arc::clone(&self.ready),
+            woken: AtomicBool::new(false),
+        })
+    }
+
+    /// Once the clients have finished, and the accepts as well so that no connection is
+    /// accepted just to be closed along with the ring.
+    fn is_finished(&self) -> bool {
+        match self.shutdown_deadline {
+            Some(deadline) => {
+                (self.clients.is_empty() && !self.accept_armed.contains(&true))
+                    || Instant::now() >= deadline
             }
+            None => false,
         }
     }
 
+    /// Arms multishot accepts on the listeners which don't have one in flight, or single ones
+    /// which are rearmed after each connection if the kernel doesn't support multishot.
     fn start_accepting(&mut self) -> Result<()> {
-        let sqe = AcceptMulti::new(Fd(self.listener.as_raw_fd()))
-            .build()
-            .user_data(Route::Accept.into());
+        let mut ring = self.ring.borrow_mut();
+
+        for (listener_id, listener) in self.listeners.iter().enumerate() {
+            if self.accept_armed[listener_id] {
+                continue;
+            }
+
+            let fd = Fd(listener.as_raw_fd());
+
+            let sqe = match self.features.accept_multi {
+                true => AcceptMulti::new(fd).build(),
+                false => Accept::new(fd, std::ptr::null_mut(), std::ptr::null_mut()).build(),
+            };
+
+            let sqe = sqe.user_data(Route::Accept(listener_id as ListenerId).into());
+            unsafe { ring.push(&[sqe]) }.context("Push accept")?;
+            self.accept_armed[listener_id] = true;
+        }
+
+        Ok(())
+    }
+
+    fn start_datagrams(&mut self) -> Result<()> {
+        for (socket_id, socket) in std::mem::take(&mut self.udp_sockets)
+            .into_iter()
+            .enumerate()
+        {
+            let socket_id = socket_id as ListenerId;
+
+            let buffer = self
+                .buffer_pool
+                .acquire(MAX_DATAGRAM_SIZE, Holder::Datagram(socket_id))
+                .context("No free buffers for datagrams")?;
+
+            self.spawn(format!("Datagram echo #{socket_id}"), |io| async move {
+                Datagram::new(socket_id, socket, buffer, io).handle().await
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Runs the future made with an [`Io`] of its own alongside the clients, reporting its
+    /// failure as `name`'s. Returns the id of the task.
+    pub fn spawn<F>(&mut self, name: String, make: impl FnOnce(Io) -> F) -> Id
+    where
+        F: Future<Output = Result<()>> + 'static,
+    {
+        let (id, generation) = self.tasks.reserve();
+        let io = Io::new(Rc::clone(&self.ring), self.operations.clone());
+
+        let task = Task {
+            fut: Box::pin(make(io)),
+            waker: self.waker(TaskId::Spawned(id, generation)),
+            recv_cqes: None,
+            upstream_recv_cqes: None,
+            span: None,
+            peer: None,
+            credentials: None,
+            stats: None,
+            limits: None,
+            socket: None,
+            kicked: false,
+        };
+
+        self.tasks.fill(id, (name, task));
+        self.poll_task(id, generation);
+        id
+    }
+
+    /// Exports the metrics of all the workers from the first one.
+    fn start_statsd(&mut self) {
+        if self.worker_id != 0 {
+            return;
+        }
+
+        let Some(statsd) = self.statsd.take() else {
+            return;
+        };
+
+        info!("Exporting metrics to statsd at {}", statsd.address);
+        let metrics = Arc::clone(&self.metrics);
+        self.spawn("Statsd export".into(), |io| statsd.export(metrics, io));
+    }
+
+    /// Accepts a single connection to the admin socket at a time, which is rare enough to rearm
+    /// after each even where multishot accept is supported.
+    fn accept_admin(&mut self) -> Result<()> {
+        let Some(ref admin) = self.admin else {
+            return Ok(());
+        };
+
+        let sqe = Accept::new(
+            Fd(admin.as_raw_fd()),
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+        .flags(libc::SOCK_CLOEXEC)
+        .build()
+        .user_data(Route::Admin.into());
 
         let mut ring = self.ring.borrow_mut();
-        unsafe { ring.submission().push(&sqe) }.context("Push AcceptMulti")?;
-        ring.submit().context("Submit AcceptMulti")?;
+        unsafe { ring.push(&[sqe]) }.context("Push admin accept")?;
         Ok(())
     }
 
-    fn wait_event(&self) -> Result<Cqe> {
+    /// Serves the admin connection in a session task of its own, which keeps working while the
+    /// server is shutting down.
+    fn handle_admin(&mut self, cqe: Cqe) {
+        match cqe.result() {
+            errno if errno < 0 => error!("Admin accept error: {}", Errno(-errno)),
+            fd => {
+                let socket = unsafe { OwnedFd::from_raw_fd(fd) };
+                let handle = self.handle.clone();
+                let metrics = Arc::clone(&self.metrics);
+
+                self.spawn("Admin session".into(), |io| {
+                    Session::new(socket, io, handle, metrics).serve()
+                });
+            }
+        }
+
+        if let Err(err) = self.accept_admin() {
+            error!("{err:#}");
+        }
+    }
+
+    fn read_signal(&mut self) -> Result<()> {
+        let Some(ref signals) = self.signals else {
+            return Ok(());
+        };
+
+        let sqe = Read::new(
+            Fd(signals.as_raw_fd()),
+            &mut *self.signal_info as *mut _ as *mut u8,
+            std::mem::size_of::<SignalInfo>() as u32,
+        )
+        .build()
+        .user_data(Route::Signal.into());
+
         let mut ring = self.ring.borrow_mut();
+        unsafe { ring.push(&[sqe]) }.context("Push signal read")?;
+        Ok(())
+    }
+
+    fn read_remote(&mut self) -> Result<()> {
+        let Some(ref remote) = self.remote else {
+            return Ok(());
+        };
 
-        unsafe { ring.submitter().enter(0, 1, 1, None as Option<&()>) }
-            .context("Wait for event")?;
+        let sqe = Read::new(
+            Fd(remote.eventfd()),
+            &mut *self.remote_count as *mut u64 as *mut u8,
+            std::mem::size_of::<u64>() as u32,
+        )
+        .build()
+        .user_data(Route::Remote.into());
 
-        let cqe = ring.completion().next().context("Empty cq after wait")?;
-        Ok(cqe)
+        let mut ring = self.ring.borrow_mut();
+        unsafe { ring.push(&[sqe]) }.context("Push remote read")?;
+        Ok(())
     }
 
-    fn handle_accept(&mut self, cqe: Cqe) {
-        if !io_uring::cqueue::more(cqe.flags()) {
-            eprintln!("The acceptor will not accept anymore");
-        }
+    fn read_ready(&mut self) -> Result<()> {
+        let sqe = Read::new(
+            Fd(self.ready.eventfd.as_raw_fd()),
+            &mut *self.ready_count as *mut u64 as *mut u8,
+            std::mem::size_of::<u64>() as u32,
+        )
+        .build()
+        .user_data(Route::Wake.into());
 
-        if cqe.result() < 0 {
-            eprintln!("Accept error: {}", Errno(-cqe.result()));
-        } else {
-            let raw_fd = RawFd::from(cqe.result());
-            let fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
+        let mut ring = self.ring.borrow_mut();
+        unsafe { ring.push(&[sqe]) }.context("Push ready queue read")?;
+        Ok(())
+    }
 
-            if let Some(buffer) = self.buffer_pool.acquire() {
-                let id = self.client_id_counter;
-                self.client_id_counter += 1;
-                let cqe = Rc::new(RefCell::new(None));
+    /// Submits the operations pushed since the last call, waits for events and takes all the
+    /// completions available into `cqes`, which stays empty if the shutdown deadline passes first.
+    fn wait_events(&mut self, cqes: &mut Vec<Cqe>) -> Result<()> {
+        // Woken tasks are due to be polled right away.
+        let want = match self.ready.start_waiting() {
+            true => 1,
+            false => 0,
+        };
 
-                let mut client =
-                    Client::new(id, fd, buffer, Rc::clone(&self.ring), Rc::clone(&cqe));
+        let mut ring = self.ring.borrow_mut();
+        let queued = ring.queued();
+        metrics::add(&self.counters.wakeups, 1);
+        metrics::add(&self.counters.submitted, queued as u64);
+        self.sq_peak = self.sq_peak.max(queued);
 
-                let fut = Box::pin(async move { client.handle().await });
-                let mut task = Task { fut, cqe };
+        if let Some(deadline) = self.shutdown_deadline {
+            let timeout = Timespec::from(deadline.saturating_duration_since(Instant::now()));
 
-                match task.poll() {
-                    Poll::Pending => {
-                        self.clients.insert(id, task);
-                    }
-                    Poll::Ready(Ok(())) => (),
-                    Poll::Ready(Err(err)) => eprintln!("Client #{id} failed: {err:#}"),
+            match ring.submit_with_timeout(want, &timeout) {
+                Err(err) if err.raw_os_error() == Some(libc::ETIME) => (),
+                result => {
+                    result.context("Wait for event")?;
                 }
-            } else {
-                eprintln!("No free buffers, disconnecting client");
             }
+        } else {
+            let spun = match self.spin {
+                _ if want == 0 => false,
+                Some(budget) => {
+                    ring.submit_and_wait(0).context("Submit")?;
+                    spin(&mut *ring, budget)
+                }
+                None => false,
+            };
+
+            // Unlike a bare enter this wakes up the SQPOLL thread if it's asleep with entries
+            // left in the submission queue.
+            if !spun {
+                ring.submit_and_wait(want).context("Wait for event")?;
+            }
+        }
+
+        let overflowed = ring
+            .complete(cqes)
+            .context("Flush overflowed completions")?;
+
+        metrics::add(&self.counters.completions, cqes.len() as u64);
+        self.cq_peak = self.cq_peak.max(cqes.len());
+
+        if overflowed && ring.overflows() == 1 {
+            warn!(
+                "The completion queue has overflowed, consider increasing ring_entries to handle \
+                 this many operations at once"
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Arms the timer waking the event loop up for periodic maintenance.
+    fn start_tick(&mut self) -> Result<()> {
+        let sqe = Timeout::new(&*self.tick)
+            .build()
+            .user_data(Route::Tick.into());
+        let mut ring = self.ring.borrow_mut();
+        unsafe { ring.push(&[sqe]) }.context("Push maintenance tick")?;
+        Ok(())
+    }
+
+    fn handle_tick(&mut self, cqe: Cqe) {
+        if cqe.result() != -libc::ETIME {
+            error!("Maintenance tick error: {}", Errno(-cqe.result()));
+        }
+
+        self.maintain();
+
+        if let Err(err) = self.start_tick() {
+            error!("{err:#}");
         }
     }
 
-    fn handle_client(&mut self, cqe: Cqe, id: Id) {
-        if let Some(task) = self.clients.get_mut(&id) {
-            *task.cqe.borrow_mut() = Some(cqe);
+    /// Does the periodic work which isn't worth checking on every event.
+    fn maintain(&mut self) {
+        let dropped = self.ring.borrow_mut().dropped();
+
+        if dropped > self.dropped_completions {
+            warn!("The kernel has dropped {dropped} completions in total");
+            self.dropped_completions = dropped;
+        }
+
+        metrics::set(&self.counters.overflows, self.ring.borrow().overflows());
+        metrics::set(&self.counters.dropped, dropped as u64);
+        metrics::set(
+            &self.counters.sq_peak,
+            std::mem::take(&mut self.sq_peak) as u64,
+        );
+        metrics::set(
+            &self.counters.cq_peak,
+            std::mem::take(&mut self.cq_peak) as u64,
+        );
+
+        let (allocated, free) = self.buffer_pool.usage();
+        let in_use = allocated.saturating_sub(free + self.buffer_ring.available());
+        metrics::set(&self.counters.buffers, allocated as u64);
+        metrics::set(&self.counters.buffers_in_use, in_use as u64);
 
-            match task.poll() {
-                Poll::Pending => return,
-                Poll::Ready(Ok(())) => (),
-                Poll::Ready(Err(err)) => eprintln!("Client #{id} failed: {err:#}"),
+        if let Some(interval) = self.stats_interval {
+            if self.last_report.0.elapsed() >= interval {
+                self.report();
             }
+        }
 
-            self.clients.remove(&id);
-        } else {
-            eprintln!("Missing client #{id}");
+        if self.soak.as_mut().is_some_and(Soak::is_due) {
+            self.check_soak();
+        }
+
+        if let Some(threshold) = self.buffer_hold_warning {
+            for (idx, holder, held) in self.buffer_pool.overdue(threshold) {
+                warn!("Buffer #{idx} has been held by {holder} for {held:.1?}");
+            }
+        }
+
+        for class in 0..self.buffer_pool.classes() {
+            let provided = self.buffer_ring.provided(class);
+            let delay = self.buffers_shrink_delay;
+            let mut ring = self.ring.borrow_mut();
+
+            if let Err(err) = self.buffer_pool.shrink(class, provided, delay, &mut *ring) {
+                error!("{err:#}");
+            }
         }
     }
-}
 
-struct Task {
-    fut: Pin<Box<dyn Future<Output = Result<()>>>>,
-    cqe: Rc<RefCell<Option<Cqe>>>,
-}
+    /// Provides the free buffers to the kernel, growing the pool by the size classes it has
+    /// run out of.
+    fn replenish(&mut self) {
+        self.buffer_ring.replenish(&self.buffer_pool);
+        let mut grown = false;
 
-impl Task {
-    fn poll(&mut self) -> Poll<Result<()>> {
-        let raw_waker = RawWaker::new(&(), &VTABLE_STUB);
-        let waker = unsafe { Waker::from_raw(raw_waker) };
-        let mut cx = Context::from_waker(&waker);
-        Pin::new(&mut self.fut).poll(&mut cx)
+        for class in 0..self.buffer_pool.classes() {
+            if self.buffer_ring.provided(class) > 0 {
+                continue;
+            }
+
+            match self.buffer_pool.grow(class, &mut *self.ring.borrow_mut()) {
+                Ok(true) => grown = true,
+                Ok(false) => (),
+                Err(err) => error!("{err:#}"),
+            }
+        }
+
+        if grown {
+            self.buffer_ring.replenish(&self.buffer_pool);
+        }
+    }
+
+    /// Takes back the buffer which the kernel has failed to get so that it's provided again.
+    fn handle_provide_buffer(&mut self, cqe: Cqe, bid: u16) {
+        if cqe.result() < 0 {
+            error!("Provide buffer #{bid}: {}", Errno(-cqe.result()));
+            self.buffer_ring.take(bid, 0).ok();
+        }
+    }
+
+    fn handle_accept(&mut self, cqe: Cqe, listener_id: ListenerId) {
+        if !io_uring::cqueue::more(cqe.flags()) {
+            if let Some(armed) = self.accept_armed.get_mut(listener_id as usize) {
+                *armed = false;
+            }
+
+            // Accepting may have been resumed before the cancellation completed. Successful
+            // accepts also end the multishot when their completion doesn't fit into the queue.
+            if cqe.result() == -libc::ECANCELED || cqe.result() >= 0 {
+                if self.shutdown_deadline.is_none() && !self.accept_paused {
+                    if let Err(err) = self.start_accepting() {
+                        error!("{err:#}");
+                    }
+                }
+            } else {
+                warn!("The acceptor #{listener_id} will not accept anymore");
+            }
+
+            if cqe.result() == -libc::ECANCELED {
+                return;
+            }
+        }
+
+        if cqe.result() < 0 {
+            error!("Accept error: {}", Errno(-cqe.result()));
+            return;
+        }
+
+        let fd = unsafe { OwnedFd::from_raw_fd(RawFd::from(cqe.result())) };
+        self.limit_accepts();
+        let peer = peer_addr(&fd);
+
+        // Closed as it's dropped.
+        if !self.is_allowed(peer) {
+            match peer {
+                Some(peer) => debug!("Denied connection from {peer}"),
+                None => debug!("Denied connection from an unknown peer"),
+            }
+
+            metrics::add(&self.counters.denied, 1);
+            return;
+        }
+
+        if self.is_full() {
+            let Some(fd) = self.hand_off(fd) else {
+                return;
+            };
+
+            warn!("Too many connections, rejecting client");
+            metrics::add(&self.counters.rejected, 1);
+            reject(&fd);
+            return;
+        }
+
+        self.start_client(fd, peer);
+    }
+
+    /// Takes the client another worker has handed off unless this one is full as well.
+    fn handle_handoff(&mut self, cqe: Cqe) {
+        let fd = unsafe { OwnedFd::from_raw_fd(RawFd::from(cqe.result())) };
+
+        // Closed right away like the clients the listeners haven't accepted anymore.
+        if self.shutdown_deadline.is_some() {
+            return;
+        }
+
+        if self.is_full() {
+            warn!("Too many connections, rejecting handed off client");
+            metrics::add(&self.counters.rejected, 1);
+            reject(&fd);
+            return;
+        }
+
+        let peer = peer_addr(&fd);
+        self.start_client(fd, peer);
+    }
+
+    /// Hands the client off to the next worker in turn by posting its fd to the worker's ring.
+    /// Returns the socket back if there's nobody to hand it off to.
+    fn hand_off(&mut self, fd: OwnedFd) -> Option<OwnedFd> {
+        let Some(ref mesh) = self.mesh else {
+            return Some(fd);
+        };
+
+        if !self.handoff {
+            return Some(fd);
+        }
+
+        let Some((worker_id, ring)) = mesh.next_peer(self.worker_id, self.handoff_peer) else {
+            return Some(fd);
+        };
+
+        self.handoff_peer = worker_id;
+
+        self.spawn(
+            format!("Handoff to worker #{worker_id}"),
+            move |io| async move {
+                let sqe = MsgRingData::new(Fd(ring), fd.as_raw_fd(), Route::Handoff.into(), None);
+
+                match io.submit(sqe.build(), "handoff").await?.result() {
+                    errno if errno < 0 => {
+                        reject(&fd);
+                        bail!("Handoff error: {}", Errno(-errno));
+                    }
+                    _ => {
+                        // The other worker owns it from now on.
+                        let _ = fd.into_raw_fd();
+                        Ok(())
+                    }
+                }
+            },
+        );
+
+        None
+    }
+
+    fn start_client(&mut self, fd: OwnedFd, peer: Option<SocketAddr>) {
+        let raw_fd = fd.as_raw_fd();
+
+        // Closed as it's dropped.
+        if !middleware::accept(&self.middleware, peer) {
+            metrics::add(&self.counters.rejected, 1);
+            return;
+        }
+
+        if let Some(ip) = peer.map(|peer| peer.ip().to_canonical()) {
+            if !self.connections_per_ip.add(ip, self.max_connections_per_ip) {
+                warn!("Too many connections from {ip}, rejecting client");
+                metrics::add(&self.counters.rejected, 1);
+                reject(&fd);
+                return;
+            }
+        }
+
+        // The options are TCP's, which vsock clients don't have an IP address of.
+        if peer.is_some() {
+            if let Err(err) = set_socket_options(&fd, &self.socket_options) {
+                warn!("Set socket options: {err:#}");
+            }
+        }
+
+        let vsock_peer = peer.is_none().then(|| vsock_peer_addr(&fd)).flatten();
+        let credentials = (peer.is_none() && vsock_peer.is_none())
+            .then(|| peer_credentials(&fd))
+            .flatten();
+        let (id, generation) = self.clients.reserve();
+        let io = Io::new(Rc::clone(&self.ring), self.operations.clone());
+        let buffers = self.buffer_ring.clone();
+        // Accepted as accepting was being cancelled, e.g. after handing the listeners over, so
+        // too late to be in the middle of anything: served until the deadline rather than
+        // disconnected as waiting for data.
+        let draining = match self.shutdown_deadline {
+            Some(_) => Rc::default(),
+            None => Rc::clone(&self.draining),
+        };
+        let counters = Arc::clone(&self.counters);
+        let options = self.client_options;
+        let mut client = Client::new(id, fd, buffers, io, options, draining, counters);
+        metrics::add(&self.counters.accepted, 1);
+        metrics::add(&self.counters.active, 1);
+
+        let span: Span = match (peer, vsock_peer, credentials) {
+            (Some(peer), _, _) => {
+                format!("client{{worker={} id={id} peer={peer}}}", self.worker_id)
+            }
+            (None, Some((cid, port)), _) => format!(
+                "client{{worker={} id={id} peer=vsock:{cid}:{port}}}",
+                self.worker_id
+            ),
+            (None, None, Some(credentials)) => format!(
+                "client{{worker={} id={id} {}}}",
+                self.worker_id,
+                describe_credentials(credentials)
+            ),
+            (None, None, None) => format!("client{{worker={} id={id}}}", self.worker_id),
+        }
+        .into();
+
+        log::in_span(Some(&span), || info!("Accepted"));
+
+        let info = ConnectionInfo {
+            id,
+            worker_id: self.worker_id,
+            peer,
+            credentials,
+        };
+
+        if !self.middleware.is_empty() {
+            let hooks = Hooks::new(Rc::clone(&self.middleware), info);
+            hooks.connected();
+            client = client.with_hooks(hooks);
+        }
+
+        let mut recv_cqes = None;
+        let mut upstream_recv_cqes = None;
+        let limits = client.limits();
+
+        if self.client_options.multishot {
+            let cqes = CqeQueue::default();
+            client = client.with_multishot(self.multishot(
+                &cqes,
+                Route::Receive(generation, id),
+                Some((Route::IdleTimer(generation, id), Rc::clone(&limits))),
+            ));
+            recv_cqes = Some(cqes);
+        }
+
+        if let Some(address) = self.forward {
+            let io = Io::new(Rc::clone(&self.ring), self.operations.clone());
+            let mut multishot = None;
+
+            if self.client_options.multishot {
+                let cqes = CqeQueue::default();
+                multishot =
+                    Some(self.multishot(&cqes, Route::UpstreamReceive(generation, id), None));
+                upstream_recv_cqes = Some(cqes);
+            }
+
+            client = client.with_upstream(Upstream::new(address, io, multishot));
+        }
+
+        if let Some(ref peers) = self.peers {
+            client = client.with_peers(Rc::clone(peers));
+        }
+
+        if let Some(ref response) = self.response {
+            client = client.with_response(Rc::clone(response));
+        }
+
+        let stats = client.stats();
+        let fut = (self.serve)(Connection::new(client, info));
+
+        let mut task = Task {
+            fut,
+            waker: self.waker(TaskId::Client(id, generation)),
+            recv_cqes,
+            upstream_recv_cqes,
+            span: Some(span),
+            peer,
+            credentials,
+            stats: Some(stats),
+            limits: Some(limits),
+            socket: Some(raw_fd),
+            kicked: false,
+        };
+
+        match task.poll() {
+            Poll::Pending => self.clients.fill(id, task),
+            Poll::Ready(result) => {
+                self.clients.remove(id, generation);
+                self.finish_client(id, task, result);
+            }
+        }
+    }
+
+    /// Creates a multishot receive routing its completions to `cqes`; only the client side
+    /// is subject to the idle timeout, checked with a timer routed to the `timer` route along
+    /// with the client's limits.
+    fn multishot(
+        &self,
+        cqes: &CqeQueue,
+        route: Route,
+        timer: Option<(Route, Rc<Limits>)>,
+    ) -> Multishot {
+        let mut stream = Stream::new(Rc::clone(&self.ring), Rc::clone(cqes), route);
+        let mut limits = None;
+
+        if let Some((timer_route, client_limits)) = timer {
+            stream = stream.with_timer_route(timer_route);
+            limits = Some(client_limits);
+        }
+
+        Multishot::new(stream, self.buffer_ring.clone(), limits)
+    }
+
+    /// Starts the cooldown once the connection just accepted is over the accept rate. Those
+    /// accepted before the cancellation completes are still served.
+    fn limit_accepts(&mut self) {
+        let Some(ref accept_rate) = self.accept_rate else {
+            return;
+        };
+
+        let now = Instant::now();
+        accept_rate.take(1.0, now);
+
+        if accept_rate.delay(now).is_none() || self.cooldown_until.is_some() {
+            return;
+        }
+
+        warn!(
+            "Accepting over {} connections per second, pausing for {:?}",
+            accept_rate.rate(),
+            self.accept_cooldown,
+        );
+
+        metrics::add(&self.counters.accept_cooldowns, 1);
+        self.cooldown_until = Some(Instant::now() + self.accept_cooldown);
+        *self.cooldown_timer = Timespec::from(self.accept_cooldown);
+
+        let sqe = Timeout::new(&*self.cooldown_timer)
+            .build()
+            .user_data(Route::Timeout.into());
+
+        if let Err(err) = unsafe { self.ring.borrow_mut().push(&[sqe]) } {
+            error!("Push accept cooldown: {err:#}");
+        }
+    }
+
+    /// Cancels accepting while the kernel has no buffers to read into or during the cooldown,
+    /// so that new connections wait in the listen backlog meanwhile, and resumes it afterwards.
+    fn update_accepting(&mut self) {
+        if self.shutdown_deadline.is_some() {
+            return;
+        }
+
+        if self
+            .cooldown_until
+            .is_some_and(|until| Instant::now() >= until)
+        {
+            self.cooldown_until = None;
+        }
+
+        let available = self.buffer_ring.available();
+        let paused = available == 0 || self.cooldown_until.is_some();
+
+        if paused && !self.accept_paused {
+            if available == 0 {
+                info!("Running out of buffers, pausing accepting");
+            }
+
+            self.accept_paused = true;
+
+            if let Err(err) = self.cancel_accepting() {
+                error!("Pause accepting: {err:#}");
+            }
+        } else if !paused && self.accept_paused {
+            info!("Resuming accepting");
+            self.accept_paused = false;
+
+            if let Err(err) = self.start_accepting() {
+                error!("Resume accepting: {err:#}");
+            }
+        }
+    }
+
+    fn cancel_accepting(&mut self) -> Result<()> {
+        let mut ring = self.ring.borrow_mut();
+
+        for (listener_id, _) in self
+            .accept_armed
+            .iter()
+            .enumerate()
+            .filter(|(_, &armed)| armed)
+        {
+            let route = Route::Accept(listener_id as ListenerId);
+
+            let sqe = AsyncCancel::new(route.into())
+                .build()
+                .user_data(Route::Cancel.into());
+
+            unsafe { ring.push(&[sqe]) }.context("Push accept cancellation")?;
+        }
+
+        Ok(())
+    }
+
+    /// Whether the `peer` is in none of the denied networks and in one of the allowed ones if
+    /// there are any. Unknown peers are allowed only if everybody is.
+    fn is_allowed(&self, peer: Option<SocketAddr>) -> bool {
+        let Some(peer) = peer else {
+            return self.allow.is_empty() && self.deny.is_empty();
+        };
+
+        let ip = peer.ip();
+
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+
+    fn is_full(&self) -> bool {
+        self.max_connections
+            .is_some_and(|max_connections| self.clients.len() >= max_connections)
+    }
+
+    /// Polls the task of the client if it's still there, finishing it once it's done.
+    fn poll_client(&mut self, id: Id, generation: Generation) {
+        let Some(task) = self.clients.get_mut(id, generation) else {
+            return;
+        };
+
+        if let Poll::Ready(result) = task.poll() {
+            if let Some(task) = self.clients.remove(id, generation) {
+                self.finish_client(id, task, result);
+            }
+        }
+    }
+
+    fn handle_receive(&mut self, cqe: Cqe, id: Id, generation: Generation, upstream: bool) {
+        let Some(task) = self.clients.get_mut(id, generation) else {
+            // Completed after the client has finished, e.g. cancelled on teardown.
+            client::take_buffer(&self.buffer_ring, &cqe).ok();
+            return;
+        };
+
+        let cqes = if upstream {
+            &task.upstream_recv_cqes
+        } else {
+            &task.recv_cqes
+        };
+
+        let Some(cqes) = cqes else {
+            error!("Unexpected multishot receive of client #{id}");
+            client::take_buffer(&self.buffer_ring, &cqe).ok();
+            return;
+        };
+
+        cqes.borrow_mut().push_back(cqe);
+        self.poll_client(id, generation);
+    }
+
+    fn handle_signal(&mut self, cqe: Cqe) {
+        if cqe.result() < 0 {
+            error!("Read signal error: {}", Errno(-cqe.result()));
+            return;
+        }
+
+        self.on_signal(self.signal_info.ssi_signo);
+
+        if let Err(err) = self.read_signal() {
+            error!("{err:#}");
+        }
+    }
+
+    fn on_signal(&mut self, signal: u32) {
+        if SHUTDOWN_SIGNALS.contains(&(signal as libc::c_int))
+            || signal as libc::c_int == DRAIN_SIGNAL
+        {
+            if let Err(err) = self.shutdown(signal) {
+                error!("Shutdown: {err:#}");
+            }
+        } else if signal as libc::c_int == RELOAD_SIGNAL {
+            self.reload();
+        } else if signal as libc::c_int == STATS_SIGNAL {
+            self.report();
+        }
+    }
+
+    /// Runs the commands sent since the last wakeup. Those sent after taking them bump the
+    /// eventfd again, so none is left behind until the next one.
+    fn handle_remote(&mut self, cqe: Cqe) {
+        if cqe.result() < 0 {
+            error!("Read remote error: {}", Errno(-cqe.result()));
+            return;
+        }
+
+        let Some(commands) = self.remote.as_ref().map(Remote::take) else {
+            return;
+        };
+
+        for command in commands {
+            match command {
+                Command::Signal(signal) => self.on_signal(signal),
+                Command::Admin(request, reply) => {
+                    if let Err(err) = reply.add(self.answer(request)) {
+                        error!("Admin reply: {err:#}");
+                    }
+                }
+            }
+        }
+
+        if let Err(err) = self.read_remote() {
+            error!("{err:#}");
+        }
+    }
+
+    /// Rearms the read of the ready queue's eventfd, the woken tasks themselves are polled
+    /// after dispatching the completion.
+    fn handle_wake(&mut self, cqe: Cqe) {
+        if cqe.result() < 0 {
+            error!("Read ready queue error: {}", Errno(-cqe.result()));
+            return;
+        }
+
+        if let Err(err) = self.read_ready() {
+            error!("{err:#}");
+        }
+    }
+
+    /// Logs a summary of the worker: clients, free buffers, throughput since the previous
+    /// summary, and the clients which have read and written the most since they've connected.
+    fn report(&mut self) {
+        let (allocated, free) = self.buffer_pool.usage();
+        let free = free + self.buffer_ring.available();
+
+        let (since, last_read, last_written) = self.last_report;
+        let elapsed = since.elapsed().as_secs_f64();
+        let read = metrics::get(&self.counters.bytes_read);
+        let written = metrics::get(&self.counters.bytes_written);
+        self.last_report = (Instant::now(), read, written);
+
+        let mut talkers = self
+            .clients
+            .iter()
+            .filter_map(|(id, task)| {
+                let stats = task.stats.as_ref()?;
+                Some((
+                    id,
+                    task.peer,
+                    stats.bytes_read.get() + stats.bytes_written.get(),
+                ))
+            })
+            .collect::<Vec<_>>();
+
+        talkers.sort_unstable_by_key(|&(_, _, bytes)| std::cmp::Reverse(bytes));
+
+        let top = talkers
+            .iter()
+            .take(TOP_TALKERS)
+            .map(|&(id, peer, bytes)| match peer {
+                Some(peer) => format!("#{id}@{peer}:{bytes}"),
+                None => format!("#{id}:{bytes}"),
+            })
+            .collect::<Vec<_>>()
+            .join(",");
+
+        info!(
+            "Worker #{} stats clients={} buffers_free={free}/{allocated} read_bps={:.0} \
+             written_bps={:.0} top={}",
+            self.worker_id,
+            self.clients.len(),
+            (read - last_read) as f64 / elapsed,
+            (written - last_written) as f64 / elapsed,
+            match top.is_empty() {
+                true => "-",
+                false => &top,
+            },
+        );
+    }
+
+    /// Checks that every buffer is either free, provided or held by a client which is still
+    /// there, and that the clients and their open sockets are all counted, stopping the server
+    /// otherwise. The first worker logs the growth of the memory and descriptors of the
+    /// process as well, which should follow the connections: the other workers open and close
+    /// descriptors meanwhile, so the count of the whole process isn't exact to check.
+    fn check_soak(&mut self) {
+        let mut violations = Vec::new();
+        let (allocated, free) = self.buffer_pool.usage();
+        let holders = self.buffer_pool.holders();
+
+        if free + holders.len() != allocated {
+            violations.push(format!(
+                "{allocated} buffers allocated but {free} free and {} held",
+                holders.len()
+            ));
+        }
+
+        let by_kernel = holders
+            .iter()
+            .filter(|holder| matches!(holder, Holder::Kernel))
+            .count();
+
+        if by_kernel != self.buffer_ring.available() {
+            violations.push(format!(
+                "{by_kernel} buffers held by the kernel but {} provided",
+                self.buffer_ring.available()
+            ));
+        }
+
+        let clients = self
+            .clients
+            .iter()
+            .map(|(id, _)| id)
+            .collect::<HashSet<_>>();
+
+        for holder in holders {
+            if let Holder::Client(id) | Holder::Upstream(id) = holder {
+                if !clients.contains(&id) {
+                    violations.push(format!("A buffer is held by the finished {holder}"));
+                }
+            }
+        }
+
+        let active = metrics::get(&self.counters.active);
+
+        if active != self.clients.len() as u64 {
+            violations.push(format!(
+                "{} clients but {active} counted as active",
+                self.clients.len()
+            ));
+        }
+
+        let sockets = metrics::get(&self.counters.sockets);
+
+        if sockets != self.clients.len() as u64 {
+            violations.push(format!(
+                "{} clients but {sockets} client sockets open",
+                self.clients.len()
+            ));
+        }
+
+        if !violations.is_empty() {
+            for violation in &violations {
+                error!("Soak check: {violation}");
+            }
+
+            self.fatal = Some(anyhow!("Soak check failed: {}", violations.join(", ")));
+            return;
+        }
+
+        debug!("Soak check passed");
+
+        if self.worker_id != 0 {
+            return;
+        }
+
+        let Some(ref mut soak) = self.soak else {
+            return;
+        };
+
+        match soak.usage() {
+            Ok((usage, baseline)) => info!(
+                "Soak rss_kib={} rss_growth_kib={:+} fds={} fds_growth={:+} connections={}",
+                usage.rss / 1024,
+                (usage.rss as i64 - baseline.rss as i64) / 1024,
+                usage.fds,
+                usage.fds as i64 - baseline.fds as i64,
+                self.metrics.snapshot().active,
+            ),
+            Err(err) => warn!("Soak usage: {err:#}"),
+        }
+    }
+
+    /// This worker's part of the reply to an admin session.
+    fn answer(&mut self, request: Request) -> Vec<String> {
+        match request {
+            Request::ListClients => self
+                .clients
+                .iter()
+                .map(|(id, task)| self.describe(id, task))
+                .collect(),
+            Request::Kick { worker_id, id } if worker_id == self.worker_id => {
+                let Some((_, generation)) = self.clients.keys().find(|&(key, _)| key == id) else {
+                    return Vec::new();
+                };
+
+                let Some(task) = self.clients.get_mut(id, generation) else {
+                    return Vec::new();
+                };
+
+                // The client sees the end of the stream and finishes as usual.
+                if let Some(socket) = task.socket {
+                    unsafe { libc::shutdown(socket, libc::SHUT_RDWR) };
+                }
+
+                task.kicked = true;
+                log::in_span(task.span.as_ref(), || info!("Kicked by admin"));
+
+                match self.clients.iter().find(|&(key, _)| key == id) {
+                    Some((id, task)) => vec![self.describe(id, task)],
+                    None => Vec::new(),
+                }
+            }
+            Request::Kick { .. } => Vec::new(),
+            Request::Set { setting, existing } => {
+                match setting {
+                    Setting::MaxConnections(max_connections) => {
+                        self.max_connections = max_connections;
+                    }
+                    Setting::IdleTimeout(idle_timeout) => {
+                        self.client_options.idle_timeout = idle_timeout;
+                    }
+                    Setting::RateLimitBytes(bytes_per_sec) => {
+                        self.client_options.rate_limit.bytes_per_sec = bytes_per_sec;
+                    }
+                    Setting::RateLimitMessages(messages_per_sec) => {
+                        self.client_options.rate_limit.messages_per_sec = messages_per_sec;
+                    }
+                }
+
+                if !existing {
+                    return Vec::new();
+                }
+
+                let mut changed = 0;
+
+                for (_, task) in self.clients.iter() {
+                    let Some(ref limits) = task.limits else {
+                        continue;
+                    };
+
+                    let mut rate_limit = limits.rate_limit();
+
+                    match setting {
+                        Setting::MaxConnections(_) => continue,
+                        Setting::IdleTimeout(idle_timeout) => limits.set_idle_timeout(idle_timeout),
+                        Setting::RateLimitBytes(bytes_per_sec) => {
+                            rate_limit.bytes_per_sec = bytes_per_sec;
+                            limits.set_rate_limit(rate_limit);
+                        }
+                        Setting::RateLimitMessages(messages_per_sec) => {
+                            rate_limit.messages_per_sec = messages_per_sec;
+                            limits.set_rate_limit(rate_limit);
+                        }
+                    }
+
+                    changed += 1;
+                }
+
+                vec![format!("worker={} changed={changed}", self.worker_id)]
+            }
+        }
+    }
+
+    /// A line about the client for the admin.
+    fn describe(&self, id: Id, task: &Task) -> String {
+        let mut line = format!("worker={} id={id}", self.worker_id);
+
+        if let Some(peer) = task.peer {
+            line += &format!(" peer={peer}");
+        }
+
+        if let Some(credentials) = task.credentials {
+            line += &format!(" {}", describe_credentials(credentials));
+        }
+
+        if let Some(ref stats) = task.stats {
+            line += &format!(
+                " duration={:.3}s bytes_in={} bytes_out={} messages={}",
+                stats.started.elapsed().as_secs_f64(),
+                stats.bytes_read.get(),
+                stats.bytes_written.get(),
+                stats.messages.get(),
+            );
+        }
+
+        line
+    }
+
+    fn reload(&mut self) {
+        let Some(ref config_loader) = self.config_loader else {
+            warn!("Nowhere to reload the config from");
+            return;
+        };
+
+        match config_loader() {
+            Ok(config) => {
+                self.apply_runtime_config(&config);
+                info!("Reloaded runtime config");
+            }
+            Err(err) => warn!("Reload config failed, keeping the current one: {err:#}"),
+        }
+    }
+
+    /// Stops accepting and lets clients finish their current exchanges until the deadline,
+    /// the drain one for [`DRAIN_SIGNAL`]. A repeated signal makes the server stop immediately.
+    fn shutdown(&mut self, signal: u32) -> Result<()> {
+        if signal as libc::c_int == DRAIN_SIGNAL {
+            self.drain = true;
+            self.handle.set_drained();
+        }
+
+        if self.shutdown_deadline.is_some() {
+            info!("Received {} again, stopping", signal::name(signal));
+            self.shutdown_deadline = Some(Instant::now());
+            return Ok(());
+        }
+
+        let timeout = match self.drain {
+            true => {
+                info!("Received {}, draining", signal::name(signal));
+                self.drain_timeout
+            }
+            false => {
+                info!("Received {}, shutting down", signal::name(signal));
+                self.shutdown_timeout
+            }
+        };
+
+        self.shutdown_deadline = Some(Instant::now() + timeout);
+        self.draining.set(true);
+
+        // Let the clients waiting for data know that they are to disconnect.
+        self.ready.extend(
+            self.clients
+                .keys()
+                .map(|(id, generation)| TaskId::Client(id, generation)),
+        );
+
+        self.cancel_accepting()?;
+
+        if let Some(ref handover) = self.handover {
+            handover.withdraw();
+        }
+
+        // The ring holds its own references to the listeners until the cancellation completes.
+        self.listeners.clear();
+        Ok(())
+    }
+
+    /// Sends the goodbye, if any, to the clients left at the drain deadline and shuts their
+    /// sockets down so that they see it followed by the end of the stream. A goodbye which
+    /// doesn't fit into the socket buffer right away is cut short rather than waited for.
+    fn say_goodbye(&self) {
+        let goodbye = self.drain_goodbye.as_deref().unwrap_or_default().as_bytes();
+
+        for (_, task) in self.clients.iter() {
+            let Some(socket) = task.socket else {
+                continue;
+            };
+
+            log::in_span(task.span.as_ref(), || info!("Disconnected by drain"));
+
+            unsafe {
+                if !goodbye.is_empty() {
+                    let flags = libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL;
+                    libc::send(socket, goodbye.as_ptr().cast(), goodbye.len(), flags);
+                }
+
+                libc::shutdown(socket, libc::SHUT_RDWR);
+            }
+        }
+    }
+
+    /// Logs the access line of the client removed from the slab along with its failure if any.
+    fn finish_client(&mut self, id: Id, task: Task, result: Result<()>) {
+        // Nobody is to broadcast to the client once it has finished.
+        if let Some(ref peers) = self.peers {
+            peers.borrow_mut().remove(&id);
+        }
+
+        metrics::sub(&self.counters.active, 1);
+
+        if let Some(ip) = task.peer.map(|peer| peer.ip().to_canonical()) {
+            self.connections_per_ip.remove(ip);
+        }
+
+        let fatal = log::in_span(task.span.as_ref(), || {
+            let (reason, err) = match result {
+                // Whatever the client has got into once its socket has been shut down.
+                _ if task.kicked => ("kicked", None),
+                Ok(()) if self.draining.get() => ("shutdown", None),
+                Ok(()) => ("closed", None),
+                Err(err) => match Error::of(&err) {
+                    Some(Error::IdleTimeout) => ("idle-timeout", None),
+                    Some(Error::FrameTimeout) => ("frame-timeout", None),
+                    Some(Error::SlowConsumer) => ("slow-consumer", None),
+                    Some(Error::ChaosReset) => ("chaos-reset", None),
+                    Some(kind) if kind.is_disconnect() => ("left", Some(err)),
+                    _ => ("failed", Some(err)),
+                },
+            };
+
+            if let Some(ref stats) = task.stats {
+                access_log(task.peer, task.credentials, stats, reason, err.as_ref());
+            }
+
+            let err = err.filter(|_| reason == "failed")?;
+            metrics::add(&self.counters.errors, 1);
+
+            match Error::of(&err) {
+                Some(kind) if kind.is_fatal() => {
+                    error!("Failed, stopping: {err:#}");
+                    Some(err)
+                }
+                _ => {
+                    error!("Failed: {err:#}");
+                    None
+                }
+            }
+        });
+
+        if let Some(err) = fatal {
+            self.fatal = Some(err.context(format!("Client #{id}")));
+        }
+    }
+
+    fn poll_task(&mut self, id: Id, generation: Generation) {
+        let Some((name, task)) = self.tasks.get_mut(id, generation) else {
+            return;
+        };
+
+        match task.poll() {
+            Poll::Pending => return,
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(err)) => error!("{name} failed: {err:#}"),
+        }
+
+        self.tasks.remove(id, generation);
+    }
+}
+
+/// A ring setup flag's name for notices along with the builder method setting it.
+type SetupFlag = (&'static str, fn(&mut Builder) -> &mut Builder);
+
+/// Builds the ring with the configured setup flags, dropping those the kernel doesn't support
+/// starting from the most recent ones.
+fn build_ring(config: &ServerConfig, sqpoll_cpu: Option<u32>) -> Result<IoUring> {
+    if config.defer_taskrun && config.sqpoll_idle_ms.is_some() {
+        bail!("DEFER_TASKRUN and SQPOLL are mutually exclusive");
+    }
+
+    if sqpoll_cpu.is_some() && config.sqpoll_idle_ms.is_none() {
+        bail!("Pinning the SQPOLL thread requires SQPOLL");
+    }
+
+    // In the order of appearance in the kernel.
+    let mut flags: Vec<SetupFlag> = Vec::new();
+
+    if config.coop_taskrun {
+        flags.push(("COOP_TASKRUN", Builder::setup_coop_taskrun));
+    }
+
+    // DEFER_TASKRUN requires SINGLE_ISSUER.
+    if config.single_issuer || config.defer_taskrun {
+        flags.push(("SINGLE_ISSUER", Builder::setup_single_issuer));
+    }
+
+    if config.defer_taskrun {
+        flags.push(("DEFER_TASKRUN", Builder::setup_defer_taskrun));
+    }
+
+    loop {
+        let mut builder = IoUring::builder();
+
+        if let Some(idle_ms) = config.sqpoll_idle_ms {
+            builder.setup_sqpoll(idle_ms);
+        }
+
+        if let Some(cpu) = sqpoll_cpu {
+            builder.setup_sqpoll_cpu(cpu);
+        }
+
+        for (_, setup) in &flags {
+            setup(&mut builder);
+        }
+
+        // Restricted before anything is submitted, which only a disabled ring can be.
+        if config.seccomp {
+            builder.setup_r_disabled();
+        }
+
+        let ring = match builder.build(config.ring_entries) {
+            Err(err) if err.raw_os_error() == Some(libc::EINVAL) && !flags.is_empty() => {
+                if let Some((name, _)) = flags.pop() {
+                    info!("The kernel doesn't support {name}, disabling it");
+                }
+
+                continue;
+            }
+            result => result
+                .map_err(|err| utils::explain(err, RING_HINTS))
+                .context("Build io_uring")?,
+        };
+
+        if config.seccomp {
+            seccomp::restrict_ring(&ring)?;
+        }
+
+        return Ok(ring);
+    }
+}
+
+/// Polls for completions without entering the kernel for up to `budget`. Returns whether any
+/// have arrived.
+fn spin(ring: &mut dyn Backend, budget: Duration) -> bool {
+    let start = Instant::now();
+
+    while !ring.is_ready() {
+        if start.elapsed() >= budget {
+            return false;
+        }
+
+        std::hint::spin_loop();
+    }
+
+    true
+}
+
+/// Picks the item for the `n`th worker, starting over when there are fewer than workers.
+fn nth_wrapping<T>(items: &[T], n: usize) -> Option<&T> {
+    match items.len() {
+        0 => None,
+        len => items.get(n % len),
+    }
+}
+
+/// Expands the configured address into addresses to bind along with their `IPV6_V6ONLY` flag.
+fn bind_addresses(address: SocketAddr, mode: Ipv6Mode) -> Result<Vec<(SocketAddr, bool)>> {
+    let IpAddr::V6(ip) = address.ip() else {
+        return Ok(vec![(address, false)]);
+    };
+
+    let addresses = match mode {
+        Ipv6Mode::Only => vec![(address, true)],
+        Ipv6Mode::DualStack => vec![(address, false)],
+        Ipv6Mode::Separate => {
+            let ipv4 = if ip.is_unspecified() {
+                Ipv4Addr::UNSPECIFIED
+            } else if ip.is_loopback() {
+                Ipv4Addr::LOCALHOST
+            } else if let Some(ipv4) = ip.to_ipv4_mapped() {
+                ipv4
+            } else {
+                bail!("No IPv4 counterpart for {ip} to listen on separately");
+            };
+
+            vec![
+                (address, true),
+                (SocketAddr::new(ipv4.into(), address.port()), false),
+            ]
+        }
+    };
+
+    Ok(addresses)
+}
+
+fn socket(
+    address: SocketAddr,
+    ty: Type,
+    protocol: Option<Protocol>,
+    only_v6: bool,
+    reuse_port: bool,
+) -> Result<Socket> {
+    let domain = Domain::for_address(address);
+
+    let socket = match Socket::new(domain, ty, protocol) {
+        Err(err)
+            if protocol == Some(Protocol::MPTCP)
+                && err
+                    .raw_os_error()
+                    .is_some_and(|errno| MPTCP_UNSUPPORTED.contains(&errno)) =>
+        {
+            warn!("The kernel doesn't support MPTCP, listening on {address} with plain TCP");
+            Socket::new(domain, ty, None)
+        }
+        result => result,
+    }
+    .context("Socket")?;
+    socket.set_reuse_address(true).context("SO_REUSEADDR")?;
+
+    if reuse_port {
+        socket.set_reuse_port(true).context("SO_REUSEPORT")?;
+    }
+
+    if address.is_ipv6() {
+        socket.set_only_v6(only_v6).context("IPV6_V6ONLY")?;
+    }
+
+    socket
+        .bind(&address.into())
+        .map_err(|err| utils::explain(err, BIND_HINTS))
+        .context("Bind")?;
+    Ok(socket)
+}
+
+/// Listens with MPTCP if `mptcp` is set and the kernel supports it, which is plain TCP to the
+/// clients which don't.
+fn listen(
+    address: SocketAddr,
+    only_v6: bool,
+    reuse_port: bool,
+    backlog: i32,
+    mptcp: bool,
+) -> Result<Socket> {
+    let protocol = mptcp.then_some(Protocol::MPTCP);
+    let socket = socket(address, Type::STREAM, protocol, only_v6, reuse_port)?;
+    socket.listen(backlog).context("Listen")?;
+    Ok(socket)
+}
+
+fn listen_vsock(address: VsockAddr, backlog: i32) -> Result<Socket> {
+    let socket = Socket::new(Domain::VSOCK, Type::STREAM, None)
+        .map_err(|err| utils::explain(err, VSOCK_HINTS))
+        .context("Socket")?;
+
+    socket
+        .bind(&SockAddr::vsock(address.cid, address.port))
+        .map_err(|err| utils::explain(err, BIND_HINTS))
+        .context("Bind")?;
+
+    socket.listen(backlog).context("Listen")?;
+    Ok(socket)
+}
+
+/// Replaces a socket left by a server which hasn't exited cleanly unless somebody still listens
+/// on it.
+fn listen_unix(path: &Path, backlog: i32) -> Result<Socket> {
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            bail!("Not a socket");
+        }
+
+        if UnixStream::connect(path).is_ok() {
+            bail!("Another server listens on it");
+        }
+
+        std::fs::remove_file(path).context("Remove stale socket")?;
+    }
+
+    let socket = Socket::new(Domain::UNIX, Type::STREAM, None).context("Socket")?;
+
+    socket
+        .bind(&SockAddr::unix(path).context("Address")?)
+        .map_err(|err| utils::explain(err, BIND_HINTS))
+        .context("Bind")?;
+
+    socket.listen(backlog).context("Listen")?;
+    Ok(socket)
+}
+
+/// Binds a UDP socket which is told where each datagram has arrived so that it's echoed from
+/// there.
+fn bind_udp(address: SocketAddr, only_v6: bool, reuse_port: bool) -> Result<UdpSocket> {
+    let socket = socket(address, Type::DGRAM, None, only_v6, reuse_port)?;
+
+    // IPv4-mapped datagrams come with the IPv6 packet info as well.
+    let (level, name) = match address {
+        SocketAddr::V4(_) => (libc::IPPROTO_IP, libc::IP_PKTINFO),
+        SocketAddr::V6(_) => (libc::IPPROTO_IPV6, libc::IPV6_RECVPKTINFO),
+    };
+
+    utils::set_option(&socket, level, name, 1).context("Packet info")?;
+    Ok(socket.into())
+}
+
+fn set_socket_options(fd: &OwnedFd, options: &SocketOptions) -> Result<()> {
+    let socket = SockRef::from(fd);
+
+    if options.nodelay {
+        socket.set_tcp_nodelay(true).context("TCP_NODELAY")?;
+    }
+
+    if options.keepalive {
+        let mut keepalive = TcpKeepalive::new();
+
+        if let Some(secs) = options.keepalive_idle_secs {
+            keepalive = keepalive.with_time(Duration::from_secs(secs));
+        }
+
+        if let Some(secs) = options.keepalive_interval_secs {
+            keepalive = keepalive.with_interval(Duration::from_secs(secs));
+        }
+
+        if let Some(count) = options.keepalive_count {
+            keepalive = keepalive.with_retries(count);
+        }
+
+        socket
+            .set_tcp_keepalive(&keepalive)
+            .context("SO_KEEPALIVE")?;
+    }
+
+    if let Some(secs) = options.linger_secs {
+        socket
+            .set_linger(Some(Duration::from_secs(secs)))
+            .context("SO_LINGER")?;
+    }
+
+    if let Some(size) = options.recv_buffer_size {
+        socket.set_recv_buffer_size(size).context("SO_RCVBUF")?;
+    }
+
+    if let Some(size) = options.send_buffer_size {
+        socket.set_send_buffer_size(size).context("SO_SNDBUF")?;
+    }
+
+    Ok(())
+}
+
+/// Logs what the finished connection has done as `key=value` pairs, the error why it has
+/// closed quoted.
+fn access_log(
+    peer: Option<SocketAddr>,
+    credentials: Option<Credentials>,
+    stats: &Stats,
+    reason: &str,
+    err: Option<&anyhow::Error>,
+) {
+    let peer = match (peer, credentials) {
+        (Some(peer), _) => peer.to_string(),
+        (None, Some(credentials)) => format!("unix {}", describe_credentials(credentials)),
+        (None, None) => "-".into(),
+    };
+
+    let error = match err {
+        Some(err) => format!(" error={:?}", format!("{err:#}")),
+        None => String::new(),
+    };
+
+    info!(
+        "Closed peer={peer} duration={:.3}s bytes_in={} bytes_out={} messages={} reason={reason}{error}",
+        stats.started.elapsed().as_secs_f64(),
+        stats.bytes_read.get(),
+        stats.bytes_written.get(),
+        stats.messages.get(),
+    );
+}
+
+/// Unknown if the peer has disconnected already.
+fn peer_addr(fd: &OwnedFd) -> Option<SocketAddr> {
+    SockRef::from(fd)
+        .peer_addr()
+        .ok()
+        .and_then(|addr| addr.as_socket())
+}
+
+/// The context id and port of a vsock peer.
+fn vsock_peer_addr(fd: &OwnedFd) -> Option<(u32, u32)> {
+    SockRef::from(fd).peer_addr().ok()?.as_vsock_address()
+}
+
+/// Of the process at the other end of a UNIX socket.
+fn peer_credentials(fd: &OwnedFd) -> Option<Credentials> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of_val(&cred) as libc::socklen_t;
+
+    let result = unsafe {
+        libc::getsockopt(
+            fd.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut cred as *mut _ as *mut libc::c_void,
+            &mut len,
+        )
+    };
+
+    (result == 0).then_some(Credentials {
+        pid: cred.pid as u32,
+        uid: cred.uid,
+        gid: cred.gid,
+    })
+}
+
+fn describe_credentials(credentials: Credentials) -> String {
+    let Credentials { pid, uid, gid } = credentials;
+    format!("pid={pid} uid={uid} gid={gid}")
+}
+
+/// Tells the client that the server is full. The socket gets closed when dropped.
+fn reject(fd: &OwnedFd) {
+    const MESSAGE: &[u8] = b"Server full\n";
+
+    // The send buffer of a fresh socket is empty so this shouldn't fail but it must not block.
+    let flags = libc::MSG_DONTWAIT | libc::MSG_NOSIGNAL;
+    let res = unsafe {
+        libc::send(
+            fd.as_raw_fd(),
+            MESSAGE.as_ptr().cast(),
+            MESSAGE.len(),
+            flags,
+        )
+    };
+
+    if res < 0 {
+        error!("Reject error: {}", std::io::Error::last_os_error());
+    }
+}
+
+/// The path of a UNIX socket listened on, removed once the server is done with it.
+struct UnixPath(PathBuf);
+
+impl Drop for UnixPath {
+    fn drop(&mut self) {
+        // The next server listens on it then.
+        if !handover::is_handed_over() {
+            std::fs::remove_file(&self.0).ok();
+        }
+    }
+}
+
+struct Task {
+    fut: Pin<Box<dyn Future<Output = Result<()>>>>,
+    waker: Arc<TaskWaker>,
+    recv_cqes: Option<CqeQueue>,
+    upstream_recv_cqes: Option<CqeQueue>,
+    /// What the events are logged in while it's polled.
+    span: Option<Span>,
+    /// Of the client if it's a client.
+    peer: Option<SocketAddr>,
+    credentials: Option<Credentials>,
+    stats: Option<Rc<Stats>>,
+    limits: Option<Rc<Limits>>,
+    /// The client's socket owned by the future, valid while the task is.
+    socket: Option<RawFd>,
+    /// Whether the admin has disconnected the client.
+    kicked: bool,
+}
+
+impl Task {
+    fn poll(&mut self) -> Poll<Result<()>> {
+        // Wakes from now on are for the state after this poll.
+        self.waker.woken.store(false, Ordering::Release);
+
+        let waker = Waker::from(Arc::clone(&self.waker));
+        let mut cx = Context::from_waker(&waker);
+        log::in_span(self.span.as_ref(), || Pin::new(&mut self.fut).poll(&mut cx))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TaskId {
+    Client(Id, Generation),
+    Spawned(Id, Generation),
+}
+
+/// Tasks to poll once the completions are dispatched. Shared with the wakers which may be sent
+/// to other threads, hence the lock, and which bump the eventfd the worker keeps a read on
+/// if it's waiting for events, so that a wake from another thread interrupts the wait.
+struct ReadyQueue {
+    state: Mutex<ReadyState>,
+    eventfd: OwnedFd,
+}
+
+#[derive(Default)]
+struct ReadyState {
+    tasks: Vec<TaskId>,
+    /// Whether the worker has found no tasks to poll before waiting for events, until it's
+    /// woken up.
+    waiting: bool,
+}
+
+impl ReadyQueue {
+    fn new() -> Result<Self> {
+        Ok(Self {
+            state: Mutex::default(),
+            eventfd: utils::eventfd().context("Create ready queue eventfd")?,
+        })
+    }
+
+    fn push(&self, id: TaskId) {
+        let waiting = {
+            let mut state = self.state();
+            state.tasks.push(id);
+            std::mem::take(&mut state.waiting)
+        };
+
+        // Once for all the tasks woken until the worker takes them.
+        if waiting {
+            if let Err(err) = utils::bump(&self.eventfd) {
+                error!("Wake worker up: {err}");
+            }
+        }
+    }
+
+    /// Queues tasks from the worker itself, which polls them before waiting again.
+    fn extend(&self, ids: impl IntoIterator<Item = TaskId>) {
+        self.state().tasks.extend(ids);
+    }
+
+    /// Takes the tasks woken since the last call.
+    fn take(&self) -> Vec<TaskId> {
+        let mut state = self.state();
+        state.waiting = false;
+        std::mem::take(&mut state.tasks)
+    }
+
+    /// Whether there are no tasks to poll, in which case the worker is about to wait and the
+    /// next wake bumps the eventfd.
+    fn start_waiting(&self) -> bool {
+        let mut state = self.state();
+        state.waiting = state.tasks.is_empty();
+        state.waiting
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, ReadyState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Queues its task to be polled, once until the task is polled again.
+struct TaskWaker {
+    id: TaskId,
+    ready: Arc<ReadyQueue>,
+    woken: AtomicBool,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.wake_by_ref();
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        if !self.woken.swap(true, Ordering::AcqRel) {
+            self.ready.push(self.id);
+        }
     }
 }
@@ -0,0 +1,174 @@
+//! The classic small services of inetd to serve instead of echo: discard (RFC 863), chargen
+//! (RFC 864) and daytime (RFC 867).
+
+use std::sync::LazyLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use anyhow::Result;
+
+use crate::handler::{Connection, Handler};
+
+/// Characters per chargen line, not counting the `\r\n`.
+const CHARGEN_LINE_LEN: usize = 72;
+
+/// Printable ASCII the chargen lines rotate through.
+const CHARGEN_CHARS: std::ops::RangeInclusive<u8> = b' '..=b'~';
+
+/// All the chargen lines, each starting one character further than the previous one, after
+/// which the pattern repeats.
+static CHARGEN_PATTERN: LazyLock<Vec<u8>> = LazyLock::new(|| {
+    let chars = CHARGEN_CHARS.collect::<Vec<_>>();
+
+    (0..chars.len())
+        .flat_map(|start| {
+            chars
+                .iter()
+                .cycle()
+                .skip(start)
+                .take(CHARGEN_LINE_LEN)
+                .chain(b"\r\n")
+                .copied()
+                .collect::<Vec<_>>()
+        })
+        .collect()
+});
+
+const WEEKDAYS: [&str; 7] = [
+    "Thursday",
+    "Friday",
+    "Saturday",
+    "Sunday",
+    "Monday",
+    "Tuesday",
+    "Wednesday",
+];
+
+const MONTHS: [&str; 12] = [
+    "January",
+    "February",
+    "March",
+    "April",
+    "May",
+    "June",
+    "July",
+    "August",
+    "September",
+    "October",
+    "November",
+    "December",
+];
+
+/// Reads and throws away whatever the client sends until it closes the connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Discard;
+
+impl Handler for Discard {
+    async fn handle(&mut self, conn: &mut Connection) -> Result<()> {
+        while conn.read().await?.is_some() {}
+        Ok(())
+    }
+}
+
+/// Writes lines of rotating printable characters for as long as the client reads them,
+/// ignoring whatever it sends, so that the writes keep up with the client continuously.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Chargen;
+
+impl Handler for Chargen {
+    async fn handle(&mut self, conn: &mut Connection) -> Result<()> {
+        while !conn.is_draining() {
+            conn.write(&CHARGEN_PATTERN).await?;
+        }
+
+        conn.shutdown().await
+    }
+}
+
+/// Writes the current date and time in UTC, then closes the connection.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Daytime;
+
+impl Handler for Daytime {
+    async fn handle(&mut self, conn: &mut Connection) -> Result<()> {
+        conn.write(daytime(SystemTime::now()).as_bytes()).await?;
+        conn.shutdown().await
+    }
+}
+
+/// Formats the `time` like `Thursday, October 15, 2026 09:30:00-UTC\r\n`, which is what
+/// RFC 867 suggests.
+fn daytime(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+
+    let days = secs / 86_400;
+    let (year, month, day) = civil_from_days(days);
+    let secs = secs % 86_400;
+
+    format!(
+        "{}, {} {day}, {year} {:02}:{:02}:{:02}-UTC\r\n",
+        WEEKDAYS[(days % 7) as usize],
+        MONTHS[month as usize - 1],
+        secs / 3600,
+        secs / 60 % 60,
+        secs % 60,
+    )
+}
+
+/// The year, month and day of the `days` since the epoch in the proleptic Gregorian calendar,
+/// after Howard Hinnant's algorithm.
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    // Counted from 0000-03-01 so that the leap day ends a year.
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days % 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month + 2) / 5 + 1;
+    let month = if month < 10 { month + 3 } else { month - 9 };
+    let year = era * 400 + year_of_era + u64::from(month <= 2);
+    (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn chargen_lines_rotate() {
+        let lines = CHARGEN_PATTERN
+            .split(|&byte| byte == b'\n')
+            .collect::<Vec<_>>();
+        assert_eq!(lines.len(), 96);
+        assert!(lines[95].is_empty());
+        assert!(lines[..95].iter().all(|line| line.len() == 73));
+        assert!(lines[0].starts_with(b" !\"#$%&"));
+        assert!(lines[1].starts_with(b"!\"#$%&'"));
+        assert!(lines[94].starts_with(b"~ !\"#$%"));
+    }
+
+    #[test]
+    fn daytime_in_utc() {
+        assert_eq!(
+            daytime(UNIX_EPOCH),
+            "Thursday, January 1, 1970 00:00:00-UTC\r\n"
+        );
+
+        let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400 + 3723);
+        assert_eq!(
+            daytime(leap_day),
+            "Tuesday, February 29, 2000 01:02:03-UTC\r\n"
+        );
+
+        let later = UNIX_EPOCH + Duration::from_secs(1_792_060_199);
+        assert_eq!(
+            daytime(later),
+            "Thursday, October 15, 2026 10:29:59-UTC\r\n"
+        );
+    }
+}
@@ -0,0 +1,103 @@
+use std::fs::File;
+use std::io::Read;
+use std::os::fd::{FromRawFd, OwnedFd};
+
+use anyhow::{Context as _, Result};
+
+use crate::builder::Handle;
+use crate::remote::Command;
+
+pub type SignalInfo = libc::signalfd_siginfo;
+
+/// Signals that make the server shut down.
+pub const SHUTDOWN_SIGNALS: [libc::c_int; 2] = [libc::SIGINT, libc::SIGTERM];
+
+/// Signal that makes the server reload its runtime config.
+pub const RELOAD_SIGNAL: libc::c_int = libc::SIGHUP;
+
+/// Signal that makes the server log a summary of its state.
+pub const STATS_SIGNAL: libc::c_int = libc::SIGUSR1;
+
+/// Signal that makes the server drain: shut down like on [`SHUTDOWN_SIGNALS`] but with its own
+/// deadline, a goodbye to the clients still connected then and a distinct exit status.
+pub const DRAIN_SIGNAL: libc::c_int = libc::SIGQUIT;
+
+/// All signals handled by the server.
+pub const HANDLED_SIGNALS: [libc::c_int; 5] = [
+    libc::SIGINT,
+    libc::SIGTERM,
+    RELOAD_SIGNAL,
+    STATS_SIGNAL,
+    DRAIN_SIGNAL,
+];
+
+/// Blocks the signals for the calling thread and the threads it spawns afterwards so that they
+/// are delivered through a signalfd only.
+pub fn block(signals: &[libc::c_int]) -> Result<()> {
+    let set = sigset(signals);
+
+    match unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, std::ptr::null_mut()) } {
+        0 => Ok(()),
+        errno => Err(std::io::Error::from_raw_os_error(errno)).context("Block signals"),
+    }
+}
+
+/// Creates a signalfd for the previously blocked signals.
+pub fn signalfd(signals: &[libc::c_int], nonblocking: bool) -> Result<OwnedFd> {
+    let set = sigset(signals);
+    let mut flags = libc::SFD_CLOEXEC;
+
+    if nonblocking {
+        flags |= libc::SFD_NONBLOCK;
+    }
+
+    match unsafe { libc::signalfd(-1, &set, flags) } {
+        fd if fd < 0 => Err(std::io::Error::last_os_error()).context("Create signalfd"),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+/// Reads signals from a blocking `signalfd` and sends them to all the servers forever.
+pub fn forward(signalfd: OwnedFd, servers: Handle) -> Result<()> {
+    let mut signalfd = File::from(signalfd);
+    let mut info: SignalInfo = unsafe { std::mem::zeroed() };
+
+    loop {
+        let bytes = unsafe {
+            std::slice::from_raw_parts_mut(
+                &mut info as *mut SignalInfo as *mut u8,
+                std::mem::size_of::<SignalInfo>(),
+            )
+        };
+
+        signalfd.read_exact(bytes).context("Read signalfd")?;
+
+        servers.send(Command::Signal(info.ssi_signo))?;
+    }
+}
+
+pub fn name(signal: u32) -> String {
+    let name = unsafe { libc::strsignal(signal as libc::c_int) };
+
+    if name.is_null() {
+        return format!("signal {signal}");
+    }
+
+    unsafe { std::ffi::CStr::from_ptr(name) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+fn sigset(signals: &[libc::c_int]) -> libc::sigset_t {
+    let mut set = unsafe { std::mem::zeroed() };
+
+    unsafe {
+        libc::sigemptyset(&mut set);
+
+        for &signal in signals {
+            libc::sigaddset(&mut set, signal);
+        }
+    }
+
+    set
+}
@@ -0,0 +1,158 @@
+use crate::common::{Generation, Id};
+
+/// Values at dense indexes reused once the values are removed, which keeps the indexes small
+/// enough to be routed along with their generations telling apart the values taking turns at
+/// the same index.
+pub struct Slab<T> {
+    entries: Vec<Entry<T>>,
+    free: Vec<Id>,
+    len: usize,
+}
+
+struct Entry<T> {
+    /// Bumped each time the value is removed.
+    generation: Generation,
+    value: Option<T>,
+}
+
+impl<T> Slab<T> {
+    pub fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+            free: Vec::new(),
+            len: 0,
+        }
+    }
+
+    /// Takes an index for a value to be [`Slab::fill`]ed in later, so that the value may know
+    /// its index and generation. It's released with [`Slab::remove`] if the value isn't needed
+    /// after all.
+    pub fn reserve(&mut self) -> (Id, Generation) {
+        let id = match self.free.pop() {
+            Some(id) => id,
+            None => {
+                self.entries.push(Entry {
+                    generation: 0,
+                    value: None,
+                });
+
+                (self.entries.len() - 1) as Id
+            }
+        };
+
+        (id, self.entries[id as usize].generation)
+    }
+
+    /// Puts the value at the index reserved for it.
+    pub fn fill(&mut self, id: Id, value: T) {
+        let entry = &mut self.entries[id as usize];
+        assert!(entry.value.is_none(), "Index #{id} is taken");
+        entry.value = Some(value);
+        self.len += 1;
+    }
+
+    pub fn get_mut(&mut self, id: Id, generation: Generation) -> Option<&mut T> {
+        match self.entries.get_mut(id as usize) {
+            Some(entry) if entry.generation == generation => entry.value.as_mut(),
+            _ => None,
+        }
+    }
+
+    /// Removes the value releasing its index, or only releases the index if it's reserved.
+    pub fn remove(&mut self, id: Id, generation: Generation) -> Option<T> {
+        let entry = match self.entries.get_mut(id as usize) {
+            Some(entry) if entry.generation == generation => entry,
+            _ => return None,
+        };
+
+        let value = entry.value.take();
+        entry.generation = entry.generation.wrapping_add(1);
+        self.free.push(id);
+
+        if value.is_some() {
+            self.len -= 1;
+        }
+
+        value
+    }
+
+    /// Indexes of the values along with their generations.
+    pub fn keys(&self) -> impl Iterator<Item = (Id, Generation)> + '_ {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| entry.value.is_some())
+            .map(|(id, entry)| (id as Id, entry.generation))
+    }
+
+    /// Values along with their indexes.
+    pub fn iter(&self) -> impl Iterator<Item = (Id, &T)> + '_ {
+        self.entries
+            .iter()
+            .enumerate()
+            .filter_map(|(id, entry)| Some((id as Id, entry.value.as_ref()?)))
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reused_indexes_tell_generations_apart() {
+        let mut slab = Slab::new();
+        let (id, generation) = slab.reserve();
+        slab.fill(id, "first");
+        assert_eq!(slab.remove(id, generation), Some("first"));
+
+        let (reused, next) = slab.reserve();
+        assert_eq!(reused, id);
+        assert_ne!(next, generation);
+        slab.fill(reused, "second");
+
+        // The key of the removed value reaches neither it nor the one at its index now.
+        assert_eq!(slab.get_mut(id, generation), None);
+        assert_eq!(slab.remove(id, generation), None);
+        assert_eq!(slab.get_mut(reused, next), Some(&mut "second"));
+        assert_eq!(slab.len(), 1);
+    }
+
+    #[test]
+    fn reserved_indexes_are_filled_later() {
+        let mut slab = Slab::new();
+        let (id, generation) = slab.reserve();
+        let (other, _) = slab.reserve();
+        assert_ne!(id, other);
+
+        // Reserved but not filled yet.
+        assert_eq!(slab.get_mut(id, generation), None);
+        assert!(slab.is_empty());
+        assert_eq!(slab.keys().count(), 0);
+
+        slab.fill(id, 1);
+        assert_eq!(slab.get_mut(id, generation), Some(&mut 1));
+        assert_eq!(slab.keys().collect::<Vec<_>>(), [(id, generation)]);
+
+        // Released without ever being filled.
+        assert_eq!(slab.remove(other, 0), None);
+        assert_eq!(slab.reserve().0, other);
+        assert_eq!(slab.len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "is taken")]
+    fn filling_a_taken_index_panics() {
+        let mut slab = Slab::new();
+        let (id, _) = slab.reserve();
+        slab.fill(id, 1);
+        slab.fill(id, 2);
+    }
+}
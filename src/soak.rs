@@ -0,0 +1,88 @@
+//! Soak mode for long runs: the workers check their accounting invariants periodically and the
+//! first one reports how much the resident memory and the open descriptors of the process have
+//! grown since the first check, so that buffers, tasks or sockets leaking slowly show up.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{Context as _, Result};
+
+/// When a worker has checked last and what the process has used then first.
+pub struct Soak {
+    pub interval: Duration,
+    last_check: Instant,
+    baseline: Option<Usage>,
+}
+
+/// Resources of the whole process.
+#[derive(Clone, Copy, Debug)]
+pub struct Usage {
+    /// Resident set size in bytes.
+    pub rss: u64,
+    pub fds: u64,
+}
+
+impl Soak {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last_check: Instant::now(),
+            baseline: None,
+        }
+    }
+
+    /// Whether it's time for the next check, in which case it's counted as done.
+    pub fn is_due(&mut self) -> bool {
+        if self.last_check.elapsed() < self.interval {
+            return false;
+        }
+
+        self.last_check = Instant::now();
+        true
+    }
+
+    /// The current usage along with the one of the first check.
+    pub fn usage(&mut self) -> Result<(Usage, Usage)> {
+        let usage = Usage::current()?;
+        Ok((usage, *self.baseline.get_or_insert(usage)))
+    }
+}
+
+impl Usage {
+    pub fn current() -> Result<Self> {
+        let statm = std::fs::read_to_string("/proc/self/statm").context("Read memory usage")?;
+
+        let pages = statm
+            .split_whitespace()
+            .nth(1)
+            .and_then(|pages| pages.parse::<u64>().ok())
+            .with_context(|| format!("Invalid memory usage {statm:?}"))?;
+
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+
+        // Counting the descriptor of the directory itself as well, the same every time.
+        let fds = std::fs::read_dir("/proc/self/fd")
+            .context("List open descriptors")?
+            .collect::<std::io::Result<Vec<_>>>()
+            .context("List open descriptors")?
+            .len() as u64;
+
+        Ok(Self {
+            rss: pages * page_size,
+            fds,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn usage_of_the_process() {
+        let usage = Usage::current().unwrap();
+        assert!(usage.rss > 0);
+
+        // At least the standard streams.
+        assert!(usage.fds >= 3);
+    }
+}
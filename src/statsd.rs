@@ -0,0 +1,118 @@
+use std::fmt::Write as _;
+use std::net::{SocketAddr, UdpSocket};
+use std::os::fd::AsRawFd;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{Context as _, Result};
+use io_uring::opcode;
+use io_uring::types::Fd;
+
+use crate::io::Io;
+use crate::metrics::{Kind, Metrics, Snapshot};
+use crate::utils::Errno;
+
+/// Keeps datagrams within the payload of an Ethernet frame so that they aren't fragmented.
+const MAX_DATAGRAM_SIZE: usize = 1432;
+
+/// Where and how to flush the metrics.
+#[derive(Clone, Debug)]
+pub struct Statsd {
+    pub address: SocketAddr,
+    /// Prepended to the metric names along with a dot.
+    pub prefix: String,
+    pub interval: Duration,
+    /// DogStatsD tags added to every metric; plain statsd if empty.
+    pub tags: Vec<String>,
+}
+
+impl Statsd {
+    /// Flushes the metrics every interval: counters as the increments since the last flush,
+    /// gauges as they are, a few metrics per datagram.
+    pub async fn export(self, metrics: Arc<Metrics>, io: Io) -> Result<()> {
+        let socket = UdpSocket::bind(match self.address {
+            SocketAddr::V4(_) => "0.0.0.0:0",
+            SocketAddr::V6(_) => "[::]:0",
+        })
+        .context("Bind statsd socket")?;
+
+        socket
+            .connect(self.address)
+            .with_context(|| format!("Connect to statsd at {}", self.address))?;
+
+        let mut last = Snapshot::default();
+
+        loop {
+            io.sleep(self.interval).await?;
+            let snapshot = metrics.snapshot();
+
+            for datagram in self.datagrams(&snapshot, &last) {
+                let sqe = opcode::Send::new(
+                    Fd(socket.as_raw_fd()),
+                    datagram.as_ptr(),
+                    datagram.len() as u32,
+                );
+
+                let (cqe, _) = io
+                    .submit_keeping(sqe.build(), datagram, "statsd send")
+                    .await?;
+
+                // Nobody listening on the other end is no reason to stop.
+                match cqe.result() {
+                    errno if errno < 0 => debug!("Send metrics error: {}", Errno(-errno)),
+                    _ => (),
+                }
+            }
+
+            last = snapshot;
+        }
+    }
+
+    fn datagrams(&self, snapshot: &Snapshot, last: &Snapshot) -> Vec<String> {
+        let mut lines = Vec::new();
+
+        for ((name, kind, value), (_, _, last)) in
+            snapshot.metrics().into_iter().zip(last.metrics())
+        {
+            lines.push(match kind {
+                Kind::Counter => format!("{}.{name}:{}|c", self.prefix, value.saturating_sub(last)),
+                Kind::Gauge => format!("{}.{name}:{value}|g", self.prefix),
+            });
+        }
+
+        let (batch, cqes) = snapshot.per_wakeup(last);
+        lines.push(format!(
+            "{}.ring.submitted_per_wakeup:{batch:.2}|g",
+            self.prefix
+        ));
+        lines.push(format!(
+            "{}.ring.completions_per_wakeup:{cqes:.2}|g",
+            self.prefix
+        ));
+
+        let mut datagrams = Vec::new();
+        let mut datagram = String::new();
+
+        for mut line in lines {
+            if !self.tags.is_empty() {
+                write!(line, "|#{}", self.tags.join(",")).ok();
+            }
+
+            if !datagram.is_empty() && datagram.len() + 1 + line.len() > MAX_DATAGRAM_SIZE {
+                datagrams.push(std::mem::take(&mut datagram));
+            }
+
+            if !datagram.is_empty() {
+                datagram.push('\n');
+            }
+
+            datagram.push_str(&line);
+        }
+
+        if !datagram.is_empty() {
+            datagrams.push(datagram);
+        }
+
+        datagrams
+    }
+}
@@ -0,0 +1,91 @@
+use crate::config::TransformKind;
+
+/// Rewrites each echoed message in place between reading and writing it, so that clients get
+/// responses which differ from their requests in a way they can check.
+pub trait Transform {
+    fn apply(&self, message: &mut [u8]);
+}
+
+/// The transform of the `kind`, with the `key` being what [`Xor`] flips the bits with.
+pub fn transform(kind: TransformKind, key: u8) -> Box<dyn Transform> {
+    match kind {
+        TransformKind::Identity => Box::new(Identity),
+        TransformKind::Uppercase => Box::new(Uppercase),
+        TransformKind::Reverse => Box::new(Reverse),
+        TransformKind::Xor => Box::new(Xor(key)),
+        TransformKind::Rot13 => Box::new(Rot13),
+    }
+}
+
+pub struct Identity;
+
+impl Transform for Identity {
+    fn apply(&self, _message: &mut [u8]) {}
+}
+
+/// ASCII letters only.
+pub struct Uppercase;
+
+impl Transform for Uppercase {
+    fn apply(&self, message: &mut [u8]) {
+        message.make_ascii_uppercase();
+    }
+}
+
+/// Bytes in reverse order.
+pub struct Reverse;
+
+impl Transform for Reverse {
+    fn apply(&self, message: &mut [u8]) {
+        message.reverse();
+    }
+}
+
+pub struct Xor(pub u8);
+
+impl Transform for Xor {
+    fn apply(&self, message: &mut [u8]) {
+        for byte in message {
+            *byte ^= self.0;
+        }
+    }
+}
+
+/// ASCII letters rotated by 13 places, so that applying it twice gives the message back.
+pub struct Rot13;
+
+impl Transform for Rot13 {
+    fn apply(&self, message: &mut [u8]) {
+        for byte in message {
+            *byte = match *byte {
+                b'a'..=b'z' => (*byte - b'a' + 13) % 26 + b'a',
+                b'A'..=b'Z' => (*byte - b'A' + 13) % 26 + b'A',
+                other => other,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn applied(transform: &dyn Transform, message: &[u8]) -> Vec<u8> {
+        let mut message = message.to_vec();
+        transform.apply(&mut message);
+        message
+    }
+
+    #[test]
+    fn transforms() {
+        assert_eq!(applied(&Identity, b"Hello, 42!"), b"Hello, 42!");
+        assert_eq!(applied(&Uppercase, b"Hello, 42!"), b"HELLO, 42!");
+        assert_eq!(applied(&Reverse, b"Hello, 42!"), b"!24 ,olleH");
+        assert_eq!(
+            applied(&Xor(0x20), b"Hello, 42!"),
+            b"hELLO\x0c\x00\x14\x12\x01"
+        );
+        assert_eq!(applied(&Rot13, b"Hello, 42!"), b"Uryyb, 42!");
+        assert_eq!(applied(&Rot13, b"Uryyb, 42!"), b"Hello, 42!");
+    }
+}
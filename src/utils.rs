@@ -1,5 +1,10 @@
+use std::cell::Cell;
 use std::ffi::CStr;
 use std::fmt;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+use crate::log;
 
 #[derive(Clone, Copy, Debug)]
 pub struct Errno(pub libc::c_int);
@@ -10,3 +15,188 @@ impl fmt::Display for Errno {
         write!(f, "{} ({})", err.to_str().map_err(|_| fmt::Error)?, self.0)
     }
 }
+
+/// Wraps the error with the hint for its errno if there's one, which tells how to get past it.
+pub fn explain(err: std::io::Error, hints: &[(libc::c_int, &'static str)]) -> anyhow::Error {
+    let hint = hints
+        .iter()
+        .find(|&&(errno, _)| err.raw_os_error() == Some(errno));
+
+    match hint {
+        Some(&(_, hint)) => anyhow::Error::new(err).context(hint),
+        None => err.into(),
+    }
+}
+
+/// Logs the message payload at the trace level if it's sampled, truncated to the configured
+/// number of bytes.
+pub fn print_message(source: impl fmt::Display, message: &[u8]) {
+    let Some(shown) = log::sample_payload(message) else {
+        return;
+    };
+
+    let truncated = match shown.len() < message.len() {
+        true => "...",
+        false => "",
+    };
+
+    // A character cut in half by truncation doesn't make the text binary.
+    let text = match std::str::from_utf8(shown) {
+        Ok(text) => Some(text),
+        Err(err) if err.error_len().is_none() && !truncated.is_empty() => {
+            std::str::from_utf8(&shown[..err.valid_up_to()]).ok()
+        }
+        Err(_) => None,
+    };
+
+    if let Some(text) = text {
+        trace!(
+            "Unicode message from {} of {} bytes: {}{}",
+            source,
+            message.len(),
+            text,
+            truncated
+        );
+    } else {
+        trace!(
+            "Binary message from {} of {} bytes: {:02x?}{}",
+            source,
+            message.len(),
+            shown,
+            truncated
+        );
+    }
+}
+
+pub fn socket_addr(storage: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match storage.ss_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddrV4::new(ip, u16::from_be(addr.sin_port)).into())
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(storage as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            let port = u16::from_be(addr.sin6_port);
+            Some(SocketAddrV6::new(ip, port, addr.sin6_flowinfo, addr.sin6_scope_id).into())
+        }
+        _ => None,
+    }
+}
+
+/// The NUMA node of the CPU, if the kernel knows of any.
+pub fn cpu_node(cpu: usize) -> Option<u32> {
+    let entries = std::fs::read_dir(format!("/sys/devices/system/cpu/cpu{cpu}")).ok()?;
+
+    entries
+        .filter_map(|entry| {
+            entry
+                .ok()?
+                .file_name()
+                .to_str()?
+                .strip_prefix("node")?
+                .parse()
+                .ok()
+        })
+        .next()
+}
+
+/// Pins the calling thread to the CPU.
+pub fn pin_thread(cpu: usize) -> std::io::Result<()> {
+    let mut set: libc::cpu_set_t = unsafe { std::mem::zeroed() };
+    unsafe { libc::CPU_SET(cpu, &mut set) };
+
+    match unsafe { libc::sched_setaffinity(0, std::mem::size_of_val(&set), &set) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+pub fn eventfd() -> std::io::Result<OwnedFd> {
+    match unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) } {
+        fd if fd < 0 => Err(std::io::Error::last_os_error()),
+        fd => Ok(unsafe { OwnedFd::from_raw_fd(fd) }),
+    }
+}
+
+/// Sets an integer socket option which the standard library and socket2 have no setter for.
+pub fn set_option(
+    socket: &impl AsRawFd,
+    level: libc::c_int,
+    name: libc::c_int,
+    value: libc::c_int,
+) -> std::io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &value as *const _ as *const libc::c_void,
+            std::mem::size_of_val(&value) as libc::socklen_t,
+        )
+    };
+
+    match result {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Creates a pipe, returning its reading end and writing end.
+pub fn pipe() -> std::io::Result<(OwnedFd, OwnedFd)> {
+    let mut fds = [0; 2];
+
+    match unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } {
+        0 => Ok(unsafe { (OwnedFd::from_raw_fd(fds[0]), OwnedFd::from_raw_fd(fds[1])) }),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+/// Adds one to the counter of the eventfd, waking up its reader. The counter only overflows
+/// after 2^64 - 1 bumps nobody has read.
+pub fn bump(eventfd: &OwnedFd) -> std::io::Result<()> {
+    let value = 1u64;
+    let result = unsafe {
+        libc::write(
+            eventfd.as_raw_fd(),
+            &value as *const u64 as *const libc::c_void,
+            std::mem::size_of::<u64>(),
+        )
+    };
+
+    match result {
+        -1 => Err(std::io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+/// A pseudorandom number from a xorshift generator of the calling thread seeded by the kernel,
+/// good enough for spreading delays but not for anything secret.
+pub fn random() -> u64 {
+    thread_local! {
+        static STATE: Cell<u64> = Cell::new(seed());
+    }
+
+    STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        state.set(x);
+        x
+    })
+}
+
+/// Never zero, which would make xorshift produce zeros only.
+fn seed() -> u64 {
+    let mut seed = 0u64;
+    unsafe {
+        libc::getrandom(
+            (&mut seed as *mut u64).cast(),
+            std::mem::size_of::<u64>(),
+            0,
+        )
+    };
+    seed | 1
+}
@@ -0,0 +1,65 @@
+//! Helpers shared by the tests running a server, each test crate using its own share of them.
+
+#![allow(dead_code)]
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A port on the loopback nobody listens on, for a server to listen on.
+pub fn free_port() -> u16 {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port()
+}
+
+/// Connects to the server at the `address`, waiting for it to start listening.
+pub fn connect(address: SocketAddr) -> TcpStream {
+    let deadline = Instant::now() + Duration::from_secs(5);
+
+    let stream = loop {
+        match TcpStream::connect(address) {
+            Ok(stream) => break stream,
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(10)),
+            Err(err) => panic!("Connect to the server: {err}"),
+        }
+    };
+
+    stream
+        .set_read_timeout(Some(Duration::from_secs(30)))
+        .unwrap();
+    stream
+}
+
+/// Writes `data` from another thread not to deadlock on full socket buffers and reads it back.
+pub fn echo(stream: &TcpStream, data: &[u8]) -> Vec<u8> {
+    let mut writer = stream.try_clone().unwrap();
+    let mut reader = stream;
+    let mut echoed = vec![0; data.len()];
+
+    thread::scope(|scope| {
+        scope.spawn(|| writer.write_all(data).unwrap());
+        reader.read_exact(&mut echoed).unwrap();
+    });
+
+    echoed
+}
+
+/// Raises the soft limit on open descriptors to the hard one and returns it.
+pub fn raise_fd_limit() -> usize {
+    let mut limit = libc::rlimit {
+        rlim_cur: 0,
+        rlim_max: 0,
+    };
+
+    assert_eq!(
+        unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) },
+        0
+    );
+    limit.rlim_cur = limit.rlim_max;
+    assert_eq!(unsafe { libc::setrlimit(libc::RLIMIT_NOFILE, &limit) }, 0);
+    limit.rlim_cur.try_into().unwrap_or(usize::MAX)
+}
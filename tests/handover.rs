@@ -0,0 +1,62 @@
+//! Upgrades through the server binary, the new server taking the listeners and the PID file over
+//! from the running one.
+
+mod common;
+
+use std::net::TcpStream;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+fn start(port: u16, dir: &Path) -> Child {
+    Command::new(env!("CARGO_BIN_EXE_uring"))
+        .args(["--address", "127.0.0.1", "--port", &port.to_string()])
+        .arg("--pid-file")
+        .arg(dir.join("uring.pid"))
+        .arg("--handover-socket")
+        .arg(dir.join("handover.sock"))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap()
+}
+
+/// Waits for the PID file to have the id of the `server`.
+fn wait_for_pid(dir: &Path, server: &Child) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let pid = format!("{}\n", server.id());
+
+    while std::fs::read_to_string(dir.join("uring.pid")).ok() != Some(pid.clone()) {
+        assert!(Instant::now() < deadline, "PID file not written");
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+#[test]
+fn takes_the_pid_file_over() {
+    let port = common::free_port();
+
+    let dir = std::env::temp_dir().join(format!("uring-handover-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    let mut old = start(port, &dir);
+    wait_for_pid(&dir, &old);
+
+    let mut new = start(port, &dir);
+    wait_for_pid(&dir, &new);
+
+    // Drains once the new server is ready, leaving the PID file to it.
+    assert!(old.wait().unwrap().success());
+    assert_eq!(
+        std::fs::read_to_string(dir.join("uring.pid")).unwrap(),
+        format!("{}\n", new.id())
+    );
+
+    TcpStream::connect(("127.0.0.1", port)).unwrap();
+    assert!(new.try_wait().unwrap().is_none());
+
+    new.kill().unwrap();
+    new.wait().unwrap();
+    std::fs::remove_dir_all(&dir).ok();
+}
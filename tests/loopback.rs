@@ -0,0 +1,214 @@
+//! Echoes over loopback through a server running in the test process.
+
+mod common;
+
+use std::future::Future;
+use std::io::{Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use futures::channel::oneshot;
+use socket2::SockRef;
+use uring::config::ServerConfig;
+use uring::{Connection, Echo, Handle, Handler, ServerBuilder};
+
+use self::common::echo;
+
+const BUFFER_SIZE: u32 = 4096;
+
+struct Server {
+    address: SocketAddr,
+    handle: Handle,
+    thread: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl Server {
+    fn start() -> Self {
+        Self::start_with(ServerConfig::default())
+    }
+
+    fn start_with(config: ServerConfig) -> Self {
+        Self::start_with_handler(config, Echo)
+    }
+
+    fn start_with_handler(
+        config: ServerConfig,
+        handler: impl Handler + Clone + Send + Sync + 'static,
+    ) -> Self {
+        let address = SocketAddr::from(([127, 0, 0, 1], common::free_port()));
+        let builder = ServerBuilder::from_config(config)
+            .address(address)
+            .buffers(256, BUFFER_SIZE)
+            .handler(handler);
+
+        let handle = builder.handle();
+        let thread = thread::spawn(move || builder.run());
+
+        // Until the server is running, which it is once it accepts.
+        common::connect(address);
+
+        Self {
+            address,
+            handle,
+            thread: Some(thread),
+        }
+    }
+
+    fn connect(&self) -> TcpStream {
+        common::connect(self.address)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.handle.shutdown().unwrap();
+        let result = self.thread.take().unwrap().join().unwrap();
+
+        if !thread::panicking() {
+            result.unwrap();
+        }
+    }
+}
+
+#[test]
+fn small_messages() {
+    let server = Server::start();
+    let stream = server.connect();
+
+    for message in [&b"a"[..], b"hello\n", b"hello, world"] {
+        assert_eq!(echo(&stream, message), message);
+    }
+}
+
+#[test]
+fn larger_than_buffer() {
+    let server = Server::start();
+    let stream = server.connect();
+    let data = (0..BUFFER_SIZE as usize * 64 + 7)
+        .map(|i| b'a' + (i % 26) as u8)
+        .collect::<Vec<_>>();
+
+    assert!(echo(&stream, &data) == data);
+}
+
+#[test]
+fn binary_data() {
+    let server = Server::start();
+    let stream = server.connect();
+    let data = (0..=u8::MAX).cycle().take(3 * 256 + 1).collect::<Vec<_>>();
+    assert!(echo(&stream, &data) == data);
+
+    // The end of the stream isn't confused with a zero byte.
+    let mut stream = stream;
+    stream.write_all(&[0; 16]).unwrap();
+    stream.shutdown(Shutdown::Write).unwrap();
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, [0; 16]);
+}
+
+#[test]
+fn survives_resets() {
+    let server = Server::start();
+
+    for _ in 0..16 {
+        let mut stream = server.connect();
+        stream.write_all(b"going away").unwrap();
+
+        // Closing with a zero linger sends a reset instead of a FIN.
+        SockRef::from(&stream)
+            .set_linger(Some(Duration::ZERO))
+            .unwrap();
+    }
+
+    let stream = server.connect();
+    assert_eq!(echo(&stream, b"still there"), b"still there");
+}
+
+/// Replies once a thread of its own wakes it up, as the sender of a channel does.
+#[derive(Clone)]
+struct WokenFromThread;
+
+impl Handler for WokenFromThread {
+    fn handle(&mut self, conn: &mut Connection) -> impl Future<Output = anyhow::Result<()>> {
+        let (sender, receiver) = oneshot::channel();
+
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            sender.send(())
+        });
+
+        async move {
+            receiver.await?;
+            conn.write(b"woken").await
+        }
+    }
+}
+
+#[test]
+fn wakes_from_other_threads() {
+    // Nothing but the wake is to interrupt waiting for events.
+    let config = ServerConfig {
+        tick_interval_ms: 60_000,
+        ..ServerConfig::default()
+    };
+
+    let server = Server::start_with_handler(config, WokenFromThread);
+    let mut stream = server.connect();
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .unwrap();
+
+    let mut reply = Vec::new();
+    stream.read_to_end(&mut reply).unwrap();
+    assert_eq!(reply, b"woken");
+}
+
+#[test]
+fn thousands_of_connections() {
+    const CONNECTIONS: usize = 2000;
+    // Both ends of each connection are in the process, which has a few open besides them.
+    const RESERVED_FDS: usize = 256;
+
+    let fd_limit = common::raise_fd_limit();
+
+    if fd_limit < 2 * CONNECTIONS + RESERVED_FDS {
+        eprintln!("Skipping, {CONNECTIONS} connections don't fit within {fd_limit} descriptors");
+        return;
+    }
+
+    let server = Server::start();
+    let streams = (0..CONNECTIONS)
+        .map(|_| server.connect())
+        .collect::<Vec<_>>();
+
+    for (i, mut stream) in streams.iter().enumerate() {
+        stream.write_all(format!("{i:08}").as_bytes()).unwrap();
+    }
+
+    for (i, mut stream) in streams.iter().enumerate() {
+        let mut echoed = [0; 8];
+        stream.read_exact(&mut echoed).unwrap();
+        assert_eq!(echoed, format!("{i:08}").as_bytes());
+    }
+}
+
+#[test]
+fn drain_says_goodbye() {
+    let server = Server::start_with(ServerConfig {
+        drain_timeout_ms: 200,
+        drain_goodbye: Some("bye\n".into()),
+        ..ServerConfig::default()
+    });
+
+    // Yet to send anything once accepted, so the client gets until the deadline to.
+    let mut stream = server.connect();
+    thread::sleep(Duration::from_millis(50));
+    server.handle.drain().unwrap();
+
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).unwrap();
+    assert_eq!(rest, b"bye\n");
+    assert!(server.handle.is_drained());
+}
@@ -0,0 +1,91 @@
+//! Echoes messages much larger than the fixed buffers through the server binary, and responds
+//! with a file as large.
+
+mod common;
+
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::process::{Child, Command, Stdio};
+
+const MESSAGE_SIZE: usize = 8 * 1024 * 1024;
+
+struct Server(Child);
+
+impl Server {
+    fn start(args: &[&str]) -> (Self, TcpStream) {
+        let port = common::free_port();
+
+        let child = Command::new(env!("CARGO_BIN_EXE_uring"))
+            .args(["--address", "127.0.0.1", "--port", &port.to_string()])
+            .args(["--buffer-size", "4096", "--buffers-count", "16"])
+            .args(args)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+
+        let server = Self(child);
+        let stream = common::connect(SocketAddr::from(([127, 0, 0, 1], port)));
+        (server, stream)
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        self.0.kill().ok();
+        self.0.wait().ok();
+    }
+}
+
+fn payload(len: usize) -> Vec<u8> {
+    (0..len).map(|i| b'a' + (i % 26) as u8).collect()
+}
+
+#[test]
+fn raw() {
+    let (_server, stream) = Server::start(&[]);
+    let data = payload(MESSAGE_SIZE);
+    assert!(common::echo(&stream, &data) == data);
+}
+
+#[test]
+fn lines() {
+    let (_server, stream) = Server::start(&["--framing", "lines"]);
+    let data = [payload(MESSAGE_SIZE), b"\n".to_vec()].concat().repeat(2);
+    assert!(common::echo(&stream, &data) == data);
+}
+
+#[test]
+fn length_prefixed() {
+    let (_server, stream) = Server::start(&["--framing", "length-prefixed"]);
+    let header = (MESSAGE_SIZE as u32).to_be_bytes().to_vec();
+    let data = [header, payload(MESSAGE_SIZE)].concat().repeat(2);
+    assert!(common::echo(&stream, &data) == data);
+}
+
+#[test]
+fn spliced() {
+    let (_server, stream) = Server::start(&["--splice-echo"]);
+    let data = payload(MESSAGE_SIZE);
+    assert!(common::echo(&stream, &data) == data);
+}
+
+#[test]
+fn response_file() {
+    let path = std::env::temp_dir().join(format!("uring-response-{}", std::process::id()));
+    let contents = payload(MESSAGE_SIZE);
+    std::fs::write(&path, &contents).unwrap();
+
+    let (_server, mut stream) = Server::start(&[
+        "--framing",
+        "lines",
+        "--response-file",
+        path.to_str().unwrap(),
+    ]);
+
+    stream.write_all(b"one\ntwo\n").unwrap();
+    let mut response = vec![0; 2 * MESSAGE_SIZE];
+    stream.read_exact(&mut response).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert!(response == contents.repeat(2));
+}